@@ -0,0 +1,29 @@
+extern crate base64;
+extern crate chrono;
+extern crate clap;
+extern crate hex;
+extern crate monosodium;
+extern crate rand;
+extern crate schemas;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+extern crate toml;
+extern crate tooling;
+extern crate ws;
+
+use std::process::{exit};
+
+pub mod server;
+
+pub fn run_main(git_head_commit: &[u8]) -> ! {
+  monosodium::init_sodium();
+  let code = match server::_dispatch(git_head_commit) {
+    Err(e) => {
+      eprintln!("guppy-registry-dev: {:?}", e);
+      1
+    }
+    Ok(_) => 0,
+  };
+  exit(code)
+}
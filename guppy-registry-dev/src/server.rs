@@ -0,0 +1,344 @@
+use chrono::{SecondsFormat, Utc};
+use clap::{App, Arg};
+use rand::prelude::*;
+use schemas::v1::{Bot2RegistryV0, Registry2BotV0, _NewCiRunV0};
+use serde_json::{Value as JsonValue};
+use tooling::config::{ApiConfig};
+use tooling::query::{Maybe, fail};
+use tooling::wire::{sign_message, verify_message_with_id};
+
+use std::fs::{File};
+use std::io::{Read, Write, BufReader, stdout};
+use std::path::{PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct ScriptRun {
+  repo_clone_url: String,
+  originator: Option<String>,
+  ref_full: Option<String>,
+  commit_hash: Option<String>,
+  /// Seconds to wait after the daemon authenticates before sending the run.
+  delay_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Script {
+  run: Option<Vec<ScriptRun>>,
+}
+
+impl Script {
+  fn open(path: &PathBuf) -> Maybe<Script> {
+    let file = File::open(path)
+      .map_err(|_| fail("failed to open script file"))?;
+    let mut text = String::new();
+    BufReader::new(file).read_to_string(&mut text)
+      .map_err(|_| fail("failed to read script file"))?;
+    toml::from_str(&text)
+      .map_err(|e| fail(format!("script file is not valid toml: {:?}", e)))
+  }
+}
+
+struct DevState {
+  runs: Vec<ScriptRun>,
+  /// Whether each scripted run has been sent. Runs go out once, over the
+  /// first connection to authenticate.
+  sent: Vec<bool>,
+  /// Highest message id received so far. Task messages with an id no higher
+  /// than this are repeats of ones already recorded.
+  last_msg_id: Option<u64>,
+  record: Box<Write + Send>,
+}
+
+impl DevState {
+  fn record(&mut self, event: &str, mut fields: JsonValue) {
+    if let JsonValue::Object(ref mut obj) = fields {
+      obj.insert("event".to_string(), JsonValue::String(event.to_string()));
+      obj.insert("recv_ts".to_string(), JsonValue::String(Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false)));
+    }
+    writeln!(&mut self.record, "{}", fields).ok();
+    self.record.flush().ok();
+  }
+}
+
+struct DevConn {
+  registry_s: ws::Sender,
+  api_key: Vec<u8>,
+  secret_token: Vec<u8>,
+  state: Arc<Mutex<DevState>>,
+}
+
+impl DevConn {
+  fn send(&mut self, msg: &Registry2BotV0) -> ws::Result<()> {
+    let bin = match sign_message(&self.secret_token, msg) {
+      Err(e) => {
+        eprintln!("TRACE: guppy-registry-dev: failed to sign message: {:?}", e);
+        return Ok(());
+      }
+      Ok(bin) => bin,
+    };
+    self.registry_s.send(bin)
+  }
+
+  fn schedule_runs(&mut self) -> ws::Result<()> {
+    let delays: Vec<_> = {
+      let mut state = self.state.lock().unwrap();
+      let mut delays = Vec::new();
+      for run_idx in 0 .. state.runs.len() {
+        if !state.sent[run_idx] {
+          state.sent[run_idx] = true;
+          delays.push((run_idx, state.runs[run_idx].delay_secs.unwrap_or(0)));
+        }
+      }
+      delays
+    };
+    for (run_idx, delay_secs) in delays.into_iter() {
+      self.registry_s.timeout(delay_secs * 1000, ws::util::Token(run_idx))?;
+    }
+    Ok(())
+  }
+}
+
+impl ws::Handler for DevConn {
+  fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+    self.state.lock().unwrap().record("open", json!({}));
+    Ok(())
+  }
+
+  fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
+    self.state.lock().unwrap().record("close", json!({
+      "code": format!("{:?}", code),
+      "reason": reason,
+    }));
+  }
+
+  fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+    let bin = match msg {
+      ws::Message::Binary(bin) => bin,
+      ws::Message::Text(_) => return Ok(()),
+    };
+    let (msg, msg_id): (Bot2RegistryV0, _) = match verify_message_with_id(&self.secret_token, &bin) {
+      Err(e) => {
+        self.state.lock().unwrap().record("bad_message", json!({
+          "error": format!("{:?}", e),
+        }));
+        return Ok(());
+      }
+      Ok(x) => x,
+    };
+    // Repeated task messages are still acked, since the daemon matches acks
+    // to messages by the order it sent them in.
+    let repeat = {
+      let mut state = self.state.lock().unwrap();
+      match (msg_id, state.last_msg_id) {
+        (Some(id), Some(last_id)) if id <= last_id => {
+          state.record("repeat", json!({"msg_id": id}));
+          true
+        }
+        (Some(id), _) => {
+          state.last_msg_id = Some(id);
+          false
+        }
+        (None, _) => false,
+      }
+    };
+    match msg {
+      Bot2RegistryV0::Auth{api_key} => {
+        let ok = api_key == self.api_key;
+        self.state.lock().unwrap().record("auth", json!({"ok": ok}));
+        match ok {
+          false => self.send(&Registry2BotV0::Auth(None)),
+          true  => {
+            self.send(&Registry2BotV0::Auth(Some(())))?;
+            self.schedule_runs()
+          }
+        }
+      }
+      Bot2RegistryV0::RegisterMachine{machine_key, ..} => {
+        self.state.lock().unwrap().record("register_machine", json!({
+          "machine_key": hex::encode(&machine_key),
+        }));
+        self.send(&Registry2BotV0::RegisterMachine(Some(())))
+      }
+      Bot2RegistryV0::RegisterCiMachine{repo_url, ..} => {
+        self.state.lock().unwrap().record("register_ci_machine", json!({
+          "repo_url": repo_url,
+        }));
+        self.send(&Registry2BotV0::RegisterCiMachine(Some(())))
+      }
+      Bot2RegistryV0::RegisterCiRepo{repo_url, ..} => {
+        // There are no webhooks to set up, so there is nothing to reply with.
+        self.state.lock().unwrap().record("register_ci_repo", json!({
+          "repo_url": repo_url,
+        }));
+        self.send(&Registry2BotV0::RegisterCiRepo(None))
+      }
+      Bot2RegistryV0::_Ping{..} => {
+        self.send(&Registry2BotV0::_Pong)
+      }
+      Bot2RegistryV0::_NewCiRun(Some(_NewCiRunV0::Accept{ci_run_key, task_count, failed_early, ts, ..})) => {
+        if !repeat {
+          self.state.lock().unwrap().record("new_ci_run", json!({
+            "msg_id": msg_id,
+            "ci_run_key": hex::encode(&ci_run_key),
+            "task_count": task_count,
+            "failed_early": failed_early,
+            "ts": ts,
+          }));
+        }
+        Ok(())
+      }
+      Bot2RegistryV0::_NewCiRun(_) => {
+        self.state.lock().unwrap().record("new_ci_run", json!({}));
+        Ok(())
+      }
+      Bot2RegistryV0::_StartCiTask{ci_run_key, task_nr, task_name, taskspec, ts, ..} => {
+        if !repeat {
+          self.state.lock().unwrap().record("start_ci_task", json!({
+            "msg_id": msg_id,
+            "ci_run_key": hex::encode(&ci_run_key),
+            "task_nr": task_nr,
+            "task_name": task_name,
+            "taskspec": taskspec.map(|t| String::from_utf8_lossy(&t).into_owned()),
+            "ts": ts,
+          }));
+        }
+        self.send(&Registry2BotV0::_StartCiTask(Some(())))
+      }
+      Bot2RegistryV0::_AppendCiTaskData{ci_run_key, task_nr, part_nr, key, data, ts, ..} => {
+        if !repeat {
+          self.state.lock().unwrap().record("append_ci_task_data", json!({
+            "msg_id": msg_id,
+            "ci_run_key": hex::encode(&ci_run_key),
+            "task_nr": task_nr,
+            "part_nr": part_nr,
+            "key": key,
+            "data": String::from_utf8_lossy(&data),
+            "ts": ts,
+          }));
+        }
+        self.send(&Registry2BotV0::_AppendCiTaskData(Some(())))
+      }
+      Bot2RegistryV0::_DoneCiTask{ci_run_key, task_nr, failed, ts, ..} => {
+        if !repeat {
+          self.state.lock().unwrap().record("done_ci_task", json!({
+            "msg_id": msg_id,
+            "ci_run_key": hex::encode(&ci_run_key),
+            "task_nr": task_nr,
+            "failed": failed,
+            "ts": ts,
+          }));
+        }
+        self.send(&Registry2BotV0::_DoneCiTask(Some(())))
+      }
+      _ => {
+        self.state.lock().unwrap().record("unhandled_message", json!({}));
+        Ok(())
+      }
+    }
+  }
+
+  fn on_timeout(&mut self, token: ws::util::Token) -> ws::Result<()> {
+    let run = self.state.lock().unwrap().runs[token.0].clone();
+    let mut ci_run_key = vec![0_u8; 32];
+    thread_rng().fill(&mut ci_run_key[..]);
+    self.state.lock().unwrap().record("send_new_ci_run", json!({
+      "ci_run_key": hex::encode(&ci_run_key),
+      "repo_clone_url": run.repo_clone_url,
+    }));
+    self.send(&Registry2BotV0::_NewCiRun{
+      api_key: self.api_key.clone(),
+      ci_run_key,
+      repo_clone_url: run.repo_clone_url,
+      originator: run.originator,
+      ref_full: run.ref_full,
+      commit_hash: run.commit_hash,
+      runspec: None,
+    })
+  }
+}
+
+fn base64_str_to_vec(len_bytes: usize, b64_str: &str) -> Maybe<Vec<u8>> {
+  let buf = base64::decode_config(b64_str.trim(), base64::URL_SAFE)
+    .map_err(|_| fail("api config: invalid base64"))?;
+  if buf.len() != len_bytes {
+    return Err(fail("api config: wrong key length"));
+  }
+  Ok(buf)
+}
+
+pub fn _dispatch(git_head_commit: &[u8]) -> Maybe {
+  let version_str = format!("dev (git: {})", str::from_utf8(git_head_commit).unwrap());
+  let matches = App::new("guppy-registry-dev")
+    .version(version_str.as_ref())
+    .about("Local stand-in for the guppybot.org registry, for development.\nPoint the daemon at it with `url = \"ws://<LISTEN_ADDR>/\"` in the\n`[registry]` section of its api config.")
+    .arg(Arg::with_name("LISTEN_ADDR")
+      .short("l")
+      .long("listen")
+      .takes_value(true)
+      .help("Address to listen on. Defaults to '127.0.0.1:8765'.")
+    )
+    .arg(Arg::with_name("API_CONFIG")
+      .long("api-config")
+      .takes_value(true)
+      .help("Api config with the credentials the daemon uses. Defaults to\n'/etc/guppybot/api'.")
+    )
+    .arg(Arg::with_name("SCRIPT")
+      .short("s")
+      .long("script")
+      .takes_value(true)
+      .help("TOML file of CI runs to send once the daemon authenticates,\nas `[[run]]` tables with `repo_clone_url` and optionally\n`originator`, `ref_full`, `commit_hash` and `delay_secs`.")
+    )
+    .arg(Arg::with_name("RUN")
+      .short("r")
+      .long("run")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+      .help("Repository URL of a CI run to send once the daemon\nauthenticates. May be repeated.")
+    )
+    .arg(Arg::with_name("RECORD")
+      .short("o")
+      .long("record")
+      .takes_value(true)
+      .help("File to record received messages to, as JSON lines.\nDefaults to standard output.")
+    )
+    .get_matches();
+  let listen_addr = matches.value_of("LISTEN_ADDR").unwrap_or("127.0.0.1:8765").to_string();
+  let api_cfg_path = matches.value_of("API_CONFIG")
+    .map(|s| PathBuf::from(s))
+    .unwrap_or_else(|| PathBuf::from("/etc/guppybot/api"));
+  let api_cfg = ApiConfig::open_path(&api_cfg_path)?;
+  let api_key = base64_str_to_vec(48, &api_cfg.auth.api_key)?;
+  let secret_token = base64_str_to_vec(32, &api_cfg.auth.secret_token)?;
+  let mut runs = match matches.value_of("SCRIPT") {
+    None => Vec::new(),
+    Some(path) => Script::open(&PathBuf::from(path))?.run.unwrap_or_default(),
+  };
+  if let Some(urls) = matches.values_of("RUN") {
+    for url in urls {
+      runs.push(ScriptRun{
+        repo_clone_url: url.to_string(),
+        ..ScriptRun::default()
+      });
+    }
+  }
+  let record: Box<Write + Send> = match matches.value_of("RECORD") {
+    None => Box::new(stdout()),
+    Some(path) => Box::new(File::create(path)
+      .map_err(|_| fail("failed to create record file"))?),
+  };
+  let sent = vec![false; runs.len()];
+  let state = Arc::new(Mutex::new(DevState{runs, sent, last_msg_id: None, record}));
+  eprintln!("TRACE: guppy-registry-dev: listening on {}", listen_addr);
+  ws::listen(listen_addr, |registry_s| {
+    DevConn{
+      registry_s,
+      api_key: api_key.clone(),
+      secret_token: secret_token.clone(),
+      state: state.clone(),
+    }
+  })
+    .map_err(|e| fail(format!("websocket server failed: {:?}", e)))
+}
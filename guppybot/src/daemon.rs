@@ -1,32 +1,43 @@
-use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 use chrono::{SecondsFormat, Utc};
-use crossbeam_channel::{Sender, Receiver, unbounded};
+use crossbeam_channel::{Sender, Receiver, after, tick, unbounded};
 use dirs::{home_dir};
-use monosodium::{auth_sign, auth_verify};
+use mio::tcp::{TcpStream};
 use monosodium::util::{CryptoBuf};
-use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+use parking_lot::{Mutex, RwLock};
 use rand::prelude::*;
 use rand::distributions::{Uniform};
-use schemas::{Revise, deserialize_revision, serialize_revision_into};
+use schemas::{Revise};
 use schemas::v1::{DistroInfoV0, GpusV0, MachineConfigV0, SystemSetupV0, Bot2RegistryV0, Registry2BotV0, _NewCiRunV0, RegisterCiRepoV0};
 use serde::{Deserialize, Serialize};
-use tooling::config::{ApiConfig, ApiAuth, Config};
+use tooling::cache::{build_cache_mounts, prepare_build_caches, set_mutable_cache_quota};
+use tooling::config::{ApiConfig, ApiAuth, BuildCacheConfig, CiConfig, Config, DaemonConfig, MutableCacheConfig, RegistryConfig, TaskLimitsConfig, trusts_run};
+use tooling::dag::{Readiness, TaskGraph, TaskNodeState};
 use tooling::docker::*;
+use tooling::gpu::{Gpu, compatible_gpus, local_gpus, task_gpus};
 use tooling::ipc::*;
+use tooling::journal::{Journal, JournalRun, JournalTaskState, RunSource};
+use tooling::outbox::{Outbox, OutboxMsg};
+use tooling::poll::{PollState};
 use tooling::query::{Maybe, Open, Query, fail};
+use tooling::runlog::{RunLog, RunLogRun};
 use tooling::state::{ImageSpec, ImageManifest, RootManifest, Sysroot};
+use tooling::taskfile::{checkout_taskspecs};
+use tooling::webhook::{WebhookServer};
+use tooling::wire::{NewCiRunReject, sign_message_with_id, verify_message};
+use url::{Url};
 
-use std::collections::{VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::fs::{File, create_dir_all};
-use std::io::{Read, Write, Cursor};
+use std::io::{Read, Write};
 use std::path::{PathBuf};
 use std::process::{exit};
 use std::str;
 use std::sync::{Arc};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread::{JoinHandle, sleep, spawn};
-use std::time::{Duration};
+use std::time::{Duration, Instant};
 
 pub fn runloop(git_head_commit: &[u8]) -> Maybe {
   Context::new(git_head_commit)?._init(false)?.runloop()
@@ -63,6 +74,7 @@ enum BotWsMsg {
 struct BotWsConn {
   delay_lo: f64,
   delay_hi: f64,
+  registry_cfg: RegistryConfig,
   loopback_s: Sender<LoopbackMsg>,
   watchdog_s: Sender<WatchdogMsg>,
   reg2bot_s: Sender<BotWsMsg>,
@@ -72,10 +84,14 @@ struct BotWsConn {
 }
 
 impl BotWsConn {
-  pub fn new(loopback_s: Sender<LoopbackMsg>, watchdog_s: Sender<WatchdogMsg>, reg2bot_s: Sender<BotWsMsg>, reg_echo_ctr: Arc<AtomicUsize>, reconnect: Arc<Mutex<Reconnect>>, registry_s: ws::Sender) -> BotWsConn {
+  pub fn new(registry_cfg: RegistryConfig, loopback_s: Sender<LoopbackMsg>, watchdog_s: Sender<WatchdogMsg>, reg2bot_s: Sender<BotWsMsg>, reg_echo_ctr: Arc<AtomicUsize>, reconnect: Arc<Mutex<Reconnect>>, registry_s: ws::Sender) -> BotWsConn {
+    // With the default hourly interval, pings go out between 45 and 57.5
+    // minutes after the last message.
+    let ping_interval = registry_cfg.ping_interval_secs as f64;
     BotWsConn{
-      delay_lo: 3600.0 - 900.0,
-      delay_hi: 3600.0 - 150.0,
+      delay_lo: ping_interval * 0.75,
+      delay_hi: ping_interval * (1.0 - 150.0 / 3600.0),
+      registry_cfg,
       loopback_s,
       watchdog_s,
       reg2bot_s,
@@ -155,6 +171,23 @@ impl ws::Handler for BotWsConn {
     self.loopback_s.send(LoopbackMsg::_Echo{echo_ctr: token.0}).unwrap();
     Ok(())
   }
+
+  fn upgrade_ssl_client(&mut self, sock: TcpStream, url: &Url) -> ws::Result<SslStream<TcpStream>> {
+    let domain = url.domain()
+      .ok_or_else(|| ws::Error::new(ws::ErrorKind::Protocol, format!("registry url has no domain: {}", url)))?;
+    let mut builder = SslConnector::builder(SslMethod::tls())
+      .map_err(|e| ws::Error::new(ws::ErrorKind::Internal, format!("failed to set up tls: {}", e)))?;
+    if let Some(ref ca_file) = self.registry_cfg.tls_ca_file {
+      builder.set_ca_file(ca_file)
+        .map_err(|e| ws::Error::new(ws::ErrorKind::Internal, format!("failed to load tls ca file: {}", e)))?;
+    }
+    if !self.registry_cfg.tls_verify {
+      eprintln!("TRACE: BotWsConn: warning: tls certificate verification is disabled");
+      builder.set_verify(SslVerifyMode::NONE);
+    }
+    builder.build().connect(domain, sock)
+      .map_err(|e| ws::Error::new(ws::ErrorKind::Internal, format!("tls handshake failed: {}", e)))
+  }
 }
 
 struct BotWsSender {
@@ -164,6 +197,12 @@ struct BotWsSender {
 
 impl BotWsSender {
   pub fn send_auth<'a, T: Revise<'a> + Serialize>(&mut self, auth: Option<&ApiAuth>, msg: &'a T) -> Maybe {
+    self.send_auth_with_id(auth, msg, None)
+  }
+
+  /// Sends a message with an id, which the registry uses to drop the message
+  /// if it is sent again.
+  pub fn send_auth_with_id<'a, T: Revise<'a> + Serialize>(&mut self, auth: Option<&ApiAuth>, msg: &'a T, msg_id: Option<u64>) -> Maybe {
     if self.secret_token_buf.is_none() {
       //if api_cfg.is_none() {
       if auth.is_none() {
@@ -176,22 +215,7 @@ impl BotWsSender {
         return Err(fail("API authentication config is required"));
       }
     }
-    let mut bin: Vec<u8> = Vec::with_capacity(64);
-    bin.resize(36, 0_u8);
-    assert_eq!(36, bin.len());
-    serialize_revision_into(&mut bin, msg).unwrap();
-    assert!(36 <= bin.len());
-    let msg_bin_len = bin.len() - 36;
-    assert!(msg_bin_len <= u32::max_value() as usize);
-    Cursor::new(&mut bin[32 .. 36])
-      .write_u32::<LittleEndian>(msg_bin_len as u32).unwrap();
-    let (sig_buf, payload_buf) = bin.split_at_mut(32);
-    auth_sign(
-        sig_buf,
-        payload_buf,
-        self.secret_token_buf.as_ref().unwrap().as_ref(),
-    )
-      .map_err(|_| fail("API message signing failure"))?;
+    let bin = sign_message_with_id(self.secret_token_buf.as_ref().unwrap().as_ref(), msg, msg_id)?;
     self.registry_s.send(bin)
       .map_err(|_| fail("websocket transmission failure"))?;
     Ok(())
@@ -208,31 +232,31 @@ impl BotWsSender {
         return Err(fail("API authentication config is required"));
       }
     }
-    if bin.len() < 36 {
-      return Err(fail("API message protocol failure"));
-    }
-    auth_verify(
-        &bin[0 .. 32],
-        &bin[32 .. ],
-        self.secret_token_buf.as_ref().unwrap().as_ref(),
-    )
-      .map_err(|_| fail("API message verification failure"))?;
-    let msg_bin_len = Cursor::new(&bin[32 .. 36])
-      .read_u32::<LittleEndian>().unwrap() as usize;
-    if msg_bin_len != bin[36 .. ].len() {
-      return Err(fail("API message self-consistency failure"));
-    }
-    let msg: T = deserialize_revision(&bin[36 .. ])
-      .map_err(|_| fail("API message deserialization failure"))?;
-    Ok(msg)
+    verify_message(self.secret_token_buf.as_ref().unwrap().as_ref(), bin)
   }
 }
 
+/// What to check out for a new CI run, wherever it came from.
+struct NewRun {
+  remote_url: String,
+  ref_full: Option<String>,
+  commit_hash: Option<String>,
+  /// Commit to compare against for `only_paths` filters, e.g. the previous
+  /// head of a pushed branch.
+  base_hash: Option<String>,
+  originator: Option<String>,
+}
+
 enum LoopbackMsg {
   _Echo{
     echo_ctr: usize,
   },
   _Echo2,
+  Shutdown,
+  LocalCiRun{
+    source: RunSource,
+    run: NewRun,
+  },
   StartCiTask{
     api_key: Vec<u8>,
     ci_run_key: Vec<u8>,
@@ -267,9 +291,21 @@ enum WorkerLbMsg {
     task_nr: u64,
     checkout: GitCheckoutSpec,
     task: TaskSpec,
+    opts: DockerRunOpts,
   },
 }
 
+/// Tasks of an in-progress CI run, for scheduling them in dependency order.
+struct RunDag {
+  api_key: Vec<u8>,
+  checkout: GitCheckoutSpec,
+  /// Whether the tasks may write to the build caches of the repository.
+  trusted: bool,
+  tasks: Vec<TaskSpec>,
+  graph: TaskGraph,
+  states: Vec<TaskNodeState>,
+}
+
 enum Event {
   RegisterCiMachine,
   CancelRegisterCiMachine,
@@ -298,7 +334,11 @@ struct Context {
   shared: Arc<RwLock<Shared>>,
   system_setup: SystemSetupV0,
   api_cfg: Option<ApiConfig>,
+  registry_cfg: RegistryConfig,
   machine_cfg: Option<MachineConfigV0>,
+  daemon_cfg: DaemonConfig,
+  ci_cfg: Option<CiConfig>,
+  gpus: Vec<Gpu>,
   loopback_r: Receiver<LoopbackMsg>,
   loopback_s: Sender<LoopbackMsg>,
   watchdog_r: Receiver<WatchdogMsg>,
@@ -309,10 +349,26 @@ struct Context {
   ctlchan_s: Sender<CtlChannel>,
   reg2bot_r: Receiver<BotWsMsg>,
   reg2bot_s: Sender<BotWsMsg>,
+  unsettled_r: Receiver<(String, String, String)>,
+  unsettled_s: Sender<(String, String, String)>,
   reg_conn_join_h: Option<JoinHandle<()>>,
   reg_sender: Option<BotWsSender>,
   reg_echo_ctr: Arc<AtomicUsize>,
   reconnect: Arc<Mutex<Reconnect>>,
+  journal: Journal,
+  outbox: Outbox,
+  outbox_sent_seq: u64,
+  /// Sequence numbers of the messages sent but not acked, each with whether
+  /// the registry replies to it.
+  outbox_inflight: VecDeque<(u64, bool)>,
+  runlog: RunLog,
+  poll_state: Arc<PollState>,
+  local_runs: HashSet<Vec<u8>>,
+  dags: HashMap<Vec<u8>, RunDag>,
+  running: Vec<(Vec<u8>, Vec<u8>, u64)>,
+  draining: bool,
+  stopping: Option<Instant>,
+  workers_stop: Arc<AtomicBool>,
   auth_maybe: bool,
   auth: bool,
   machine_reg_maybe: bool,
@@ -384,13 +440,47 @@ impl Context {
     eprintln!("TRACE: system setup: {:?}", system_setup);
     let api_cfg = ApiConfig::open(&config).ok();
     eprintln!("TRACE: api cfg: {:?}", api_cfg);
+    let registry_cfg = api_cfg.as_ref().and_then(|cfg| cfg.registry.clone()).unwrap_or_default();
+    eprintln!("TRACE: registry cfg: {:?}", registry_cfg);
     let machine_cfg = MachineConfigV0::open(&config).ok();
     eprintln!("TRACE: machine cfg: {:?}", machine_cfg);
+    let daemon_cfg = match DaemonConfig::open(&config) {
+      Err(e) => {
+        eprintln!("TRACE: invalid daemon config, not starting: {:?}", e);
+        return Err(e);
+      }
+      Ok(cfg) => cfg,
+    };
+    eprintln!("TRACE: daemon cfg: {:?}", daemon_cfg);
+    let ci_cfg = match CiConfig::open_optional(&config) {
+      Err(e) => {
+        eprintln!("TRACE: invalid ci config, not starting: {:?}", e);
+        return Err(e);
+      }
+      Ok(cfg) => cfg,
+    };
+    if ci_cfg.is_none() {
+      eprintln!("TRACE: no ci config, accepting ci runs for any repository");
+    }
+    let gpus = local_gpus(&config);
+    eprintln!("TRACE: gpus: {:?}", gpus);
+    update_mutable_cache_quota(&config, &sysroot);
+    let draining = root_manifest.drain_bit();
+    if draining {
+      eprintln!("TRACE: draining, not accepting new ci runs");
+    }
+    let journal = Journal::open(&sysroot)?;
+    let outbox = Outbox::open(&sysroot)?;
+    eprintln!("TRACE: outbox: {} pending", outbox.len());
+    let outbox_sent_seq = outbox.acked_seq();
+    let runlog = RunLog::open(&sysroot)?;
+    let poll_state = Arc::new(PollState::open(&sysroot)?);
     let (loopback_s, loopback_r) = unbounded();
     let (watchdog_s, watchdog_r) = unbounded();
     let (workerlb_s, workerlb_r) = unbounded();
     let (ctlchan_s, ctlchan_r) = unbounded();
     let (reg2bot_s, reg2bot_r) = unbounded();
+    let (unsettled_s, unsettled_r) = unbounded();
     Ok(Context{
       shared: Arc::new(RwLock::new(Shared{
         sysroot,
@@ -399,7 +489,11 @@ impl Context {
       })),
       system_setup,
       api_cfg,
+      registry_cfg,
       machine_cfg,
+      daemon_cfg,
+      ci_cfg,
+      gpus,
       loopback_r,
       loopback_s,
       watchdog_r,
@@ -410,6 +504,8 @@ impl Context {
       ctlchan_s,
       reg2bot_r,
       reg2bot_s,
+      unsettled_r,
+      unsettled_s,
       reg_conn_join_h: None,
       reg_sender: None,
       reg_echo_ctr: Arc::new(AtomicUsize::new(0)),
@@ -423,6 +519,18 @@ impl Context {
         backoff_delay_lo: 0.0,
         backoff_delay_hi: 0.0,
       })),
+      journal,
+      outbox,
+      outbox_sent_seq,
+      outbox_inflight: VecDeque::new(),
+      runlog,
+      poll_state,
+      local_runs: HashSet::new(),
+      dags: HashMap::new(),
+      running: Vec::new(),
+      draining,
+      stopping: None,
+      workers_stop: Arc::new(AtomicBool::new(false)),
       auth_maybe: false,
       auth: false,
       machine_reg_maybe: false,
@@ -493,16 +601,17 @@ impl Context {
     if self.reg_conn_join_h.is_some() {
       eprintln!("TRACE: guppybot: reconnecting to registry");
     }
-    let api_cfg = self.api_cfg.as_ref().unwrap();
+    let registry_cfg = self.registry_cfg.clone();
     let loopback_s = self.loopback_s.clone();
     let watchdog_s = self.watchdog_s.clone();
     let reg2bot_s = self.reg2bot_s.clone();
     let reg_echo_ctr = self.reg_echo_ctr.clone();
     let reconnect = self.reconnect.clone();
     self.reg_conn_join_h = Some(spawn(move || {
-      eprintln!("TRACE: guppybot: connecting to registry...");
-      match ws::connect("wss://guppybot.org:443/w/v1/", |registry_s| {
+      eprintln!("TRACE: guppybot: connecting to registry: {}", registry_cfg.url);
+      match ws::connect(registry_cfg.url.clone(), |registry_s| {
         BotWsConn::new(
+          registry_cfg.clone(),
           loopback_s.clone(),
           watchdog_s.clone(),
           reg2bot_s.clone(),
@@ -517,13 +626,18 @@ impl Context {
         Ok(_) => {}
       }
     }));
+    let timeout = after(Duration::from_secs(self.registry_cfg.connect_timeout_secs));
     select! {
-      // FIXME: need timeout case.
       recv(self.reg2bot_r) -> msg => match msg {
         Ok(BotWsMsg::Open(s)) => {
           self.reg_sender = Some(s);
+          self._reset_outbox();
         }
         _ => return None,
+      },
+      recv(timeout) -> _ => {
+        eprintln!("TRACE: guppybot: timed out connecting to registry");
+        return None;
       }
     }
     if self.reg_sender.is_none() {
@@ -563,6 +677,613 @@ impl Context {
     None
   }
 
+  fn _push_outbox(&mut self, msg: OutboxMsg) {
+    match self.outbox.push(msg) {
+      Err(e) => {
+        eprintln!("TRACE: guppybot: outbox: failed to queue message: {:?}", e);
+      }
+      Ok(_) => {
+        self._flush_outbox();
+      }
+    }
+  }
+
+  fn _flush_outbox(&mut self) {
+    if !self.auth {
+      return;
+    }
+    if self.reg_sender.is_none() {
+      return;
+    }
+    let machine_key = self.shared.read().root_manifest.key_buf().as_vec().clone();
+    for (seq, msg) in self.outbox.pending_after(self.outbox_sent_seq).into_iter() {
+      let expects_reply = msg.expects_reply();
+      let res = match msg {
+        OutboxMsg::StartCiTask{api_key, ci_run_key, task_nr, task_name, taskspec, ts} => {
+          self.reg_sender.as_mut().unwrap()
+            .send_auth_with_id(
+                self.api_cfg.as_ref().map(|api| &api.auth),
+                &Bot2RegistryV0::_StartCiTask{
+                  api_key,
+                  machine_key: machine_key.clone(),
+                  ci_run_key,
+                  task_nr,
+                  task_name,
+                  taskspec,
+                  ts: Some(ts),
+                },
+                Some(seq),
+            )
+        }
+        OutboxMsg::AppendCiTaskData{api_key, ci_run_key, task_nr, part_nr, key, data, ts} => {
+          self.reg_sender.as_mut().unwrap()
+            .send_auth_with_id(
+                self.api_cfg.as_ref().map(|api| &api.auth),
+                &Bot2RegistryV0::_AppendCiTaskData{
+                  api_key,
+                  ci_run_key,
+                  task_nr,
+                  part_nr,
+                  ts: Some(ts),
+                  key,
+                  data,
+                },
+                Some(seq),
+            )
+        }
+        OutboxMsg::DoneCiTask{api_key, ci_run_key, task_nr, failed, ts} => {
+          self.reg_sender.as_mut().unwrap()
+            .send_auth_with_id(
+                self.api_cfg.as_ref().map(|api| &api.auth),
+                &Bot2RegistryV0::_DoneCiTask{
+                  api_key,
+                  ci_run_key,
+                  task_nr,
+                  failed,
+                  ts: Some(ts),
+                },
+                Some(seq),
+            )
+        }
+        OutboxMsg::FailedEarlyCiRun{api_key, ci_run_key, ts} => {
+          self.reg_sender.as_mut().unwrap()
+            .send_auth_with_id(
+                self.api_cfg.as_ref().map(|api| &api.auth),
+                &Bot2RegistryV0::_NewCiRun(Some(_NewCiRunV0::Accept{
+                  api_key,
+                  ci_run_key,
+                  task_count: None,
+                  failed_early: true,
+                  ts: Some(ts),
+                })),
+                Some(seq),
+            )
+        }
+      };
+      if res.is_err() {
+        // Retried from the same point after the next reconnect.
+        break;
+      }
+      self.outbox_sent_seq = seq;
+      self.outbox_inflight.push_back((seq, expects_reply));
+      self._ack_unreplied_outbox();
+    }
+  }
+
+  fn _ack_outbox(&mut self) {
+    // The registry replies to task messages in the order they were sent.
+    let seq = match self.outbox_inflight.pop_front() {
+      None => {
+        eprintln!("TRACE: guppybot: outbox: unexpected ack");
+        return;
+      }
+      Some((seq, _)) => seq,
+    };
+    if let Err(e) = self.outbox.ack(seq) {
+      eprintln!("TRACE: guppybot: outbox: failed to ack message: {:?}", e);
+    }
+    self._ack_unreplied_outbox();
+  }
+
+  /// Acks the messages which get no reply, once every message sent before
+  /// them has been acked.
+  fn _ack_unreplied_outbox(&mut self) {
+    while let Some(&(seq, false)) = self.outbox_inflight.front() {
+      self.outbox_inflight.pop_front();
+      if let Err(e) = self.outbox.ack(seq) {
+        eprintln!("TRACE: guppybot: outbox: failed to ack message: {:?}", e);
+      }
+    }
+  }
+
+
+  /// Returns `true` if this completed the run.
+  fn _journal_task(&mut self, ci_run_key: &[u8], task_nr: u64, state: JournalTaskState) -> bool {
+    match self.journal.set_task_state(ci_run_key, task_nr, state) {
+      Err(e) => {
+        eprintln!("TRACE: guppybot: journal: failed to record task state: {:?}", e);
+        false
+      }
+      Ok(true) => {
+        eprintln!("TRACE: guppybot: journal: ci run finished");
+        true
+      }
+      Ok(false) => false,
+    }
+  }
+
+  /// Reports a task message to wherever its run came from: the registry,
+  /// through the outbox, or the local run log.
+  fn _report(&mut self, msg: OutboxMsg) {
+    if !self.local_runs.contains(msg.ci_run_key()) {
+      self._push_outbox(msg);
+      return;
+    }
+    if let Err(e) = self.runlog.record(&msg) {
+      eprintln!("TRACE: guppybot: run log: failed to record task message: {:?}", e);
+    }
+  }
+
+  fn _respec_run(&self, run: &JournalRun, checkout: &GitCheckoutSpec) -> Option<Vec<TaskSpec>> {
+    let shared = self.shared.read();
+    let mut image_manifest = ImageManifest::load(&shared.sysroot, &shared.root_manifest).ok()?;
+    let builtin_imagespec = ImageSpec::builtin_default();
+    let builtin_image = image_manifest.lookup_docker_image(&builtin_imagespec, &shared.sysroot, &shared.root_manifest).ok()?;
+    let spec_limits = TaskLimitsConfig::open(&shared.config).ok()?.limits_of(&TaskLimits::default());
+    let (_, tasks) = checkout_taskspecs(&builtin_image, checkout, &shared.sysroot, &spec_limits);
+    let tasks = tasks.ok()?;
+    if tasks.len() as u64 != run.task_count {
+      return None;
+    }
+    Some(tasks)
+  }
+
+  fn _resume_runs(&mut self) {
+    let runs = match self.journal.load_runs() {
+      Err(e) => {
+        eprintln!("TRACE: guppybot: journal: failed to load runs: {:?}", e);
+        return;
+      }
+      Ok(runs) => runs,
+    };
+    for (run, states) in runs.into_iter() {
+      eprintln!("TRACE: guppybot: journal: resuming ci run with {} task(s)", run.task_count);
+      if run.source != RunSource::Registry {
+        self.local_runs.insert(run.ci_run_key.clone());
+      }
+      let all_done = states.iter().all(|&state| match state {
+        JournalTaskState::Done{..} => true,
+        _ => false,
+      });
+      if all_done {
+        // Every task finished before guppybot stopped, but the run was not
+        // cleaned up. The last results may not have been reported yet, so
+        // report them again before finishing the run.
+        eprintln!("TRACE: guppybot: journal: ci run already finished");
+        for (task_idx, &state) in states.iter().enumerate() {
+          if let JournalTaskState::Done{failed} = state {
+            self._report(OutboxMsg::DoneCiTask{
+              api_key: run.api_key.clone(),
+              ci_run_key: run.ci_run_key.clone(),
+              task_nr: task_idx as u64 + 1,
+              failed,
+              ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+            });
+          }
+        }
+        if let Err(e) = self.journal.finish_run(&run.ci_run_key) {
+          eprintln!("TRACE: guppybot: journal: failed to finish run: {:?}", e);
+        }
+        self.local_runs.remove(&run.ci_run_key);
+        continue;
+      }
+      let checkout = GitCheckoutSpec{
+        remote_url: run.remote_url.clone(),
+        ref_full: run.ref_full.clone(),
+        commit_hash: run.commit_hash.clone(),
+        dir: Dir::Path(run.checkout_dir(&self.journal)),
+      };
+      let tasks = match states.iter().any(|&state| state == JournalTaskState::Pending) {
+        false => None,
+        true  => {
+          let tasks = self._respec_run(&run, &checkout);
+          if tasks.is_none() {
+            eprintln!("TRACE: guppybot: journal: failed to recover taskspecs");
+          }
+          tasks
+        }
+      };
+      let mut node_states = Vec::with_capacity(states.len());
+      let mut finished = false;
+      for (task_idx, &state) in states.iter().enumerate() {
+        let task_nr = task_idx as u64 + 1;
+        let node_state = match (state, tasks.is_some()) {
+          (JournalTaskState::Done{failed: false}, _) => TaskNodeState::Passed,
+          (JournalTaskState::Done{failed: true}, _) => TaskNodeState::Failed,
+          (JournalTaskState::Pending, true) => TaskNodeState::Waiting,
+          (JournalTaskState::Pending, false) |
+          (JournalTaskState::Running, _) => {
+            let reason = match state {
+              JournalTaskState::Running => "interrupted: guppybot stopped while the task was running",
+              _ => "interrupted: guppybot stopped before the task could start",
+            };
+            self._report(OutboxMsg::AppendCiTaskData{
+              api_key: run.api_key.clone(),
+              ci_run_key: run.ci_run_key.clone(),
+              task_nr,
+              part_nr: 1,
+              key: "Status".to_string(),
+              data: reason.as_bytes().to_vec(),
+              ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+            });
+            self._report(OutboxMsg::DoneCiTask{
+              api_key: run.api_key.clone(),
+              ci_run_key: run.ci_run_key.clone(),
+              task_nr,
+              failed: true,
+              ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+            });
+            finished = self._journal_task(&run.ci_run_key, task_nr, JournalTaskState::Done{failed: true});
+            TaskNodeState::Failed
+          }
+        };
+        node_states.push(node_state);
+      }
+      if finished {
+        self.local_runs.remove(&run.ci_run_key);
+        continue;
+      }
+      if let Some(tasks) = tasks {
+        // Dependencies were checked when the taskspecs were parsed.
+        let graph = TaskGraph::new(&tasks).unwrap();
+        // The originator is not journaled, so resumed runs of pull requests
+        // are not trusted.
+        let trusted = trusts_run(self.ci_cfg.as_ref(), &run.remote_url, run.ref_full.as_ref().map(|s| s.as_str()), None);
+        self.dags.insert(run.ci_run_key.clone(), RunDag{
+          api_key: run.api_key.clone(),
+          checkout,
+          trusted,
+          tasks,
+          graph,
+          states: node_states,
+        });
+        self._schedule(&run.ci_run_key);
+      }
+    }
+  }
+
+  /// Checks the CI run against the policies in the ci config, if there is
+  /// one, then checks it out and gets its taskspecs. On failure, returns why
+  /// the run should be rejected, along with the gup.py output if there was
+  /// any.
+  fn _prepare_ci_run(&self, ci_run_key: &[u8], run: &NewRun) -> Result<(GitCheckoutSpec, Vec<TaskSpec>, Vec<Option<String>>), (NewCiRunReject, Option<Vec<u8>>)> {
+    if let Some(ref ci_cfg) = self.ci_cfg {
+      ci_cfg.check_run(&run.remote_url, run.ref_full.as_ref().map(|s| s.as_str()), run.originator.as_ref().map(|s| s.as_str()))
+        .map_err(|message| (NewCiRunReject::Policy{message}, None))?;
+    }
+    let shared = self.shared.read();
+    let checkout_dir = self.journal.prepare_run(ci_run_key)
+      .map_err(|e| (NewCiRunReject::Internal{
+        message: format!("failed to prepare run directory: {}", e.excuses.join(": ")),
+      }, None))?;
+    let checkout = GitCheckoutSpec::with_remote_url_in(run.remote_url.clone(), &checkout_dir)
+      .map_err(|e| (NewCiRunReject::Checkout{
+        message: format!("invalid repository url: {}", e.excuses.join(": ")),
+      }, None))?
+      .at_revision(run.ref_full.clone(), run.commit_hash.clone())
+      .map_err(|e| (NewCiRunReject::Checkout{
+        message: e.excuses.join(": "),
+      }, None))?;
+    let mut image_manifest = ImageManifest::load(&shared.sysroot, &shared.root_manifest)
+      .map_err(|e| (NewCiRunReject::Internal{
+        message: format!("failed to load image manifest: {}", e.excuses.join(": ")),
+      }, None))?;
+    let builtin_imagespec = ImageSpec::builtin_default();
+    let builtin_image = image_manifest.lookup_docker_image(&builtin_imagespec, &shared.sysroot, &shared.root_manifest)
+      .map_err(|e| (NewCiRunReject::Internal{
+        message: format!("failed to prepare builtin image: {}", e.excuses.join(": ")),
+      }, None))?;
+    builtin_image._run_checkout(&checkout, &shared.sysroot)
+      .map_err(|e| (NewCiRunReject::Checkout{
+        message: e.excuses.join(": "),
+      }, None))?;
+    // gup.py runs under the default limits of tasks.
+    let spec_limits = TaskLimitsConfig::open(&shared.config)
+      .map_err(|e| (NewCiRunReject::Internal{
+        message: format!("invalid machine config: {}", e.excuses.join(": ")),
+      }, None))?
+      .limits_of(&TaskLimits::default());
+    let (spec_out, tasks) = checkout_taskspecs(&builtin_image, &checkout, &shared.sysroot, &spec_limits);
+    let tasks = match tasks {
+      Err(e) => {
+        return Err((NewCiRunReject::Taskspec{
+          message: e.message,
+          line_nr: e.line_nr,
+        }, Some(spec_out)));
+      }
+      Ok(tasks) => tasks,
+    };
+    for (task_idx, task) in tasks.iter().enumerate() {
+      if task.image_candidate().is_none() {
+        return Err((NewCiRunReject::NoImage{
+          task_nr: task_idx as u64 + 1,
+          task_name: task.name.clone(),
+        }, Some(spec_out)));
+      }
+      if let Some(ref req) = task.require_gpu_arch {
+        if compatible_gpus(&self.gpus, req).is_empty() {
+          return Err((NewCiRunReject::NoGpu{
+            task_nr: task_idx as u64 + 1,
+            task_name: task.name.clone(),
+            require_gpu_arch: req.to_string(),
+          }, Some(spec_out)));
+        }
+      }
+    }
+    // Filters are best effort: if the changes cannot be determined, every
+    // task runs.
+    let changes = match builtin_image._run_changes(&checkout, run.base_hash.as_ref().map(|s| s.as_str()), &shared.sysroot) {
+      Err(e) => {
+        eprintln!("TRACE: guppybot: failed to get changes of ci run: {:?}", e);
+        None
+      }
+      Ok(changes) => Some(changes),
+    };
+    let skips = tasks.iter().map(|task| {
+      match changes {
+        Some((ref message, _)) if commit_skips_ci(message) => {
+          Some("skipped: [skip ci] in commit message".to_string())
+        }
+        _ => task.skip_reason(
+            run.ref_full.as_ref().map(|s| s.as_str()),
+            changes.as_ref().map(|&(_, ref paths)| &paths[..]),
+        ),
+      }
+    }).collect();
+    Ok((checkout, tasks, skips))
+  }
+
+  /// Hands the tasks of an accepted CI run to the workers, each once the
+  /// tasks it depends on have passed. Skipped tasks are reported as started
+  /// and then done, with the reason as their status.
+  fn _enqueue_tasks(&mut self, api_key: &[u8], ci_run_key: &[u8], checkout: &GitCheckoutSpec, trusted: bool, tasks: Vec<TaskSpec>, skips: Vec<Option<String>>) {
+    // Dependencies were checked when the taskspecs were parsed.
+    let graph = TaskGraph::new(&tasks).unwrap();
+    let mut states = Vec::with_capacity(tasks.len());
+    for (task_idx, (task, skip)) in tasks.iter().zip(skips.into_iter()).enumerate() {
+      match skip {
+        None => {
+          states.push(TaskNodeState::Waiting);
+        }
+        Some(reason) => {
+          states.push(TaskNodeState::Skipped);
+          self._skip_task(api_key, ci_run_key, task_idx as u64 + 1, task, reason);
+        }
+      }
+    }
+    self.dags.insert(ci_run_key.to_vec(), RunDag{
+      api_key: api_key.to_vec(),
+      checkout: checkout.clone(),
+      trusted,
+      tasks,
+      graph,
+      states,
+    });
+    self._schedule(ci_run_key);
+  }
+
+  fn _skip_task(&self, api_key: &[u8], ci_run_key: &[u8], task_nr: u64, task: &TaskSpec, reason: String) {
+    eprintln!("TRACE: guppybot: ci task: {}: {}", task_nr, reason);
+    self.loopback_s.send(LoopbackMsg::StartCiTask{
+      api_key: api_key.to_vec(),
+      ci_run_key: ci_run_key.to_vec(),
+      task_nr,
+      task_name: Some(task.name.clone()),
+      taskspec: TaskSpecReport::new(task, None, None).to_json_bytes(),
+    }).unwrap();
+    self.loopback_s.send(LoopbackMsg::AppendCiTaskData{
+      api_key: api_key.to_vec(),
+      ci_run_key: ci_run_key.to_vec(),
+      task_nr,
+      part_nr: 1,
+      key: "Status".to_string(),
+      data: reason.into_bytes(),
+    }).unwrap();
+    self.loopback_s.send(LoopbackMsg::DoneCiTask{
+      api_key: api_key.to_vec(),
+      ci_run_key: ci_run_key.to_vec(),
+      task_nr,
+      failed: false,
+    }).unwrap();
+  }
+
+  /// Queues the waiting tasks of a run whose dependencies have all passed,
+  /// and skips the ones depending on a task that failed or was skipped.
+  fn _schedule(&mut self, ci_run_key: &[u8]) {
+    let mut ready = Vec::new();
+    let mut skipped = Vec::new();
+    let api_key = {
+      let dag = match self.dags.get_mut(ci_run_key) {
+        None => return,
+        Some(dag) => dag,
+      };
+      // Dependencies come first, so skips cascade in a single pass.
+      for task_idx in dag.graph.topo_order() {
+        if dag.states[task_idx] != TaskNodeState::Waiting {
+          continue;
+        }
+        match dag.graph.readiness(task_idx, &dag.states) {
+          Readiness::Blocked => {}
+          Readiness::Ready => {
+            dag.states[task_idx] = TaskNodeState::Queued;
+            let deps: Vec<_> = dag.graph.deps(task_idx).iter()
+              .map(|&dep_idx| (dag.tasks[dep_idx].name.clone(), dep_idx as u64 + 1))
+              .collect();
+            ready.push((task_idx as u64 + 1, dag.tasks[task_idx].clone(), deps));
+          }
+          Readiness::DepFailed(dep_idx) => {
+            dag.states[task_idx] = TaskNodeState::Skipped;
+            skipped.push((task_idx as u64 + 1, dag.tasks[task_idx].clone(),
+                format!("skipped: depends on failed task {:?}", dag.tasks[dep_idx].name)));
+          }
+          Readiness::DepSkipped(dep_idx) => {
+            dag.states[task_idx] = TaskNodeState::Skipped;
+            skipped.push((task_idx as u64 + 1, dag.tasks[task_idx].clone(),
+                format!("skipped: depends on skipped task {:?}", dag.tasks[dep_idx].name)));
+          }
+        }
+      }
+      dag.api_key.clone()
+    };
+    for (task_nr, task, reason) in skipped.into_iter() {
+      self._skip_task(&api_key, ci_run_key, task_nr, &task, reason);
+    }
+    for (task_nr, mut task, deps) in ready.into_iter() {
+      task.retries = task.retries.min(self.daemon_cfg.max_task_retries);
+      let mut opts = DockerRunOpts::default();
+      match self.journal.artifacts_dir(ci_run_key, task_nr) {
+        Err(e) => eprintln!("TRACE: guppybot: ci task: {}: {:?}", task_nr, e),
+        Ok(dir) => opts.artifacts_dir = Some(dir),
+      }
+      opts.gpu_devices = Some(task_gpus(&self.gpus, task.require_gpu_arch.as_ref()));
+      for (dep_name, dep_nr) in deps.into_iter() {
+        match self.journal.artifacts_dir(ci_run_key, dep_nr) {
+          Err(e) => eprintln!("TRACE: guppybot: ci task: {}: {:?}", task_nr, e),
+          Ok(dir) => opts.dep_artifacts.push((dep_name, dir)),
+        }
+      }
+      let checkout = self.dags[ci_run_key].checkout.clone();
+      opts.build_caches = build_cache_mounts(&task, &checkout.remote_url, self.dags[ci_run_key].trusted, &self.shared.read().sysroot);
+      self.workerlb_s.send(WorkerLbMsg::CiTask{
+        api_key: api_key.clone(),
+        ci_run_key: ci_run_key.to_vec(),
+        task_nr,
+        checkout,
+        task,
+        opts,
+      }).unwrap();
+    }
+  }
+
+  /// Turns down a `_NewCiRun`, then reports why through task 0. All of it
+  /// goes through the outbox, so that it reaches the registry even if the
+  /// connection is down.
+  fn _reject_ci_run(&mut self, api_key: &[u8], ci_run_key: &[u8], reason: NewCiRunReject, spec_out: Option<Vec<u8>>) {
+    self._push_outbox(OutboxMsg::FailedEarlyCiRun{
+      api_key: api_key.to_vec(),
+      ci_run_key: ci_run_key.to_vec(),
+      ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+    });
+    self._push_outbox(OutboxMsg::AppendCiTaskData{
+      api_key: api_key.to_vec(),
+      ci_run_key: ci_run_key.to_vec(),
+      task_nr: 0,
+      part_nr: 1,
+      key: "Reason".to_string(),
+      data: reason.to_json(),
+      ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+    });
+    if let Some(spec_out) = spec_out {
+      // Same part size as the console output of tasks.
+      for (part_idx, part) in spec_out.chunks(512).enumerate() {
+        self._push_outbox(OutboxMsg::AppendCiTaskData{
+          api_key: api_key.to_vec(),
+          ci_run_key: ci_run_key.to_vec(),
+          task_nr: 0,
+          part_nr: part_idx as u64 + 1,
+          key: "SpecOut".to_string(),
+          data: part.to_vec(),
+          ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+        });
+      }
+    }
+  }
+
+  /// Starts a CI run that did not come from the registry. Its results go to
+  /// the local run log, including the reason if it is rejected.
+  /// Returns `true` if the run was journaled, or rejected for a reason that
+  /// would not change on a retry, so that it need not be offered again. Runs
+  /// rejected because the machine is not accepting them, the checkout failed
+  /// or of an internal error are not settled.
+  fn _begin_local_run(&mut self, source: RunSource, run: NewRun) -> bool {
+    let mut ci_run_key = vec![0_u8; 32];
+    thread_rng().fill(&mut ci_run_key[..]);
+    eprintln!("TRACE: guppybot: new local ci run:");
+    eprintln!("TRACE: guppybot:   source: {:?}", source);
+    eprintln!("TRACE: guppybot:   ci run key: {}", hex::encode(&ci_run_key));
+    eprintln!("TRACE: guppybot:   repo clone url: {:?}", run.remote_url);
+    eprintln!("TRACE: guppybot:   originator: {:?}", run.originator);
+    eprintln!("TRACE: guppybot:   ref full: {:?}", run.ref_full);
+    eprintln!("TRACE: guppybot:   commit hash: {:?}", run.commit_hash);
+    let mut log_run = RunLogRun{
+      source,
+      remote_url: run.remote_url.clone(),
+      ref_full: run.ref_full.clone(),
+      commit_hash: run.commit_hash.clone(),
+      originator: run.originator.clone(),
+      task_count: None,
+      ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+    };
+    let prepared = match self.draining || self.stopping.is_some() {
+      true  => {
+        let message = match self.draining {
+          false => "machine is shutting down",
+          true  => "machine is draining for maintenance",
+        };
+        Err((NewCiRunReject::NotAccepting{message: message.to_string()}, None))
+      }
+      false => self._prepare_ci_run(&ci_run_key, &run),
+    };
+    let (checkout, tasks, skips) = match prepared {
+      Err((reason, spec_out)) => {
+        eprintln!("TRACE: guppybot: new local ci run: rejected: {:?}", reason);
+        self.journal.finish_run(&ci_run_key).ok();
+        if let Err(e) = self.runlog.reject_run(&ci_run_key, &log_run, &reason, spec_out.as_ref().map(|buf| &buf[..])) {
+          eprintln!("TRACE: guppybot: run log: failed to record rejected run: {:?}", e);
+        }
+        return match reason {
+          NewCiRunReject::NotAccepting{..} |
+          NewCiRunReject::Checkout{..} |
+          NewCiRunReject::Internal{..} => false,
+          _ => true,
+        };
+      }
+      Ok(x) => x,
+    };
+    let task_count = tasks.len() as u64;
+    let trusted = trusts_run(self.ci_cfg.as_ref(), &run.remote_url, run.ref_full.as_ref().map(|s| s.as_str()), run.originator.as_ref().map(|s| s.as_str()));
+    eprintln!("TRACE: guppybot: new local ci run: confirmed:");
+    eprintln!("TRACE: guppybot:   task count: {}", task_count);
+    if let Err(e) = self.journal.begin_run(&JournalRun{
+      source,
+      api_key: Vec::new(),
+      ci_run_key: ci_run_key.clone(),
+      remote_url: run.remote_url,
+      ref_full: run.ref_full,
+      commit_hash: run.commit_hash,
+      task_count,
+    }) {
+      eprintln!("TRACE: guppybot: new local ci run: journal failed: {:?}", e);
+      self.journal.finish_run(&ci_run_key).ok();
+      return false;
+    }
+    log_run.task_count = Some(task_count);
+    if let Err(e) = self.runlog.begin_run(&ci_run_key, &log_run) {
+      eprintln!("TRACE: guppybot: run log: failed to record run: {:?}", e);
+    }
+    self.local_runs.insert(ci_run_key.clone());
+    self._enqueue_tasks(&[], &ci_run_key, &checkout, trusted, tasks, skips);
+    true
+  }
+
+  fn _reset_outbox(&mut self) {
+    // Anything sent but not acked on the old connection is replayed. A
+    // message whose ack was lost with the connection reaches the registry
+    // twice, under the same sequence number, so the registry drops it.
+    self.outbox_sent_seq = self.outbox.acked_seq();
+    self.outbox_inflight.clear();
+  }
+
   fn register_ci_machine(&mut self, repo_url: String) -> Option<()> {
     if self.api_cfg.is_none() {
       return None;
@@ -654,25 +1375,45 @@ impl Context {
   }
 }
 
+/// Passes the mutable cache quota of the machine config on to the cache
+/// index, where it is enforced.
+fn update_mutable_cache_quota(config: &Config, sysroot: &Sysroot) {
+  let cache_cfg = match MutableCacheConfig::open(config) {
+    Err(e) => {
+      eprintln!("TRACE: invalid mutable cache config, keeping the quota: {:?}", e);
+      return;
+    }
+    Ok(cfg) => cfg,
+  };
+  eprintln!("TRACE: mutable cache cfg: {:?}", cache_cfg);
+  if let Err(e) = set_mutable_cache_quota(sysroot, cache_cfg.max_size) {
+    eprintln!("TRACE: failed to set mutable cache quota: {:?}", e);
+  }
+}
+
 fn handle_workerlb_ci_task(
-    shared: RwLockReadGuard<Shared>,
+    shared: &RwLock<Shared>,
     loopback_s: &Sender<LoopbackMsg>,
     api_key: Vec<u8>,
     ci_run_key: Vec<u8>,
     task_nr: u64,
     checkout: GitCheckoutSpec,
     task: TaskSpec,
+    mut opts: DockerRunOpts,
+    workers_stop: &AtomicBool,
 ) {
   eprintln!("TRACE: guppybot: worker: ci task: {}", task_nr);
+  eprintln!("TRACE: guppybot: worker:   get imagespec...");
+  let image = task.image_candidate();
+  let image_hash = image.as_ref().map(|image| image.to_hash_digest(&shared.read().root_manifest));
   loopback_s.send(LoopbackMsg::StartCiTask{
     api_key: api_key.clone(),
     ci_run_key: ci_run_key.clone(),
     task_nr,
     task_name: Some(task.name.clone()),
-    taskspec: None,
+    taskspec: TaskSpecReport::new(&task, image.as_ref(), image_hash).to_json_bytes(),
   }).unwrap();
-  eprintln!("TRACE: guppybot: worker:   get imagespec...");
-  let image = match task.image_candidate() {
+  let image = match image {
     None => {
       loopback_s.send(LoopbackMsg::DoneCiTask{
         api_key: api_key.clone(),
@@ -684,6 +1425,10 @@ fn handle_workerlb_ci_task(
     }
     Some(image) => image,
   };
+  // The shared state is only held for the image lookup: a write to it, e.g.
+  // to reload the config, must not wait for tasks to finish.
+  let shared_guard = shared.read();
+  let shared = &*shared_guard;
   eprintln!("TRACE: guppybot: worker:   load manifest...");
   let mut image_manifest = match ImageManifest::load(&shared.sysroot, &shared.root_manifest) {
     Err(_) => {
@@ -714,22 +1459,15 @@ fn handle_workerlb_ci_task(
     }
     Ok(im) => im,
   };
-  eprintln!("TRACE: guppybot: worker:   run...");
-  let output = {
-    let loopback_s = loopback_s.clone();
-    let api_key = api_key.clone();
-    let ci_run_key = ci_run_key.clone();
-    DockerOutput::Buffer{buf_sz: 512, consumer: Box::new(move |part_nr, data| loopback_s.send(LoopbackMsg::AppendCiTaskData{
-      api_key: api_key.clone(),
-      ci_run_key: ci_run_key.clone(),
-      task_nr,
-      part_nr,
-      key: "Console".to_string(),
-      data,
-    }).unwrap())}
-  };
-  let status = match docker_image.run(&checkout, &task, &shared.sysroot, Some(output)) {
-    Err(_) => {
+  let sysroot = shared.sysroot.clone();
+  let cfgs = BuildCacheConfig::open(&shared.config)
+    .and_then(|build_cache_cfg| TaskLimitsConfig::open(&shared.config).map(|task_limits_cfg| (build_cache_cfg, task_limits_cfg)));
+  drop(shared_guard);
+  // Running the task without the caps of an invalid machine config could
+  // let it take over the machine, so fail it instead.
+  let (build_cache_cfg, task_limits_cfg) = match cfgs {
+    Err(e) => {
+      eprintln!("TRACE: guppybot: worker:   invalid machine config: {:?}", e);
       loopback_s.send(LoopbackMsg::DoneCiTask{
         api_key: api_key.clone(),
         ci_run_key: ci_run_key.clone(),
@@ -738,38 +1476,359 @@ fn handle_workerlb_ci_task(
       }).unwrap();
       return;
     }
-    Ok(status) => {
-      eprintln!("TRACE: guppybot: worker:   status: {:?}", status);
-      status
-    }
+    Ok(cfgs) => cfgs,
   };
-  match status {
-    DockerRunStatus::Failure => {
-      loopback_s.send(LoopbackMsg::DoneCiTask{
-        api_key,
-        ci_run_key,
+  opts.limits = task_limits_cfg.limits_of(&task.limits);
+  eprintln!("TRACE: guppybot: worker:   prepare build caches...");
+  if let Err(e) = prepare_build_caches(&mut opts.build_caches, &checkout.remote_url, build_cache_cfg.max_size) {
+    eprintln!("TRACE: guppybot: worker:   {:?}", e);
+    loopback_s.send(LoopbackMsg::DoneCiTask{
+      api_key: api_key.clone(),
+      ci_run_key: ci_run_key.clone(),
+      task_nr,
+      failed: true,
+    }).unwrap();
+    return;
+  }
+  opts.container_name = Some(task_container_name(&ci_run_key, task_nr));
+  let mut attempt = 1;
+  let failed = loop {
+    eprintln!("TRACE: guppybot: worker:   run (attempt {})...", attempt);
+    // Each attempt reports its console output under a key of its own.
+    let console_key = match attempt {
+      1 => "Console".to_string(),
+      _ => format!("Console.{}", attempt),
+    };
+    let output = {
+      let loopback_s = loopback_s.clone();
+      let api_key = api_key.clone();
+      let ci_run_key = ci_run_key.clone();
+      DockerOutput::Buffer{buf_sz: 512, consumer: Box::new(move |part_nr, data| loopback_s.send(LoopbackMsg::AppendCiTaskData{
+        api_key: api_key.clone(),
+        ci_run_key: ci_run_key.clone(),
         task_nr,
-        failed: true,
-      }).unwrap();
+        part_nr,
+        key: console_key.clone(),
+        data,
+      }).unwrap())}
+    };
+    let status = match docker_image.run(&checkout, &task, &sysroot, &opts, Some(output)) {
+      Err(_) => break true,
+      Ok(status) => {
+        eprintln!("TRACE: guppybot: worker:   status: {:?}", status);
+        status
+      }
+    };
+    match status {
+      DockerRunStatus::Success => {
+        if attempt > 1 {
+          loopback_s.send(LoopbackMsg::AppendCiTaskData{
+            api_key: api_key.clone(),
+            ci_run_key: ci_run_key.clone(),
+            task_nr,
+            part_nr: 1,
+            key: "Status".to_string(),
+            data: format!("passed on retry: attempt {} of {}", attempt, task.retries.saturating_add(1)).into_bytes(),
+          }).unwrap();
+        }
+        break false;
+      }
+      DockerRunStatus::Failure{exit_code} => {
+        // Tasks killed for shutdown are not retried, and already have a
+        // status saying so.
+        if workers_stop.load(Ordering::SeqCst) {
+          break true;
+        }
+        if !task.should_retry(attempt, exit_code) {
+          if attempt > 1 {
+            loopback_s.send(LoopbackMsg::AppendCiTaskData{
+              api_key: api_key.clone(),
+              ci_run_key: ci_run_key.clone(),
+              task_nr,
+              part_nr: 1,
+              key: "Status".to_string(),
+              data: format!("failed after {} attempts", attempt).into_bytes(),
+            }).unwrap();
+          }
+          break true;
+        }
+        eprintln!("TRACE: guppybot: worker:   retrying after exit code {:?}", exit_code);
+        attempt += 1;
+      }
     }
-    DockerRunStatus::Success => {
-      loopback_s.send(LoopbackMsg::DoneCiTask{
-        api_key,
-        ci_run_key,
-        task_nr,
-        failed: false,
-      }).unwrap();
+  };
+  loopback_s.send(LoopbackMsg::DoneCiTask{
+    api_key,
+    ci_run_key,
+    task_nr,
+    failed,
+  }).unwrap();
+}
+
+fn poll_ls_remote(shared: &Shared, remote_url: &str, patterns: &[String]) -> Maybe<Vec<(String, String)>> {
+  let mut image_manifest = ImageManifest::load(&shared.sysroot, &shared.root_manifest)?;
+  let builtin_imagespec = ImageSpec::builtin_default();
+  let builtin_image = image_manifest.lookup_docker_image(&builtin_imagespec, &shared.sysroot, &shared.root_manifest)?;
+  builtin_image._run_ls_remote(remote_url, patterns, &shared.sysroot)
+}
+
+/// Polls the refs of the repos in the ci config which have `poll_refs`, and
+/// starts a local CI run for each ref that moved. The ci config is reloaded
+/// on every round. Polling pauses while the machine is draining or stopping,
+/// so that refs which move in the meantime are picked up afterwards.
+fn poll_loop(shared: Arc<RwLock<Shared>>, poll_state: Arc<PollState>, loopback_s: Sender<LoopbackMsg>, unsettled_r: Receiver<(String, String, String)>, workers_stop: Arc<AtomicBool>) {
+  // Moves already offered to the daemon, which are not offered again while
+  // the daemon is still deciding on them. The daemon sends back the moves it
+  // did not settle, which are offered again when
+  // their repo is next polled.
+  let mut offered: HashMap<(String, String), String> = HashMap::new();
+  let mut next_due: HashMap<String, Instant> = HashMap::new();
+  let ticker = tick(Duration::from_secs(10));
+  loop {
+    ticker.recv().ok();
+    for (remote_url, ref_full, commit_hash) in unsettled_r.try_iter() {
+      let offer_key = (remote_url, ref_full);
+      if offered.get(&offer_key) == Some(&commit_hash) {
+        offered.remove(&offer_key);
+      }
+    }
+    if workers_stop.load(Ordering::SeqCst) {
+      continue;
+    }
+    let ci_cfg = {
+      let shared = shared.read();
+      if shared.root_manifest.drain_bit() {
+        // Runs offered just before the drain may have been refused.
+        offered.clear();
+        continue;
+      }
+      match CiConfig::open(&shared.config) {
+        Err(_) => continue,
+        Ok(cfg) => cfg,
+      }
+    };
+    for repo in ci_cfg.repos.iter() {
+      if repo.poll_refs.is_empty() {
+        continue;
+      }
+      let now = Instant::now();
+      match next_due.get(&repo.remote_url) {
+        Some(&due) if due > now => continue,
+        _ => {}
+      }
+      next_due.insert(repo.remote_url.clone(), now + Duration::from_secs(repo.poll_interval_secs.max(10)));
+      let refs = match poll_ls_remote(&shared.read(), &repo.remote_url, &repo.poll_refs) {
+        Err(e) => {
+          eprintln!("TRACE: guppybot: poll: {}: {:?}", repo.remote_url, e);
+          continue;
+        }
+        Ok(refs) => refs,
+      };
+      let moved = match poll_state.moved(&repo.remote_url, &refs) {
+        Err(e) => {
+          eprintln!("TRACE: guppybot: poll: {}: {:?}", repo.remote_url, e);
+          continue;
+        }
+        Ok(moved) => moved,
+      };
+      offered.retain(|&(ref remote_url, ref ref_full), _| {
+        remote_url != &repo.remote_url || moved.iter().any(|&(ref r, _, _)| r == ref_full)
+      });
+      for (ref_full, commit_hash, prev_hash) in moved.into_iter() {
+        let offer_key = (repo.remote_url.clone(), ref_full.clone());
+        if offered.get(&offer_key) == Some(&commit_hash) {
+          continue;
+        }
+        offered.insert(offer_key, commit_hash.clone());
+        eprintln!("TRACE: guppybot: poll: {}: {} moved to {}", repo.remote_url, ref_full, commit_hash);
+        loopback_s.send(LoopbackMsg::LocalCiRun{
+          source: RunSource::Poll,
+          run: NewRun{
+            remote_url: repo.remote_url.clone(),
+            ref_full: Some(ref_full),
+            commit_hash: Some(commit_hash),
+            base_hash: prev_hash,
+            originator: None,
+          },
+        }).unwrap();
+      }
     }
   }
 }
 
 impl Context {
+  fn _handle_loopback(&mut self, msg: LoopbackMsg) {
+    match msg {
+      LoopbackMsg::_Echo{echo_ctr} => {
+        if echo_ctr == 0 {
+          eprintln!("TRACE: guppybot: warning: got zero-valued echo");
+        }
+        let reg_echo_ctr = self.reg_echo_ctr.load(Ordering::Relaxed);
+        if echo_ctr != reg_echo_ctr {
+        } else if echo_ctr == reg_echo_ctr {
+          //eprintln!("TRACE: guppybot: ping...");
+          if self.reg_sender.is_none() {
+            return;
+          }
+          if self.reg_sender.as_mut().unwrap()
+            .send_auth(
+                self.api_cfg.as_ref().map(|api| &api.auth),
+                &Bot2RegistryV0::_Ping{
+                  // FIXME
+                  api_key: vec![],
+                  machine_key: self.shared.read().root_manifest.key_buf().as_vec().clone(),
+                }
+            ).is_err()
+          {
+            return;
+          }
+        } else {
+          unreachable!();
+        }
+      }
+      LoopbackMsg::_Echo2 => {
+        eprintln!("TRACE: guppybot: trying to reconnect...");
+        self._init(true).ok();
+      }
+      LoopbackMsg::Shutdown => {
+        match self.stopping {
+          None => {
+            let grace = Duration::from_secs(self.daemon_cfg.shutdown_grace_secs);
+            eprintln!("TRACE: guppybot: shutting down, {} task(s) running, grace period: {:?}",
+                self.running.len(), grace);
+            self.stopping = Some(Instant::now() + grace);
+            self.workers_stop.store(true, Ordering::SeqCst);
+          }
+          Some(_) => {
+            eprintln!("TRACE: guppybot: shutting down now");
+            self.stopping = Some(Instant::now());
+          }
+        }
+      }
+      LoopbackMsg::LocalCiRun{source, run} => {
+        let polled = match (source, &run.ref_full, &run.commit_hash) {
+          (RunSource::Poll, &Some(ref ref_full), &Some(ref commit_hash)) => {
+            Some((run.remote_url.clone(), ref_full.clone(), commit_hash.clone()))
+          }
+          _ => None,
+        };
+        let settled = self._begin_local_run(source, run);
+        match (settled, polled) {
+          (true, Some((remote_url, ref_full, commit_hash))) => {
+            if let Err(e) = self.poll_state.record(&remote_url, &ref_full, &commit_hash) {
+              eprintln!("TRACE: guppybot: poll: {}: {:?}", remote_url, e);
+            }
+          }
+          (false, Some(unsettled)) => {
+            self.unsettled_s.send(unsettled).unwrap();
+          }
+          (_, None) => {}
+        }
+      }
+      LoopbackMsg::StartCiTask{api_key, ci_run_key, task_nr, task_name, taskspec} => {
+        self._journal_task(&ci_run_key, task_nr, JournalTaskState::Running);
+        self.running.push((api_key.clone(), ci_run_key.clone(), task_nr));
+        self._report(OutboxMsg::StartCiTask{
+          api_key,
+          ci_run_key,
+          task_nr,
+          task_name,
+          taskspec,
+          ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+        });
+      }
+      LoopbackMsg::AppendCiTaskData{api_key, ci_run_key, task_nr, part_nr, key, data} => {
+        self._report(OutboxMsg::AppendCiTaskData{
+          api_key,
+          ci_run_key,
+          task_nr,
+          part_nr,
+          key,
+          data,
+          ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+        });
+      }
+      LoopbackMsg::DoneCiTask{api_key, ci_run_key, task_nr, failed} => {
+        let finished = self._journal_task(&ci_run_key, task_nr, JournalTaskState::Done{failed});
+        self.running.retain(|&(_, ref key, nr)| !(key == &ci_run_key && nr == task_nr));
+        self._report(OutboxMsg::DoneCiTask{
+          api_key,
+          ci_run_key: ci_run_key.clone(),
+          task_nr,
+          failed,
+          ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+        });
+        match finished {
+          false => {
+            if let Some(dag) = self.dags.get_mut(&ci_run_key) {
+              let state = &mut dag.states[task_nr as usize - 1];
+              // Skipped tasks were already marked as such when skipped.
+              if !state.is_finished() {
+                *state = match failed {
+                  false => TaskNodeState::Passed,
+                  true  => TaskNodeState::Failed,
+                };
+              }
+            }
+            self._schedule(&ci_run_key);
+          }
+          true  => {
+            self.local_runs.remove(&ci_run_key);
+            self.dags.remove(&ci_run_key);
+          }
+        }
+      }
+    }
+  }
+
+  fn _ready_to_stop(&self) -> bool {
+    match self.stopping {
+      None => false,
+      Some(deadline) => self.running.is_empty() || Instant::now() >= deadline,
+    }
+  }
+
+  fn _kill_running_tasks(&mut self) {
+    let running = self.running.clone();
+    for (api_key, ci_run_key, task_nr) in running.into_iter() {
+      eprintln!("TRACE: guppybot: killing ci task: {}", task_nr);
+      self._report(OutboxMsg::AppendCiTaskData{
+        api_key,
+        ci_run_key: ci_run_key.clone(),
+        task_nr,
+        part_nr: 1,
+        key: "Status".to_string(),
+        data: b"killed: guppybot shut down before the task finished".to_vec(),
+        ts: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+      });
+      if let Err(e) = kill_container(&task_container_name(&ci_run_key, task_nr)) {
+        eprintln!("TRACE: guppybot: failed to kill ci task: {:?}", e);
+      }
+    }
+    // Give the workers a chance to report the killed tasks as done; anything
+    // still running after this is reported as interrupted on the next start.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !self.running.is_empty() {
+      let now = Instant::now();
+      if now >= deadline {
+        break;
+      }
+      match self.loopback_r.recv_timeout(deadline - now) {
+        Err(_) => break,
+        Ok(LoopbackMsg::_Echo{..}) |
+        Ok(LoopbackMsg::_Echo2) |
+        Ok(LoopbackMsg::Shutdown) => {}
+        Ok(msg) => self._handle_loopback(msg),
+      }
+    }
+  }
+
   pub fn runloop(&mut self) -> Maybe {
     let shared = self.shared.clone();
     let loopback_s = self.loopback_s.clone();
     let watchdog_r = self.watchdog_r.clone();
     let reconnect = self.reconnect.clone();
-    let watchdog_join_h = spawn(move || {
+    spawn(move || {
       loop {
         select! {
           recv(watchdog_r) -> msg => match msg {
@@ -804,27 +1863,76 @@ impl Context {
         }
       }
     });
-    let loopback_s = self.loopback_s.clone();
-    let workerlb_r = self.workerlb_r.clone();
-    let worker_join_h = spawn(move || {
-      let shared = shared;
-      let loopback_s = loopback_s;
-      loop {
-        match workerlb_r.recv() {
-          Err(_) => continue,
-          Ok(WorkerLbMsg::CiTask{api_key, ci_run_key, task_nr, checkout, task}) => {
-            handle_workerlb_ci_task(
-                shared.read(),
-                &loopback_s,
-                api_key, ci_run_key, task_nr, checkout, task,
-            );
+    let task_workers = self.machine_cfg.as_ref()
+      .map(|machine_cfg| machine_cfg.local_machine.task_workers)
+      .unwrap_or(1).max(1);
+    for _ in 0 .. task_workers {
+      let shared = shared.clone();
+      let loopback_s = self.loopback_s.clone();
+      let workerlb_r = self.workerlb_r.clone();
+      let workers_stop = self.workers_stop.clone();
+      spawn(move || {
+        loop {
+          match workerlb_r.recv() {
+            Err(_) => continue,
+            Ok(WorkerLbMsg::CiTask{..}) if workers_stop.load(Ordering::SeqCst) => {
+              // Left pending in the journal, to be resumed on the next start.
+              continue;
+            }
+            Ok(WorkerLbMsg::CiTask{api_key, ci_run_key, task_nr, checkout, task, opts}) => {
+              handle_workerlb_ci_task(
+                  &shared,
+                  &loopback_s,
+                  api_key, ci_run_key, task_nr, checkout, task, opts,
+                  &workers_stop,
+              );
+            }
           }
         }
+      });
+    }
+    self._resume_runs();
+    let webhook_cfg = CiConfig::open(&self.shared.read().config).ok().and_then(|cfg| cfg.webhook);
+    if let Some(webhook_cfg) = webhook_cfg {
+      let config = Config::with_dir(self.shared.read().config.config_dir.clone());
+      match WebhookServer::bind(&webhook_cfg, config) {
+        Err(e) => {
+          eprintln!("TRACE: guppybot: webhook: {:?}", e);
+        }
+        Ok(server) => {
+          eprintln!("TRACE: guppybot: webhook: listening on {}", webhook_cfg.listen_addr);
+          let loopback_s = self.loopback_s.clone();
+          spawn(move || {
+            server.serve(|event| {
+              loopback_s.send(LoopbackMsg::LocalCiRun{
+                source: RunSource::Webhook,
+                run: NewRun{
+                  remote_url: event.remote_url,
+                  ref_full: Some(event.ref_full),
+                  commit_hash: Some(event.commit_hash),
+                  base_hash: event.base_hash,
+                  originator: event.originator,
+                },
+              }).unwrap();
+            });
+          });
+        }
       }
-    });
+    }
+    let shared = self.shared.clone();
+    let loopback_s = self.loopback_s.clone();
+    let poll_state = self.poll_state.clone();
+    let unsettled_r = self.unsettled_r.clone();
+    let workers_stop = self.workers_stop.clone();
+    spawn(move || poll_loop(shared, poll_state, loopback_s, unsettled_r, workers_stop));
+    let loopback_s = self.loopback_s.clone();
+    ctrlc::set_handler(move || {
+      loopback_s.send(LoopbackMsg::Shutdown).ok();
+    })
+      .map_err(|_| fail("failed to set signal handler"))?;
     let shared = self.shared.clone();
     let ctlchan_s = self.ctlchan_s.clone();
-    let ctl_server_join_h = spawn(move || {
+    spawn(move || {
       let ctl_server = {
         let shared = shared.read();
         let &Shared{ref sysroot, ..} = &*shared;
@@ -843,103 +1951,17 @@ impl Context {
         }
       }
     });
+    let ticker = tick(Duration::from_secs(1));
     loop {
+      if self._ready_to_stop() {
+        break;
+      }
       select! {
         recv(self.loopback_r) -> msg => match msg {
           Err(_) => {}
-          Ok(LoopbackMsg::_Echo{echo_ctr}) => {
-            if echo_ctr == 0 {
-              eprintln!("TRACE: guppybot: warning: got zero-valued echo");
-            }
-            let reg_echo_ctr = self.reg_echo_ctr.load(Ordering::Relaxed);
-            if echo_ctr != reg_echo_ctr {
-            } else if echo_ctr == reg_echo_ctr {
-              //eprintln!("TRACE: guppybot: ping...");
-              if self.reg_sender.is_none() {
-                continue;
-              }
-              if self.reg_sender.as_mut().unwrap()
-                .send_auth(
-                    self.api_cfg.as_ref().map(|api| &api.auth),
-                    &Bot2RegistryV0::_Ping{
-                      // FIXME
-                      api_key: vec![],
-                      machine_key: self.shared.read().root_manifest.key_buf().as_vec().clone(),
-                    }
-                ).is_err()
-              {
-                continue;
-              }
-            } else {
-              unreachable!();
-            }
-          }
-          Ok(LoopbackMsg::_Echo2) => {
-            eprintln!("TRACE: guppybot: trying to reconnect...");
-            self._init(true).ok();
-          }
-          Ok(LoopbackMsg::StartCiTask{api_key, ci_run_key, task_nr, task_name, taskspec}) => {
-            if self.reg_sender.is_none() {
-              continue;
-            }
-            if self.reg_sender.as_mut().unwrap()
-              .send_auth(
-                  self.api_cfg.as_ref().map(|api| &api.auth),
-                  &Bot2RegistryV0::_StartCiTask{
-                    api_key,
-                    machine_key: self.shared.read().root_manifest.key_buf().as_vec().clone(),
-                    ci_run_key,
-                    task_nr,
-                    task_name,
-                    taskspec,
-                    ts: Some(Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false)),
-                  }
-              ).is_err()
-            {
-              continue;
-            }
-          }
-          Ok(LoopbackMsg::AppendCiTaskData{api_key, ci_run_key, task_nr, part_nr, key, data}) => {
-            if self.reg_sender.is_none() {
-              continue;
-            }
-            if self.reg_sender.as_mut().unwrap()
-              .send_auth(
-                  self.api_cfg.as_ref().map(|api| &api.auth),
-                  &Bot2RegistryV0::_AppendCiTaskData{
-                    api_key,
-                    ci_run_key,
-                    task_nr,
-                    part_nr,
-                    ts: Some(Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false)),
-                    key,
-                    data,
-                  }
-              ).is_err()
-            {
-              continue;
-            }
-          }
-          Ok(LoopbackMsg::DoneCiTask{api_key, ci_run_key, task_nr, failed}) => {
-            if self.reg_sender.is_none() {
-              continue;
-            }
-            if self.reg_sender.as_mut().unwrap()
-              .send_auth(
-                  self.api_cfg.as_ref().map(|api| &api.auth),
-                  &Bot2RegistryV0::_DoneCiTask{
-                    api_key,
-                    ci_run_key,
-                    task_nr,
-                    failed,
-                    ts: Some(Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, false)),
-                  }
-              ).is_err()
-            {
-              continue;
-            }
-          }
+          Ok(msg) => self._handle_loopback(msg),
         },
+        recv(ticker) -> _ => {}
         recv(self.ctlchan_r) -> chan => match chan {
           Err(_) => {}
           Ok(mut chan) => {
@@ -960,6 +1982,7 @@ impl Context {
                     api_key: api_id,
                     secret_token,
                   },
+                  registry: self.api_cfg.as_ref().and_then(|cfg| cfg.registry.clone()),
                 };
                 let cfg_path = PathBuf::from("/etc/guppybot/api");
                 let mut cfg_file = File::create(&cfg_path).unwrap();
@@ -987,6 +2010,21 @@ impl Context {
               Ctl2Bot::_UndoApiAuth => {
                 Bot2Ctl::_UndoApiAuth(None)
               }
+              Ctl2Bot::Drain => {
+                let mut shared = self.shared.write();
+                let &mut Shared{ref sysroot, ref mut root_manifest, ..} = &mut *shared;
+                match root_manifest.set_drain_bit(true, sysroot) {
+                  Err(_) => Bot2Ctl::Drain(None),
+                  Ok(_) => {
+                    eprintln!("TRACE: guppybot: draining, not accepting new ci runs");
+                    self.draining = true;
+                    Bot2Ctl::Drain(Some(Drain{
+                      running_tasks: self.running.len() as u64,
+                      queued_tasks: self.workerlb_s.len() as u64,
+                    }))
+                  }
+                }
+              }
               Ctl2Bot::EchoApiId => {
                 Bot2Ctl::EchoApiId(None)
               }
@@ -1065,9 +2103,38 @@ impl Context {
               }
               Ctl2Bot::ReloadConfig => {
                 let shared = self.shared.read();
-                self.api_cfg = ApiConfig::open(&shared.config).ok();
-                self.machine_cfg = MachineConfigV0::open(&shared.config).ok();
-                Bot2Ctl::ReloadConfig(Some(()))
+                match (CiConfig::open_optional(&shared.config), DaemonConfig::open(&shared.config)) {
+                  (Err(e), _) => {
+                    eprintln!("TRACE: guppybot: reload config: invalid ci config, keeping the previous config: {:?}", e);
+                    Bot2Ctl::ReloadConfig(None)
+                  }
+                  (_, Err(e)) => {
+                    eprintln!("TRACE: guppybot: reload config: invalid daemon config, keeping the previous config: {:?}", e);
+                    Bot2Ctl::ReloadConfig(None)
+                  }
+                  (Ok(ci_cfg), Ok(daemon_cfg)) => {
+                    self.api_cfg = ApiConfig::open(&shared.config).ok();
+                    self.registry_cfg = self.api_cfg.as_ref().and_then(|cfg| cfg.registry.clone()).unwrap_or_default();
+                    self.machine_cfg = MachineConfigV0::open(&shared.config).ok();
+                    self.daemon_cfg = daemon_cfg;
+                    self.ci_cfg = ci_cfg;
+                    self.gpus = local_gpus(&shared.config);
+                    update_mutable_cache_quota(&shared.config, &shared.sysroot);
+                    Bot2Ctl::ReloadConfig(Some(()))
+                  }
+                }
+              }
+              Ctl2Bot::Resume => {
+                let mut shared = self.shared.write();
+                let &mut Shared{ref sysroot, ref mut root_manifest, ..} = &mut *shared;
+                match root_manifest.set_drain_bit(false, sysroot) {
+                  Err(_) => Bot2Ctl::Resume(None),
+                  Ok(_) => {
+                    eprintln!("TRACE: guppybot: resumed accepting new ci runs");
+                    self.draining = false;
+                    Bot2Ctl::Resume(Some(()))
+                  }
+                }
               }
               Ctl2Bot::UnregisterCiMachine => {
                 Bot2Ctl::UnregisterCiMachine(None)
@@ -1136,49 +2203,55 @@ impl Context {
                 if self.reg_sender.is_none() {
                   continue;
                 }
+                if self.draining || self.stopping.is_some() {
+                  eprintln!("TRACE: guppybot: new ci run: rejected, not accepting new ci runs");
+                  let message = match self.draining {
+                    false => "machine is shutting down",
+                    true  => "machine is draining for maintenance",
+                  };
+                  self._reject_ci_run(&api_key, &ci_run_key, NewCiRunReject::NotAccepting{
+                    message: message.to_string(),
+                  }, None);
+                  continue;
+                }
                 // FIXME: if "local_machine.task_workers" is zero, redirect to a
                 // remote machine, if one is available, otherwise reject.
-                // FIXME: better error handling.
-                let shared = self.shared.read();
-                let checkout = match GitCheckoutSpec::with_remote_url(repo_clone_url) {
-                  Err(_) => {
-                    eprintln!("TRACE: guppybot: new ci run: git checkout spec failed");
-                    continue;
-                  }
-                  Ok(x) => x,
-                };
-                let mut image_manifest = match ImageManifest::load(&shared.sysroot, &shared.root_manifest) {
-                  Err(_) => {
-                    eprintln!("TRACE: guppybot: new ci run: image manifest load failed");
-                    continue;
-                  }
-                  Ok(x) => x,
+                let run = NewRun{
+                  remote_url: repo_clone_url,
+                  ref_full,
+                  commit_hash,
+                  base_hash: None,
+                  originator,
                 };
-                let builtin_imagespec = ImageSpec::builtin_default();
-                let builtin_image = match image_manifest.lookup_docker_image(&builtin_imagespec, &shared.sysroot, &shared.root_manifest) {
-                  Err(_) => {
-                    eprintln!("TRACE: guppybot: new ci run: image lookup failed");
-                    continue;
-                  }
-                  Ok(x) => x,
-                };
-                match builtin_image._run_checkout(&checkout, &shared.sysroot) {
-                  Err(e) => {
-                    eprintln!("TRACE: guppybot: new ci run: checkout failed: {:?}", e);
-                    continue;
-                  }
-                  Ok(_) => {}
-                }
-                let (_spec_out, tasks) = match builtin_image._run_spec(&checkout, &shared.sysroot) {
-                  Err(e) => {
-                    eprintln!("TRACE: guppybot: new ci run: taskspec failed: {:?}", e);
+                let (checkout, tasks, skips) = match self._prepare_ci_run(&ci_run_key, &run) {
+                  Err((reason, spec_out)) => {
+                    eprintln!("TRACE: guppybot: new ci run: rejected: {:?}", reason);
+                    self.journal.finish_run(&ci_run_key).ok();
+                    self._reject_ci_run(&api_key, &ci_run_key, reason, spec_out);
                     continue;
                   }
                   Ok(x) => x,
                 };
                 let task_count = tasks.len() as u64;
+                let trusted = trusts_run(self.ci_cfg.as_ref(), &run.remote_url, run.ref_full.as_ref().map(|s| s.as_str()), run.originator.as_ref().map(|s| s.as_str()));
                 eprintln!("TRACE: guppybot: new ci run: confirmed:");
                 eprintln!("TRACE: guppybot:   task count: {}", task_count);
+                if let Err(e) = self.journal.begin_run(&JournalRun{
+                  source: RunSource::Registry,
+                  api_key: api_key.clone(),
+                  ci_run_key: ci_run_key.clone(),
+                  remote_url: run.remote_url,
+                  ref_full: run.ref_full,
+                  commit_hash: run.commit_hash,
+                  task_count,
+                }) {
+                  eprintln!("TRACE: guppybot: new ci run: journal failed: {:?}", e);
+                  self.journal.finish_run(&ci_run_key).ok();
+                  self._reject_ci_run(&api_key, &ci_run_key, NewCiRunReject::Internal{
+                    message: "failed to journal the ci run".to_string(),
+                  }, None);
+                  continue;
+                }
                 let api_cfg = self.api_cfg.as_ref().unwrap();
                 if self.reg_sender.as_mut().unwrap()
                   .send_auth(
@@ -1193,31 +2266,23 @@ impl Context {
                       }))
                   ).is_err()
                 {
+                  self.journal.finish_run(&ci_run_key).ok();
                   continue;
                 }
-                for task_idx in 0 .. task_count {
-                  let task_nr = task_idx + 1;
-                  assert!(task_nr != 0);
-                  self.workerlb_s.send(WorkerLbMsg::CiTask{
-                    api_key: api_key.clone(),
-                    ci_run_key: ci_run_key.clone(),
-                    task_nr,
-                    checkout: checkout.clone(),
-                    task: tasks[task_idx as usize].clone(),
-                  });
-                }
-              }
-              Registry2BotV0::_StartCiTask(Some(_)) => {
-              }
-              Registry2BotV0::_StartCiTask(None) => {
-              }
-              Registry2BotV0::_AppendCiTaskData(Some(_)) => {
-              }
-              Registry2BotV0::_AppendCiTaskData(None) => {
+                self._enqueue_tasks(&api_key, &ci_run_key, &checkout, trusted, tasks, skips);
               }
+              Registry2BotV0::_StartCiTask(Some(_)) |
+              Registry2BotV0::_AppendCiTaskData(Some(_)) |
               Registry2BotV0::_DoneCiTask(Some(_)) => {
+                self._ack_outbox();
               }
+              Registry2BotV0::_StartCiTask(None) |
+              Registry2BotV0::_AppendCiTaskData(None) |
               Registry2BotV0::_DoneCiTask(None) => {
+                // The registry refused the message; resending it would not
+                // change the answer.
+                eprintln!("TRACE: guppybot: outbox: message refused by registry");
+                self._ack_outbox();
               }
               Registry2BotV0::Auth(Some(_)) => {
                 let mut shared = self.shared.write();
@@ -1244,6 +2309,8 @@ impl Context {
                   }
                 }
                 self.auth = true;
+                drop(shared);
+                self._flush_outbox();
               }
               Registry2BotV0::Auth(None) => {
                 let mut shared = self.shared.write();
@@ -1306,22 +2373,31 @@ impl Context {
               _ => {}
             }
           }
+          Ok(BotWsMsg::Open(s)) => {
+            // The connection finished opening after `_reconnect_reg` timed
+            // out waiting for it.
+            self.reg_sender = Some(s);
+            self._reset_outbox();
+            self._init(false).ok();
+          }
           Ok(BotWsMsg::Hup) | Ok(BotWsMsg::Error) => {
             // FIXME: try to reconnect/reauth.
             if let Some(h) = self.reg_conn_join_h.take() {
               h.join().ok();
             }
+            self.reg_sender = None;
+            self.auth = false;
+            self._reset_outbox();
           }
           _ => {}
         }
       }
     }
-    watchdog_join_h.join().ok();
-    worker_join_h.join().ok();
-    ctl_server_join_h.join().ok();
-    if let Some(h) = self.reg_conn_join_h.take() {
-      h.join().ok();
+    self._kill_running_tasks();
+    if let Some(ref reg_sender) = self.reg_sender {
+      reg_sender.registry_s.close(ws::CloseCode::Away).ok();
     }
+    eprintln!("TRACE: guppybot: stopped");
     Ok(())
   }
 }
@@ -5,12 +5,16 @@ extern crate chrono;
 #[macro_use] extern crate crossbeam_channel;
 extern crate ctrlc;
 extern crate dirs;
+extern crate hex;
+extern crate mio;
 extern crate monosodium;
+extern crate openssl;
 extern crate parking_lot;
 extern crate rand;
 extern crate schemas;
 extern crate toml;
 extern crate tooling;
+extern crate url;
 extern crate ws;
 
 use std::process::{exit};
@@ -1,3 +1,5 @@
+use crate::report::{LOG_EXCERPT_LINES, TaskOutcome, TaskResult, write_json_report, write_junit_report};
+
 use clap::{App, Arg, ArgMatches, SubCommand};
 use crossbeam_utils::{Backoff};
 //use curl::easy::{Easy, List};
@@ -6,24 +8,30 @@ use dirs::{home_dir};
 use schemas::wire_protocol::{DistroInfoV0, GpusV0, MachineConfigV0};
 use semver::{Version};
 use serde_json::{Value as JsonValue};
-use tempfile::{NamedTempFile};
+use tempfile::{NamedTempFile, TempDir};
 use tooling::assets::{GUPPYBOT_SERVICE};
-use tooling::config::{Config, ApiConfig};
+use tooling::cache::{BuildCacheMount, CacheIndex, build_cache_mounts, build_caches, prepare_build_caches};
+use tooling::config::{Config, ApiConfig, BuildCacheConfig, DaemonConfig, MutableCacheConfig, TaskLimitsConfig, parse_byte_size};
+use tooling::dag::{Readiness, TaskGraph, TaskNodeState};
 use tooling::deps::{DockerDeps, Docker, NvidiaDocker2};
-use tooling::docker::{GitCheckoutSpec, DockerOutput, DockerRunStatus};
+use tooling::docker::{GitCheckoutSpec, DockerOutput, DockerRunOpts, DockerRunStatus, TaskLimits, TaskSpec, TaskSpecReport, remove_container};
+use tooling::glob::{glob_match};
+use tooling::gpu::{Gpu, local_gpus, task_gpus};
 use tooling::ipc::*;
-use tooling::query::{Maybe, Query, fail};
+use tooling::query::{Maybe, Open, Query, fail};
 use tooling::state::{ImageManifest, ImageSpec, RootManifest, Sysroot};
+use tooling::taskfile::{TaskFileFormat, find_taskfile, load_taskfile};
 //use url::{Url};
 
 use std::env::{current_dir};
 use std::fs::{File, Permissions, create_dir_all};
 use std::io::{Write, stdin, stdout};
 use std::os::unix::fs::{PermissionsExt};
-use std::path::{PathBuf};
-use std::process::{Command, exit};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, exit};
 use std::str;
-use std::time::{Instant};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
   let version_str = format!("beta (git: {})", str::from_utf8(git_head_commit).unwrap());
@@ -51,12 +59,116 @@ pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
         .help("User-mode installation prefix. Defaults to '$HOME/.guppybot'.")
       )
     )
+    .subcommand(SubCommand::with_name("cache")
+      .about("Manage the mutable cache")
+      .arg(Arg::with_name("USER")
+        .short("U")
+        .long("user")
+        .takes_value(false)
+        .global(true)
+        .help("User-mode. Assumes installation in the directory given by\n'--user-prefix'.")
+      )
+      .arg(Arg::with_name("USER_PREFIX")
+        .long("user-prefix")
+        .takes_value(true)
+        .global(true)
+        .help("User-mode prefix. Defaults to '$HOME/.guppybot'.")
+      )
+      .subcommand(SubCommand::with_name("builds")
+        .about("List the build caches of tasks, by repository and toolchain")
+      )
+      .subcommand(SubCommand::with_name("list")
+        .about("List the entries of the mutable cache")
+      )
+      .subcommand(SubCommand::with_name("verify")
+        .about("Check the entries of the mutable cache against their recorded\nsizes and checksums")
+        .arg(Arg::with_name("PATH")
+          .index(1)
+          .multiple(true)
+          .help("Paths of the entries to check, relative to the cache. The\ndefault is every entry.")
+        )
+        .arg(Arg::with_name("REMOVE")
+          .long("rm")
+          .takes_value(false)
+          .help("Remove the entries which fail the check.")
+        )
+      )
+      .subcommand(SubCommand::with_name("rm")
+        .about("Remove entries from the mutable cache")
+        .arg(Arg::with_name("PATH")
+          .index(1)
+          .required(true)
+          .multiple(true)
+          .help("Paths of the entries to remove, relative to the cache.\nSymlinks to the removed entries are also removed.")
+        )
+      )
+      .subcommand(SubCommand::with_name("prune")
+        .about("Remove files missing from the cache index, and evict the least\nrecently used entries down to the size quota")
+        .arg(Arg::with_name("MAX_SIZE")
+          .long("max-size")
+          .takes_value(true)
+          .help("Evict down to this size (e.g. '20g') instead of the quota\nin the machine config.")
+        )
+      )
+    )
+    .subcommand(SubCommand::with_name("drain")
+      .about("Stop accepting new CI runs, letting queued and running tasks finish")
+      .arg(Arg::with_name("USER")
+        .short("U")
+        .long("user")
+        .takes_value(false)
+        .help("User-mode. Assumes installation in the directory given by\n'--user-prefix'.")
+      )
+      .arg(Arg::with_name("USER_PREFIX")
+        .long("user-prefix")
+        .takes_value(true)
+        .help("User-mode prefix. Defaults to '$HOME/.guppybot'.")
+      )
+    )
     /*.subcommand(SubCommand::with_name("echo-api-id")
       .about("Print the registered API identifier")
     )
     .subcommand(SubCommand::with_name("echo-machine-id")
       .about("Print the registered machine identifier")
     )*/
+    .subcommand(SubCommand::with_name("explain")
+      .about("Print what running a local gup.py script would build and run")
+      .arg(Arg::with_name("FILE")
+        .index(1)
+        .help("Path to local gup.py script or gup.toml/gup.json taskspec\nfile. The default path is '<WORKING_DIR>/gup.toml' or\n'<WORKING_DIR>/gup.json' if either exists, otherwise\n'<WORKING_DIR>/gup.py'.")
+      )
+      .arg(Arg::with_name("TASK")
+        .short("t")
+        .long("task")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Only explain the tasks with this name or matching this glob\npattern, along with the tasks they depend on. May be given\nmore than once.")
+      )
+      .arg(Arg::with_name("MUTABLE")
+        .short("m")
+        .long("mut")
+        .takes_value(false)
+        .help("Explain a run with a mutable local working directory.")
+      )
+      .arg(Arg::with_name("WORKING_DIR")
+        .short("d")
+        .long("dir")
+        .takes_value(true)
+        .help("The local working directory. If not provided, the default\nis the current directory.")
+      )
+      .arg(Arg::with_name("USER")
+        .short("U")
+        .long("user")
+        .takes_value(false)
+        .help("User-mode. Assumes installation in the directory given by\n'--user-prefix'.")
+      )
+      .arg(Arg::with_name("USER_PREFIX")
+        .long("user-prefix")
+        .takes_value(true)
+        .help("User-mode prefix. Defaults to '$HOME/.guppybot'.")
+      )
+    )
     /*.subcommand(SubCommand::with_name("print-config")
       .about("Print the currently loaded configuration")
     )*/
@@ -106,6 +218,20 @@ pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
     .subcommand(SubCommand::with_name("reload-config")
       .about("Reload configuration")
     )
+    .subcommand(SubCommand::with_name("resume")
+      .about("Resume accepting new CI runs after `drain`")
+      .arg(Arg::with_name("USER")
+        .short("U")
+        .long("user")
+        .takes_value(false)
+        .help("User-mode. Assumes installation in the directory given by\n'--user-prefix'.")
+      )
+      .arg(Arg::with_name("USER_PREFIX")
+        .long("user-prefix")
+        .takes_value(true)
+        .help("User-mode prefix. Defaults to '$HOME/.guppybot'.")
+      )
+    )
     /*.subcommand(SubCommand::with_name("run")
       .about("")
     )*/
@@ -128,13 +254,50 @@ pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
         .help("Debug option: alternative sysroot path. The default sysroot\npath is '/var/lib/guppybot'.")
       )
     )
+    .subcommand(SubCommand::with_name("shell")
+      .about("Start an interactive shell in the environment of a task")
+      .arg(Arg::with_name("FILE")
+        .index(1)
+        .help("Path to local gup.py script or gup.toml/gup.json taskspec\nfile. The default path is '<WORKING_DIR>/gup.toml' or\n'<WORKING_DIR>/gup.json' if either exists, otherwise\n'<WORKING_DIR>/gup.py'.")
+      )
+      .arg(Arg::with_name("TASK")
+        .short("t")
+        .long("task")
+        .takes_value(true)
+        .required(true)
+        .help("Name of the task whose environment to start the shell in.")
+      )
+      .arg(Arg::with_name("MUTABLE")
+        .short("m")
+        .long("mut")
+        .takes_value(false)
+        .help("Make the local working directory mutable, allowing the\nshell to modify the host filesystem.")
+      )
+      .arg(Arg::with_name("WORKING_DIR")
+        .short("d")
+        .long("dir")
+        .takes_value(true)
+        .help("The local working directory. If not provided, the default\nis the current directory.")
+      )
+      .arg(Arg::with_name("USER")
+        .short("U")
+        .long("user")
+        .takes_value(false)
+        .help("User-mode. Assumes installation in the directory given by\n'--user-prefix'.")
+      )
+      .arg(Arg::with_name("USER_PREFIX")
+        .long("user-prefix")
+        .takes_value(true)
+        .help("User-mode prefix. Defaults to '$HOME/.guppybot'.")
+      )
+    )
     .subcommand(SubCommand::with_name("tmp-run")
       .about("Run a local gup.py script in a local working directory")
       .arg(Arg::with_name("FILE")
         .short("f")
         .long("file")
         .takes_value(true)
-        .help("Alternative path to local script file. The default path\nis '<WORKING_DIR>/gup.py'.")
+        .help("Alternative path to local gup.py script or gup.toml/gup.json\ntaskspec file. The default path is '<WORKING_DIR>/gup.toml'\nor '<WORKING_DIR>/gup.json' if either exists, otherwise\n'<WORKING_DIR>/gup.py'.")
       )
       .arg(Arg::with_name("MUTABLE")
         .short("m")
@@ -154,6 +317,55 @@ pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
         .takes_value(false)
         .help("Quiet mode. Suppress some logging output.")
       )
+      .arg(Arg::with_name("LIST")
+        .short("l")
+        .long("list")
+        .takes_value(false)
+        .help("List the tasks and their images instead of running them.")
+      )
+      .arg(Arg::with_name("JSON")
+        .long("json")
+        .takes_value(false)
+        .requires("LIST")
+        .help("With --list, print the tasks as JSON, in the format sent\nto the registry.")
+      )
+      .arg(Arg::with_name("TASK")
+        .short("t")
+        .long("task")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Only run the tasks with this name or matching this glob\npattern, along with the tasks they depend on. May be given\nmore than once.")
+      )
+      .arg(Arg::with_name("KEEP_GOING")
+        .short("k")
+        .long("keep-going")
+        .takes_value(false)
+        .help("After a task fails, keep running the tasks that do not\ndepend on it.")
+      )
+      .arg(Arg::with_name("REPORT_JSON")
+        .long("report-json")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Write a JSON report of the run to this path.")
+      )
+      .arg(Arg::with_name("REPORT_JUNIT")
+        .long("report-junit")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Write a JUnit XML report of the run to this path.")
+      )
+      .arg(Arg::with_name("SHELL_ON_FAILURE")
+        .long("shell-on-failure")
+        .takes_value(false)
+        .help("When a task fails, start an interactive shell in a snapshot\nof its container before going on.")
+      )
+      .arg(Arg::with_name("DRY_RUN")
+        .short("n")
+        .long("dry-run")
+        .takes_value(false)
+        .help("Print the tasks with their Dockerfiles and 'docker run'\ncommands, without building images or running tasks.")
+      )
       .arg(Arg::with_name("WORKING_DIR")
         .short("d")
         .long("dir")
@@ -214,6 +426,53 @@ pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
         Ok(_) => 0,
       }
     }
+    ("cache", Some(matches)) => {
+      let user = matches.is_present("USER");
+      let user_prefix = matches.value_of("USER_PREFIX")
+        .map(|s| PathBuf::from(s));
+      let res = match matches.subcommand() {
+        ("builds", Some(_)) => cache_builds(user, user_prefix),
+        ("list", Some(_)) => cache_list(user, user_prefix),
+        ("verify", Some(matches)) => {
+          let paths: Vec<_> = matches.values_of("PATH")
+            .map(|vals| vals.map(|s| s.to_string()).collect())
+            .unwrap_or_default();
+          cache_verify(user, user_prefix, &paths, matches.is_present("REMOVE"))
+        }
+        ("rm", Some(matches)) => {
+          let paths: Vec<_> = matches.values_of("PATH")
+            .map(|vals| vals.map(|s| s.to_string()).collect())
+            .unwrap_or_default();
+          cache_rm(user, user_prefix, &paths)
+        }
+        ("prune", Some(matches)) => {
+          cache_prune(user, user_prefix, matches.value_of("MAX_SIZE"))
+        }
+        _ => {
+          println!("{}", matches.usage());
+          Ok(())
+        }
+      };
+      match res {
+        Err(e) => {
+          eprintln!("cache: {:?}", e);
+          1
+        }
+        Ok(_) => 0,
+      }
+    }
+    ("drain", Some(matches)) => {
+      let user = matches.is_present("USER");
+      let user_prefix = matches.value_of("USER_PREFIX")
+        .map(|s| PathBuf::from(s));
+      match drain(user, user_prefix) {
+        Err(e) => {
+          eprintln!("drain: {:?}", e);
+          1
+        }
+        Ok(_) => 0,
+      }
+    }
     /*("print-config", Some(_matches)) => {
       match print_config() {
         Err(e) => {
@@ -241,6 +500,31 @@ pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
         Ok(_) => 0,
       }
     }*/
+    ("explain", Some(matches)) => {
+      let user = matches.is_present("USER");
+      let user_prefix = matches.value_of("USER_PREFIX")
+        .map(|s| PathBuf::from(s));
+      let opts = RunLocalOpts{
+        mutable: matches.is_present("MUTABLE"),
+        task_patterns: matches.values_of("TASK")
+          .map(|vals| vals.map(|s| s.to_string()).collect())
+          .unwrap_or_default(),
+        dry_run: true,
+        .. RunLocalOpts::default()
+      };
+      let working_dir = matches.value_of("WORKING_DIR")
+        .map(|s| PathBuf::from(s))
+        .or_else(|| current_dir().ok());
+      let spec_path = matches.value_of("FILE")
+        .map(|s| PathBuf::from(s));
+      match run_local(user, user_prefix, spec_path, working_dir, &opts) {
+        Err(e) => {
+          eprintln!("explain: {:?}", e);
+          1
+        }
+        Ok(_) => 0,
+      }
+    }
     ("register", Some(matches)) => {
       let user = matches.is_present("USER");
       let user_prefix = matches.value_of("USER_PREFIX")
@@ -262,6 +546,18 @@ pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
         Ok(_) => 0,
       }
     }
+    ("resume", Some(matches)) => {
+      let user = matches.is_present("USER");
+      let user_prefix = matches.value_of("USER_PREFIX")
+        .map(|s| PathBuf::from(s));
+      match resume(user, user_prefix) {
+        Err(e) => {
+          eprintln!("resume: {:?}", e);
+          1
+        }
+        Ok(_) => 0,
+      }
+    }
     /*("run", Some(matches)) => {
     }*/
     ("self-install", Some(matches)) => {
@@ -288,23 +584,52 @@ pub fn _dispatch(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
         Ok(_) => 0,
       }
     }
-    ("tmp-run", Some(matches)) => {
+    ("shell", Some(matches)) => {
       let user = matches.is_present("USER");
       let user_prefix = matches.value_of("USER_PREFIX")
         .map(|s| PathBuf::from(s));
+      let task_name = matches.value_of("TASK").unwrap();
       let mutable = matches.is_present("MUTABLE");
-      let stdout = matches.is_present("STDOUT");
-      let quiet = matches.is_present("QUIET");
       let working_dir = matches.value_of("WORKING_DIR")
         .map(|s| PathBuf::from(s))
         .or_else(|| current_dir().ok());
-      let gup_py_path = matches.value_of("FILE")
+      let spec_path = matches.value_of("FILE")
+        .map(|s| PathBuf::from(s));
+      match shell(user, user_prefix, spec_path, working_dir, task_name, mutable) {
+        Err(e) => {
+          eprintln!("shell: {:?}", e);
+          1
+        }
+        Ok(_) => 0,
+      }
+    }
+    ("tmp-run", Some(matches)) => {
+      let user = matches.is_present("USER");
+      let user_prefix = matches.value_of("USER_PREFIX")
+        .map(|s| PathBuf::from(s));
+      let opts = RunLocalOpts{
+        mutable: matches.is_present("MUTABLE"),
+        quiet: matches.is_present("QUIET"),
+        stdout: matches.is_present("STDOUT"),
+        list: matches.is_present("LIST"),
+        json: matches.is_present("JSON"),
+        task_patterns: matches.values_of("TASK")
+          .map(|vals| vals.map(|s| s.to_string()).collect())
+          .unwrap_or_default(),
+        keep_going: matches.is_present("KEEP_GOING"),
+        report_json: matches.value_of("REPORT_JSON")
+          .map(|s| PathBuf::from(s)),
+        report_junit: matches.value_of("REPORT_JUNIT")
+          .map(|s| PathBuf::from(s)),
+        shell_on_failure: matches.is_present("SHELL_ON_FAILURE"),
+        dry_run: matches.is_present("DRY_RUN"),
+      };
+      let working_dir = matches.value_of("WORKING_DIR")
         .map(|s| PathBuf::from(s))
-        .unwrap_or_else(|| match &working_dir {
-          &None => PathBuf::from("gup.py"),
-          &Some(ref p) => p.join("gup.py"),
-        });
-      match run_local(user, user_prefix, mutable, quiet, stdout, gup_py_path, working_dir) {
+        .or_else(|| current_dir().ok());
+      let spec_path = matches.value_of("FILE")
+        .map(|s| PathBuf::from(s));
+      match run_local(user, user_prefix, spec_path, working_dir, &opts) {
         Err(e) => {
           eprintln!("run-local: {:?}", e);
           1
@@ -510,6 +835,198 @@ pub fn unauth() -> Maybe {
   Ok(())
 }
 
+pub fn drain(user: bool, user_prefix: Option<PathBuf>) -> Maybe {
+  let mut chan = CtlChannel::open_user(user, user_prefix)?;
+  chan.send(&Ctl2Bot::Drain)?;
+  let rep = match chan.recv()? {
+    Bot2Ctl::Drain(Some(rep)) => rep,
+    Bot2Ctl::Drain(None) => {
+      return Err(fail("failed to drain"));
+    }
+    _ => return Err(fail("IPC protocol error")),
+  };
+  chan.hup();
+  println!("Draining: no longer accepting new CI runs.");
+  println!("Running tasks: {}", rep.running_tasks);
+  println!("Queued tasks: {}", rep.queued_tasks);
+  Ok(())
+}
+
+pub fn resume(user: bool, user_prefix: Option<PathBuf>) -> Maybe {
+  let mut chan = CtlChannel::open_user(user, user_prefix)?;
+  chan.send(&Ctl2Bot::Resume)?;
+  match chan.recv()? {
+    Bot2Ctl::Resume(Some(_)) => {}
+    Bot2Ctl::Resume(None) => {
+      return Err(fail("failed to resume"));
+    }
+    _ => return Err(fail("IPC protocol error")),
+  }
+  chan.hup();
+  println!("Resumed: accepting new CI runs.");
+  Ok(())
+}
+
+fn user_config(user: bool, user_prefix: Option<PathBuf>) -> Maybe<Config> {
+  match user {
+    false => Ok(Config::default()),
+    true  => {
+      let d = user_prefix.or_else(|| {
+        home_dir().map(|d| d.join(".guppybot"))
+      })
+        .ok_or_else(|| fail("Failed to find user directory"))?
+        .join("conf");
+      Ok(Config::with_dir(d))
+    }
+  }
+}
+
+fn user_sysroot(user: bool, user_prefix: Option<PathBuf>) -> Maybe<Sysroot> {
+  match user {
+    false => Ok(Sysroot::default()),
+    true  => {
+      let d = user_prefix.or_else(|| {
+        home_dir().map(|d| d.join(".guppybot"))
+      })
+        .ok_or_else(|| fail("Failed to find user directory"))?;
+      Ok(Sysroot{base_dir: d.join("lib"), sock_dir: d.join("run")})
+    }
+  }
+}
+
+fn format_size(size: u64) -> String {
+  let mut x = size as f64;
+  for unit in ["K", "M", "G", "T"].iter() {
+    x /= 1024.0;
+    if x < 1024.0 {
+      return format!("{:.1}{}", x, unit);
+    }
+  }
+  format!("{:.1}P", x / 1024.0)
+}
+
+fn format_limits(limits: &TaskLimits) -> String {
+  let mut parts = Vec::new();
+  if let Some(cpus) = limits.cpus {
+    parts.push(format!("cpus={}", cpus));
+  }
+  if let Some(memory) = limits.memory {
+    parts.push(format!("memory={}", format_size(memory)));
+  }
+  if let Some(pids) = limits.pids {
+    parts.push(format!("pids={}", pids));
+  }
+  if let Some(shm) = limits.shm {
+    parts.push(format!("shm={}", format_size(shm)));
+  }
+  parts.join(" ")
+}
+
+pub fn cache_list(user: bool, user_prefix: Option<PathBuf>) -> Maybe {
+  let sysroot = user_sysroot(user, user_prefix)?;
+  let index = CacheIndex::load(&sysroot)?;
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0);
+  for (path, entry) in index.entries.iter() {
+    let last_used = format_elapsed(Duration::from_secs(now.saturating_sub(entry.last_used)));
+    let sha256 = entry.sha256.as_ref().map(|s| s.get( .. 12).unwrap_or(s.as_str())).unwrap_or("-");
+    println!("{:>8}  {:>12} ago  {}  {}  ({} {})",
+        format_size(entry.size), last_used, sha256, path, entry.op, entry.src);
+  }
+  let quota = match index.max_size {
+    None => "none".to_string(),
+    Some(max_size) => format_size(max_size),
+  };
+  println!("{} entries, {} total (quota: {}).", index.entries.len(), format_size(index.total_size()), quota);
+  Ok(())
+}
+
+pub fn cache_builds(user: bool, user_prefix: Option<PathBuf>) -> Maybe {
+  let sysroot = user_sysroot(user, user_prefix.clone())?;
+  let caches = build_caches(&sysroot)?;
+  let mut total_size = 0;
+  for cache in caches.iter() {
+    let repo = cache.remote_url.as_ref().map(|s| s.as_str()).unwrap_or("(local)");
+    println!("{:>8}  {}  {}  {}", format_size(cache.size), repo, cache.toolchain, cache.name);
+    println!("          {}", cache.dir.display());
+    total_size += cache.size;
+  }
+  let max_size = user_config(user, user_prefix).ok()
+    .and_then(|config| BuildCacheConfig::open(&config).ok())
+    .and_then(|cfg| cfg.max_size);
+  let limit = match max_size {
+    None => "none".to_string(),
+    Some(max_size) => format_size(max_size),
+  };
+  println!("{} build caches, {} total (limit per cache: {}).", caches.len(), format_size(total_size), limit);
+  Ok(())
+}
+
+pub fn cache_verify(user: bool, user_prefix: Option<PathBuf>, paths: &[String], remove: bool) -> Maybe {
+  let sysroot = user_sysroot(user, user_prefix)?;
+  let mut index = CacheIndex::load(&sysroot)?;
+  let paths: Vec<String> = match paths.is_empty() {
+    false => paths.to_vec(),
+    true  => index.entries.keys().cloned().collect(),
+  };
+  let mut bad = Vec::new();
+  for path in paths.iter() {
+    match index.verify(path, &sysroot) {
+      Err(e) => {
+        println!("  BAD  {}: {}", path, e.excuses.join(": "));
+        bad.push(path.clone());
+      }
+      Ok(_) => println!("  OK   {}", path),
+    }
+  }
+  if remove && !bad.is_empty() {
+    for path in bad.iter() {
+      for removed_path in index.remove(path, &sysroot)?.iter() {
+        println!("Removed {}", removed_path);
+      }
+    }
+    index.save(&sysroot)?;
+  }
+  match bad.len() {
+    0 => Ok(()),
+    n => Err(fail(format!("{} of {} entries failed to verify", n, paths.len()))),
+  }
+}
+
+pub fn cache_rm(user: bool, user_prefix: Option<PathBuf>, paths: &[String]) -> Maybe {
+  let sysroot = user_sysroot(user, user_prefix)?;
+  let mut index = CacheIndex::load(&sysroot)?;
+  for path in paths.iter() {
+    for removed_path in index.remove(path, &sysroot)?.iter() {
+      println!("Removed {}", removed_path);
+    }
+  }
+  index.save(&sysroot)
+}
+
+pub fn cache_prune(user: bool, user_prefix: Option<PathBuf>, max_size: Option<&str>) -> Maybe {
+  let max_size = match max_size {
+    Some(s) => Some(parse_byte_size(s)
+      .ok_or_else(|| fail(format!("invalid size {:?}", s)))?),
+    None => {
+      let config = user_config(user, user_prefix.clone())?;
+      MutableCacheConfig::open(&config)?.max_size
+    }
+  };
+  let sysroot = user_sysroot(user, user_prefix)?;
+  let mut index = CacheIndex::load(&sysroot)?;
+  // Without a machine config, fall back to the quota last set by the daemon.
+  let max_size = max_size.or(index.max_size);
+  let removed = index.prune(max_size, &sysroot)?;
+  index.save(&sysroot)?;
+  for path in removed.iter() {
+    println!("Removed {}", path);
+  }
+  println!("{} entries, {} total.", index.entries.len(), format_size(index.total_size()));
+  Ok(())
+}
+
 pub fn install_deps() -> Maybe {
   let distro_info = DistroInfoV0::query()?;
   DockerDeps::check(&distro_info)?
@@ -796,8 +1313,155 @@ pub fn reload_config() -> Maybe {
   Ok(())
 }
 
-fn _run_local(user: bool, user_prefix: Option<PathBuf>, mutable: bool, quiet: bool, stdout_: bool, gup_py_path: PathBuf, working_dir: Option<PathBuf>) -> Maybe<DockerRunStatus> {
-  let run_start = Instant::now();
+/// Options of `tmp-run`, besides where to find the script and sysroot.
+#[derive(Default)]
+pub struct RunLocalOpts {
+  pub mutable: bool,
+  pub quiet: bool,
+  pub stdout: bool,
+  /// Only list the tasks, without running them.
+  pub list: bool,
+  /// List the tasks as JSON.
+  pub json: bool,
+  /// Names or glob patterns of the tasks to run; empty to run every task.
+  pub task_patterns: Vec<String>,
+  /// Run the tasks not depending on a failed task after a failure, instead
+  /// of stopping at it.
+  pub keep_going: bool,
+  /// Where to write a JSON report of the run, if anywhere.
+  pub report_json: Option<PathBuf>,
+  /// Where to write a JUnit XML report of the run, if anywhere.
+  pub report_junit: Option<PathBuf>,
+  /// Start a shell in a snapshot of the container of each failed task.
+  pub shell_on_failure: bool,
+  /// Print what would be built and run for each task, without doing it.
+  pub dry_run: bool,
+}
+
+impl RunLocalOpts {
+  fn wants_report(&self) -> bool {
+    self.report_json.is_some() || self.report_junit.is_some()
+  }
+}
+
+fn format_elapsed(dur: Duration) -> String {
+  let ms = dur.subsec_millis() as u64;
+  let s = dur.as_secs() + ms / 500;
+  let m = s / 60;
+  let h = m / 60;
+  if h > 0 {
+    format!("{}h {:02}m {:02}s", h, m % 60, s % 60)
+  } else if m > 0 {
+    format!("{}m {:02}s", m, s % 60)
+  } else {
+    format!("{}s", s)
+  }
+}
+
+fn print_summary(results: &[TaskResult], run_dur: Duration) {
+  let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+  println!("Summary:");
+  for result in results.iter() {
+    match result.outcome {
+      TaskOutcome::Passed => {
+        passed += 1;
+        let retried = match result.attempts {
+          1 => String::new(),
+          n => format!(" (passed on attempt {})", n),
+        };
+        println!("  PASSED  {:>10}  {}{}", format_elapsed(result.duration), result.name, retried);
+      }
+      TaskOutcome::Failed => {
+        failed += 1;
+        println!("  FAILED  {:>10}  {}", format_elapsed(result.duration), result.name);
+      }
+      TaskOutcome::Skipped(ref reason) => {
+        skipped += 1;
+        println!("  SKIPPED {:>10}  {} ({})", "-", result.name, reason);
+      }
+    }
+  }
+  println!("{} passed, {} failed, {} skipped (elapsed: {}).", passed, failed, skipped, format_elapsed(run_dur));
+  stdout().flush().unwrap();
+}
+
+/// Indices of the tasks to run: those matching any of the patterns, and the
+/// tasks they depend on.
+fn select_tasks(tasks: &[TaskSpec], graph: &TaskGraph, patterns: &[String]) -> Maybe<Vec<bool>> {
+  if patterns.is_empty() {
+    return Ok(vec![true; tasks.len()]);
+  }
+  let mut selected = vec![false; tasks.len()];
+  for pattern in patterns.iter() {
+    let mut matched = false;
+    for (task_idx, task) in tasks.iter().enumerate() {
+      if &task.name == pattern || glob_match(pattern, &task.name) {
+        selected[task_idx] = true;
+        matched = true;
+      }
+    }
+    if !matched {
+      return Err(fail(format!("no task matches {:?}", pattern)));
+    }
+  }
+  // Dependencies come first, so walk backwards to pull them in.
+  for task_idx in graph.topo_order().into_iter().rev() {
+    if selected[task_idx] {
+      for &dep_idx in graph.deps(task_idx).iter() {
+        selected[dep_idx] = true;
+      }
+    }
+  }
+  Ok(selected)
+}
+
+/// Path of the taskspec of a working directory: its `gup.toml` or
+/// `gup.json` if it has one, otherwise its `gup.py`.
+fn default_spec_path(working_dir: &Option<PathBuf>) -> Maybe<PathBuf> {
+  let dir = match working_dir {
+    &None => PathBuf::from("."),
+    &Some(ref p) => p.clone(),
+  };
+  match find_taskfile(&dir)? {
+    None => Ok(dir.join("gup.py")),
+    Some(path) => Ok(path),
+  }
+}
+
+/// Gets the taskspecs of a gup.py or taskspec file, with their retries capped
+/// as the daemon would.
+fn local_taskspecs(spec_path: &Path, image_manifest: &mut ImageManifest, sysroot: &Sysroot, root_manifest: &RootManifest, dry_run: bool) -> Maybe<Vec<TaskSpec>> {
+  let mut tasks = _local_taskspecs(spec_path, image_manifest, sysroot, root_manifest, dry_run)?;
+  let max_retries = DaemonConfig::open(&Config::default())?.max_task_retries;
+  for task in tasks.iter_mut() {
+    task.retries = task.retries.min(max_retries);
+  }
+  Ok(tasks)
+}
+
+/// Evaluates a local taskspec. Declarative taskspec files are parsed
+/// directly, while gup.py scripts are run in the builtin image. A `dry_run`
+/// leaves the mutable cache alone.
+fn _local_taskspecs(spec_path: &Path, image_manifest: &mut ImageManifest, sysroot: &Sysroot, root_manifest: &RootManifest, dry_run: bool) -> Maybe<Vec<TaskSpec>> {
+  if TaskFileFormat::of_path(spec_path).is_some() {
+    return Ok(load_taskfile(spec_path, sysroot, dry_run)?);
+  }
+  let builtin_imagespec = ImageSpec::builtin_default();
+  let builtin_image = match dry_run {
+    false => image_manifest.lookup_docker_image(&builtin_imagespec, sysroot, root_manifest)?,
+    true  => match image_manifest.peek_docker_image(&builtin_imagespec, root_manifest) {
+      (_, false) => return Err(fail("the builtin image, needed to evaluate gup.py, is not built yet")),
+      (image, true) => image,
+    },
+  };
+  let gup_py_path = spec_path.canonicalize()
+    .map_err(|_| fail("failed to get canonical absolute path, required for docker"))?;
+  assert!(gup_py_path.is_absolute());
+  builtin_image._run_taskspec_direct(&gup_py_path, sysroot, dry_run)
+}
+
+fn _run_local(user: bool, user_prefix: Option<PathBuf>, spec_path: Option<PathBuf>, working_dir: Option<PathBuf>, opts: &RunLocalOpts) -> Maybe<Vec<TaskResult>> {
+  let quiet = opts.quiet;
 
   let sysroot = Sysroot::default();
   let root_manifest = RootManifest::load(&sysroot)
@@ -809,13 +1473,77 @@ fn _run_local(user: bool, user_prefix: Option<PathBuf>, mutable: bool, quiet: bo
     Some(ref path) => GitCheckoutSpec::with_local_dir(path)?,
   };
 
-  let builtin_imagespec = ImageSpec::builtin_default();
-  let builtin_image = image_manifest.lookup_docker_image(&builtin_imagespec, &sysroot, &root_manifest)?;
-  let gup_py_path = gup_py_path.canonicalize()
-    .map_err(|_| fail("failed to get canonical absolute path, required for docker"))?;
-  assert!(gup_py_path.is_absolute());
-  let tasks = builtin_image._run_taskspec_direct(&gup_py_path, &sysroot)?;
-  let num_tasks = tasks.len();
+  let spec_path = match spec_path {
+    None => default_spec_path(&working_dir)?,
+    Some(path) => path,
+  };
+  let tasks = local_taskspecs(&spec_path, &mut image_manifest, &sysroot, &root_manifest, opts.dry_run)?;
+  let graph = TaskGraph::new(&tasks)?;
+  let selected = select_tasks(&tasks, &graph, &opts.task_patterns)?;
+
+  if opts.list && opts.json {
+    let images: Vec<_> = tasks.iter().map(|task| task.image_candidate()).collect();
+    let reports: Vec<_> = tasks.iter().zip(images.iter()).enumerate()
+      .filter(|&(task_idx, _)| selected[task_idx])
+      .map(|(_, (task, image))| {
+        let image_hash = image.as_ref().map(|image| image.to_hash_digest(&root_manifest));
+        TaskSpecReport::new(task, image.as_ref(), image_hash)
+      })
+      .collect();
+    let out = serde_json::to_string_pretty(&reports)
+      .map_err(|_| fail("failed to serialize tasks"))?;
+    println!("{}", out);
+    return Ok(Vec::new());
+  }
+
+  let gpus = local_gpus(&Config::default());
+  if opts.list || opts.dry_run {
+    for (task_idx, task) in tasks.iter().enumerate() {
+      if !selected[task_idx] {
+        continue;
+      }
+      println!("{}. {}", task_idx + 1, task.name);
+      match task.image_candidate() {
+        None => println!("   image: none matching"),
+        Some(image) => {
+          println!("   image: {}", image.to_desc().trim());
+          println!("   image hash: {}", image.to_hash_digest(&root_manifest));
+        }
+      }
+      if let Some(ref req) = task.require_gpu_arch {
+        println!("   require_gpu_arch: {}", req);
+      }
+      for &dep_idx in graph.deps(task_idx).iter() {
+        println!("   depends_on: {}", tasks[dep_idx].name);
+      }
+      if !task.only_refs.is_empty() {
+        println!("   only_refs: {}", task.only_refs.join(" "));
+      }
+      if !task.only_paths.is_empty() {
+        println!("   only_paths: {}", task.only_paths.join(" "));
+      }
+      if task.retries > 0 {
+        println!("   retries: {}", task.retries);
+      }
+      for cache in task.caches.iter() {
+        println!("   cache: {} at {}", cache.name, cache.path);
+      }
+      let limits = local_task_limits(task)?;
+      if limits != TaskLimits::default() {
+        println!("   limits: {}", format_limits(&limits));
+      }
+      if opts.dry_run {
+        explain_task(&tasks, &graph, task_idx, &checkout, &sysroot, &root_manifest, &image_manifest, &gpus, &limits, opts);
+      }
+    }
+    stdout().flush().unwrap();
+    return Ok(Vec::new());
+  }
+
+  let artifacts_root = TempDir::new()
+    .map_err(|_| fail("failed to create temporary artifacts directory"))?;
+  let order: Vec<_> = graph.topo_order().into_iter().filter(|&idx| selected[idx]).collect();
+  let num_tasks = order.len();
   if !quiet {
     match num_tasks {
       0 => {}
@@ -824,97 +1552,332 @@ fn _run_local(user: bool, user_prefix: Option<PathBuf>, mutable: bool, quiet: bo
     }
     stdout().flush().unwrap();
   }
-  for (task_idx, task) in tasks.iter().enumerate() {
+  let mut states = vec![TaskNodeState::Waiting; tasks.len()];
+  let mut results = Vec::with_capacity(num_tasks);
+  let mut stopped = false;
+  // Tasks run one at a time, after the tasks they depend on.
+  for (run_idx, task_idx) in order.into_iter().enumerate() {
+    let task = &tasks[task_idx];
+    let image = task.image_candidate();
+    let mut result = TaskResult{
+      name: task.name.clone(),
+      image_hash: image.as_ref().map(|im| im.to_hash_digest(&root_manifest)),
+      image: image.map(|im| im.to_desc().trim().to_string()),
+      outcome: TaskOutcome::Passed,
+      not_started: None,
+      attempts: 0,
+      duration: Duration::from_secs(0),
+      log_excerpt: Vec::new(),
+    };
+    let skip_reason = match graph.readiness(task_idx, &states) {
+      _ if stopped => Some("not run after an earlier failure".to_string()),
+      Readiness::DepFailed(dep_idx) => Some(format!("depends on failed task {:?}", tasks[dep_idx].name)),
+      Readiness::DepSkipped(dep_idx) => Some(format!("depends on skipped task {:?}", tasks[dep_idx].name)),
+      Readiness::Ready | Readiness::Blocked => None,
+    };
+    if let Some(reason) = skip_reason {
+      states[task_idx] = TaskNodeState::Skipped;
+      result.outcome = TaskOutcome::Skipped(reason);
+      results.push(result);
+      continue;
+    }
     // FIXME: sanitize the task name.
     let task_start = Instant::now();
     if !quiet {
-      println!("Running task {}/{} ({})...", task_idx + 1, num_tasks, task.name);
+      println!("Running task {}/{} ({})...", run_idx + 1, num_tasks, task.name);
       stdout().flush().unwrap();
     }
-    let image = match task.image_candidate() {
-      None => {
+    let failed = match _run_local_task(&tasks, &graph, task_idx, &checkout, &sysroot, &root_manifest, &mut image_manifest, &gpus, artifacts_root.path(), opts, &mut result)? {
+      Err(not_started) => {
         if !quiet {
-          println!("- NOT STARTED: No matching image candidate.");
+          println!("- NOT STARTED: {}", not_started);
           stdout().flush().unwrap();
         }
-        return Ok(DockerRunStatus::Failure);
+        result.not_started = Some(not_started);
+        true
+      }
+      Ok(DockerRunStatus::Success) => {
+        if !quiet {
+          match result.attempts {
+            1 => println!("- PASSED."),
+            n => println!("- PASSED ON RETRY: Attempt {}/{}.", n, task.retries.saturating_add(1)),
+          }
+          stdout().flush().unwrap();
+        }
+        false
+      }
+      Ok(DockerRunStatus::Failure{..}) => {
+        if !quiet {
+          println!("- FAILED.");
+          stdout().flush().unwrap();
+        }
+        true
       }
-      Some(im) => im,
     };
-    let docker_image = image_manifest.lookup_docker_image(&image, &sysroot, &root_manifest)?;
-    let output = match stdout_ {
-      false => None,
-      true  => Some(DockerOutput::Stdout),
+    result.duration = Instant::now() - task_start;
+    match failed {
+      false => {
+        states[task_idx] = TaskNodeState::Passed;
+      }
+      true  => {
+        states[task_idx] = TaskNodeState::Failed;
+        result.outcome = TaskOutcome::Failed;
+        if !opts.keep_going {
+          stopped = true;
+        }
+      }
+    }
+    results.push(result);
+  }
+  Ok(results)
+}
+
+/// Prints what running a task would involve, without building or running
+/// anything: the Dockerfile of its image, its script, and the `docker run`
+/// command line.
+fn explain_task(
+    tasks: &[TaskSpec],
+    graph: &TaskGraph,
+    task_idx: usize,
+    checkout: &GitCheckoutSpec,
+    sysroot: &Sysroot,
+    root_manifest: &RootManifest,
+    image_manifest: &ImageManifest,
+    gpus: &[Gpu],
+    limits: &TaskLimits,
+    opts: &RunLocalOpts,
+) {
+  let task = &tasks[task_idx];
+  let image = match task.image_candidate() {
+    None => return,
+    Some(im) => im,
+  };
+  let (docker_image, built) = image_manifest.peek_docker_image(&image, root_manifest);
+  match image.to_docker_base_image() {
+    None => println!("   base image: none matching"),
+    Some(base_image) => println!("   base image: {}", base_image),
+  }
+  match built {
+    false => println!("   docker image: gup/{} (not built yet)", docker_image.hash_digest),
+    true  => println!("   docker image: gup/{}", docker_image.hash_digest),
+  }
+  println!("   Dockerfile:");
+  match docker_image.render_dockerfile(sysroot) {
+    Err(e) => println!("     (failed to render: {:?})", e),
+    Ok(dockerfile) => {
+      for line in dockerfile.lines() {
+        println!("     {}", line);
+      }
+    }
+  }
+  println!("   script:");
+  for line in task.to_script().lines() {
+    println!("     {}", line);
+  }
+  let gpu_devices = task_gpus(gpus, task.require_gpu_arch.as_ref());
+  if let Some(ref req) = task.require_gpu_arch {
+    if gpu_devices.is_empty() {
+      println!("   note: no GPU matching require_gpu_arch {}, so the task would not start", req);
+    }
+  }
+  // The artifact directories only exist during a run.
+  let artifacts_root = Path::new("<ARTIFACTS>");
+  let run_opts = DockerRunOpts{
+    artifacts_dir: Some(artifacts_root.join(format!("{}", task_idx + 1))),
+    dep_artifacts: graph.deps(task_idx).iter()
+      .map(|&dep_idx| (tasks[dep_idx].name.clone(), artifacts_root.join(format!("{}", dep_idx + 1))))
+      .collect(),
+    gpu_devices: Some(gpu_devices),
+    build_caches: build_cache_mounts(task, &checkout.remote_url, true, sysroot),
+    limits: *limits,
+    .. DockerRunOpts::default()
+  };
+  println!("   command:");
+  println!("     {}", docker_image.explain_run(checkout, task, sysroot, &run_opts, opts.mutable));
+}
+
+/// Prepares the build caches of a task run locally, which is always trusted
+/// to write to them. The caches stay locked until the mounts are dropped.
+fn local_build_caches(task: &TaskSpec, checkout: &GitCheckoutSpec, sysroot: &Sysroot) -> Maybe<Vec<BuildCacheMount>> {
+  let mut build_caches = build_cache_mounts(task, &checkout.remote_url, true, sysroot);
+  let max_size = BuildCacheConfig::open(&Config::default())?.max_size;
+  prepare_build_caches(&mut build_caches, &checkout.remote_url, max_size)?;
+  Ok(build_caches)
+}
+
+/// Resource limits of a task run locally, under the machine policy if there
+/// is a machine config. An invalid machine config is an error rather than no
+/// policy at all.
+fn local_task_limits(task: &TaskSpec) -> Maybe<TaskLimits> {
+  let cfg = TaskLimitsConfig::open(&Config::default())?;
+  Ok(cfg.limits_of(&task.limits))
+}
+
+/// Runs one task, retrying it as it asks. The inner error says why the task
+/// could not be started.
+fn _run_local_task(
+    tasks: &[TaskSpec],
+    graph: &TaskGraph,
+    task_idx: usize,
+    checkout: &GitCheckoutSpec,
+    sysroot: &Sysroot,
+    root_manifest: &RootManifest,
+    image_manifest: &mut ImageManifest,
+    gpus: &[Gpu],
+    artifacts_root: &Path,
+    opts: &RunLocalOpts,
+    result: &mut TaskResult,
+) -> Maybe<Result<DockerRunStatus, String>> {
+  let task = &tasks[task_idx];
+  let image = match task.image_candidate() {
+    None => return Ok(Err("No matching image candidate.".to_string())),
+    Some(im) => im,
+  };
+  let gpu_devices = task_gpus(gpus, task.require_gpu_arch.as_ref());
+  if let Some(ref req) = task.require_gpu_arch {
+    if gpu_devices.is_empty() {
+      return Ok(Err(format!("No GPU matching require_gpu_arch {}.", req)));
+    }
+  }
+  let docker_image = image_manifest.lookup_docker_image(&image, sysroot, root_manifest)?;
+  let artifacts_dir = artifacts_root.join(format!("{}", task_idx + 1));
+  create_dir_all(&artifacts_dir)
+    .map_err(|_| fail("failed to create artifacts directory"))?;
+  let mut run_opts = DockerRunOpts{
+    artifacts_dir: Some(artifacts_dir),
+    dep_artifacts: graph.deps(task_idx).iter()
+      .map(|&dep_idx| (tasks[dep_idx].name.clone(), artifacts_root.join(format!("{}", dep_idx + 1))))
+      .collect(),
+    gpu_devices: Some(gpu_devices),
+    build_caches: local_build_caches(task, checkout, sysroot)?,
+    limits: local_task_limits(task)?,
+    .. DockerRunOpts::default()
+  };
+  if opts.shell_on_failure {
+    // Keep the container around to snapshot it if the task fails.
+    run_opts.container_name = Some(format!("gup-tmp-run-{}-{}", process::id(), task_idx + 1));
+    run_opts.keep_container = true;
+  }
+  let log_excerpt = Arc::new(Mutex::new(Vec::new()));
+  loop {
+    result.attempts += 1;
+    let output = match (opts.wants_report(), opts.stdout) {
+      (true, echo) => {
+        let log_excerpt = log_excerpt.clone();
+        Some(DockerOutput::Tail{
+          echo,
+          max_lines: LOG_EXCERPT_LINES,
+          consumer: Box::new(move |lines| {
+            *log_excerpt.lock().unwrap() = lines;
+          }),
+        })
+      }
+      (false, false) => None,
+      (false, true)  => Some(DockerOutput::Stdout),
     };
-    let status = match mutable {
-      false => docker_image.run(&checkout, task, &sysroot, output),
-      true  => docker_image.run_mut(&checkout, task, &sysroot, output),
+    let status = match opts.mutable {
+      false => docker_image.run(checkout, task, sysroot, &run_opts, output),
+      true  => docker_image.run_mut(checkout, task, sysroot, &run_opts, output),
     }?;
-    if let DockerRunStatus::Failure = status {
-      if !quiet {
-        // FIXME: report on the task that failed.
-        let task_end = Instant::now();
-        let task_dur = task_end - task_start;
-        let task_ms = task_dur.subsec_millis() as u64;
-        let task_s = task_dur.as_secs() + task_ms / 500;
-        let task_m = task_s / 60;
-        let task_h = task_m / 60;
-        if task_h > 0 {
-          println!("- FAILED: Total time elapsed: {}h {:02}m {:02}s", task_h, task_m % 60, task_s % 60);
-        } else if task_m > 0 {
-          println!("- FAILED: Total time elapsed: {}m {:02}s", task_m, task_s % 60);
-        } else {
-          println!("- FAILED: Total time elapsed: {}s", task_s);
-        }
+    result.log_excerpt = log_excerpt.lock().unwrap().clone();
+    let retry = match status {
+      DockerRunStatus::Failure{exit_code} => task.should_retry(result.attempts, exit_code),
+      DockerRunStatus::Success => false,
+    };
+    if let Some(ref container_name) = run_opts.container_name {
+      if let (&DockerRunStatus::Failure{..}, false) = (&status, retry) {
+        println!("- FAILED: Starting a shell in the task container, exit the shell to continue...");
         stdout().flush().unwrap();
+        let shell_opts = DockerRunOpts{
+          container_name: None,
+          keep_container: false,
+          .. run_opts.clone()
+        };
+        if let Err(e) = docker_image.shell(checkout, task, sysroot, &shell_opts, opts.mutable, Some(container_name)) {
+          println!("- SHELL FAILED: {:?}", e);
+        }
       }
-      return Ok(DockerRunStatus::Failure);
+      remove_container(container_name).ok();
     }
-    if !quiet {
-      let task_end = Instant::now();
-      let task_dur = task_end - task_start;
-      let task_ms = task_dur.subsec_millis() as u64;
-      let task_s = task_dur.as_secs() + task_ms / 500;
-      let task_m = task_s / 60;
-      let task_h = task_m / 60;
-      if task_h > 0 {
-        println!("- DONE: Total time elapsed: {}h {:02}m {:02}s", task_h, task_m % 60, task_s % 60);
-      } else if task_m > 0 {
-        println!("- DONE: Total time elapsed: {}m {:02}s", task_m, task_s % 60);
-      } else {
-        println!("- DONE: Total time elapsed: {}s", task_s);
-      }
+    if !retry {
+      return Ok(Ok(status));
+    }
+    if !opts.quiet {
+      println!("- RETRYING: Attempt {}/{}...", result.attempts + 1, task.retries.saturating_add(1));
       stdout().flush().unwrap();
     }
   }
+}
 
-  if !quiet {
-    print!("All tasks ran successfully");
-    let run_end = Instant::now();
-    let run_dur = run_end - run_start;
-    let run_ms = run_dur.subsec_millis() as u64;
-    let run_s = run_dur.as_secs() + run_ms / 500;
-    let run_m = run_s / 60;
-    let run_h = run_m / 60;
-    if run_h > 0 {
-      println!(" (elapsed: {}h {:02}m {:02}s).", run_h, run_m % 60, run_s % 60);
-    } else if run_m > 0 {
-      println!(" (elapsed: {}m {:02}s).", run_m, run_s % 60);
-    } else {
-      println!(" (elapsed: {}s).", run_s);
+pub fn shell(user: bool, user_prefix: Option<PathBuf>, spec_path: Option<PathBuf>, working_dir: Option<PathBuf>, task_name: &str, mutable: bool) -> Maybe {
+  let sysroot = Sysroot::default();
+  let root_manifest = RootManifest::load(&sysroot)
+    .or_else(|_| RootManifest::fresh(&sysroot))?;
+  let mut image_manifest = ImageManifest::load(&sysroot, &root_manifest)?;
+
+  let checkout = match working_dir {
+    None => GitCheckoutSpec::with_current_dir()?,
+    Some(ref path) => GitCheckoutSpec::with_local_dir(path)?,
+  };
+
+  let spec_path = match spec_path {
+    None => default_spec_path(&working_dir)?,
+    Some(path) => path,
+  };
+  let tasks = local_taskspecs(&spec_path, &mut image_manifest, &sysroot, &root_manifest, false)?;
+  let task = match tasks.iter().find(|task| task.name == task_name) {
+    None => return Err(fail(format!("no task named {:?}", task_name))),
+    Some(task) => task,
+  };
+  let image = match task.image_candidate() {
+    None => return Err(fail("no matching image candidate")),
+    Some(im) => im,
+  };
+  let gpu_devices = task_gpus(&local_gpus(&Config::default()), task.require_gpu_arch.as_ref());
+  if let Some(ref req) = task.require_gpu_arch {
+    if gpu_devices.is_empty() {
+      return Err(fail(format!("no GPU matching require_gpu_arch {}", req)));
     }
-    stdout().flush().unwrap();
   }
-
-  Ok(DockerRunStatus::Success)
+  let docker_image = image_manifest.lookup_docker_image(&image, &sysroot, &root_manifest)?;
+  let artifacts_root = TempDir::new()
+    .map_err(|_| fail("failed to create temporary artifacts directory"))?;
+  if !task.depends_on.is_empty() {
+    println!("Note: artifacts of the tasks that {:?} depends on are not available.", task.name);
+  }
+  let opts = DockerRunOpts{
+    artifacts_dir: Some(artifacts_root.path().to_path_buf()),
+    gpu_devices: Some(gpu_devices),
+    build_caches: local_build_caches(task, &checkout, &sysroot)?,
+    limits: local_task_limits(task)?,
+    .. DockerRunOpts::default()
+  };
+  docker_image.shell(&checkout, task, &sysroot, &opts, mutable, None)
 }
 
-pub fn run_local(user: bool, user_prefix: Option<PathBuf>, mutable: bool, quiet: bool, stdout: bool, gup_py_path: PathBuf, working_dir: Option<PathBuf>) -> Maybe {
-  match _run_local(user, user_prefix, mutable, quiet, stdout, gup_py_path, working_dir)? {
-    DockerRunStatus::Success => {
-      Ok(())
-    }
-    DockerRunStatus::Failure => {
+pub fn run_local(user: bool, user_prefix: Option<PathBuf>, spec_path: Option<PathBuf>, working_dir: Option<PathBuf>, opts: &RunLocalOpts) -> Maybe {
+  let run_start = Instant::now();
+  let results = _run_local(user, user_prefix, spec_path, working_dir, opts)?;
+  if opts.list || opts.dry_run {
+    return Ok(());
+  }
+  let run_dur = Instant::now() - run_start;
+  if !opts.quiet {
+    print_summary(&results, run_dur);
+  }
+  if let Some(ref path) = opts.report_json {
+    write_json_report(path, &results, run_dur)?;
+  }
+  if let Some(ref path) = opts.report_junit {
+    write_junit_report(path, &results, run_dur)?;
+  }
+  let failed = results.iter().any(|result| match result.outcome {
+    TaskOutcome::Failed => true,
+    _ => false,
+  });
+  match failed {
+    false => Ok(()),
+    true  => {
       println!("Some tasks failed.");
       Err(fail("Some tasks failed"))
     }
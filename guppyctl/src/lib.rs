@@ -11,6 +11,7 @@ extern crate tooling;
 //extern crate url;
 
 pub(crate) mod cli;
+pub(crate) mod report;
 
 pub fn run_main(git_head_commit: &[u8], guppybot_bin: &[u8]) -> ! {
   monosodium::init_sodium();
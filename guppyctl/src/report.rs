@@ -0,0 +1,145 @@
+use tooling::query::{Maybe, fail};
+
+use std::fs::{File};
+use std::io::{BufWriter, Write};
+use std::path::{Path};
+use std::time::{Duration};
+
+/// Number of lines kept from the end of each task's output for reports.
+pub const LOG_EXCERPT_LINES: usize = 100;
+
+#[derive(Clone, Debug)]
+pub enum TaskOutcome {
+  Passed,
+  Failed,
+  Skipped(String),
+}
+
+impl TaskOutcome {
+  pub fn to_desc_str(&self) -> &'static str {
+    match self {
+      &TaskOutcome::Passed => "passed",
+      &TaskOutcome::Failed => "failed",
+      &TaskOutcome::Skipped(_) => "skipped",
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct TaskResult {
+  pub name: String,
+  /// Description of the resolved `ImageSpec`, if any.
+  pub image: Option<String>,
+  pub image_hash: Option<String>,
+  pub outcome: TaskOutcome,
+  /// Why the task failed without running, if it did not start.
+  pub not_started: Option<String>,
+  pub attempts: u32,
+  pub duration: Duration,
+  /// Last lines of output of the last attempt.
+  pub log_excerpt: Vec<String>,
+}
+
+fn duration_secs(dur: Duration) -> f64 {
+  dur.as_secs() as f64 + dur.subsec_millis() as f64 / 1000.0
+}
+
+pub fn write_json_report(path: &Path, results: &[TaskResult], run_dur: Duration) -> Maybe {
+  let tasks: Vec<_> = results.iter().map(|result| {
+    let reason = match (&result.outcome, &result.not_started) {
+      (&TaskOutcome::Skipped(ref reason), _) => Some(reason.clone()),
+      (_, &Some(ref reason)) => Some(reason.clone()),
+      _ => None,
+    };
+    serde_json::json!({
+      "name": result.name,
+      "image": result.image,
+      "image_hash": result.image_hash,
+      "status": result.outcome.to_desc_str(),
+      "reason": reason,
+      "attempts": result.attempts,
+      "duration_secs": duration_secs(result.duration),
+      "log_excerpt": result.log_excerpt.join("\n"),
+    })
+  }).collect();
+  let count = |desc: &str| results.iter().filter(|r| r.outcome.to_desc_str() == desc).count();
+  let report = serde_json::json!({
+    "passed": count("passed"),
+    "failed": count("failed"),
+    "skipped": count("skipped"),
+    "duration_secs": duration_secs(run_dur),
+    "tasks": tasks,
+  });
+  let file = File::create(path)
+    .map_err(|_| fail(format!("failed to create JSON report: {}", path.display())))?;
+  serde_json::to_writer_pretty(BufWriter::new(file), &report)
+    .map_err(|_| fail(format!("failed to write JSON report: {}", path.display())))?;
+  Ok(())
+}
+
+fn xml_escape(s: &str) -> String {
+  let mut buf = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '&' => buf.push_str("&amp;"),
+      '<' => buf.push_str("&lt;"),
+      '>' => buf.push_str("&gt;"),
+      '"' => buf.push_str("&quot;"),
+      '\'' => buf.push_str("&apos;"),
+      '\n' | '\t' => buf.push(c),
+      // Other control characters are not allowed in XML 1.0.
+      c if (c as u32) < 0x20 => {}
+      c => buf.push(c),
+    }
+  }
+  buf
+}
+
+fn _write_junit_report<W: Write>(w: &mut W, results: &[TaskResult], run_dur: Duration) -> std::io::Result<()> {
+  let count = |desc: &str| results.iter().filter(|r| r.outcome.to_desc_str() == desc).count();
+  writeln!(w, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+  writeln!(w, "<testsuites>")?;
+  writeln!(w, "  <testsuite name=\"guppy\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+      results.len(), count("failed"), count("skipped"), duration_secs(run_dur))?;
+  for result in results.iter() {
+    writeln!(w, "    <testcase name=\"{}\" classname=\"guppy\" time=\"{:.3}\">",
+        xml_escape(&result.name), duration_secs(result.duration))?;
+    writeln!(w, "      <properties>")?;
+    if let Some(ref image) = result.image {
+      writeln!(w, "        <property name=\"image\" value=\"{}\"/>", xml_escape(image))?;
+    }
+    if let Some(ref image_hash) = result.image_hash {
+      writeln!(w, "        <property name=\"image_hash\" value=\"{}\"/>", xml_escape(image_hash))?;
+    }
+    writeln!(w, "        <property name=\"attempts\" value=\"{}\"/>", result.attempts)?;
+    writeln!(w, "      </properties>")?;
+    match result.outcome {
+      TaskOutcome::Passed => {}
+      TaskOutcome::Failed => {
+        let message = match result.not_started {
+          None => "task failed".to_string(),
+          Some(ref reason) => format!("not started: {}", reason),
+        };
+        writeln!(w, "      <failure message=\"{}\"/>", xml_escape(&message))?;
+      }
+      TaskOutcome::Skipped(ref reason) => {
+        writeln!(w, "      <skipped message=\"{}\"/>", xml_escape(reason))?;
+      }
+    }
+    if !result.log_excerpt.is_empty() {
+      writeln!(w, "      <system-out>{}</system-out>", xml_escape(&result.log_excerpt.join("\n")))?;
+    }
+    writeln!(w, "    </testcase>")?;
+  }
+  writeln!(w, "  </testsuite>")?;
+  writeln!(w, "</testsuites>")?;
+  w.flush()
+}
+
+pub fn write_junit_report(path: &Path, results: &[TaskResult], run_dur: Duration) -> Maybe {
+  let file = File::create(path)
+    .map_err(|_| fail(format!("failed to create JUnit report: {}", path.display())))?;
+  _write_junit_report(&mut BufWriter::new(file), results, run_dur)
+    .map_err(|_| fail(format!("failed to write JUnit report: {}", path.display())))?;
+  Ok(())
+}
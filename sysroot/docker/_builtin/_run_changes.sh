@@ -0,0 +1,22 @@
+#!/usr/bin/env sh
+set -eu
+cd /checkout
+base=""
+if [ -n "${GUPPY_GIT_BASE:-}" ] && git cat-file -e "${GUPPY_GIT_BASE}^{commit}" 2>/dev/null; then
+  base="${GUPPY_GIT_BASE}"
+fi
+if [ -z "${base}" ]; then
+  base="$(git merge-base HEAD origin/HEAD 2>/dev/null || true)"
+  if [ "${base}" = "$(git rev-parse HEAD)" ]; then
+    base=""
+  fi
+fi
+if [ -z "${base}" ]; then
+  base="$(git rev-parse -q --verify HEAD^ 2>/dev/null || true)"
+fi
+printf '%s\0' "$(git log -1 --format=%B HEAD)"
+if [ -n "${base}" ]; then
+  git diff --name-only -z "${base}" HEAD
+else
+  git ls-tree -r --name-only -z HEAD
+fi
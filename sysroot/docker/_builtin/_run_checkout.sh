@@ -1,3 +1,14 @@
 #!/usr/bin/env sh
 set -eu
 git clone -q --recursive ${GUPPY_GIT_REMOTE_URL} /checkout
+cd /checkout
+if [ -n "${GUPPY_GIT_REF:-}" ]; then
+  git fetch -q origin "${GUPPY_GIT_REF}"
+  git checkout -q FETCH_HEAD
+fi
+if [ -n "${GUPPY_GIT_COMMIT:-}" ]; then
+  git checkout -q "${GUPPY_GIT_COMMIT}"
+fi
+if [ -n "${GUPPY_GIT_REF:-}${GUPPY_GIT_COMMIT:-}" ]; then
+  git submodule -q update --init --recursive
+fi
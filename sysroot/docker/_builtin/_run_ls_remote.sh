@@ -0,0 +1,3 @@
+#!/usr/bin/env sh
+set -eu
+git ls-remote ${GUPPY_GIT_REMOTE_URL} "$@"
@@ -1,12 +1,30 @@
+import json
+import os
 import os.path
 import urllib.parse
 import sys
 
+def _protocol():
+    # The bot lists the protocols it understands; prefer the newest.
+    protocols = os.environ.get("GUPPY_TASKSPEC_PROTOCOL", "v0").split(",")
+    if "v1" in protocols:
+        return "v1"
+    return "v0"
+
+_v1_started = False
+
+def _out_v1(obj):
+    global _v1_started
+    if not _v1_started:
+        print(json.dumps({"kind": "protocol", "version": "v1"}))
+        _v1_started = True
+    print(json.dumps(obj, sort_keys=True))
+
 class MutableCache(object):
     def __init__(self):
         self._append_kvs = []
 
-    def fetch_once(self, src_url, path=None):
+    def fetch_once(self, src_url, path=None, sha256=None):
         if path is None:
             src_url_parts = urllib.parse.urlsplit(src_url)
             src_url_path = src_url_parts[2]
@@ -16,7 +34,7 @@ class MutableCache(object):
                 assert False
             path = maybe_path
         if path not in self._append_kvs:
-            self._append_kvs.append((path, {"op": "fetch_once", "src_url": src_url}))
+            self._append_kvs.append((path, {"op": "fetch_once", "src_url": src_url, "sha256": sha256}))
 
     def copy_once(self, src_path, path=None):
         if path is None:
@@ -39,10 +57,17 @@ class MutableCache(object):
             self._append_kvs.append((path, {"op": "symlink_once", "src_path": src_path}))
 
     def out(self):
+        if _protocol() == "v1":
+            for path, v in self._append_kvs:
+                src = v["src_url"] if v["op"] == "fetch_once" else v["src_path"]
+                _out_v1({"kind": "mutable_cache", "path": path, "op": v["op"], "src": src, "sha256": v.get("sha256")})
+            return
         for path, v in self._append_kvs:
             value_str = None
             if v["op"] == "fetch_once":
                 value_str = "fetch_once {}".format(v["src_url"])
+                if v["sha256"] is not None:
+                    value_str += " sha256={}".format(v["sha256"])
             elif v["op"] == "copy_once":
                 value_str = "copy_once {}".format(v["src_path"])
             elif v["op"] == "symlink_once":
@@ -60,6 +85,10 @@ class Run(object):
         self._tasks.append(task)
 
     def out(self):
+        if _protocol() == "v1":
+            for task in self._tasks:
+                _out_v1(task._to_v1())
+            return
         #print("#-guppy:v0.pre_run:begin")
         #print("#-guppy:v0.pre_run:end")
         for task in self._tasks:
@@ -69,12 +98,31 @@ class Run(object):
                 print("#-guppy:v0.task:toolchain {}".format(task._toolchain))
             print("#-guppy:v0.task:require_docker {}".format("true" if task._require_docker else "false"))
             print("#-guppy:v0.task:require_nvidia_docker {}".format("true" if task._require_nvidia_docker else "false"))
-            print("#-guppy:v0.task:require_distro {}".format(task._require_distro))
+            if task._require_distro is not None:
+                print("#-guppy:v0.task:require_distro {}".format(task._require_distro))
             if task._require_cuda is not None:
                 print("#-guppy:v0.task:require_cuda {}".format(task._require_cuda))
             if task._require_gpu_arch is not None:
                 print("#-guppy:v0.task:require_gpu_arch {}".format(task._require_gpu_arch))
             print("#-guppy:v0.task:allow_errors {}".format("true" if task._allow_errors else "false"))
+            if task._retries > 0:
+                print("#-guppy:v0.task:retries {}".format(task._retries))
+            if task._retry_on:
+                print("#-guppy:v0.task:retry_on {}".format(" ".join(str(c) for c in task._retry_on)))
+            if task._only_refs:
+                print("#-guppy:v0.task:only_refs {}".format(" ".join(task._only_refs)))
+            if task._only_paths:
+                print("#-guppy:v0.task:only_paths {}".format(" ".join(task._only_paths)))
+            for dep_name in task._depends_on:
+                print("#-guppy:v0.task:depends_on {}".format(dep_name))
+            for key, values in task._matrix:
+                print("#-guppy:v0.task:matrix {}={}".format(key, ",".join(values)))
+            for rule in task._matrix_exclude:
+                print("#-guppy:v0.task:matrix_exclude {}".format(" ".join("{}={}".format(k, v) for k, v in rule)))
+            for name, path in sorted(task._caches.items()):
+                print("#-guppy:v0.task:cache {} {}".format(name, path))
+            if task._limits:
+                print("#-guppy:v0.task:limits {}".format(" ".join("{}={}".format(k, v) for k, v in sorted(task._limits.items()))))
             for sh_line in task._sh_lines:
                 print("{}".format(sh_line))
             print("#-guppy:v0.task:end")
@@ -92,8 +140,42 @@ class Task(object):
         self._require_cuda = None
         self._require_gpu_arch = "*"
         self._allow_errors = False
+        self._retries = 0
+        self._retry_on = []
+        self._only_refs = []
+        self._only_paths = []
+        self._depends_on = []
+        self._matrix = []
+        self._matrix_exclude = []
+        self._env = {}
+        self._caches = {}
+        self._limits = {}
         self._sh_lines = []
 
+    def _to_v1(self):
+        return {
+            "kind": "task",
+            "name": self._name,
+            "toolchain": self._toolchain,
+            "require_docker": self._require_docker,
+            "require_nvidia_docker": self._require_nvidia_docker,
+            "require_distro": self._require_distro,
+            "require_cuda": self._require_cuda,
+            "require_gpu_arch": self._require_gpu_arch,
+            "allow_errors": self._allow_errors,
+            "retries": self._retries,
+            "retry_on": self._retry_on,
+            "only_refs": self._only_refs,
+            "only_paths": self._only_paths,
+            "depends_on": self._depends_on,
+            "matrix": [{"key": k, "values": v} for k, v in self._matrix],
+            "matrix_exclude": [dict(rule) for rule in self._matrix_exclude],
+            "env": self._env,
+            "caches": self._caches,
+            "limits": self._limits,
+            "sh": self._sh_lines,
+        }
+
     def name(self, opt):
         self._name = opt
 
@@ -121,6 +203,37 @@ class Task(object):
     def allow_errors(self, opt):
         self._allow_errors = opt
 
+    def retries(self, opt, retry_on=[]):
+        self._retries = opt
+        self._retry_on.extend(retry_on)
+
+    def only_refs(self, patterns):
+        self._only_refs.extend(patterns)
+
+    def only_paths(self, patterns):
+        self._only_paths.extend(patterns)
+
+    def depends_on(self, tasks):
+        for dep in tasks:
+            self._depends_on.append(dep._name if isinstance(dep, Task) else dep)
+
+    def matrix(self, key, values):
+        self._matrix.append((key, [str(v) for v in values]))
+
+    def matrix_exclude(self, rule):
+        self._matrix_exclude.append([(k, str(v)) for k, v in sorted(rule.items())])
+
+    def env(self, key, value):
+        self._env[key] = str(value)
+
+    def cache(self, name, path):
+        self._caches[name] = path
+
+    def limits(self, cpus=None, memory=None, pids=None, shm=None):
+        for key, value in (("cpus", cpus), ("memory", memory), ("pids", pids), ("shm", shm)):
+            if value is not None:
+                self._limits[key] = str(value)
+
     def sh(self, sh_line):
         self._sh_lines.append(sh_line)
 
@@ -140,6 +253,16 @@ def task(
         require_cuda=None,
         require_gpu_arch="*",
         allow_errors=False,
+        retries=0,
+        retry_on=[],
+        only_refs=[],
+        only_paths=[],
+        depends_on=[],
+        matrix={},
+        matrix_exclude=[],
+        env={},
+        caches={},
+        limits={},
         sh=[]):
     assert name is not None, "guppy: tasks must have a name"
     task = Task(name, toolchain)
@@ -149,6 +272,19 @@ def task(
     task.require_cuda(require_cuda)
     task.require_gpu_arch(require_gpu_arch)
     task.allow_errors(allow_errors)
+    task.retries(retries, retry_on)
+    task.only_refs(only_refs)
+    task.only_paths(only_paths)
+    task.depends_on(depends_on)
+    for key, values in matrix.items():
+        task.matrix(key, values)
+    for rule in matrix_exclude:
+        task.matrix_exclude(rule)
+    for key, value in env.items():
+        task.env(key, value)
+    for name, path in caches.items():
+        task.cache(name, path)
+    task.limits(**limits)
     for sh_line in sh:
         task.sh(sh_line)
     return task
@@ -0,0 +1,918 @@
+use crate::docker::{TaskSpec};
+use crate::query::{Maybe, fail};
+use crate::state::{Sysroot};
+use crate::webhook::{normalize_repo_url};
+
+use curl::easy::{Easy as CurlEasy, List as CurlList};
+use openssl::sha::{Sha256};
+use tempfile::{NamedTempFile};
+use url::{Url};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions, create_dir, create_dir_all, metadata, read_dir, remove_dir, remove_dir_all, remove_file, rename, symlink_metadata};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::fs::{symlink};
+use std::os::unix::io::{AsRawFd};
+use std::path::{Path, PathBuf, Component};
+use std::sync::{Arc};
+use std::thread::{sleep};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many times a `fetch_once` source is fetched before giving up.
+const FETCH_ATTEMPTS: u32 = 3;
+/// How many redirects a `fetch_once` download follows.
+const FETCH_MAX_REDIRECTS: u32 = 10;
+
+/// How an entry of the mutable cache is filled in, the first time it is
+/// appended.
+#[derive(Clone, Debug)]
+pub enum CacheOp {
+  /// Download from a URL, checking the SHA-256 of the download if given.
+  FetchOnce{url: String, sha256: Option<String>},
+  /// Copy a file of the checkout, by its path relative to the checkout.
+  CopyOnce{src_path: String},
+  /// Link to another entry of the mutable cache, by its path in the cache.
+  SymlinkOnce{src_path: String},
+}
+
+impl CacheOp {
+  /// Parses the op and source of a `mutable_cache:append` directive.
+  pub fn new(op: &str, src: &str, sha256: Option<&str>) -> Maybe<CacheOp> {
+    if src.is_empty() {
+      return Err(fail(format!("{} missing source argument", op)));
+    }
+    let sha256 = match sha256 {
+      None => None,
+      Some(hex) => {
+        if op != "fetch_once" {
+          return Err(fail(format!("{} does not take a sha256", op)));
+        }
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+          return Err(fail(format!("invalid sha256 {:?}, expected 64 hex digits", hex)));
+        }
+        Some(hex.to_ascii_lowercase())
+      }
+    };
+    match op {
+      "fetch_once" => Ok(CacheOp::FetchOnce{url: src.to_string(), sha256}),
+      "copy_once" => Ok(CacheOp::CopyOnce{src_path: src.to_string()}),
+      "symlink_once" => Ok(CacheOp::SymlinkOnce{src_path: src.to_string()}),
+      _ => Err(fail(format!("unknown op {:?}", op))),
+    }
+  }
+
+  pub fn op_name(&self) -> &'static str {
+    match self {
+      &CacheOp::FetchOnce{..} => "fetch_once",
+      &CacheOp::CopyOnce{..} => "copy_once",
+      &CacheOp::SymlinkOnce{..} => "symlink_once",
+    }
+  }
+
+  pub fn src(&self) -> &str {
+    match self {
+      &CacheOp::FetchOnce{ref url, ..} => url,
+      &CacheOp::CopyOnce{ref src_path} => src_path,
+      &CacheOp::SymlinkOnce{ref src_path} => src_path,
+    }
+  }
+}
+
+/// Record of an entry of the mutable cache, kept in the cache index.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CacheEntry {
+  /// The op which filled in the entry: `fetch_once`, `copy_once` or
+  /// `symlink_once`.
+  pub op: String,
+  /// The URL or path the entry was filled in from.
+  pub src: String,
+  pub size: u64,
+  /// SHA-256 of the entry when it was filled in; `None` for symlinks.
+  pub sha256: Option<String>,
+  /// When the entry was last appended, in seconds since the Unix epoch.
+  pub last_used: u64,
+}
+
+/// Index of the mutable cache, kept in `<sysroot>/mutable_cache.json`
+/// rather than in the cache itself, which tasks can see.
+///
+/// Updates are not locked against each other; appends racing each other at
+/// worst lose an update, which the next append of the same entry restores.
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct CacheIndex {
+  /// The total size quota in bytes, as last set by the daemon from the
+  /// machine config.
+  #[serde(default)]
+  pub max_size: Option<u64>,
+  /// Entries by their path in the cache.
+  #[serde(default)]
+  pub entries: BTreeMap<String, CacheEntry>,
+}
+
+fn unix_now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+impl CacheIndex {
+  fn path(sysroot: &Sysroot) -> PathBuf {
+    sysroot.base_dir.join("mutable_cache.json")
+  }
+
+  pub fn load(sysroot: &Sysroot) -> Maybe<CacheIndex> {
+    let file = match File::open(CacheIndex::path(sysroot)) {
+      Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(CacheIndex::default()),
+      Err(_) => return Err(fail("mutable cache: failed to open cache index")),
+      Ok(f) => f,
+    };
+    serde_json::from_reader(BufReader::new(file))
+      .map_err(|_| fail("mutable cache: corrupt cache index"))
+  }
+
+  pub fn save(&self, sysroot: &Sysroot) -> Maybe {
+    let mut tmp_file = NamedTempFile::new_in(&sysroot.base_dir)
+      .map_err(|_| fail("mutable cache: failed to write cache index"))?;
+    serde_json::to_writer_pretty(&mut tmp_file, self)
+      .map_err(|_| fail("mutable cache: failed to write cache index"))?;
+    tmp_file.persist(CacheIndex::path(sysroot))
+      .map_err(|_| fail("mutable cache: failed to write cache index"))?;
+    Ok(())
+  }
+
+  pub fn total_size(&self) -> u64 {
+    self.entries.values().map(|entry| entry.size).sum()
+  }
+
+  /// Records that the entry at `path` was appended. The entry is hashed again
+  /// only if it was just filled in, or has changed size since.
+  fn touch(&mut self, path: &str, op: &CacheOp, file_path: &Path, filled: bool) -> Maybe {
+    let last_used = unix_now();
+    let (size, sha256) = match op {
+      &CacheOp::SymlinkOnce{..} => (0, None),
+      _ => {
+        let size = metadata(file_path)
+          .map_err(|_| fail(format!("mutable cache: failed to stat {:?}", path)))?
+          .len();
+        let known_sha256 = match (filled, op, self.entries.get(path)) {
+          (_, &CacheOp::FetchOnce{sha256: Some(ref sha256), ..}, _) => Some(sha256.clone()),
+          (false, _, Some(entry)) if entry.size == size => entry.sha256.clone(),
+          _ => None,
+        };
+        let sha256 = match known_sha256 {
+          Some(sha256) => sha256,
+          None => file_sha256(file_path)?,
+        };
+        (size, Some(sha256))
+      }
+    };
+    match (filled, self.entries.get_mut(path)) {
+      (false, Some(entry)) => {
+        entry.size = size;
+        entry.sha256 = sha256;
+        entry.last_used = last_used;
+        return Ok(());
+      }
+      _ => {}
+    }
+    self.entries.insert(path.to_string(), CacheEntry{
+      op: op.op_name().to_string(),
+      src: op.src().to_string(),
+      size,
+      sha256,
+      last_used,
+    });
+    Ok(())
+  }
+
+  /// Removes the entry at `path` and its file, along with the `symlink_once`
+  /// entries linking to it, which would be left dangling. Returns the paths
+  /// removed.
+  pub fn remove(&mut self, path: &str, sysroot: &Sysroot) -> Maybe<Vec<String>> {
+    let mut removed = Vec::new();
+    let mut queue = vec![path.to_string()];
+    while let Some(path) = queue.pop() {
+      let file_path = sandboxed_join(&mutable_cache_dir(sysroot), &path)?;
+      match remove_file(&file_path) {
+        Err(ref e) if e.kind() != ErrorKind::NotFound => {
+          return Err(fail(format!("mutable cache: failed to remove {:?}", path)));
+        }
+        _ => {}
+      }
+      if self.entries.remove(&path).is_some() {
+        for (link_path, entry) in self.entries.iter() {
+          if entry.op == "symlink_once" && entry.src == path {
+            queue.push(link_path.clone());
+          }
+        }
+      }
+      removed.push(path);
+    }
+    Ok(removed)
+  }
+
+  /// Removes the least recently used entries until the cache takes up at
+  /// most `max_size` bytes, leaving alone the `pinned` entries and those they
+  /// link to. Returns the paths removed.
+  pub fn evict_to(&mut self, max_size: u64, pinned: &BTreeSet<String>, sysroot: &Sysroot) -> Maybe<Vec<String>> {
+    let mut kept = BTreeSet::new();
+    let mut queue: Vec<_> = pinned.iter().cloned().collect();
+    while let Some(path) = queue.pop() {
+      if let Some(entry) = self.entries.get(&path) {
+        if entry.op == "symlink_once" && !kept.contains(&entry.src) {
+          queue.push(entry.src.clone());
+        }
+      }
+      kept.insert(path);
+    }
+    let mut lru: Vec<_> = self.entries.iter()
+      .filter(|&(path, entry)| entry.size > 0 && !kept.contains(path))
+      .map(|(path, entry)| (entry.last_used, path.clone()))
+      .collect();
+    lru.sort();
+    let mut evicted = Vec::new();
+    for (_, path) in lru.into_iter() {
+      if self.total_size() <= max_size {
+        break;
+      }
+      if !self.entries.contains_key(&path) {
+        continue;
+      }
+      evicted.extend(self.remove(&path, sysroot)?);
+    }
+    Ok(evicted)
+  }
+
+  /// Drops the entries whose files are gone, removes the files of the cache
+  /// which are not in the index, and then evicts entries down to `max_size`
+  /// if given. Returns the paths removed.
+  pub fn prune(&mut self, max_size: Option<u64>, sysroot: &Sysroot) -> Maybe<Vec<String>> {
+    let cache_dir = mutable_cache_dir(sysroot);
+    let mut removed = Vec::new();
+    let missing: Vec<_> = self.entries.keys()
+      .filter(|path| match sandboxed_join(&cache_dir, path) {
+        Err(_) => true,
+        Ok(file_path) => symlink_metadata(file_path).is_err(),
+      })
+      .cloned()
+      .collect();
+    for path in missing.into_iter() {
+      self.entries.remove(&path);
+      removed.push(path);
+    }
+    let mut files = Vec::new();
+    if cache_dir.is_dir() {
+      list_files(&cache_dir, "", &mut files)?;
+    }
+    for path in files.into_iter() {
+      if !self.entries.contains_key(&path) {
+        remove_file(cache_dir.join(&path))
+          .map_err(|_| fail(format!("mutable cache: failed to remove {:?}", path)))?;
+        removed.push(path);
+      }
+    }
+    if let Some(max_size) = max_size {
+      removed.extend(self.evict_to(max_size, &BTreeSet::new(), sysroot)?);
+    }
+    Ok(removed)
+  }
+
+  /// Checks that the entry at `path` is still as it was filled in.
+  pub fn verify(&self, path: &str, sysroot: &Sysroot) -> Maybe {
+    let entry = self.entries.get(path)
+      .ok_or_else(|| fail("not in the cache index"))?;
+    let file_path = sandboxed_join(&mutable_cache_dir(sysroot), path)?;
+    let link_meta = symlink_metadata(&file_path)
+      .map_err(|_| fail("missing"))?;
+    if entry.op == "symlink_once" {
+      if !link_meta.file_type().is_symlink() {
+        return Err(fail("not a symlink"));
+      }
+      if metadata(&file_path).is_err() {
+        return Err(fail("dangling symlink"));
+      }
+      return Ok(());
+    }
+    if !link_meta.is_file() {
+      return Err(fail("not a file"));
+    }
+    if link_meta.len() != entry.size {
+      return Err(fail(format!("size is {}, expected {}", link_meta.len(), entry.size)));
+    }
+    if let Some(ref sha256) = entry.sha256 {
+      let digest = file_sha256(&file_path)?;
+      if &digest != sha256 {
+        return Err(fail(format!("sha256 is {}, expected {}", digest, sha256)));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Collects the paths of the files and symlinks under `dir`, relative to the
+/// cache, removing empty directories along the way.
+fn list_files(dir: &Path, prefix: &str, files: &mut Vec<String>) -> Maybe {
+  let entries = read_dir(dir)
+    .map_err(|_| fail(format!("mutable cache: failed to read {}", dir.display())))?;
+  let mut empty = true;
+  for entry in entries {
+    let entry = entry
+      .map_err(|_| fail(format!("mutable cache: failed to read {}", dir.display())))?;
+    let name = entry.file_name().to_string_lossy().into_owned();
+    let path = match prefix.is_empty() {
+      false => format!("{}/{}", prefix, name),
+      true  => name,
+    };
+    match entry.file_type() {
+      Ok(ref ty) if ty.is_dir() => list_files(&entry.path(), &path, files)?,
+      _ => files.push(path),
+    }
+    empty = false;
+  }
+  if empty && !prefix.is_empty() {
+    remove_dir(dir).ok();
+  }
+  Ok(())
+}
+
+/// Sets the total size quota of the mutable cache, which is then enforced
+/// before each new `fetch_once` download.
+pub fn set_mutable_cache_quota(sysroot: &Sysroot, max_size: Option<u64>) -> Maybe {
+  let mut index = CacheIndex::load(sysroot)?;
+  if index.max_size == max_size {
+    return Ok(());
+  }
+  index.max_size = max_size;
+  index.save(sysroot)
+}
+
+pub fn mutable_cache_dir(sysroot: &Sysroot) -> PathBuf {
+  sysroot.base_dir.join("mutable_cache")
+}
+
+/// Joins a relative path onto `root`, refusing anything which could point
+/// outside of it: absolute paths, `..` and the like.
+fn sandboxed_join(root: &Path, rel_path: &str) -> Maybe<PathBuf> {
+  let mut path = root.to_path_buf();
+  let mut empty = true;
+  for comp in Path::new(rel_path).components() {
+    match comp {
+      Component::Normal(c) => {
+        path.push(c);
+        empty = false;
+      }
+      _ => return Err(fail(format!("invalid path {:?}", rel_path))),
+    }
+  }
+  if empty {
+    return Err(fail(format!("invalid path {:?}", rel_path)));
+  }
+  Ok(path)
+}
+
+/// Joins `path` onto the mutable cache as `sandboxed_join` does, creating its
+/// parent directories. Parents which are not plain directories, such as
+/// `symlink_once` entries, are refused, since links could lead the path out
+/// of the cache, and so is a path which is a directory itself.
+fn cache_file_path(cache_dir: &Path, path: &str) -> Maybe<PathBuf> {
+  let rel_path = sandboxed_join(Path::new(""), path)?;
+  create_dir_all(cache_dir)
+    .map_err(|_| fail("failed to create the cache directory"))?;
+  let mut file_path = cache_dir.to_path_buf();
+  let mut comps = rel_path.components().peekable();
+  while let Some(comp) = comps.next() {
+    file_path.push(comp);
+    let is_dir = match symlink_metadata(&file_path) {
+      Err(ref e) if e.kind() == ErrorKind::NotFound => None,
+      Err(_) => return Err(fail(format!("failed to look up {:?}", path))),
+      Ok(meta) => Some(meta.file_type().is_dir()),
+    };
+    match (comps.peek().is_some(), is_dir) {
+      (true, None) => {
+        match create_dir(&file_path) {
+          Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+          Err(_) => return Err(fail(format!("failed to create cache directory for {:?}", path))),
+          Ok(_) => {}
+        }
+        // Check again, in case something else was made there meanwhile.
+        match symlink_metadata(&file_path) {
+          Ok(ref meta) if meta.file_type().is_dir() => {}
+          _ => return Err(fail(format!("failed to create cache directory for {:?}", path))),
+        }
+      }
+      (true, Some(true)) => {}
+      (true, Some(false)) => {
+        let parent = file_path.strip_prefix(cache_dir).unwrap_or(&file_path);
+        return Err(fail(format!("{:?} is not a directory", parent)));
+      }
+      (false, Some(true)) => return Err(fail(format!("{:?} is a directory", path))),
+      (false, _) => {}
+    }
+  }
+  Ok(file_path)
+}
+
+/// Checks that a `fetch_once` URL is an http or https one, and returns it as
+/// it is to be fetched. Other schemes, such as `file://`, could read from the
+/// machine guppybot runs on.
+fn fetch_url(url: &str) -> Maybe<Url> {
+  let parsed = Url::parse(url)
+    .map_err(|_| fail(format!("fetch_once: invalid url {:?}", url)))?;
+  match parsed.scheme() {
+    "http" | "https" => Ok(parsed),
+    scheme => Err(fail(format!("fetch_once: unsupported url scheme {:?}, expected http or https", scheme))),
+  }
+}
+
+/// Checks a `mutable_cache:append` without touching the cache, for dry runs:
+/// the path and source must be ones `mutable_cache_append` would accept.
+pub fn check_mutable_cache_append(path: &str, op: &CacheOp) -> Maybe {
+  let rel_path = sandboxed_join(Path::new(""), path)?;
+  match op {
+    &CacheOp::FetchOnce{ref url, ..} => {
+      fetch_url(url)?;
+    }
+    &CacheOp::CopyOnce{ref src_path} => {
+      sandboxed_join(Path::new(""), src_path)
+        .map_err(|e| fail(format!("copy_once: {}", e.excuses.join(": "))))?;
+    }
+    &CacheOp::SymlinkOnce{ref src_path} => {
+      let src_rel_path = sandboxed_join(Path::new(""), src_path)
+        .map_err(|e| fail(format!("symlink_once: {}", e.excuses.join(": "))))?;
+      if src_rel_path == rel_path {
+        return Err(fail(format!("symlink_once: {:?} links to itself", path)));
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Carries out the `mutable_cache:append` directives of one taskspec, in
+/// order. The entries appended are never evicted to make room for later
+/// ones, since the tasks of the taskspec are about to use them. On a
+/// `dry_run`, the directives are only checked.
+pub struct CacheAppender<'a> {
+  checkout_dir: Option<&'a Path>,
+  sysroot: &'a Sysroot,
+  dry_run: bool,
+  appended: BTreeSet<String>,
+}
+
+impl<'a> CacheAppender<'a> {
+  pub fn new(checkout_dir: Option<&'a Path>, sysroot: &'a Sysroot, dry_run: bool) -> CacheAppender<'a> {
+    CacheAppender{
+      checkout_dir,
+      sysroot,
+      dry_run,
+      appended: BTreeSet::new(),
+    }
+  }
+
+  pub fn append(&mut self, path: &str, op: &CacheOp) -> Maybe {
+    if self.dry_run {
+      return check_mutable_cache_append(path, op);
+    }
+    mutable_cache_append(path, op, self.checkout_dir, self.sysroot, &self.appended)?;
+    self.appended.insert(path.to_string());
+    Ok(())
+  }
+}
+
+/// Fills in the entry of the mutable cache at `path`, unless it is already
+/// there, and records its use in the cache index. Sources of `copy_once` are
+/// looked up in `checkout_dir`. Room is made for downloads by evicting
+/// entries other than the `pinned` ones.
+pub fn mutable_cache_append(path: &str, op: &CacheOp, checkout_dir: Option<&Path>, sysroot: &Sysroot, pinned: &BTreeSet<String>) -> Maybe {
+  check_mutable_cache_append(path, op)?;
+  let cache_dir = mutable_cache_dir(sysroot);
+  let file_path = cache_file_path(&cache_dir, path)?;
+  let mut index = CacheIndex::load(sysroot)?;
+  let filled = match op {
+    &CacheOp::FetchOnce{ref url, ref sha256} => {
+      let sha256 = sha256.as_ref().map(|s| s.as_str());
+      match is_fetched(&file_path, sha256)? {
+        true  => false,
+        false => {
+          if let Some(max_size) = index.max_size {
+            for evicted_path in index.evict_to(max_size, pinned, sysroot)?.iter() {
+              eprintln!("TRACE: mutable cache: evicted {:?}", evicted_path);
+            }
+          }
+          fetch_once(&file_path, url, sha256)?;
+          true
+        }
+      }
+    }
+    &CacheOp::CopyOnce{ref src_path} => {
+      let checkout_dir = checkout_dir
+        .ok_or_else(|| fail("copy_once: no checkout to copy from"))?;
+      copy_once(&file_path, checkout_dir, src_path)?
+    }
+    &CacheOp::SymlinkOnce{ref src_path} => {
+      symlink_once(&file_path, &cache_dir, path, src_path)?
+    }
+  };
+  index.touch(path, op, &file_path, filled)?;
+  index.save(sysroot)
+}
+
+/// SHA-256 of a file, in lowercase hex.
+pub fn file_sha256(file_path: &Path) -> Maybe<String> {
+  let mut file = File::open(file_path)
+    .map_err(|_| fail(format!("failed to open {}", file_path.display())))?;
+  let mut hasher = Sha256::new();
+  let mut buf = vec![0; 64 * 1024];
+  loop {
+    let n = file.read(&mut buf)
+      .map_err(|_| fail(format!("failed to read {}", file_path.display())))?;
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buf[ .. n]);
+  }
+  Ok(hex::encode(&hasher.finish()))
+}
+
+/// Whether a `fetch_once` entry is already there. An entry which does not
+/// match `sha256` is refused rather than fetched again, since it may be
+/// another repository's.
+fn is_fetched(file_path: &Path, sha256: Option<&str>) -> Maybe<bool> {
+  if symlink_metadata(file_path).is_err() {
+    return Ok(false);
+  }
+  match sha256 {
+    None => Ok(true),
+    Some(sha256) => {
+      let digest = file_sha256(file_path)?;
+      if digest != sha256 {
+        return Err(fail(format!("fetch_once: already in the cache with sha256 {}, not {}", digest, sha256)));
+      }
+      Ok(true)
+    }
+  }
+}
+
+fn fetch_once(file_path: &Path, url: &str, sha256: Option<&str>) -> Maybe {
+  let mut attempt = 1;
+  loop {
+    match _fetch(file_path, url, sha256) {
+      Ok(_) => return Ok(()),
+      Err(e) => {
+        if attempt >= FETCH_ATTEMPTS {
+          return Err(fail(format!("fetch_once: gave up after {} attempts: {}", attempt, e.excuses.join(": "))));
+        }
+        eprintln!("TRACE: mutable cache: fetch_once: attempt {} failed: {:?}", attempt, e);
+        sleep(Duration::from_secs(1 << (attempt - 1)));
+        attempt += 1;
+      }
+    }
+  }
+}
+
+/// Downloads `url` next to `file_path`, and only renames it into place once
+/// it is complete and matches `sha256`, so that a failed download leaves
+/// nothing behind. Redirects are followed here rather than by curl, so that
+/// each URL along the way is checked by `fetch_url`.
+fn _fetch(file_path: &Path, url: &str, sha256: Option<&str>) -> Maybe {
+  let dir = file_path.parent()
+    .ok_or_else(|| fail("fetch_once: invalid cache path"))?;
+  let mut url = url.to_string();
+  let mut redirects = 0;
+  loop {
+    let parsed_url = fetch_url(&url)?;
+    let tmp_file = NamedTempFile::new_in(dir)
+      .map_err(|_| fail("fetch_once: failed to create temporary file"))?;
+    let mut hasher = Sha256::new();
+    let mut headers = CurlList::new();
+    headers.append("Accept: application/octet-stream")
+      .map_err(|_| fail("fetch_once: failed to set up request"))?;
+    let mut ez = CurlEasy::new();
+    ez.http_headers(headers)
+      .and_then(|_| ez.follow_location(false))
+      .and_then(|_| ez.fail_on_error(true))
+      .map_err(|_| fail("fetch_once: failed to set up request"))?;
+    ez.url(parsed_url.as_str())
+      .map_err(|_| fail(format!("fetch_once: invalid url {:?}", url)))?;
+    {
+      let mut writer = BufWriter::new(tmp_file.as_file());
+      let mut write_ok = true;
+      let result = {
+        let mut xfer = ez.transfer();
+        xfer.write_function(|data| {
+          if writer.write_all(data).is_err() {
+            // Returning a short count aborts the transfer.
+            write_ok = false;
+            return Ok(0);
+          }
+          hasher.update(data);
+          Ok(data.len())
+        }).map_err(|_| fail("fetch_once: failed to set up request"))?;
+        xfer.perform()
+      };
+      match (result, write_ok) {
+        (_, false) => return Err(fail("fetch_once: write error")),
+        (Err(e), _) => return Err(fail(format!("fetch_once: failed to fetch {:?}: {}", url, e))),
+        (Ok(_), true) => {}
+      }
+      writer.flush()
+        .map_err(|_| fail("fetch_once: write error"))?;
+    }
+    let code = ez.response_code()
+      .map_err(|_| fail("fetch_once: failed to get response code"))?;
+    if code >= 300 && code < 400 {
+      let next_url = ez.redirect_url()
+        .map_err(|_| fail("fetch_once: failed to get redirect"))?
+        .map(|next_url| next_url.to_string());
+      match next_url {
+        None => return Err(fail(format!("fetch_once: failed to fetch {:?}: HTTP status {} without a location", url, code))),
+        Some(next_url) => {
+          if redirects >= FETCH_MAX_REDIRECTS {
+            return Err(fail(format!("fetch_once: failed to fetch {:?}: too many redirects", url)));
+          }
+          redirects += 1;
+          url = next_url;
+          continue;
+        }
+      }
+    }
+    if code < 200 || code >= 300 {
+      return Err(fail(format!("fetch_once: failed to fetch {:?}: HTTP status {}", url, code)));
+    }
+    let digest = hex::encode(&hasher.finish());
+    if let Some(sha256) = sha256 {
+      if digest != sha256 {
+        return Err(fail(format!("fetch_once: sha256 of {:?} is {}, expected {}", url, digest, sha256)));
+      }
+    }
+    tmp_file.persist(file_path)
+      .map_err(|_| fail("fetch_once: failed to move download into place"))?;
+    return Ok(());
+  }
+}
+
+/// Returns whether the entry was filled in, i.e. was not already there.
+fn copy_once(file_path: &Path, checkout_dir: &Path, src_path: &str) -> Maybe<bool> {
+  if symlink_metadata(file_path).is_ok() {
+    return Ok(false);
+  }
+  let src_file_path = sandboxed_join(checkout_dir, src_path)
+    .map_err(|e| fail(format!("copy_once: {}", e.excuses.join(": "))))?;
+  // Symlinks in the checkout must not lead out of it either.
+  let checkout_dir = checkout_dir.canonicalize()
+    .map_err(|_| fail("copy_once: failed to resolve checkout"))?;
+  let src_file_path = src_file_path.canonicalize()
+    .map_err(|_| fail(format!("copy_once: no such file {:?}", src_path)))?;
+  if !src_file_path.starts_with(&checkout_dir) {
+    return Err(fail(format!("copy_once: {:?} is outside of the checkout", src_path)));
+  }
+  match metadata(&src_file_path) {
+    Ok(ref meta) if meta.is_file() => {}
+    _ => return Err(fail(format!("copy_once: {:?} is not a file", src_path))),
+  }
+  let dir = file_path.parent()
+    .ok_or_else(|| fail("copy_once: invalid cache path"))?;
+  let mut tmp_file = NamedTempFile::new_in(dir)
+    .map_err(|_| fail("copy_once: failed to create temporary file"))?;
+  let mut src_file = File::open(&src_file_path)
+    .map_err(|_| fail(format!("copy_once: failed to open {:?}", src_path)))?;
+  io::copy(&mut src_file, &mut tmp_file)
+    .and_then(|_| tmp_file.flush())
+    .map_err(|_| fail(format!("copy_once: failed to copy {:?}", src_path)))?;
+  tmp_file.persist(file_path)
+    .map_err(|_| fail("copy_once: failed to move copy into place"))?;
+  Ok(true)
+}
+
+/// Returns whether the entry was filled in, i.e. was not already there.
+/// Directories are not linked to, so that no link can stand in for a parent
+/// directory of another entry.
+fn symlink_once(file_path: &Path, cache_dir: &Path, path: &str, src_path: &str) -> Maybe<bool> {
+  if symlink_metadata(file_path).is_ok() {
+    return Ok(false);
+  }
+  let src_rel_path = sandboxed_join(Path::new(""), src_path)
+    .map_err(|e| fail(format!("symlink_once: {}", e.excuses.join(": "))))?;
+  let rel_path = sandboxed_join(Path::new(""), path)?;
+  if src_rel_path == rel_path {
+    return Err(fail(format!("symlink_once: {:?} links to itself", path)));
+  }
+  match metadata(cache_dir.join(&src_rel_path)) {
+    Ok(ref meta) if meta.is_dir() => {
+      return Err(fail(format!("symlink_once: {:?} is a directory", src_path)));
+    }
+    _ => {}
+  }
+  // The target is relative, so that the link also resolves where the cache
+  // is mounted in task containers.
+  let mut target = PathBuf::new();
+  for _ in 1 .. rel_path.components().count() {
+    target.push("..");
+  }
+  target.push(&src_rel_path);
+  symlink(&target, file_path)
+    .map_err(|_| fail(format!("symlink_once: failed to link {:?} to {:?}", path, src_path)))?;
+  Ok(true)
+}
+
+/// A build cache of a task, as mounted into its container.
+#[derive(Clone, Debug)]
+pub struct BuildCacheMount {
+  pub name: String,
+  /// Directory of the cache in the sysroot.
+  pub dir: PathBuf,
+  /// Where the cache is mounted in the task container.
+  pub path: String,
+  pub read_only: bool,
+  /// Shared lock on the cache, taken by `prepare_build_caches` and held for
+  /// as long as the mount is around, so that the cache is not emptied while
+  /// a task has it mounted.
+  pub lock: Option<Arc<File>>,
+}
+
+pub fn build_caches_dir(sysroot: &Sysroot) -> PathBuf {
+  sysroot.base_dir.join("build_caches")
+}
+
+/// Directory name for the build caches of a repository: its URL, made safe
+/// for a file name, and a hash of the URL to keep apart URLs made the same.
+/// Local runs, which have no URL, share `_local`.
+fn build_cache_repo_name(remote_url: &str) -> String {
+  if remote_url.is_empty() {
+    return "_local".to_string();
+  }
+  let url = normalize_repo_url(remote_url);
+  let mut hasher = Sha256::new();
+  hasher.update(url.as_bytes());
+  let digest = hex::encode(&hasher.finish());
+  let safe_url: String = url.chars()
+    .map(|c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+      false => '_',
+      true  => c,
+    })
+    .collect();
+  format!("{}-{}", safe_url, &digest[ .. 12])
+}
+
+/// Where the build caches of a task go, under
+/// `<sysroot>/build_caches/<REPO>/<TOOLCHAIN>/<NAME>`. Runs which are not
+/// trusted get them read only, so that they cannot leave anything behind for
+/// later runs.
+pub fn build_cache_mounts(task: &TaskSpec, remote_url: &str, trusted: bool, sysroot: &Sysroot) -> Vec<BuildCacheMount> {
+  let toolchain = task.toolchain.as_ref()
+    .map(|toolchain| toolchain.to_desc_string())
+    .unwrap_or_else(|| "default".to_string());
+  let toolchain_dir = build_caches_dir(sysroot)
+    .join(build_cache_repo_name(remote_url))
+    .join(toolchain);
+  task.caches.iter().map(|cache| {
+    BuildCacheMount{
+      name: cache.name.clone(),
+      dir: toolchain_dir.join(&cache.name),
+      path: cache.path.clone(),
+      read_only: !trusted,
+      lock: None,
+    }
+  }).collect()
+}
+
+/// Takes or changes an flock on `file`, as `libc::flock` does.
+fn flock(file: &File, op: libc::c_int) -> io::Result<()> {
+  loop {
+    if unsafe { libc::flock(file.as_raw_fd(), op) } == 0 {
+      return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() != ErrorKind::Interrupted {
+      return Err(e);
+    }
+  }
+}
+
+/// Creates the build caches of a task and takes a shared lock on each, kept
+/// in its mount. Writable caches which have grown over `max_size` bytes are
+/// emptied, but only under an exclusive lock, i.e. when no other task has
+/// them mounted. Also notes the URL of the repository, for `guppyctl cache
+/// builds`.
+pub fn prepare_build_caches(mounts: &mut [BuildCacheMount], remote_url: &str, max_size: Option<u64>) -> Maybe {
+  for mount in mounts.iter_mut() {
+    if let Some(parent_dir) = mount.dir.parent() {
+      create_dir_all(parent_dir)
+        .map_err(|_| fail(format!("build cache: failed to create {:?}", mount.name)))?;
+    }
+    let lock_file = OpenOptions::new().read(true).write(true).create(true)
+      .open(mount.dir.with_file_name(format!(".{}.lock", mount.name)))
+      .map_err(|_| fail(format!("build cache: failed to lock {:?}", mount.name)))?;
+    if let (false, Some(max_size), true) = (mount.read_only, max_size, mount.dir.is_dir()) {
+      let size = dir_size(&mount.dir);
+      if size > max_size {
+        match flock(&lock_file, libc::LOCK_EX | libc::LOCK_NB) {
+          Err(_) => {
+            eprintln!("TRACE: build cache: {} is over the size limit ({} > {}), but in use", mount.dir.display(), size, max_size);
+          }
+          Ok(_) => {
+            eprintln!("TRACE: build cache: {} is over the size limit ({} > {}), emptying it", mount.dir.display(), size, max_size);
+            // Move the cache out of the way first, so that nothing sees it
+            // half removed.
+            let old_dir = mount.dir.with_file_name(format!(".{}.old", mount.name));
+            remove_dir_all(&old_dir).ok();
+            rename(&mount.dir, &old_dir)
+              .map_err(|_| fail(format!("build cache: failed to empty {:?}", mount.name)))?;
+            remove_dir_all(&old_dir).ok();
+          }
+        }
+      }
+    }
+    // Also turns the exclusive lock taken to empty the cache into a shared
+    // one.
+    flock(&lock_file, libc::LOCK_SH)
+      .map_err(|_| fail(format!("build cache: failed to lock {:?}", mount.name)))?;
+    mount.lock = Some(Arc::new(lock_file));
+    create_dir_all(&mount.dir)
+      .map_err(|_| fail(format!("build cache: failed to create {:?}", mount.name)))?;
+    let url_path = mount.dir.parent().and_then(|dir| dir.parent())
+      .map(|repo_dir| repo_dir.join(".remote_url"));
+    if let Some(url_path) = url_path {
+      if !url_path.exists() && !remote_url.is_empty() {
+        let url = normalize_repo_url(remote_url);
+        File::create(&url_path)
+          .and_then(|mut file| writeln!(file, "{}", url))
+          .map_err(|_| fail("build cache: failed to note repository url"))?;
+      }
+    }
+  }
+  Ok(())
+}
+
+/// A build cache directory in the sysroot, as listed by `build_caches`.
+#[derive(Clone, Debug)]
+pub struct BuildCacheInfo {
+  /// The repository URL, or `None` for local runs.
+  pub remote_url: Option<String>,
+  pub toolchain: String,
+  pub name: String,
+  pub dir: PathBuf,
+  pub size: u64,
+}
+
+/// Lists the build caches in the sysroot.
+pub fn build_caches(sysroot: &Sysroot) -> Maybe<Vec<BuildCacheInfo>> {
+  let mut caches = Vec::new();
+  let root_dir = build_caches_dir(sysroot);
+  if !root_dir.is_dir() {
+    return Ok(caches);
+  }
+  for repo_dir in sorted_subdirs(&root_dir)?.into_iter() {
+    let mut remote_url = String::new();
+    File::open(repo_dir.join(".remote_url"))
+      .and_then(|mut file| file.read_to_string(&mut remote_url))
+      .ok();
+    let remote_url = match remote_url.trim() {
+      "" => None,
+      url => Some(url.to_string()),
+    };
+    for toolchain_dir in sorted_subdirs(&repo_dir)?.into_iter() {
+      for dir in sorted_subdirs(&toolchain_dir)?.into_iter() {
+        caches.push(BuildCacheInfo{
+          remote_url: remote_url.clone(),
+          toolchain: toolchain_dir.file_name().unwrap().to_string_lossy().into_owned(),
+          name: dir.file_name().unwrap().to_string_lossy().into_owned(),
+          size: dir_size(&dir),
+          dir,
+        });
+      }
+    }
+  }
+  Ok(caches)
+}
+
+/// The subdirectories of `dir`, leaving out hidden ones, in order.
+fn sorted_subdirs(dir: &Path) -> Maybe<Vec<PathBuf>> {
+  let entries = read_dir(dir)
+    .map_err(|_| fail(format!("build cache: failed to read {}", dir.display())))?;
+  let mut dirs: Vec<_> = entries
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+    .filter(|entry| entry.file_type().map(|ty| ty.is_dir()).unwrap_or(false))
+    .map(|entry| entry.path())
+    .collect();
+  dirs.sort();
+  Ok(dirs)
+}
+
+/// Total size of the files under `dir`, not following symlinks. Anything
+/// which cannot be read counts as empty.
+fn dir_size(dir: &Path) -> u64 {
+  let entries = match read_dir(dir) {
+    Err(_) => return 0,
+    Ok(entries) => entries,
+  };
+  let mut size = 0;
+  for entry in entries.filter_map(|entry| entry.ok()) {
+    match entry.file_type() {
+      Ok(ref ty) if ty.is_dir() => size += dir_size(&entry.path()),
+      Ok(ref ty) if ty.is_file() => size += entry.metadata().map(|meta| meta.len()).unwrap_or(0),
+      _ => {}
+    }
+  }
+  size
+}
@@ -4,7 +4,9 @@ use self::config_toml::{
   CiConfig as CiToml,
 };
 
+use crate::docker::{TaskLimits};
 use crate::query::{Maybe, Open, Query, fail};
+use crate::webhook::{normalize_repo_url};
 
 use schemas::v1::{
   GpusV0,
@@ -13,13 +15,15 @@ use schemas::v1::{
 };
 use url::{Url};
 
-use std::fs::{File, create_dir_all};
-use std::io::{Write, BufWriter};
+use std::fs::{File, create_dir_all, metadata};
+use std::io::{ErrorKind, Write, BufWriter};
+use std::os::unix::fs::{PermissionsExt};
 use std::path::{Path, PathBuf};
 
 mod config_toml {
   use crate::query::{Maybe, fail};
 
+  use std::collections::{BTreeMap};
   use std::fs::{File};
   use std::io::{Read, BufReader};
   use std::os::unix::fs::{PermissionsExt};
@@ -31,9 +35,19 @@ mod config_toml {
     pub secret_token: Option<String>,
   }
 
+  #[derive(Debug, Default, Deserialize)]
+  pub struct Registry {
+    pub url: Option<String>,
+    pub tls_ca_file: Option<String>,
+    pub tls_verify: Option<bool>,
+    pub connect_timeout_secs: Option<u64>,
+    pub ping_interval_secs: Option<u64>,
+  }
+
   #[derive(Debug, Default, Deserialize)]
   pub struct ApiConfig {
     pub auth: Option<ApiAuth>,
+    pub registry: Option<Registry>,
   }
 
   impl ApiConfig {
@@ -76,10 +90,31 @@ mod config_toml {
     pub user_prefix: Option<String>,
   }
 
+  #[derive(Debug, Default, Deserialize)]
+  pub struct Daemon {
+    pub shutdown_grace_secs: Option<u64>,
+    pub max_task_retries: Option<u32>,
+  }
+
+  #[derive(Debug, Default, Deserialize)]
+  pub struct MutableCache {
+    pub max_size: Option<String>,
+  }
+
+  #[derive(Debug, Default, Deserialize)]
+  pub struct BuildCache {
+    pub max_size: Option<String>,
+  }
+
   #[derive(Debug, Default, Deserialize)]
   pub struct MachineConfig {
     pub local_machine: Option<LocalMachine>,
     pub remote_machine: Option<Vec<RemoteMachine>>,
+    pub daemon: Option<Daemon>,
+    pub mutable_cache: Option<MutableCache>,
+    pub build_cache: Option<BuildCache>,
+    /// Task limits by key, such as `cpus` or `max_memory`.
+    pub task_limits: Option<BTreeMap<String, toml::Value>>,
   }
 
   impl MachineConfig {
@@ -107,10 +142,20 @@ mod config_toml {
     pub commit_policy: Option<String>,
     pub pr_policy: Option<String>,
     pub allowed_users: Option<Vec<String>>,
+    pub webhook_secret: Option<String>,
+    pub poll_refs: Option<Vec<String>>,
+    pub poll_interval_secs: Option<u64>,
+  }
+
+  #[derive(Debug, Default, Deserialize)]
+  pub struct Webhook {
+    pub listen_addr: Option<String>,
+    pub max_body_bytes: Option<u64>,
   }
 
   #[derive(Debug, Default, Deserialize)]
   pub struct CiConfig {
+    pub webhook: Option<Webhook>,
     pub repos: Option<Vec<CiRepo>>,
   }
 
@@ -140,9 +185,34 @@ pub struct ApiAuth {
   pub secret_token: String,
 }
 
+#[derive(Clone, Debug, Serialize)]
+pub struct RegistryConfig {
+  pub url: String,
+  pub tls_ca_file: Option<PathBuf>,
+  pub tls_verify: bool,
+  pub connect_timeout_secs: u64,
+  pub ping_interval_secs: u64,
+}
+
+impl Default for RegistryConfig {
+  fn default() -> RegistryConfig {
+    RegistryConfig{
+      url: "wss://guppybot.org:443/w/v1/".to_string(),
+      tls_ca_file: None,
+      tls_verify: true,
+      connect_timeout_secs: 30,
+      ping_interval_secs: 3600,
+    }
+  }
+}
+
 #[derive(Debug, Serialize)]
 pub struct ApiConfig {
   pub auth: ApiAuth,
+  /// Only present if the api config has a `[registry]` section; otherwise
+  /// the defaults apply.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub registry: Option<RegistryConfig>,
 }
 
 impl Open for ApiConfig {
@@ -166,8 +236,27 @@ impl ApiConfig {
       api_key: auth.api_key.ok_or_else(|| fail("api config: auth: missing api_key"))?,
       secret_token: auth.secret_token.ok_or_else(|| fail("api config: auth: missing secret_token"))?,
     };
+    let registry = match api.registry {
+      None => None,
+      Some(registry) => {
+        let default = RegistryConfig::default();
+        let url = registry.url.unwrap_or(default.url);
+        match Url::parse(&url) {
+          Ok(ref u) if u.scheme() == "ws" || u.scheme() == "wss" => {}
+          _ => return Err(fail("api config: registry: url must be a ws:// or wss:// url")),
+        }
+        Some(RegistryConfig{
+          url,
+          tls_ca_file: registry.tls_ca_file.map(|s| PathBuf::from(s)),
+          tls_verify: registry.tls_verify.unwrap_or(default.tls_verify),
+          connect_timeout_secs: registry.connect_timeout_secs.unwrap_or(default.connect_timeout_secs),
+          ping_interval_secs: registry.ping_interval_secs.unwrap_or(default.ping_interval_secs),
+        })
+      }
+    };
     Ok(ApiConfig{
       auth,
+      registry,
     })
   }
 }
@@ -195,6 +284,364 @@ impl Open for MachineConfigV0 {
   }
 }
 
+#[derive(Debug)]
+pub struct DaemonConfig {
+  pub shutdown_grace_secs: u64,
+  /// Most retries of a failed task, whatever its taskspec asks for.
+  pub max_task_retries: u32,
+}
+
+impl Default for DaemonConfig {
+  fn default() -> DaemonConfig {
+    DaemonConfig{
+      shutdown_grace_secs: 0,
+      max_task_retries: 5,
+    }
+  }
+}
+
+impl Open for DaemonConfig {
+  type Context = Config;
+
+  fn open(config: &Config) -> Maybe<DaemonConfig> {
+    let cfg = open_machine_toml(config)?;
+    let daemon = cfg.daemon.unwrap_or_default();
+    let default = DaemonConfig::default();
+    Ok(DaemonConfig{
+      shutdown_grace_secs: daemon.shutdown_grace_secs.unwrap_or(default.shutdown_grace_secs),
+      max_task_retries: daemon.max_task_retries.unwrap_or(default.max_task_retries),
+    })
+  }
+}
+
+/// Opens the machine config for the settings which all have defaults, as if
+/// it were empty when there is none. A machine config which exists but
+/// cannot be read or is invalid is still an error.
+fn open_machine_toml(config: &Config) -> Maybe<MachineToml> {
+  let path = config.config_dir.join("machine");
+  match metadata(&path) {
+    Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(MachineToml::default()),
+    _ => MachineToml::open(&path),
+  }
+}
+
+/// Parses a size in bytes, such as `512m` or `50G`, with an optional
+/// binary-unit suffix (`k`, `m`, `g` or `t`, optionally followed by `b`).
+pub fn parse_byte_size(s: &str) -> Option<u64> {
+  let s = s.trim().to_ascii_lowercase();
+  let s = match s.ends_with('b') {
+    false => &s[ .. ],
+    true  => &s[ .. s.len() - 1],
+  };
+  let (digits, shift) = match s.chars().last() {
+    Some('k') => (&s[ .. s.len() - 1], 10),
+    Some('m') => (&s[ .. s.len() - 1], 20),
+    Some('g') => (&s[ .. s.len() - 1], 30),
+    Some('t') => (&s[ .. s.len() - 1], 40),
+    _ => (s, 0),
+  };
+  if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+    return None;
+  }
+  let n: u64 = digits.parse().ok()?;
+  n.checked_mul(1 << shift)
+}
+
+/// Limits on the mutable cache, from the `[mutable_cache]` section of the
+/// machine config.
+#[derive(Clone, Default, Debug)]
+pub struct MutableCacheConfig {
+  /// Total size of the cache in bytes, above which the least recently used
+  /// entries are evicted; unlimited if `None`.
+  pub max_size: Option<u64>,
+}
+
+impl Open for MutableCacheConfig {
+  type Context = Config;
+
+  fn open(config: &Config) -> Maybe<MutableCacheConfig> {
+    let cfg = open_machine_toml(config)?;
+    let mutable_cache = cfg.mutable_cache.unwrap_or_default();
+    let max_size = match mutable_cache.max_size {
+      None => None,
+      Some(ref s) => Some(parse_byte_size(s)
+        .ok_or_else(|| fail(format!("machine config: mutable_cache: invalid max_size {:?}", s)))?),
+    };
+    Ok(MutableCacheConfig{
+      max_size,
+    })
+  }
+}
+
+/// Limits on the build caches of tasks, from the `[build_cache]` section of
+/// the machine config.
+#[derive(Clone, Default, Debug)]
+pub struct BuildCacheConfig {
+  /// Size of each build cache in bytes, above which it is emptied before the
+  /// next task using it starts; unlimited if `None`.
+  pub max_size: Option<u64>,
+}
+
+impl Open for BuildCacheConfig {
+  type Context = Config;
+
+  fn open(config: &Config) -> Maybe<BuildCacheConfig> {
+    let cfg = open_machine_toml(config)?;
+    let build_cache = cfg.build_cache.unwrap_or_default();
+    let max_size = match build_cache.max_size {
+      None => None,
+      Some(ref s) => Some(parse_byte_size(s)
+        .ok_or_else(|| fail(format!("machine config: build_cache: invalid max_size {:?}", s)))?),
+    };
+    Ok(BuildCacheConfig{
+      max_size,
+    })
+  }
+}
+
+/// Resource limits of task containers, from the `[task_limits]` section of
+/// the machine config: `cpus`, `memory`, `pids` and `shm` are the defaults
+/// for tasks that do not set their own limits, and `max_cpus`, `max_memory`,
+/// `max_pids` and `max_shm` cap what any task may ask for.
+#[derive(Clone, Default, Debug)]
+pub struct TaskLimitsConfig {
+  pub defaults: TaskLimits,
+  pub maxima: TaskLimits,
+}
+
+impl TaskLimitsConfig {
+  /// The limits of a task container under this policy.
+  pub fn limits_of(&self, task_limits: &TaskLimits) -> TaskLimits {
+    task_limits.under_policy(&self.defaults, &self.maxima)
+  }
+}
+
+impl Open for TaskLimitsConfig {
+  type Context = Config;
+
+  fn open(config: &Config) -> Maybe<TaskLimitsConfig> {
+    let cfg = open_machine_toml(config)?;
+    let mut limits = TaskLimitsConfig::default();
+    for (key, value) in cfg.task_limits.unwrap_or_default().iter() {
+      let value = match value {
+        &toml::Value::String(ref s) => s.clone(),
+        &toml::Value::Integer(n) => n.to_string(),
+        &toml::Value::Float(x) => x.to_string(),
+        _ => return Err(fail(format!("machine config: task_limits: {}: expected a number or a string", key))),
+      };
+      let res = match key.starts_with("max_") {
+        false => limits.defaults.set(key, &value),
+        true  => limits.maxima.set(&key["max_".len() .. ], &value),
+      };
+      res.map_err(|e| fail(format!("machine config: task_limits: {}", e.excuses.join(": "))))?;
+    }
+    Ok(limits)
+  }
+}
+
+/// PCI slots of the GPUs given to guppybot, from the `[local_machine]`
+/// section of the machine config.
+#[derive(Clone, Default, Debug)]
+pub struct LocalGpusConfig {
+  pub slots: Vec<String>,
+}
+
+impl Open for LocalGpusConfig {
+  type Context = Config;
+
+  fn open(config: &Config) -> Maybe<LocalGpusConfig> {
+    let cfg = open_machine_toml(config)?;
+    let local_machine = cfg.local_machine.unwrap_or_default();
+    Ok(LocalGpusConfig{
+      slots: local_machine.gpus.unwrap_or_default(),
+    })
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+  pub listen_addr: String,
+  pub max_body_bytes: u64,
+}
+
+impl Default for WebhookConfig {
+  fn default() -> WebhookConfig {
+    WebhookConfig{
+      listen_addr: "0.0.0.0:8766".to_string(),
+      max_body_bytes: 4 * 1024 * 1024,
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CiEventPolicy {
+  Always,
+  AllowedUsers,
+  Never,
+}
+
+impl CiEventPolicy {
+  pub fn from_str(s: &str) -> Option<CiEventPolicy> {
+    match s {
+      "always" => Some(CiEventPolicy::Always),
+      "allowed_users" => Some(CiEventPolicy::AllowedUsers),
+      "never" => Some(CiEventPolicy::Never),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct CiRepoConfig {
+  pub remote_url: String,
+  /// Who may trigger runs of pushed branches and tags. Defaults to always.
+  pub commit_policy: CiEventPolicy,
+  /// Who may trigger runs of pull requests. Defaults to `allowed_users`.
+  pub pr_policy: CiEventPolicy,
+  pub allowed_users: Vec<String>,
+  /// Shared secret for webhook signatures; webhooks for a repo without one
+  /// are refused.
+  pub webhook_secret: Option<String>,
+  /// Refs to poll with `git ls-remote`, as full ref names or patterns that
+  /// `git ls-remote` accepts. Empty if the repo is not polled.
+  pub poll_refs: Vec<String>,
+  pub poll_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CiConfig {
+  /// Only present if the ci config has a `[webhook]` section, in which case
+  /// the daemon listens for webhooks.
+  pub webhook: Option<WebhookConfig>,
+  pub repos: Vec<CiRepoConfig>,
+}
+
+impl Open for CiConfig {
+  type Context = Config;
+
+  fn open(config: &Config) -> Maybe<CiConfig> {
+    let path = config.config_dir.join("ci");
+    let cfg = CiToml::open(&path)?;
+    let webhook = cfg.webhook.map(|webhook| {
+      let default = WebhookConfig::default();
+      WebhookConfig{
+        listen_addr: webhook.listen_addr.unwrap_or(default.listen_addr),
+        max_body_bytes: webhook.max_body_bytes.unwrap_or(default.max_body_bytes),
+      }
+    });
+    let mut repos = Vec::new();
+    for repo in cfg.repos.unwrap_or_default().into_iter() {
+      let commit_policy = match repo.commit_policy {
+        None => CiEventPolicy::Always,
+        Some(s) => CiEventPolicy::from_str(&s)
+          .ok_or_else(|| fail(format!("ci config: repos: invalid commit_policy: {:?}", s)))?,
+      };
+      let pr_policy = match repo.pr_policy {
+        None => CiEventPolicy::AllowedUsers,
+        Some(s) => CiEventPolicy::from_str(&s)
+          .ok_or_else(|| fail(format!("ci config: repos: invalid pr_policy: {:?}", s)))?,
+      };
+      repos.push(CiRepoConfig{
+        remote_url: repo.remote_url.ok_or_else(|| fail("ci config: repos: missing remote_url"))?,
+        commit_policy,
+        pr_policy,
+        allowed_users: repo.allowed_users.unwrap_or_default(),
+        webhook_secret: repo.webhook_secret,
+        poll_refs: repo.poll_refs.unwrap_or_default(),
+        poll_interval_secs: repo.poll_interval_secs.unwrap_or(300),
+      });
+    }
+    if repos.iter().any(|repo| repo.webhook_secret.is_some()) {
+      let meta = metadata(&path)
+        .map_err(|_| fail("failed to get ci config metadata"))?;
+      match meta.permissions().mode() & 0o077 {
+        0 => {}
+        _ => return Err(fail("ci config: file permissions are too open for webhook secrets")),
+      }
+    }
+    Ok(CiConfig{
+      webhook,
+      repos,
+    })
+  }
+}
+
+impl CiConfig {
+  /// Opens the ci config, or returns `None` if there is none. A ci config
+  /// which exists but cannot be read or is invalid is an error, since having
+  /// no ci config means accepting runs for any repository.
+  pub fn open_optional(config: &Config) -> Maybe<Option<CiConfig>> {
+    match metadata(config.config_dir.join("ci")) {
+      Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+      _ => CiConfig::open(config).map(Some),
+    }
+  }
+
+  pub fn find_repo(&self, remote_url: &str) -> Option<&CiRepoConfig> {
+    let remote_url = normalize_repo_url(remote_url);
+    self.repos.iter().find(|repo| normalize_repo_url(&repo.remote_url) == remote_url)
+  }
+
+  /// Decides whether a CI run may go ahead. Runs of pull requests, i.e. of
+  /// `refs/pull/*` or `refs/merge-requests/*`, fall under the `pr_policy` of
+  /// the repo, and all other runs under its `commit_policy`. Returns why the
+  /// run is refused otherwise.
+  pub fn check_run(&self, remote_url: &str, ref_full: Option<&str>, originator: Option<&str>) -> Result<(), String> {
+    let repo = match self.find_repo(remote_url) {
+      None => return Err(format!("repository is not in the ci config: {}", remote_url)),
+      Some(repo) => repo,
+    };
+    let (policy, what) = match is_pr_ref(ref_full) {
+      false => (repo.commit_policy, "commits"),
+      true  => (repo.pr_policy, "pull requests"),
+    };
+    match policy {
+      CiEventPolicy::Always => Ok(()),
+      CiEventPolicy::Never => Err(format!("runs of {} are disabled for this repository", what)),
+      CiEventPolicy::AllowedUsers => {
+        let originator = match originator {
+          None => return Err(format!("runs of {} need a known originator", what)),
+          Some(originator) => originator,
+        };
+        match repo.allowed_users.iter().any(|user| user.eq_ignore_ascii_case(originator)) {
+          false => Err(format!("{} is not allowed to run {} for this repository", originator, what)),
+          true  => Ok(()),
+        }
+      }
+    }
+  }
+}
+
+/// Whether a run may write to the build caches of its repository: runs of
+/// commits may, and runs of pull requests only if their originator is one of
+/// the `allowed_users` of the repository in the ci config, if any. Runs of
+/// an unknown ref may not, since they could be of pull requests.
+pub fn trusts_run(ci_cfg: Option<&CiConfig>, remote_url: &str, ref_full: Option<&str>, originator: Option<&str>) -> bool {
+  if ref_full.is_none() {
+    return false;
+  }
+  if !is_pr_ref(ref_full) {
+    return true;
+  }
+  let repo = match ci_cfg.and_then(|cfg| cfg.find_repo(remote_url)) {
+    None => return false,
+    Some(repo) => repo,
+  };
+  match originator {
+    None => false,
+    Some(originator) => repo.allowed_users.iter().any(|user| user.eq_ignore_ascii_case(originator)),
+  }
+}
+
+/// Whether a ref is of a pull request, i.e. `refs/pull/*` or
+/// `refs/merge-requests/*`.
+pub fn is_pr_ref(ref_full: Option<&str>) -> bool {
+  match ref_full {
+    Some(r) => r.starts_with("refs/pull/") || r.starts_with("refs/merge-requests/"),
+    None => false,
+  }
+}
+
 pub struct Config {
   pub config_dir: PathBuf,
 }
@@ -0,0 +1,131 @@
+use crate::docker::{TaskSpec};
+use crate::query::{Maybe, fail};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskNodeState {
+  Waiting,
+  Queued,
+  Passed,
+  Failed,
+  Skipped,
+}
+
+impl TaskNodeState {
+  pub fn is_finished(&self) -> bool {
+    match self {
+      &TaskNodeState::Passed |
+      &TaskNodeState::Failed |
+      &TaskNodeState::Skipped => true,
+      _ => false,
+    }
+  }
+}
+
+/// What a waiting task should do next, given the states of its dependencies.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Readiness {
+  /// Every dependency passed.
+  Ready,
+  /// Some dependency is not finished yet.
+  Blocked,
+  /// The dependency at this index failed.
+  DepFailed(usize),
+  /// The dependency at this index was skipped, so its results are missing.
+  DepSkipped(usize),
+}
+
+/// Dependencies between the tasks of a run, from `v0.task:depends_on`, as
+/// indices into the task list.
+#[derive(Clone, Debug)]
+pub struct TaskGraph {
+  deps: Vec<Vec<usize>>,
+}
+
+impl TaskGraph {
+  /// Resolves the dependencies of each task by name. Fails on unknown or
+  /// ambiguous names, and on cycles.
+  pub fn new(tasks: &[TaskSpec]) -> Maybe<TaskGraph> {
+    let mut deps = Vec::with_capacity(tasks.len());
+    for task in tasks.iter() {
+      let mut task_deps = Vec::with_capacity(task.depends_on.len());
+      for dep_name in task.depends_on.iter() {
+        let mut matches: Vec<_> = tasks.iter().enumerate()
+          .filter(|&(_, t)| &t.name == dep_name)
+          .map(|(idx, _)| idx)
+          .collect();
+        if matches.len() > 1 {
+          return Err(fail(format!("task {:?} depends on {:?}, but more than one task has that name", task.name, dep_name)));
+        }
+        if matches.is_empty() {
+          // Depending on a matrix task means depending on each of its
+          // expansions whose values agree with those of this task.
+          matches = tasks.iter().enumerate()
+            .filter(|&(_, t)| t.matrix_of.as_ref() == Some(dep_name))
+            .filter(|&(_, t)| t.matrix.iter().all(|&(ref key, ref value)| {
+              task.matrix.iter().all(|&(ref k, ref v)| k != key || v == value)
+            }))
+            .map(|(idx, _)| idx)
+            .collect();
+        }
+        if matches.is_empty() {
+          return Err(fail(format!("task {:?} depends on unknown task {:?}", task.name, dep_name)));
+        }
+        for dep_idx in matches.into_iter() {
+          if !task_deps.contains(&dep_idx) {
+            task_deps.push(dep_idx);
+          }
+        }
+      }
+      deps.push(task_deps);
+    }
+    let graph = TaskGraph{deps};
+    if graph.topo_order().len() != tasks.len() {
+      return Err(fail("task dependencies contain a cycle"));
+    }
+    Ok(graph)
+  }
+
+  pub fn len(&self) -> usize {
+    self.deps.len()
+  }
+
+  pub fn deps(&self, task_idx: usize) -> &[usize] {
+    &self.deps[task_idx]
+  }
+
+  /// Task indices ordered so that every task comes after its dependencies,
+  /// and otherwise in their original order. Tasks on a cycle are left out.
+  pub fn topo_order(&self) -> Vec<usize> {
+    let mut order = Vec::with_capacity(self.deps.len());
+    let mut placed = vec![false; self.deps.len()];
+    loop {
+      let next = (0 .. self.deps.len()).find(|&idx| {
+        !placed[idx] && self.deps[idx].iter().all(|&dep_idx| placed[dep_idx])
+      });
+      match next {
+        None => break,
+        Some(idx) => {
+          placed[idx] = true;
+          order.push(idx);
+        }
+      }
+    }
+    order
+  }
+
+  pub fn readiness(&self, task_idx: usize, states: &[TaskNodeState]) -> Readiness {
+    let mut ready = true;
+    for &dep_idx in self.deps[task_idx].iter() {
+      match states[dep_idx] {
+        TaskNodeState::Failed => return Readiness::DepFailed(dep_idx),
+        TaskNodeState::Skipped => return Readiness::DepSkipped(dep_idx),
+        TaskNodeState::Passed => {}
+        _ => ready = false,
+      }
+    }
+    match ready {
+      false => Readiness::Blocked,
+      true  => Readiness::Ready,
+    }
+  }
+}
@@ -1,26 +1,35 @@
-use crate::query::{Maybe, fail};
+use crate::cache::{BuildCacheMount, CacheAppender, CacheOp};
+use crate::config::{parse_byte_size};
+use crate::dag::{TaskGraph};
+use crate::glob::{glob_match};
+use crate::gpu::{GpuArchReq};
+use crate::query::{Failure, Maybe, fail};
 use crate::state::{ImageSpec, Toolchain, Sysroot};
 
 //use chrono::prelude::*;
 use crossbeam_channel::{Sender, bounded};
-use curl::easy::{Easy as CurlEasy, List as CurlList};
 use schemas::v1::{
   CudaVersionV0,
   DistroIdV0,
   DistroCodenameV0,
   SystemSetupV0,
 };
+use serde::{Serialize, Serializer};
+use serde_json::{Value as JsonValue};
 use tempfile::{NamedTempFile, TempDir, tempdir};
 use url::{Url};
 
+use std::collections::{BTreeMap, VecDeque};
 use std::env::{current_dir};
+use std::fmt;
 use std::fs::{File, create_dir_all};
-use std::io::{BufRead, Read, Write, BufReader, BufWriter, Cursor};
-use std::path::{Path, PathBuf, Component};
-use std::process::{Command, Stdio};
+use std::io::{self, BufRead, Read, Write, BufReader, BufWriter};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Stdio};
 use std::thread;
 use std::str::{from_utf8};
 use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Clone, Debug)]
 pub enum Dir {
@@ -40,6 +49,8 @@ impl Dir {
 #[derive(Clone, Debug)]
 pub struct GitCheckoutSpec {
   pub remote_url: String,
+  pub ref_full: Option<String>,
+  pub commit_hash: Option<String>,
   pub dir: Dir,
 }
 
@@ -49,6 +60,8 @@ impl GitCheckoutSpec {
     Ok(GitCheckoutSpec{
       // TODO
       remote_url: "".to_string(),
+      ref_full: None,
+      commit_hash: None,
       dir: Dir::Path(cwd),
     })
   }
@@ -57,6 +70,8 @@ impl GitCheckoutSpec {
     Ok(GitCheckoutSpec{
       // TODO
       remote_url: "".to_string(),
+      ref_full: None,
+      commit_hash: None,
       dir: Dir::Path(path.into()),
     })
   }
@@ -64,19 +79,155 @@ impl GitCheckoutSpec {
   pub fn with_remote_url(remote_url: String) -> Maybe<GitCheckoutSpec> {
     Ok(GitCheckoutSpec{
       remote_url,
+      ref_full: None,
+      commit_hash: None,
       dir: Dir::Temp(Arc::new(tempdir().map_err(|_| fail("failed to create temp dir"))?)),
     })
   }
+
+  pub fn with_remote_url_in(remote_url: String, dir: &Path) -> Maybe<GitCheckoutSpec> {
+    Ok(GitCheckoutSpec{
+      remote_url,
+      ref_full: None,
+      commit_hash: None,
+      dir: Dir::Path(dir.into()),
+    })
+  }
+
+  /// Checks out a specific revision instead of the default branch: `ref_full`
+  /// is fetched first (e.g. for pull request refs, which are not cloned), then
+  /// `commit_hash` is checked out.
+  pub fn at_revision(self, ref_full: Option<String>, commit_hash: Option<String>) -> Maybe<GitCheckoutSpec> {
+    if let Some(ref r) = ref_full {
+      if !r.starts_with("refs/") || r.contains("..") || r.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(fail(format!("invalid git ref: {:?}", r)));
+      }
+    }
+    if let Some(ref h) = commit_hash {
+      if h.is_empty() || !h.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(fail(format!("invalid git commit hash: {:?}", h)));
+      }
+    }
+    Ok(GitCheckoutSpec{
+      ref_full,
+      commit_hash,
+      ..self
+    })
+  }
+}
+
+/// A build cache of a task, from `v0.task:cache`: a writable directory kept
+/// between runs of the same repository and toolchain.
+#[derive(Clone, PartialEq, Eq, Serialize, Debug)]
+pub struct TaskCache {
+  pub name: String,
+  /// Where the cache is mounted in the task container.
+  pub path: String,
+}
+
+/// Resource limits of a task container; `None` for no limit.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Debug)]
+pub struct TaskLimits {
+  pub cpus: Option<f64>,
+  /// Memory in bytes.
+  pub memory: Option<u64>,
+  /// Number of processes and threads.
+  pub pids: Option<u64>,
+  /// Size of `/dev/shm` in bytes.
+  pub shm: Option<u64>,
+}
+
+impl TaskLimits {
+  /// Sets a limit from `v0.task:limits <KEY>=<VALUE>`.
+  pub fn set(&mut self, key: &str, value: &str) -> Maybe {
+    match key {
+      "cpus" => {
+        let cpus: f64 = value.parse()
+          .map_err(|_| fail(format!("cpus: expected a number, not {:?}", value)))?;
+        if !(cpus > 0.0 && cpus.is_finite()) {
+          return Err(fail(format!("cpus: expected a positive number, not {:?}", value)));
+        }
+        self.cpus = Some(cpus);
+      }
+      "memory" | "shm" => {
+        let size = match parse_byte_size(value) {
+          Some(size) if size > 0 => size,
+          _ => return Err(fail(format!("{}: expected a size such as 512m or 8g, not {:?}", key, value))),
+        };
+        match key {
+          "memory" => self.memory = Some(size),
+          _ => self.shm = Some(size),
+        }
+      }
+      "pids" => {
+        let pids = match value.parse() {
+          Ok(pids) if pids > 0 => pids,
+          _ => return Err(fail(format!("pids: expected a positive integer, not {:?}", value))),
+        };
+        self.pids = Some(pids);
+      }
+      _ => return Err(fail(format!("unknown limit {:?}, expected cpus, memory, pids or shm", key))),
+    }
+    Ok(())
+  }
+
+  /// The limits a task gets under a machine policy: its own limits, or else
+  /// the machine defaults, each capped by the machine maxima. A limit that
+  /// is set by neither is the maximum, if any.
+  pub fn under_policy(&self, defaults: &TaskLimits, maxima: &TaskLimits) -> TaskLimits {
+    fn cap<T: PartialOrd + Copy>(own: Option<T>, default: Option<T>, max: Option<T>) -> Option<T> {
+      match (own.or(default), max) {
+        (Some(x), Some(max)) if x > max => Some(max),
+        (None, max) => max,
+        (x, _) => x,
+      }
+    }
+    TaskLimits{
+      cpus: cap(self.cpus, defaults.cpus, maxima.cpus),
+      memory: cap(self.memory, defaults.memory, maxima.memory),
+      pids: cap(self.pids, defaults.pids, maxima.pids),
+      shm: cap(self.shm, defaults.shm, maxima.shm),
+    }
+  }
+
+  fn add_to(&self, cmd: &mut Command) {
+    if let Some(cpus) = self.cpus {
+      cmd.arg("--cpus").arg(format!("{}", cpus));
+    }
+    if let Some(memory) = self.memory {
+      cmd.arg("--memory").arg(format!("{}", memory));
+    }
+    if let Some(pids) = self.pids {
+      cmd.arg("--pids-limit").arg(format!("{}", pids));
+    }
+    if let Some(shm) = self.shm {
+      cmd.arg("--shm-size").arg(format!("{}", shm));
+    }
+  }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// Paths of the task container that guppybot mounts or fills in itself,
+/// which build caches may not be mounted over or under.
+const RESERVED_TASK_PATHS: &'static [&'static str] = &[
+  "/_python",
+  "/artifacts",
+  "/checkout",
+  "/entry.sh",
+  "/mutable_cache",
+  "/run_task.sh",
+  "/task",
+  "/work",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum Version {
   Exact,
   AtLeast,
   Any,
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 struct TaskSpecBuilder {
   name: String,
   toolchain: Option<Toolchain>,
@@ -84,13 +235,142 @@ struct TaskSpecBuilder {
   require_nvidia_docker: bool,
   require_distro: Option<(Version, DistroCodenameV0)>,
   require_cuda: Option<(Version, Option<CudaVersionV0>)>,
-  require_gpu_arch: Option<()>,
+  require_gpu_arch: Option<GpuArchReq>,
   allow_errors: bool,
+  retries: u32,
+  retry_on: Vec<i32>,
+  only_refs: Vec<String>,
+  only_paths: Vec<String>,
+  depends_on: Vec<String>,
+  matrix: Vec<(String, Vec<String>)>,
+  matrix_exclude: Vec<Vec<(String, String)>>,
+  env: Vec<(String, String)>,
+  caches: Vec<TaskCache>,
+  limits: TaskLimits,
   sh: Vec<String>,
 }
 
 impl TaskSpecBuilder {
+  fn add_cache(&mut self, name: &str, path: &str) -> Maybe {
+    if !is_cache_name(name) {
+      return Err(fail(format!("invalid cache name {:?}", name)));
+    }
+    let mut norm_path = PathBuf::from("/");
+    for (comp_idx, comp) in Path::new(path).components().enumerate() {
+      match (comp_idx, comp) {
+        (0, Component::RootDir) => {}
+        (0, _) => return Err(fail(format!("cache path {:?} is not absolute", path))),
+        (_, Component::Normal(c)) => norm_path.push(c),
+        _ => return Err(fail(format!("invalid cache path {:?}", path))),
+      }
+    }
+    if norm_path == Path::new("/") {
+      return Err(fail("cache path must not be /"));
+    }
+    for reserved in RESERVED_TASK_PATHS.iter() {
+      if norm_path.starts_with(reserved) || Path::new(reserved).starts_with(&norm_path) {
+        return Err(fail(format!("cache path {:?} overlaps {}", path, reserved)));
+      }
+    }
+    let norm_path = norm_path.to_string_lossy().into_owned();
+    for cache in self.caches.iter() {
+      if cache.name == name {
+        return Err(fail(format!("duplicate cache {:?}", name)));
+      }
+      if cache.path == norm_path {
+        return Err(fail(format!("caches {:?} and {:?} have the same path", cache.name, name)));
+      }
+    }
+    self.caches.push(TaskCache{
+      name: name.to_string(),
+      path: norm_path,
+    });
+    Ok(())
+  }
+
+  /// Sets what a matrix value selects, for the keys which select part of the
+  /// image: `cuda`, `distro` and `toolchain`. Other keys only set the task
+  /// environment.
+  fn apply_matrix_value(&mut self, key: &str, value: &str) -> Maybe {
+    match key {
+      "cuda" => {
+        self.require_cuda = Some(parse_require_cuda(value)?);
+      }
+      "distro" => {
+        let distro_toks: Vec<_> = value.splitn(2, ':').collect();
+        if distro_toks.len() != 2 {
+          return Err(fail(format!("v0.task:matrix: distro values are <DISTRO>:<VERSION>, not {:?}", value)));
+        }
+        self.require_distro = Some(parse_require_distro(distro_toks[0], distro_toks[1])?);
+      }
+      "toolchain" => {
+        self.toolchain = Some(Toolchain::from_desc_str_no_builtin(value)
+          .ok_or_else(|| fail(format!("unsupported toolchain {:?}", value)))?);
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  /// Expands the task over its matrix, if it has one: one task per
+  /// combination of values that is not excluded. The combinations are
+  /// counted first, so that a matrix expanding to more than `max_tasks`
+  /// tasks is refused before any is made.
+  fn into_tasks(self, max_tasks: usize) -> Maybe<Vec<TaskSpec>> {
+    let combo_count = self.matrix.iter()
+      .fold(1_usize, |count, &(_, ref values)| count.saturating_mul(values.len()));
+    if combo_count > max_tasks {
+      return Err(fail(format!("task {:?} makes more than the {} tasks allowed in a taskspec", self.name, MAX_EXPANDED_TASKS)));
+    }
+    if self.matrix.is_empty() {
+      if !self.matrix_exclude.is_empty() {
+        return Err(fail("v0.task:matrix_exclude without v0.task:matrix"));
+      }
+      return Ok(vec![self.into_task()?]);
+    }
+    for rule in self.matrix_exclude.iter() {
+      for &(ref key, _) in rule.iter() {
+        if !self.matrix.iter().any(|&(ref k, _)| k == key) {
+          return Err(fail(format!("v0.task:matrix_exclude: unknown matrix key {:?}", key)));
+        }
+      }
+    }
+    let mut combos: Vec<Vec<(String, String)>> = vec![Vec::new()];
+    for &(ref key, ref values) in self.matrix.iter() {
+      combos = combos.into_iter().flat_map(|combo| {
+        values.iter().map(move |value| {
+          let mut combo = combo.clone();
+          combo.push((key.clone(), value.clone()));
+          combo
+        })
+      }).collect();
+    }
+    let mut tasks = Vec::new();
+    for combo in combos.into_iter() {
+      if self.matrix_exclude.iter().any(|rule| rule.iter().all(|kv| combo.contains(kv))) {
+        continue;
+      }
+      let mut builder = self.clone();
+      for &(ref key, ref value) in combo.iter() {
+        builder.apply_matrix_value(key, value)?;
+      }
+      let values: Vec<_> = combo.iter().map(|&(ref k, ref v)| format!("{}={}", k, v)).collect();
+      builder.name = format!("{} [{}]", self.name, values.join(", "));
+      let mut task = builder.into_task()?;
+      task.matrix_of = Some(self.name.clone());
+      task.matrix = combo;
+      tasks.push(task);
+    }
+    if tasks.is_empty() {
+      return Err(fail(format!("v0.task:matrix_exclude excludes every task of {:?}", self.name)));
+    }
+    Ok(tasks)
+  }
+
   fn into_task(self) -> Maybe<TaskSpec> {
+    if self.require_distro.is_none() {
+      return Err(fail(format!("task {:?} is missing v0.task:require_distro", self.name)));
+    }
     Ok(TaskSpec{
       name: self.name,
       toolchain: self.toolchain,
@@ -99,25 +379,129 @@ impl TaskSpecBuilder {
       require_distro: self.require_distro
         .ok_or_else(|| fail("missing require_distro"))?,
       require_cuda: self.require_cuda,
+      require_gpu_arch: self.require_gpu_arch,
       allow_errors: self.allow_errors,
+      retries: self.retries,
+      retry_on: self.retry_on,
+      only_refs: self.only_refs,
+      only_paths: self.only_paths,
+      depends_on: self.depends_on,
+      matrix_of: None,
+      matrix: Vec::new(),
+      env: self.env,
+      caches: self.caches,
+      limits: self.limits,
       sh: self.sh,
     })
   }
 }
 
-#[derive(Clone, Debug)]
+/// Serialized with descriptions instead of the wire protocol types, as in
+/// `TaskSpecReport`.
+#[derive(Clone, Serialize, Debug)]
 pub struct TaskSpec {
   pub name: String,
   pub toolchain: Option<Toolchain>,
   pub require_docker: bool,
   pub require_nvidia_docker: bool,
+  #[serde(serialize_with = "serialize_require_distro")]
   pub require_distro: (Version, DistroCodenameV0),
+  #[serde(serialize_with = "serialize_require_cuda")]
   pub require_cuda: Option<(Version, Option<CudaVersionV0>)>,
+  /// GPU architecture the task needs a device of; `None` for any.
+  pub require_gpu_arch: Option<GpuArchReq>,
   pub allow_errors: bool,
+  /// How many times to re-run the task after it fails.
+  pub retries: u32,
+  /// Exit codes the task is retried on; empty to retry on any failure.
+  pub retry_on: Vec<i32>,
+  /// Glob patterns of the refs the task runs for; empty to run for any ref.
+  pub only_refs: Vec<String>,
+  /// Glob patterns of the paths the task depends on; empty to run for any
+  /// change.
+  pub only_paths: Vec<String>,
+  /// Names of the tasks which must pass before this one runs.
+  pub depends_on: Vec<String>,
+  /// Name of the task this one was expanded from by `v0.task:matrix`.
+  pub matrix_of: Option<String>,
+  /// Matrix values of this task, as `(key, value)` pairs.
+  pub matrix: Vec<(String, String)>,
+  /// Environment variables set by gup.py, as `(name, value)` pairs.
+  pub env: Vec<(String, String)>,
+  /// Build caches mounted into the task container.
+  pub caches: Vec<TaskCache>,
+  /// Resource limits asked for by the task, before the machine policy.
+  pub limits: TaskLimits,
   pub sh: Vec<String>,
 }
 
 impl TaskSpec {
+  /// Returns why the task should be skipped for a run of `ref_full` which
+  /// changed `changed_paths`, if it should be. Either may be unknown, in
+  /// which case the corresponding filter is not applied.
+  pub fn skip_reason(&self, ref_full: Option<&str>, changed_paths: Option<&[String]>) -> Option<String> {
+    if let Some(ref_full) = ref_full {
+      if !self.only_refs.is_empty() && !self.only_refs.iter().any(|p| glob_match(p, ref_full)) {
+        return Some(format!("skipped: {} does not match only_refs", ref_full));
+      }
+    }
+    if let Some(changed_paths) = changed_paths {
+      if !self.only_paths.is_empty() && !changed_paths.iter().any(|path| self.only_paths.iter().any(|p| glob_match(p, path))) {
+        return Some("skipped: no changes match only_paths".to_string());
+      }
+    }
+    None
+  }
+
+  /// Whether to run the task again after attempt number `attempt` (counting
+  /// from 1) failed with `exit_code`, which is `None` if the container was
+  /// killed by a signal.
+  pub fn should_retry(&self, attempt: u32, exit_code: Option<i32>) -> bool {
+    if attempt > self.retries {
+      return false;
+    }
+    match exit_code {
+      Some(0) => false,
+      Some(code) => self.retry_on.is_empty() || self.retry_on.contains(&code),
+      None => self.retry_on.is_empty(),
+    }
+  }
+
+  /// The script run for the task, as mounted at `/task` in the container.
+  pub fn to_script(&self) -> String {
+    let mut buf = String::new();
+    buf.push_str("#!/bin/bash\n");
+    buf.push_str("set -x\n");
+    buf.push_str("set -u\n");
+    if !self.allow_errors {
+      buf.push_str("set -e\n");
+      buf.push_str("set -o pipefail\n");
+    }
+    for sh in self.sh.iter() {
+      buf.push_str(sh);
+      buf.push_str("\n");
+    }
+    buf
+  }
+
+  /// Environment variables holding the matrix values of the task, as
+  /// `GUPPY_MATRIX_<KEY>=<VALUE>`.
+  pub fn matrix_env(&self) -> Vec<String> {
+    self.matrix.iter().map(|&(ref key, ref value)| {
+      format!("GUPPY_MATRIX_{}={}", key.to_ascii_uppercase(), value)
+    }).collect()
+  }
+
+  /// Environment variables of the task container: those set by gup.py,
+  /// followed by those of `matrix_env`.
+  pub fn task_env(&self) -> Vec<String> {
+    let mut env: Vec<_> = self.env.iter().map(|&(ref name, ref value)| {
+      format!("{}={}", name, value)
+    }).collect();
+    env.extend(self.matrix_env());
+    env
+  }
+
   pub fn image_candidate(&self) -> Option<ImageSpec> {
     if !self.require_docker {
       return None;
@@ -152,15 +536,285 @@ impl TaskSpec {
   }
 }
 
+#[derive(Serialize)]
+struct VersionReq {
+  version: Version,
+  value: Option<String>,
+}
+
+fn serialize_require_distro<S: Serializer>(req: &(Version, DistroCodenameV0), serializer: S) -> Result<S::Ok, S::Error> {
+  VersionReq{
+    version: req.0,
+    value: Some(req.1.to_desc_str().to_string()),
+  }.serialize(serializer)
+}
+
+fn serialize_require_cuda<S: Serializer>(req: &Option<(Version, Option<CudaVersionV0>)>, serializer: S) -> Result<S::Ok, S::Error> {
+  req.map(|(version, cuda)| VersionReq{
+    version,
+    value: cuda.map(|cuda| cuda.to_desc_str().to_string()),
+  }).serialize(serializer)
+}
+
+/// Version of the format of `TaskSpecReport`, to be bumped whenever a field
+/// is removed or changes meaning.
+pub const TASKSPEC_REPORT_VERSION: u32 = 1;
+
+/// What a task was asked to do: its spec, with the image it resolved to.
+/// Sent to the registry in `_StartCiTask`, and printed by
+/// `guppyctl tmp-run --list --json`.
+#[derive(Serialize, Debug)]
+pub struct TaskSpecReport<'a> {
+  pub version: u32,
+  pub task: &'a TaskSpec,
+  pub image: Option<&'a ImageSpec>,
+  /// Description of `image`, as from `ImageSpec::to_desc`.
+  pub image_desc: Option<String>,
+  pub image_hash: Option<String>,
+}
+
+impl<'a> TaskSpecReport<'a> {
+  pub fn new(task: &'a TaskSpec, image: Option<&'a ImageSpec>, image_hash: Option<String>) -> TaskSpecReport<'a> {
+    TaskSpecReport{
+      version: TASKSPEC_REPORT_VERSION,
+      task,
+      image,
+      image_desc: image.map(|image| image.to_desc().trim().to_string()),
+      image_hash,
+    }
+  }
+
+  /// The report as sent in `_StartCiTask`.
+  pub fn to_json_bytes(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+}
+
+/// Whether a commit message asks for CI to be skipped, with `[skip ci]` or
+/// `[ci skip]`.
+pub fn commit_skips_ci(message: &str) -> bool {
+  let message = message.to_lowercase();
+  message.contains("[skip ci]") || message.contains("[ci skip]")
+}
+
+/// A line of gup.py output that could not be understood.
+#[derive(Clone, Debug)]
+pub struct DirectiveError {
+  pub line_nr: u64,
+  /// The offending line.
+  pub line: String,
+  pub message: String,
+  /// Expected form of the directive, if the line is a known directive.
+  pub expected: Option<&'static str>,
+}
+
+impl fmt::Display for DirectiveError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}: {} (in {:?})", self.line_nr, self.message, self.line)?;
+    if let Some(expected) = self.expected {
+      write!(f, ", expected: {}", expected)?;
+    }
+    Ok(())
+  }
+}
+
+/// Failure to get taskspecs out of a gup.py script.
+#[derive(Debug)]
+pub struct SpecError {
+  pub message: String,
+  /// Line of the gup.py output that could not be understood, if any.
+  pub line_nr: Option<u64>,
+  /// Every line of the output that could not be understood.
+  pub errors: Vec<DirectiveError>,
+}
+
+impl SpecError {
+  pub fn from_errors(errors: Vec<DirectiveError>) -> SpecError {
+    let messages: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+    SpecError{
+      message: messages.join("\n"),
+      line_nr: errors.first().map(|e| e.line_nr),
+      errors,
+    }
+  }
+}
+
+impl From<Failure> for SpecError {
+  fn from(e: Failure) -> SpecError {
+    SpecError{
+      message: e.excuses.join(": "),
+      line_nr: None,
+      errors: Vec::new(),
+    }
+  }
+}
+
+impl From<SpecError> for Failure {
+  fn from(e: SpecError) -> Failure {
+    if e.errors.is_empty() {
+      return match e.line_nr {
+        None => fail(e.message),
+        Some(line_nr) => fail(e.message).push(format!("at line {} of gup.py output", line_nr)),
+      };
+    }
+    let mut failure = fail(format!("{} errors in gup.py output", e.errors.len()));
+    for error in e.errors.iter() {
+      failure = failure.push(error.to_string());
+    }
+    failure
+  }
+}
+
 pub enum DockerOutput {
   Stdout,
   Buffer{buf_sz: usize, consumer: Box<Fn(u64, Vec<u8>) + Send>},
+  /// Keeps the last `max_lines` lines of output, also printing every line
+  /// if `echo` is set, and hands them to the consumer once the task exits.
+  Tail{echo: bool, max_lines: usize, consumer: Box<Fn(Vec<String>) + Send>},
 }
 
 #[derive(Debug)]
 pub enum DockerRunStatus {
   Success,
-  Failure,
+  Failure{
+    /// Exit code of the task, if it ran and was not killed by a signal.
+    exit_code: Option<i32>,
+  },
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct DockerRunOpts {
+  /// Name given to the task container, so that it can be found and stopped
+  /// from outside of `docker run`.
+  pub container_name: Option<String>,
+  /// Leaves the container in place after it exits, instead of removing it,
+  /// so that its state can be looked at; see `remove_container`.
+  pub keep_container: bool,
+  /// Directory mounted at `/artifacts/out`, for the task to leave files in
+  /// for the tasks that depend on it.
+  pub artifacts_dir: Option<PathBuf>,
+  /// Artifact directories of the tasks this one depends on, by task name,
+  /// each mounted read-only at `/artifacts/deps/<NAME>`.
+  pub dep_artifacts: Vec<(String, PathBuf)>,
+  /// Indices of the GPUs the task may use; see `task_gpus`. Tasks are
+  /// always given a list, so that they only see the GPUs of guppybot, while
+  /// `None` is for the builtin containers, which do not use the nvidia
+  /// runtime.
+  pub gpu_devices: Option<Vec<u32>>,
+  /// Build caches of the task, as prepared by `prepare_build_caches`.
+  pub build_caches: Vec<BuildCacheMount>,
+  /// Resource limits of the task container, under the machine policy.
+  pub limits: TaskLimits,
+}
+
+impl DockerRunOpts {
+  fn add_to(&self, cmd: &mut Command) {
+    if let Some(ref container_name) = self.container_name {
+      cmd.arg("--name").arg(container_name);
+    }
+    if !self.keep_container {
+      cmd.arg("--rm");
+    }
+    if let Some(ref artifacts_dir) = self.artifacts_dir {
+      cmd.arg("--volume").arg(format!("{}:/artifacts/out:rw", artifacts_dir.display()));
+    }
+    for &(ref name, ref dir) in self.dep_artifacts.iter() {
+      cmd.arg("--volume").arg(format!("{}:/artifacts/deps/{}:ro", dir.display(), artifact_dir_name(name)));
+    }
+    for cache in self.build_caches.iter() {
+      let mode = match cache.read_only {
+        false => "rw",
+        true  => "ro",
+      };
+      cmd.arg("--volume").arg(format!("{}:{}:{}", cache.dir.display(), cache.path, mode));
+    }
+    self.limits.add_to(cmd);
+  }
+
+  /// Adds the environment variables of the options, which go after those of
+  /// the task so that they always win.
+  fn add_env_to(&self, cmd: &mut Command) {
+    if let Some(ref gpu_devices) = self.gpu_devices {
+      let gpu_devices: Vec<_> = gpu_devices.iter().map(|idx| format!("{}", idx)).collect();
+      let visible = match gpu_devices.is_empty() {
+        false => gpu_devices.join(","),
+        true  => "none".to_string(),
+      };
+      cmd.arg("--env").arg(format!("NVIDIA_VISIBLE_DEVICES={}", visible));
+    }
+  }
+}
+
+/// How the container of a task is attached to.
+#[derive(Clone, Copy)]
+enum RunMode<'a> {
+  /// With its console piped out, for `run`.
+  Attached,
+  /// With a terminal, for `shell`; in a snapshot of a stopped task
+  /// container, if given.
+  Shell{snapshot: Option<&'a str>},
+}
+
+/// Directory name for the artifacts of a task, from its name.
+pub fn artifact_dir_name(task_name: &str) -> String {
+  task_name.chars()
+    .map(|c| match c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' {
+      false => '_',
+      true  => c,
+    })
+    .collect()
+}
+
+/// Most bytes of output, on stdout and on stderr each, read from gup.py by
+/// `_run_spec`.
+pub const MAX_SPEC_OUTPUT: u64 = 4 * 1024 * 1024;
+
+/// Most tasks a taskspec may have, counting each combination of a matrix.
+pub const MAX_EXPANDED_TASKS: usize = 256;
+
+/// Most keys in the matrix of a task.
+pub const MAX_MATRIX_KEYS: usize = 8;
+
+/// Most values of a key in the matrix of a task.
+pub const MAX_MATRIX_VALUES: usize = 64;
+
+/// Numbers the containers of `_run_spec` within the process, for their
+/// names.
+static SPEC_CONTAINER_NR: AtomicUsize = AtomicUsize::new(0);
+
+/// Name of the container running task `task_nr` of a CI run.
+pub fn task_container_name(ci_run_key: &[u8], task_nr: u64) -> String {
+  let key_len = ci_run_key.len().min(8);
+  format!("gup-{}-{}", hex::encode(&ci_run_key[ .. key_len]), task_nr)
+}
+
+pub fn kill_container(container_name: &str) -> Maybe {
+  let status = Command::new("docker")
+    .arg("kill")
+    .arg(container_name)
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .status()
+    .map_err(|_| fail("failed to run `docker kill`"))?;
+  match status.success() {
+    false => Err(fail(format!("`docker kill` failed for container {}", container_name))),
+    true  => Ok(()),
+  }
+}
+
+pub fn remove_container(container_name: &str) -> Maybe {
+  let status = Command::new("docker")
+    .arg("rm")
+    .arg("--force")
+    .arg(container_name)
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .status()
+    .map_err(|_| fail("failed to run `docker rm`"))?;
+  match status.success() {
+    false => Err(fail(format!("`docker rm` failed for container {}", container_name))),
+    true  => Ok(()),
+  }
 }
 
 pub struct DockerImage {
@@ -171,32 +825,38 @@ pub struct DockerImage {
 }
 
 impl DockerImage {
-  pub fn _build(&self, fresh: bool, sysroot: &Sysroot) -> Maybe {
-    let toolchain_image_dir = self.imagespec.to_toolchain_image_dir(sysroot);
+  /// The Dockerfile that `_build` builds the image from: the `FROM` line of
+  /// the base image, followed by the toolchain template for the distro (or
+  /// the default one).
+  pub fn render_dockerfile(&self, sysroot: &Sysroot) -> Maybe<String> {
     let toolchain_template_dir = self.imagespec.to_toolchain_docker_template_dir(sysroot);
     let distro_toolchain_template_dir = toolchain_template_dir.join(self.imagespec.distro_codename.to_desc_str());
+    let src_file = File::open(distro_toolchain_template_dir.join("Dockerfile.template"))
+      .or_else(|_| File::open(toolchain_template_dir.join("Dockerfile.default_template")))
+      .map_err(|_| fail("failed to open Dockerfile template"))?;
+    let mut reader = BufReader::new(src_file);
+    let mut src_buf = String::new();
+    reader.read_to_string(&mut src_buf)
+      .map_err(|_| fail("failed to read Dockerfile template"))?;
+    let base_docker_image = self.imagespec.to_docker_base_image()
+      .ok_or_else(|| fail("no docker base image candidate"))?;
+    let mut buf = String::new();
+    buf.push_str(&format!("# automatically generated for: gup/{}\n", self.hash_digest));
+    buf.push_str("\n");
+    buf.push_str(&format!("FROM {}\n", base_docker_image));
+    buf.push_str("\n");
+    buf.push_str(&src_buf);
+    Ok(buf)
+  }
+
+  pub fn _build(&self, fresh: bool, sysroot: &Sysroot) -> Maybe {
+    let toolchain_image_dir = self.imagespec.to_toolchain_image_dir(sysroot);
     {
-      let src_file = File::open(distro_toolchain_template_dir.join("Dockerfile.template"))
-        .or_else(|_| File::open(toolchain_template_dir.join("Dockerfile.default_template")))
-        .map_err(|_| fail("failed to open Dockerfile template"))?;
-      let mut reader = BufReader::new(src_file);
-      let mut src_buf = String::new();
-      reader.read_to_string(&mut src_buf)
-        .map_err(|_| fail("failed to read Dockerfile template"))?;
+      let dockerfile = self.render_dockerfile(sysroot)?;
       create_dir_all(toolchain_image_dir.join(&self.hash_digest)).ok();
       let dst_file = File::create(toolchain_image_dir.join(&self.hash_digest).join("Dockerfile")).unwrap();
       let mut writer = BufWriter::new(dst_file);
-      writeln!(&mut writer, "# automatically generated for: gup/{}", self.hash_digest)
-        .map_err(|_| fail("failed to write Dockerfile"))?;
-      writeln!(&mut writer, "")
-        .map_err(|_| fail("failed to write Dockerfile"))?;
-      let base_docker_image = self.imagespec.to_docker_base_image()
-        .ok_or_else(|| fail("no docker base image candidate"))?;
-      writeln!(&mut writer, "FROM {}", base_docker_image)
-        .map_err(|_| fail("failed to write Dockerfile"))?;
-      writeln!(&mut writer, "")
-        .map_err(|_| fail("failed to write Dockerfile"))?;
-      writer.write_all(src_buf.as_bytes())
+      writer.write_all(dockerfile.as_bytes())
         .map_err(|_| fail("failed to write Dockerfile"))?;
     }
     let mut cmd = Command::new("docker");
@@ -257,6 +917,14 @@ impl DockerImage {
       .arg("--volume").arg(format!("{}:/checkout:rw", checkout.dir.path().display()))
       .arg("--volume").arg(format!("{}:/entry.sh:ro", toolchain_dir.join("_run_checkout.sh").display()))
       .arg("--env").arg(format!("GUPPY_GIT_REMOTE_URL={}", remote_url.as_str()))
+    ;
+    if let Some(ref ref_full) = checkout.ref_full {
+      cmd.arg("--env").arg(format!("GUPPY_GIT_REF={}", ref_full));
+    }
+    if let Some(ref commit_hash) = checkout.commit_hash {
+      cmd.arg("--env").arg(format!("GUPPY_GIT_COMMIT={}", commit_hash));
+    }
+    cmd
       .arg("--env").arg("CI=1")
       .arg(format!("gup/{}", self.hash_digest))
       .arg("/entry.sh")
@@ -281,12 +949,118 @@ impl DockerImage {
     }
   }
 
+  /// Lists the refs of a remote matching `patterns`, as `(ref, commit)`
+  /// pairs. Annotated tags are listed with the commit they point to.
+  pub fn _run_ls_remote(&self, remote_url: &str, patterns: &[String], sysroot: &Sysroot) -> Maybe<Vec<(String, String)>> {
+    let remote_url = Url::parse(remote_url)
+      .map_err(|_| fail("invalid remote URL"))?;
+    if patterns.iter().any(|p| p.is_empty() || p.starts_with('-')) {
+      return Err(fail("ls-remote: invalid ref pattern"));
+    }
+    let toolchain_dir = self.imagespec.to_toolchain_docker_template_dir(sysroot);
+    let mut cmd = Command::new("docker");
+    cmd
+      .arg("run")
+      .arg("--runtime").arg("runc")
+      .arg("--rm")
+      .arg("--log-driver").arg("none")
+      .arg("--attach").arg("stdout")
+      .arg("--attach").arg("stderr")
+      .arg("--volume").arg(format!("{}:/entry.sh:ro", toolchain_dir.join("_run_ls_remote.sh").display()))
+      .arg("--env").arg(format!("GUPPY_GIT_REMOTE_URL={}", remote_url.as_str()))
+      .arg("--env").arg("CI=1")
+      .arg(format!("gup/{}", self.hash_digest))
+      .arg("/entry.sh")
+      .args(patterns)
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+    ;
+    let out = cmd.output()
+      .map_err(|_| fail("ls-remote: failed to run `docker run`"))?;
+    if !out.status.success() {
+      return Err(fail(format!("ls-remote: `docker run` exited with nonzero status: {}",
+          String::from_utf8_lossy(&out.stderr).trim())));
+    }
+    let mut refs: Vec<(String, String)> = Vec::new();
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+      let mut toks = line.split('\t');
+      let (commit_hash, ref_full) = match (toks.next(), toks.next()) {
+        (Some(h), Some(r)) => (h.trim().to_string(), r.trim().to_string()),
+        _ => continue,
+      };
+      if ref_full.ends_with("^{}") {
+        let tag = &ref_full[ .. ref_full.len() - 3];
+        for &mut (ref r, ref mut h) in refs.iter_mut() {
+          if r == tag {
+            *h = commit_hash.clone();
+          }
+        }
+        continue;
+      }
+      refs.push((ref_full, commit_hash));
+    }
+    Ok(refs)
+  }
+
+  /// Gets the commit message of a checkout and the paths it changed, relative
+  /// to `base_hash` if that commit is in the checkout, and otherwise relative
+  /// to the merge base with the default branch or, failing that, the parent
+  /// commit.
+  pub fn _run_changes(&self, checkout: &GitCheckoutSpec, base_hash: Option<&str>, sysroot: &Sysroot) -> Maybe<(String, Vec<String>)> {
+    if let Some(h) = base_hash {
+      if h.is_empty() || !h.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(fail(format!("invalid git commit hash: {:?}", h)));
+      }
+    }
+    let toolchain_dir = self.imagespec.to_toolchain_docker_template_dir(sysroot);
+    let mut cmd = Command::new("docker");
+    cmd
+      .arg("run")
+      .arg("--runtime").arg("runc")
+      .arg("--rm")
+      .arg("--log-driver").arg("none")
+      .arg("--attach").arg("stdout")
+      .arg("--attach").arg("stderr")
+      .arg("--volume").arg(format!("{}:/checkout:ro", checkout.dir.path().display()))
+      .arg("--volume").arg(format!("{}:/entry.sh:ro", toolchain_dir.join("_run_changes.sh").display()))
+    ;
+    if let Some(h) = base_hash {
+      cmd.arg("--env").arg(format!("GUPPY_GIT_BASE={}", h));
+    }
+    cmd
+      .arg("--env").arg("CI=1")
+      .arg(format!("gup/{}", self.hash_digest))
+      .arg("/entry.sh")
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+    ;
+    let out = cmd.output()
+      .map_err(|_| fail("changes: failed to run `docker run`"))?;
+    if !out.status.success() {
+      return Err(fail(format!("changes: `docker run` exited with nonzero status: {}",
+          String::from_utf8_lossy(&out.stderr).trim())));
+    }
+    // The commit message, then the changed paths, each terminated by NUL.
+    let mut fields = out.stdout.split(|&b| b == 0)
+      .map(|field| String::from_utf8_lossy(field).into_owned());
+    let message = fields.next().unwrap_or_default();
+    let paths = fields.filter(|path| !path.is_empty()).collect();
+    Ok((message, paths))
+  }
+
   pub fn _run_checkout_ssh(&self, checkout: &GitCheckoutSpec, key_path: String, sysroot: &Sysroot) -> Maybe {
     unimplemented!();
   }
 
-  pub fn _run_spec(&self, checkout: &GitCheckoutSpec, sysroot: &Sysroot) -> Maybe<(Vec<u8>, Vec<TaskSpec>)> {
+  /// Runs the gup.py script of a checkout to get its taskspecs, in a
+  /// container under `limits`. The console output of the script is returned
+  /// even when it fails, for reporting; output past `MAX_SPEC_OUTPUT` bytes
+  /// fails the script.
+  pub fn _run_spec(&self, checkout: &GitCheckoutSpec, sysroot: &Sysroot, limits: &TaskLimits) -> (Vec<u8>, Result<Vec<TaskSpec>, SpecError>) {
     let toolchain_dir = self.imagespec.to_toolchain_docker_template_dir(sysroot);
+    let container_name = format!("gup-spec-{}-{}", process::id(), SPEC_CONTAINER_NR.fetch_add(1, Ordering::SeqCst));
     let mut cmd = Command::new("docker");
     cmd
       .arg("run")
@@ -297,6 +1071,7 @@ impl DockerImage {
       cmd.arg("--runtime").arg("runc");
     }
     cmd
+      .arg("--name").arg(&container_name)
       .arg("--rm")
       .arg("--interactive")
       .arg("--log-driver").arg("none")
@@ -304,46 +1079,74 @@ impl DockerImage {
       .arg("--attach").arg("stdin")
       .arg("--attach").arg("stdout")
       .arg("--attach").arg("stderr")
+    ;
+    limits.add_to(&mut cmd);
+    cmd
       .arg("--volume").arg(format!("{}:/_python:ro", sysroot.base_dir.join("python3.6/site-packages").display()))
       .arg("--volume").arg(format!("{}:/checkout:ro", checkout.dir.path().display()))
       .arg("--volume").arg(format!("{}:/entry.sh:ro", toolchain_dir.join("_run_taskspec.sh").display()))
       .arg("--env").arg("PYTHONPATH=/_python")
+      .arg("--env").arg(format!("GUPPY_TASKSPEC_PROTOCOL={}", TASKSPEC_PROTOCOLS))
       .arg("--env").arg("CI=1")
       .arg(format!("gup/{}", self.hash_digest))
       .arg("/entry.sh")
       .stdout(Stdio::piped())
       .stderr(Stdio::piped())
     ;
-    let mut proc = cmd.spawn()
-      .map_err(|_| fail("taskspec: failed to run `docker run`"))?;
-    let (out, tasks) = if let Some(ref mut stdout) = proc.stdout {
-      match _taskspecs(stdout, sysroot) {
-        Err(e) => {
-          proc.wait().ok();
-          return Err(e);
-        }
-        Ok((out, tasks)) => (out, tasks),
-      }
-    } else {
-      (Vec::new(), Vec::new())
+    let mut proc = match cmd.spawn() {
+      Err(_) => return (Vec::new(), Err(fail("taskspec: failed to run `docker run`").into())),
+      Ok(proc) => proc,
     };
-    if let Some(ref mut stderr) = proc.stderr {
-      let mut buf = String::new();
-      stderr.read_to_string(&mut buf).unwrap();
-      if !(buf.is_empty() || buf == "\n") {
+    // Stderr is read alongside stdout, so that neither pipe fills up and
+    // stalls gup.py; what is past the limit is read and dropped.
+    let err_reader = proc.stderr.take().map(|mut stderr| thread::spawn(move || {
+      let mut err_out = Vec::new();
+      (&mut stderr).take(MAX_SPEC_OUTPUT).read_to_end(&mut err_out).ok();
+      io::copy(&mut stderr, &mut io::sink()).ok();
+      err_out
+    }));
+    let mut out = Vec::with_capacity(4096);
+    if let Some(stdout) = proc.stdout.take() {
+      let res = stdout.take(MAX_SPEC_OUTPUT + 1).read_to_end(&mut out);
+      let too_long = out.len() as u64 > MAX_SPEC_OUTPUT;
+      if res.is_err() || too_long {
+        kill_container(&container_name).ok();
         proc.wait().ok();
-        return Err(fail("taskspec: `docker run` returned nonempty stderr"));
+        out.truncate(MAX_SPEC_OUTPUT as usize);
+        let message = match too_long {
+          false => "taskspec: failed to read gup.py output".to_string(),
+          true  => format!("taskspec: gup.py wrote more than {} bytes of output", MAX_SPEC_OUTPUT),
+        };
+        return (out, Err(fail(message).into()));
       }
     }
-    let status = proc.wait()
-      .map_err(|_| fail("taskspec: failed to wait for `docker run`"))?;
-    match status.success() {
-      false => Err(fail("taskspec: `docker run` exited with nonzero status")),
-      true  => Ok((out, tasks))
+    let err_out = err_reader
+      .and_then(|err_reader| err_reader.join().ok())
+      .unwrap_or_default();
+    let status = proc.wait();
+    // Errors raised by gup.py itself (including python syntax errors) end up
+    // on stderr, so keep them next to the regular output.
+    let nonempty_stderr = !(err_out.is_empty() || err_out == b"\n");
+    if nonempty_stderr {
+      out.extend_from_slice(&err_out);
+    }
+    let status = match status {
+      Err(_) => return (out, Err(fail("taskspec: failed to wait for `docker run`").into())),
+      Ok(status) => status,
+    };
+    if nonempty_stderr {
+      return (out, Err(fail("taskspec: gup.py wrote to stderr").into()));
+    }
+    if !status.success() {
+      return (out, Err(fail("taskspec: gup.py exited with nonzero status").into()));
     }
+    let tasks = _taskspecs(&out, Some(checkout.dir.path()), sysroot, false);
+    (out, tasks)
   }
 
-  pub fn _run_taskspec_direct(&self, gup_py_path: &PathBuf, sysroot: &Sysroot) -> Maybe<Vec<TaskSpec>> {
+  /// Runs a gup.py script outside of any checkout to get its taskspecs. On a
+  /// `dry_run`, the mutable cache is only checked and not filled in.
+  pub fn _run_taskspec_direct(&self, gup_py_path: &PathBuf, sysroot: &Sysroot, dry_run: bool) -> Maybe<Vec<TaskSpec>> {
     let toolchain_dir = self.imagespec.to_toolchain_docker_template_dir(sysroot);
     let mut cmd = Command::new("docker");
     cmd
@@ -366,6 +1169,7 @@ impl DockerImage {
       .arg("--volume").arg(format!("{}:/gup.py:ro", gup_py_path.display()))
       .arg("--volume").arg(format!("{}:/entry.sh:ro", toolchain_dir.join("_run_taskspec_direct.sh").display()))
       .arg("--env").arg("PYTHONPATH=/_python")
+      .arg("--env").arg(format!("GUPPY_TASKSPEC_PROTOCOL={}", TASKSPEC_PROTOCOLS))
       .arg("--env").arg("CI=1")
       .arg(format!("gup/{}", self.hash_digest))
       .arg("/entry.sh")
@@ -375,12 +1179,15 @@ impl DockerImage {
     let mut proc = cmd.spawn()
       .map_err(|_| fail("failed to run `docker run`"))?;
     let tasks = if let Some(ref mut stdout) = proc.stdout {
-      match _taskspecs(stdout, sysroot) {
+      let mut out = Vec::with_capacity(4096);
+      stdout.read_to_end(&mut out)
+        .map_err(|_| fail("failed to read gup.py output"))?;
+      match _taskspecs(&out, gup_py_path.parent(), sysroot, dry_run) {
         Err(e) => {
           proc.wait().ok();
-          return Err(e);
+          return Err(e.into());
         }
-        Ok((_, tasks)) => tasks,
+        Ok(tasks) => tasks,
       }
     } else {
       Vec::new()
@@ -401,33 +1208,28 @@ impl DockerImage {
     Ok(tasks)
   }
 
-  pub fn run(&self, checkout: &GitCheckoutSpec, task: &TaskSpec, sysroot: &Sysroot, output: Option<DockerOutput>) -> Maybe<DockerRunStatus> {
+  /// Writes the task script mounted at `/task` to a temporary file.
+  fn _write_task_file(&self, task: &TaskSpec) -> Maybe<NamedTempFile> {
+    let mut task_file = NamedTempFile::new()
+      .map_err(|_| fail("failed to create temporary script file"))?;
+    task_file.write_all(task.to_script().as_bytes())
+      .map_err(|_| fail("failed to write to script file"))?;
+    task_file.flush()
+      .map_err(|_| fail("failed to write to script file"))?;
+    Ok(task_file)
+  }
+
+  /// The `docker run` command of `run` (or `run_mut`, if `mutable`), with
+  /// the task script at `task_path`, or of `shell`.
+  fn _run_command(&self, checkout: &GitCheckoutSpec, task: &TaskSpec, task_path: &Path, sysroot: &Sysroot, opts: &DockerRunOpts, mutable: bool, mode: RunMode) -> Command {
     let toolchain_dir = self.imagespec.to_toolchain_docker_template_dir(sysroot);
     // FIXME
     //let distro_toolchain_dir = toolchain_dir.join(self.imagespec.distro_codename.to_desc_str());
     //eprintln!("TRACE: docker image: toolchain dir: {}", toolchain_dir.display());
-    let mut task_file = NamedTempFile::new()
-      .map_err(|_| fail("failed to create temporary script file"))?;
-    {
-      writeln!(task_file, "#!/bin/bash")
-        .map_err(|_| fail("failed to write to script file"))?;
-      writeln!(task_file, "set -x")
-        .map_err(|_| fail("failed to write to script file"))?;
-      writeln!(task_file, "set -u")
-        .map_err(|_| fail("failed to write to script file"))?;
-      if !task.allow_errors {
-        writeln!(task_file, "set -e")
-          .map_err(|_| fail("failed to write to script file"))?;
-        writeln!(task_file, "set -o pipefail")
-          .map_err(|_| fail("failed to write to script file"))?;
-      }
-      for sh in task.sh.iter() {
-        writeln!(task_file, "{}", sh)
-          .map_err(|_| fail("failed to write to script file"))?;
-      }
-      task_file.flush()
-        .map_err(|_| fail("failed to write to script file"))?;
-    }
+    let (checkout_mode, entry_sh) = match mutable {
+      false => ("ro", "run.sh"),
+      true  => ("rw", "run_mut.sh"),
+    };
     let mut cmd = Command::new("docker");
     cmd
       .arg("run")
@@ -437,21 +1239,65 @@ impl DockerImage {
     } else {
       cmd.arg("--runtime").arg("runc");
     }
+    opts.add_to(&mut cmd);
+    match mode {
+      RunMode::Attached => {
+        cmd
+          .arg("--interactive")
+          .arg("--log-driver").arg("none")
+          .arg("--attach").arg("stdin")
+          .arg("--attach").arg("stdout")
+          .arg("--attach").arg("stderr")
+        ;
+      }
+      RunMode::Shell{..} => {
+        cmd
+          .arg("--interactive")
+          .arg("--tty")
+        ;
+      }
+    }
     cmd
-      .arg("--rm")
-      .arg("--interactive")
-      .arg("--log-driver").arg("none")
-      //.arg("--tty")
-      .arg("--attach").arg("stdin")
-      .arg("--attach").arg("stdout")
-      .arg("--attach").arg("stderr")
       .arg("--volume").arg(format!("{}:/mutable_cache:ro", sysroot.base_dir.join("mutable_cache").display()))
-      .arg("--volume").arg(format!("{}:/checkout:ro", checkout.dir.path().display()))
-      .arg("--volume").arg(format!("{}:/task:ro", task_file.path().display()))
-      .arg("--volume").arg(format!("{}:/entry.sh:ro", toolchain_dir.join("run.sh").display()))
-      .arg("--env").arg("CI=1")
-      .arg(format!("gup/{}", self.hash_digest))
-      .arg("/entry.sh")
+      .arg("--volume").arg(format!("{}:/checkout:{}", checkout.dir.path().display(), checkout_mode))
+      .arg("--volume").arg(format!("{}:/task:ro", task_path.display()))
+      .arg("--volume").arg(format!("{}:/entry.sh:ro", toolchain_dir.join(entry_sh).display()))
+    ;
+    for env in task.task_env().iter() {
+      cmd.arg("--env").arg(env);
+    }
+    cmd.arg("--env").arg("CI=1");
+    opts.add_env_to(&mut cmd);
+    match mode {
+      RunMode::Shell{snapshot: Some(snapshot)} => {
+        // The snapshot is past the entrypoint, so skip straight to the shell.
+        cmd
+          .arg("--workdir").arg("/work")
+          .arg(snapshot)
+          .arg("/bin/bash").arg("-i")
+        ;
+      }
+      _ => {
+        cmd
+          .arg(format!("gup/{}", self.hash_digest))
+          .arg("/entry.sh")
+        ;
+      }
+    }
+    cmd
+  }
+
+  /// The `docker run` command line that `run` (or `run_mut`, if `mutable`)
+  /// would use, with `<TASK_SCRIPT>` in place of the task script path.
+  pub fn explain_run(&self, checkout: &GitCheckoutSpec, task: &TaskSpec, sysroot: &Sysroot, opts: &DockerRunOpts, mutable: bool) -> String {
+    let cmd = self._run_command(checkout, task, Path::new("<TASK_SCRIPT>"), sysroot, opts, mutable, RunMode::Attached);
+    format!("{:?}", cmd)
+  }
+
+  fn _run(&self, checkout: &GitCheckoutSpec, task: &TaskSpec, sysroot: &Sysroot, opts: &DockerRunOpts, mutable: bool, output: Option<DockerOutput>) -> Maybe<DockerRunStatus> {
+    let task_file = self._write_task_file(task)?;
+    let mut cmd = self._run_command(checkout, task, task_file.path(), sysroot, opts, mutable, RunMode::Attached);
+    cmd
       .stdout(Stdio::piped())
       .stderr(Stdio::piped())
     ;
@@ -468,430 +1314,802 @@ impl DockerImage {
       Some(DockerOutput::Buffer{buf_sz, consumer}) => {
         ConsoleMonitor::serialize_to_buffer(proc.stdout.take().unwrap(), proc.stderr.take().unwrap(), buf_sz, consumer)
       }
+      Some(DockerOutput::Tail{echo, max_lines, consumer}) => {
+        ConsoleMonitor::serialize_to_tail(proc.stdout.take().unwrap(), proc.stderr.take().unwrap(), echo, max_lines, consumer)
+      }
     };
     let maybe_status = proc.wait();
     mon_h.join().ok();
     //println!("### END MONITOR ###");
     let status = maybe_status
       .map_err(|_| fail("failed to wait for `docker run`"))?;
-    match status.success() {
-      false => Ok(DockerRunStatus::Failure),
-      true  => Ok(DockerRunStatus::Success),
+    match status.code() {
+      Some(0) => Ok(DockerRunStatus::Success),
+      exit_code => Ok(DockerRunStatus::Failure{exit_code}),
     }
   }
 
-  pub fn run_mut(&self, checkout: &GitCheckoutSpec, task: &TaskSpec, sysroot: &Sysroot, output: Option<DockerOutput>) -> Maybe<DockerRunStatus> {
-    let toolchain_dir = self.imagespec.to_toolchain_docker_template_dir(sysroot);
-    // FIXME
-    //let distro_toolchain_dir = toolchain_dir.join(self.imagespec.distro_codename.to_desc_str());
-    //eprintln!("TRACE: docker image: toolchain dir: {}", toolchain_dir.display());
+  pub fn run(&self, checkout: &GitCheckoutSpec, task: &TaskSpec, sysroot: &Sysroot, opts: &DockerRunOpts, output: Option<DockerOutput>) -> Maybe<DockerRunStatus> {
+    self._run(checkout, task, sysroot, opts, false, output)
+  }
+
+  pub fn run_mut(&self, checkout: &GitCheckoutSpec, task: &TaskSpec, sysroot: &Sysroot, opts: &DockerRunOpts, output: Option<DockerOutput>) -> Maybe<DockerRunStatus> {
+    self._run(checkout, task, sysroot, opts, true, output)
+  }
+
+  /// Starts an interactive shell with the mounts and environment that `run`
+  /// (or `run_mut`, if `mutable`) would give the task, in place of the task
+  /// script, and waits for the shell to exit.
+  /// Given the name of a stopped task container, the shell instead starts in
+  /// a snapshot of that container, with whatever the task left behind.
+  pub fn shell(&self, checkout: &GitCheckoutSpec, task: &TaskSpec, sysroot: &Sysroot, opts: &DockerRunOpts, mutable: bool, from_container: Option<&str>) -> Maybe {
     let mut task_file = NamedTempFile::new()
       .map_err(|_| fail("failed to create temporary script file"))?;
     {
       writeln!(task_file, "#!/bin/bash")
         .map_err(|_| fail("failed to write to script file"))?;
-      writeln!(task_file, "set -x")
+      writeln!(task_file, "exec /bin/bash -i")
         .map_err(|_| fail("failed to write to script file"))?;
-      writeln!(task_file, "set -u")
+      task_file.flush()
         .map_err(|_| fail("failed to write to script file"))?;
-      if !task.allow_errors {
-        writeln!(task_file, "set -e")
-          .map_err(|_| fail("failed to write to script file"))?;
-        writeln!(task_file, "set -o pipefail")
-          .map_err(|_| fail("failed to write to script file"))?;
+    }
+    let snapshot = match from_container {
+      None => None,
+      Some(container_name) => {
+        let snapshot = format!("gup-shell/{}", container_name);
+        let status = Command::new("docker")
+          .arg("commit")
+          .arg(container_name)
+          .arg(&snapshot)
+          .stdout(Stdio::null())
+          .status()
+          .map_err(|_| fail("failed to run `docker commit`"))?;
+        if !status.success() {
+          return Err(fail(format!("`docker commit` failed for container {}", container_name)));
+        }
+        Some(snapshot)
       }
-      for sh in task.sh.iter() {
-        writeln!(task_file, "{}", sh)
-          .map_err(|_| fail("failed to write to script file"))?;
+    };
+    let mut cmd = self._run_command(checkout, task, task_file.path(), sysroot, opts, mutable, RunMode::Shell{
+      snapshot: snapshot.as_ref().map(|s| s.as_str()),
+    });
+    let status = cmd.status()
+      .map_err(|_| fail("failed to run `docker run`"));
+    if let Some(ref snapshot) = snapshot {
+      Command::new("docker")
+        .arg("rmi")
+        .arg(snapshot)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .ok();
+    }
+    status?;
+    Ok(())
+  }
+}
+
+pub struct DockerPreImage {
+}
+
+/// Expected forms of the v0 directives, by directive.
+const V0_DIRECTIVE_FORMS: &'static [(&'static str, &'static str)] = &[
+  ("v0.mutable_cache:append", "#-guppy:v0.mutable_cache:append <PATH> fetch_once|copy_once|symlink_once <SOURCE> [sha256=<HEX>]"),
+  ("v0.task:begin", "#-guppy:v0.task:begin"),
+  ("v0.task:end", "#-guppy:v0.task:end"),
+  ("v0.task:name", "#-guppy:v0.task:name <NAME>"),
+  ("v0.task:toolchain", "#-guppy:v0.task:toolchain <TOOLCHAIN>"),
+  ("v0.task:require_docker", "#-guppy:v0.task:require_docker true|false"),
+  ("v0.task:require_nvidia_docker", "#-guppy:v0.task:require_nvidia_docker true|false"),
+  ("v0.task:require_distro", "#-guppy:v0.task:require_distro <DISTRO> <VERSION>"),
+  ("v0.task:require_cuda", "#-guppy:v0.task:require_cuda <VERSION>"),
+  ("v0.task:require_gpu_arch", "#-guppy:v0.task:require_gpu_arch [>=|==]<ARCH>|*"),
+  ("v0.task:depends_on", "#-guppy:v0.task:depends_on <NAME>"),
+  ("v0.task:matrix", "#-guppy:v0.task:matrix <KEY>=<VALUE>[,<VALUE>...] ..."),
+  ("v0.task:matrix_exclude", "#-guppy:v0.task:matrix_exclude <KEY>=<VALUE> ..."),
+  ("v0.task:only_refs", "#-guppy:v0.task:only_refs <PATTERN> ..."),
+  ("v0.task:only_paths", "#-guppy:v0.task:only_paths <PATTERN> ..."),
+  ("v0.task:retries", "#-guppy:v0.task:retries <N>"),
+  ("v0.task:retry_on", "#-guppy:v0.task:retry_on <EXIT_CODE> ..."),
+  ("v0.task:allow_errors", "#-guppy:v0.task:allow_errors true|false"),
+  ("v0.task:cache", "#-guppy:v0.task:cache <NAME> <CONTAINER_PATH>"),
+  ("v0.task:limits", "#-guppy:v0.task:limits [cpus=<N>] [memory=<SIZE>] [pids=<N>] [shm=<SIZE>]"),
+];
+
+/// The directive on a line of gup.py output, as `<DIRECTIVE>:<SUBDIRECTIVE>`
+/// (e.g. `v0.task:name`), if the line is a directive.
+fn v0_directive_key(line: &str) -> Option<String> {
+  if !line.starts_with("#-guppy:") {
+    return None;
+  }
+  let mut toks = line["#-guppy:".len() .. ].splitn(2, ':');
+  let directive = toks.next().unwrap_or("");
+  let subdirective = toks.next().unwrap_or("").split_whitespace().next().unwrap_or("");
+  Some(format!("{}:{}", directive, subdirective))
+}
+
+/// Expected form of the directive on a line of gup.py output, if it is a
+/// known directive.
+fn v0_directive_form(line: &str) -> Option<&'static str> {
+  let key = v0_directive_key(line)?;
+  V0_DIRECTIVE_FORMS.iter()
+    .find(|&&(k, _)| k == key)
+    .map(|&(_, form)| form)
+}
+
+/// Protocols of gup.py output understood by `_taskspecs`, as passed to
+/// gup.py in `GUPPY_TASKSPEC_PROTOCOL`.
+pub const TASKSPEC_PROTOCOLS: &'static str = "v0,v1";
+
+/// Splits the output of gup.py into numbered lines, reporting the lines which
+/// are not valid utf8.
+fn _taskspec_lines(raw_out: &[u8], errors: &mut Vec<DirectiveError>) -> Vec<(u64, String)> {
+  let mut lines = Vec::new();
+  let mut raw_lines: Vec<_> = raw_out.split(|&c| c == b'\n').collect();
+  if raw_lines.last().map(|raw_line| raw_line.is_empty()).unwrap_or(false) {
+    raw_lines.pop();
+  }
+  for (line_idx, raw_line) in raw_lines.into_iter().enumerate() {
+    let line_nr = line_idx as u64 + 1;
+    let raw_line = match raw_line.last() {
+      Some(&b'\r') => &raw_line[ .. raw_line.len() - 1],
+      _ => raw_line,
+    };
+    match from_utf8(raw_line) {
+      Err(_) => {
+        errors.push(DirectiveError{
+          line_nr,
+          line: String::from_utf8_lossy(raw_line).into_owned(),
+          message: "line is not valid utf8".to_string(),
+          expected: None,
+        });
       }
-      task_file.flush()
-        .map_err(|_| fail("failed to write to script file"))?;
+      Ok(line) => lines.push((line_nr, line.to_string())),
     }
-    let mut cmd = Command::new("docker");
-    cmd
-      .arg("run")
-    ;
-    if self.imagespec.nvidia_docker {
-      cmd.arg("--runtime").arg("nvidia");
-    } else {
-      cmd.arg("--runtime").arg("runc");
+  }
+  lines
+}
+
+/// Parses the output of gup.py into taskspecs, filling in the mutable cache
+/// as it asks, from `checkout_dir` for `copy_once`. On a `dry_run`, the
+/// mutable cache directives are only checked, and nothing is downloaded,
+/// copied or evicted. Output starting with a JSON object is in the v1
+/// protocol, and anything else in the v0 protocol. Every line that cannot be
+/// understood is reported, not only the first one.
+pub fn _taskspecs(raw_out: &[u8], checkout_dir: Option<&Path>, sysroot: &Sysroot, dry_run: bool) -> Result<Vec<TaskSpec>, SpecError> {
+  let mut errors = Vec::new();
+  let mut cache = CacheAppender::new(checkout_dir, sysroot, dry_run);
+  let lines = _taskspec_lines(raw_out, &mut errors);
+  let v1 = lines.iter()
+    .find(|&&(_, ref line)| !line.trim().is_empty())
+    .map(|&(_, ref line)| line.trim_start().starts_with('{'))
+    .unwrap_or(false);
+  let tasks = match v1 {
+    false => _taskspecs_v0(lines, &mut errors, &mut cache),
+    true  => _taskspecs_v1(lines, &mut errors, &mut cache),
+  };
+  if !errors.is_empty() {
+    return Err(SpecError::from_errors(errors));
+  }
+  TaskGraph::new(&tasks)?;
+  Ok(tasks)
+}
+
+fn _taskspecs_v0(lines: Vec<(u64, String)>, errors: &mut Vec<DirectiveError>, cache: &mut CacheAppender) -> Vec<TaskSpec> {
+  let mut tasks = Vec::new();
+  let mut task_builder: Option<TaskSpecBuilder> = None;
+  let mut begin_line = (0, String::new());
+  for (line_nr, line) in lines.into_iter() {
+    if v0_directive_key(&line).as_ref().map(|key| key.as_str()) == Some("v0.task:begin") {
+      begin_line = (line_nr, line.clone());
     }
-    cmd
-      .arg("--rm")
-      .arg("--interactive")
-      .arg("--log-driver").arg("none")
-      //.arg("--tty")
-      .arg("--attach").arg("stdin")
-      .arg("--attach").arg("stdout")
-      .arg("--attach").arg("stderr")
-      .arg("--volume").arg(format!("{}:/mutable_cache:ro", sysroot.base_dir.join("mutable_cache").display()))
-      .arg("--volume").arg(format!("{}:/checkout:rw", checkout.dir.path().display()))
-      .arg("--volume").arg(format!("{}:/task:ro", task_file.path().display()))
-      .arg("--volume").arg(format!("{}:/entry.sh:ro", toolchain_dir.join("run_mut.sh").display()))
-      .arg("--env").arg("CI=1")
-      .arg(format!("gup/{}", self.hash_digest))
-      .arg("/entry.sh")
-      .stdout(Stdio::piped())
-      .stderr(Stdio::piped())
-    ;
-    let mut proc = cmd.spawn()
-      .expect("failed to run `docker run`");
-    //println!("### BEGIN MONITOR ###");
-    let mon_h = match output {
-      None => {
-        ConsoleMonitor::sink(proc.stdout.take().unwrap(), proc.stderr.take().unwrap())
+    if let Err(e) = _taskspec_line(line.clone(), &mut task_builder, &mut tasks, cache) {
+      errors.push(DirectiveError{
+        line_nr,
+        expected: v0_directive_form(&line),
+        line,
+        message: e.excuses.join(": "),
+      });
+    }
+  }
+  if task_builder.is_some() {
+    errors.push(DirectiveError{
+      line_nr: begin_line.0,
+      line: begin_line.1,
+      message: "task is missing v0.task:end".to_string(),
+      expected: Some("#-guppy:v0.task:end"),
+    });
+  }
+  tasks
+}
+
+/// A line of gup.py output in the v1 protocol.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum V1Directive {
+  Protocol(V1Protocol),
+  MutableCache(V1MutableCache),
+  Task(V1Task),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct V1Protocol {
+  version: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct V1MutableCache {
+  path: String,
+  op: String,
+  src: String,
+  sha256: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct V1MatrixKey {
+  key: String,
+  values: Vec<String>,
+}
+
+fn v1_default_true() -> bool {
+  true
+}
+
+/// A task in the v1 protocol, with the same fields and defaults as
+/// `guppy.task()`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct V1Task {
+  name: String,
+  toolchain: Option<String>,
+  #[serde(default = "v1_default_true")]
+  require_docker: bool,
+  #[serde(default = "v1_default_true")]
+  require_nvidia_docker: bool,
+  require_distro: Option<String>,
+  require_cuda: Option<String>,
+  require_gpu_arch: Option<String>,
+  #[serde(default)]
+  allow_errors: bool,
+  #[serde(default)]
+  retries: u32,
+  #[serde(default)]
+  retry_on: Vec<i32>,
+  #[serde(default)]
+  only_refs: Vec<String>,
+  #[serde(default)]
+  only_paths: Vec<String>,
+  #[serde(default)]
+  depends_on: Vec<String>,
+  #[serde(default)]
+  matrix: Vec<V1MatrixKey>,
+  #[serde(default)]
+  matrix_exclude: Vec<BTreeMap<String, String>>,
+  #[serde(default)]
+  env: BTreeMap<String, String>,
+  /// Container paths of the build caches, by cache name.
+  #[serde(default)]
+  caches: BTreeMap<String, String>,
+  /// Resource limits, as numbers or strings such as `"32g"`.
+  #[serde(default)]
+  limits: BTreeMap<String, JsonValue>,
+  #[serde(default)]
+  sh: Vec<String>,
+}
+
+impl V1Task {
+  fn into_builder(self) -> Maybe<TaskSpecBuilder> {
+    if self.name.trim().is_empty() || self.name.contains(|c| c == '\n' || c == '\r') {
+      return Err(fail(format!("name: expected a nonempty single line, not {:?}", self.name)));
+    }
+    let mut builder = TaskSpecBuilder::default();
+    builder.name = self.name;
+    if let Some(toolchain) = self.toolchain {
+      builder.toolchain = Some(Toolchain::from_desc_str_no_builtin(&toolchain)
+        .ok_or_else(|| fail(format!("toolchain: unsupported toolchain {:?}", toolchain)))?);
+    }
+    builder.require_docker = self.require_docker;
+    builder.require_nvidia_docker = self.require_nvidia_docker;
+    if let Some(require_distro) = self.require_distro {
+      let distro_toks: Vec<_> = require_distro.split_whitespace().collect();
+      if distro_toks.len() != 2 {
+        return Err(fail(format!("require_distro: expected \"<DISTRO> <VERSION>\", not {:?}", require_distro)));
       }
-      Some(DockerOutput::Stdout) => {
-        ConsoleMonitor::serialize_to_stdout(proc.stdout.take().unwrap(), proc.stderr.take().unwrap())
+      builder.require_distro = Some(parse_require_distro(distro_toks[0], distro_toks[1])?);
+    }
+    if let Some(require_cuda) = self.require_cuda {
+      builder.require_cuda = Some(parse_require_cuda(&require_cuda)?);
+    }
+    if let Some(require_gpu_arch) = self.require_gpu_arch {
+      builder.require_gpu_arch = GpuArchReq::from_str(&require_gpu_arch)?;
+    }
+    builder.allow_errors = self.allow_errors;
+    builder.retries = self.retries;
+    if self.retry_on.contains(&0) {
+      return Err(fail("retry_on: exit code 0 is not a failure"));
+    }
+    builder.retry_on = self.retry_on;
+    builder.only_refs = self.only_refs;
+    builder.only_paths = self.only_paths;
+    for dep_name in self.depends_on.iter() {
+      if dep_name.trim().is_empty() {
+        return Err(fail("depends_on: empty task name"));
       }
-      Some(DockerOutput::Buffer{buf_sz, consumer}) => {
-        ConsoleMonitor::serialize_to_buffer(proc.stdout.take().unwrap(), proc.stderr.take().unwrap(), buf_sz, consumer)
+    }
+    builder.depends_on = self.depends_on;
+    for matrix_key in self.matrix.into_iter() {
+      if !is_matrix_key(&matrix_key.key) {
+        return Err(fail(format!("matrix: invalid matrix key {:?}", matrix_key.key)));
       }
-    };
-    let maybe_status = proc.wait();
-    mon_h.join().ok();
-    //println!("### END MONITOR ###");
-    let status = maybe_status
-      .map_err(|_| fail("failed to wait for `docker run`"))?;
-    match status.success() {
-      false => Ok(DockerRunStatus::Failure),
-      true  => Ok(DockerRunStatus::Success),
+      if builder.matrix.iter().any(|&(ref k, _)| k == &matrix_key.key) {
+        return Err(fail(format!("matrix: duplicate matrix key {:?}", matrix_key.key)));
+      }
+      if matrix_key.values.is_empty() {
+        return Err(fail(format!("matrix: no values for matrix key {:?}", matrix_key.key)));
+      }
+      if builder.matrix.len() >= MAX_MATRIX_KEYS {
+        return Err(fail(format!("matrix: more than {} matrix keys", MAX_MATRIX_KEYS)));
+      }
+      if matrix_key.values.len() > MAX_MATRIX_VALUES {
+        return Err(fail(format!("matrix: more than {} values for matrix key {:?}", MAX_MATRIX_VALUES, matrix_key.key)));
+      }
+      for value in matrix_key.values.iter() {
+        TaskSpecBuilder::default().apply_matrix_value(&matrix_key.key, value)?;
+      }
+      builder.matrix.push((matrix_key.key, matrix_key.values));
+    }
+    for rule in self.matrix_exclude.into_iter() {
+      if rule.is_empty() {
+        return Err(fail("matrix_exclude: empty rule"));
+      }
+      builder.matrix_exclude.push(rule.into_iter().collect());
     }
+    for (name, value) in self.env.into_iter() {
+      if !is_env_name(&name) {
+        return Err(fail(format!("env: invalid variable name {:?}", name)));
+      }
+      if is_reserved_env_name(&name) {
+        return Err(fail(format!("env: {} is set by guppybot", name)));
+      }
+      if value.contains('\0') {
+        return Err(fail(format!("env: value of {} contains a NUL character", name)));
+      }
+      builder.env.push((name, value));
+    }
+    for (name, path) in self.caches.iter() {
+      builder.add_cache(name, path)
+        .map_err(|e| fail(format!("caches: {}", e.excuses.join(": "))))?;
+    }
+    for (key, value) in self.limits.iter() {
+      let value = match value {
+        &JsonValue::String(ref s) => s.clone(),
+        &JsonValue::Number(ref n) => n.to_string(),
+        _ => return Err(fail(format!("limits: {}: expected a number or a string", key))),
+      };
+      builder.limits.set(key, &value)
+        .map_err(|e| fail(format!("limits: {}", e.excuses.join(": "))))?;
+    }
+    builder.sh = self.sh;
+    Ok(builder)
   }
 }
 
-pub struct DockerPreImage {
+/// Whether a matrix key can be used in `GUPPY_MATRIX_<KEY>`.
+fn is_matrix_key(key: &str) -> bool {
+  !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Whether a name is valid for a build cache, which names its directory.
+fn is_cache_name(name: &str) -> bool {
+  !name.is_empty() && !name.starts_with('.') &&
+      name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+/// Whether a name is valid for an environment variable set by gup.py.
+fn is_env_name(name: &str) -> bool {
+  let mut chars = name.chars();
+  match chars.next() {
+    Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+    _ => return false,
+  }
+  chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
-fn _taskspecs<R: Read>(stdout: &mut R, sysroot: &Sysroot) -> Maybe<(Vec<u8>, Vec<TaskSpec>)> {
+/// Whether an environment variable is one guppybot sets for tasks itself,
+/// such as `CI`, `NVIDIA_VISIBLE_DEVICES` or `GUPPY_MATRIX_<KEY>`, which
+/// gup.py must not set.
+fn is_reserved_env_name(name: &str) -> bool {
+  name == "CI" || name.starts_with("NVIDIA_") || name.starts_with("GUPPY_")
+}
+
+fn _taskspecs_v1(lines: Vec<(u64, String)>, errors: &mut Vec<DirectiveError>, cache: &mut CacheAppender) -> Vec<TaskSpec> {
   let mut tasks = Vec::new();
-  let mut task_builder: Option<TaskSpecBuilder> = None;
-  let mut raw_out = Vec::with_capacity(4096);
-  stdout.read_to_end(&mut raw_out)
-    .map_err(|_| fail("failed to read gup.py output"))?;
-  let mut cursor = Cursor::new(&raw_out);
-  for line in cursor.lines() {
-    let line = line.map_err(|_| fail("failed to understand gup.py output"))?;
-    let line_toks: Vec<_> = line.splitn(2, "#-guppy:").collect();
-    if line_toks.len() == 2 && line_toks[0].is_empty() {
-      //eprintln!("DEBUG: directive? line toks: {:?}", line_toks);
-      let directive_toks: Vec<_> = line_toks[1].splitn(2, ":").collect();
-      match directive_toks[0] {
-        "v0.mutable_cache" => {
-          // FIXME: use `split_ascii_whitespace` as soon as stabilized:
-          // https://github.com/rust-lang/rust/pull/58047
-          let cache_toks: Vec<_> = directive_toks[1].split_whitespace().collect();
-          match cache_toks[0] {
-            "append" => {
-              if cache_toks.len() <= 2 {
-                return Err(fail("gup.py: v0.mutable_cache:append takes at least 2 arguments"));
+  let mut header = false;
+  for (line_nr, line) in lines.into_iter() {
+    if line.trim().is_empty() {
+      continue;
+    }
+    if let Err(e) = _taskspec_line_v1(&line, &mut header, &mut tasks, cache) {
+      errors.push(DirectiveError{
+        line_nr,
+        line,
+        message: e.excuses.join(": "),
+        expected: None,
+      });
+    }
+  }
+  tasks
+}
+
+fn _taskspec_line_v1(line: &str, header: &mut bool, tasks: &mut Vec<TaskSpec>, cache: &mut CacheAppender) -> Maybe {
+  let directive: V1Directive = serde_json::from_str(line)
+    .map_err(|e| fail(format!("v1: invalid directive: {}", e)))?;
+  match (directive, *header) {
+    (V1Directive::Protocol(protocol), false) => {
+      if protocol.version != "v1" {
+        return Err(fail(format!("v1: unsupported protocol version {:?}", protocol.version)));
+      }
+      *header = true;
+    }
+    (V1Directive::Protocol(_), true) => {
+      return Err(fail("v1: protocol directive after the first line"));
+    }
+    (_, false) => {
+      return Err(fail("v1: output must start with a protocol directive"));
+    }
+    (V1Directive::MutableCache(entry), true) => {
+      let op = CacheOp::new(&entry.op, &entry.src, entry.sha256.as_ref().map(|s| s.as_str()))
+        .map_err(|e| fail(format!("mutable_cache {:?}: {}", entry.path, e.excuses.join(": "))))?;
+      cache.append(&entry.path, &op)
+        .map_err(|e| fail(format!("mutable_cache {:?}: {}", entry.path, e.excuses.join(": "))))?;
+    }
+    (V1Directive::Task(task), true) => {
+      let name = task.name.clone();
+      let task_tasks = task.into_builder()
+        .and_then(|builder| builder.into_tasks(MAX_EXPANDED_TASKS.saturating_sub(tasks.len())))
+        .map_err(|e| fail(format!("task {:?}: {}", name, e.excuses.join(": "))))?;
+      tasks.extend(task_tasks);
+    }
+  }
+  Ok(())
+}
+
+fn parse_require_distro(distro: &str, version: &str) -> Maybe<(Version, DistroCodenameV0)> {
+  let distro_id = match distro {
+    "alpine" => DistroIdV0::Alpine,
+    "centos" => DistroIdV0::Centos,
+    "debian" => DistroIdV0::Debian,
+    "ubuntu" => DistroIdV0::Ubuntu,
+    _ => return Err(fail(format!("unsupported distro {:?}", distro))),
+  };
+  let mut ver = Version::Exact;
+  let mut ver_pat = None;
+  if version.starts_with("==") {
+    ver = Version::Exact;
+    ver_pat = Some("==");
+  } else if version.starts_with(">=") {
+    ver = Version::AtLeast;
+    ver_pat = Some(">=");
+  }
+  let code_str = match ver_pat {
+    None => version,
+    Some(pat) => &version[pat.len() .. ],
+  };
+  let code = match (distro_id, code_str) {
+    (DistroIdV0::Alpine, "3.8") => DistroCodenameV0::Alpine3_8,
+    (DistroIdV0::Alpine, "3.9") => DistroCodenameV0::Alpine3_9,
+    (DistroIdV0::Centos, "6") => DistroCodenameV0::Centos6,
+    (DistroIdV0::Centos, "7") => DistroCodenameV0::Centos7,
+    (DistroIdV0::Debian, "wheezy") => DistroCodenameV0::DebianWheezy,
+    (DistroIdV0::Debian, "7") |
+    (DistroIdV0::Debian, "wheezy") => DistroCodenameV0::DebianWheezy,
+    (DistroIdV0::Debian, "8") |
+    (DistroIdV0::Debian, "jessie") => DistroCodenameV0::DebianJessie,
+    (DistroIdV0::Debian, "9") |
+    (DistroIdV0::Debian, "stretch") => DistroCodenameV0::DebianStretch,
+    (DistroIdV0::Debian, "10") |
+    (DistroIdV0::Debian, "buster") => DistroCodenameV0::DebianBuster,
+    (DistroIdV0::Ubuntu, "14.04") |
+    (DistroIdV0::Ubuntu, "trusty") => DistroCodenameV0::UbuntuTrusty,
+    (DistroIdV0::Ubuntu, "16.04") |
+    (DistroIdV0::Ubuntu, "xenial") => DistroCodenameV0::UbuntuXenial,
+    (DistroIdV0::Ubuntu, "18.04") |
+    (DistroIdV0::Ubuntu, "bionic") => DistroCodenameV0::UbuntuBionic,
+    _ => return Err(fail(format!("unsupported version {:?} of distro {}", code_str, distro))),
+  };
+  Ok((ver, code))
+}
+
+fn parse_require_cuda(version: &str) -> Maybe<(Version, Option<CudaVersionV0>)> {
+  let mut ver = Version::Exact;
+  let mut ver_pat = None;
+  if version == "*" {
+    ver = Version::Any;
+  } else if version.starts_with("==") {
+    ver = Version::Exact;
+    ver_pat = Some("==");
+  } else if version.starts_with(">=") {
+    ver = Version::AtLeast;
+    ver_pat = Some(">=");
+  }
+  let maybe_code = if ver == Version::Any {
+    None
+  } else {
+    let code_str = match ver_pat {
+      None => version,
+      Some(pat) => &version[pat.len() .. ],
+    };
+    let code = match code_str {
+      "6.5" => CudaVersionV0{major: 6, minor: 5},
+      "7.0" => CudaVersionV0{major: 7, minor: 0},
+      "7.5" => CudaVersionV0{major: 7, minor: 5},
+      "8.0" => CudaVersionV0{major: 8, minor: 0},
+      "9.0" => CudaVersionV0{major: 9, minor: 0},
+      "9.1" => CudaVersionV0{major: 9, minor: 1},
+      "9.2" => CudaVersionV0{major: 9, minor: 2},
+      "10.0" => CudaVersionV0{major: 10, minor: 0},
+      "10.1" => CudaVersionV0{major: 10, minor: 1},
+      _ => return Err(fail(format!("unsupported cuda version {:?}", code_str))),
+    };
+    Some(code)
+  };
+  Ok((ver, maybe_code))
+}
+
+fn _task_builder<'a>(task_builder: &'a mut Option<TaskSpecBuilder>, directive: &str) -> Maybe<&'a mut TaskSpecBuilder> {
+  task_builder.as_mut()
+    .ok_or_else(|| fail(format!("v0.task:{} outside of a task, missing v0.task:begin", directive)))
+}
+
+fn _taskspec_line(line: String, task_builder: &mut Option<TaskSpecBuilder>, tasks: &mut Vec<TaskSpec>, cache: &mut CacheAppender) -> Maybe {
+  let line_toks: Vec<_> = line.splitn(2, "#-guppy:").collect();
+  if line_toks.len() == 2 && line_toks[0].is_empty() {
+    //eprintln!("DEBUG: directive? line toks: {:?}", line_toks);
+    let directive_toks: Vec<_> = line_toks[1].splitn(2, ":").collect();
+    let directive_args = directive_toks.get(1).cloned().unwrap_or("");
+    match directive_toks[0] {
+      "v0.mutable_cache" => {
+        // FIXME: use `split_ascii_whitespace` as soon as stabilized:
+        // https://github.com/rust-lang/rust/pull/58047
+        let cache_toks: Vec<_> = directive_args.split_whitespace().collect();
+        match cache_toks.get(0).cloned().unwrap_or("") {
+          "append" => {
+            if cache_toks.len() <= 3 || cache_toks.len() > 5 {
+              return Err(fail("v0.mutable_cache:append takes 3 or 4 arguments"));
+            }
+            let sha256 = match cache_toks.get(4) {
+              None => None,
+              Some(tok) if tok.starts_with("sha256=") => Some(&tok["sha256=".len() .. ]),
+              Some(tok) => return Err(fail(format!("v0.mutable_cache:append: expected sha256=<HEX>, not {:?}", tok))),
+            };
+            let op = CacheOp::new(cache_toks[2], cache_toks[3], sha256)
+              .map_err(|e| fail(format!("v0.mutable_cache:append: {}", e.excuses.join(": "))))?;
+            cache.append(cache_toks[1], &op)
+              .map_err(|e| fail(format!("v0.mutable_cache:append: {}", e.excuses.join(": "))))?;
+          }
+          "" => return Err(fail("v0.mutable_cache: missing directive")),
+          op => return Err(fail(format!("unknown directive v0.mutable_cache:{}", op))),
+        }
+      }
+      "v0.pre_run" | "v0.run_prelude" => {
+        // TODO
+      }
+      "v0.post_run" => {
+        // TODO
+      }
+      "task" | "mutable_cache" => {
+        return Err(fail(format!("directive {} must specify a version, as in v0.{}", directive_toks[0], directive_toks[0])));
+      }
+      "v0.task" => {
+        // FIXME: use `split_ascii_whitespace` as soon as stabilized:
+        // https://github.com/rust-lang/rust/pull/58047
+        let task_toks: Vec<_> = directive_args.split_whitespace().collect();
+        let directive = task_toks.get(0).cloned().unwrap_or("");
+        match directive {
+          "begin" => {
+            // Start over with a new task either way, so that the directives
+            // after this one are checked against it.
+            let prev_builder = task_builder.replace(TaskSpecBuilder::default());
+            if prev_builder.is_some() {
+              return Err(fail("v0.task:begin inside of a task, missing v0.task:end"));
+            }
+          }
+          "end" => {
+            let task_builder = match task_builder.take() {
+              None => return Err(fail("v0.task:end outside of a task, missing v0.task:begin")),
+              Some(task_builder) => task_builder,
+            };
+            tasks.extend(task_builder.into_tasks(MAX_EXPANDED_TASKS.saturating_sub(tasks.len()))?);
+          }
+          "name" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            // The rest of the line is the name, which may contain spaces.
+            let name = directive_args.trim_start()
+              .splitn(2, char::is_whitespace).nth(1).unwrap_or("").trim_start();
+            if name.is_empty() {
+              return Err(fail("v0.task:name takes 1 argument"));
+            }
+            task_builder.name = name.to_string();
+          }
+          "toolchain" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:toolchain takes 1 argument"));
+            }
+            let toolchain = match Toolchain::from_desc_str_no_builtin(task_toks[1]) {
+              Some(toolchain) => toolchain,
+              None => return Err(fail(format!("v0.task:toolchain: unsupported toolchain {:?}", task_toks[1]))),
+            };
+            task_builder.toolchain = Some(toolchain);
+          }
+          "require_docker" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:require_docker takes 1 argument"));
+            }
+            task_builder.require_docker = task_toks[1].parse()
+              .map_err(|_| fail("v0.task:require_docker takes boolean argument"))?;
+          }
+          "require_nvidia_docker" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:require_nvidia_docker takes 1 argument"));
+            }
+            task_builder.require_nvidia_docker = task_toks[1].parse()
+              .map_err(|_| fail("v0.task:require_nvidia_docker takes boolean argument"))?;
+          }
+          "require_distro" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 2 {
+              return Err(fail("v0.task:require_distro takes 2 arguments"));
+            }
+            task_builder.require_distro = Some(parse_require_distro(task_toks[1], task_toks[2])?);
+          }
+          "require_cuda" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:require_cuda takes 1 argument"));
+            }
+            task_builder.require_cuda = Some(parse_require_cuda(task_toks[1])?);
+          }
+          "require_gpu_arch" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:require_gpu_arch takes 1 argument"));
+            }
+            task_builder.require_gpu_arch = GpuArchReq::from_str(task_toks[1])?;
+          }
+          "depends_on" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            // The rest of the line is the name, as for v0.task:name.
+            let dep_name = directive_args.trim_start()
+              .splitn(2, char::is_whitespace).nth(1).unwrap_or("").trim_start();
+            if dep_name.is_empty() {
+              return Err(fail("v0.task:depends_on takes 1 argument"));
+            }
+            task_builder.depends_on.push(dep_name.to_string());
+          }
+          "matrix" | "matrix_exclude" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail(format!("v0.task:{} takes at least 1 argument", directive)));
+            }
+            let mut pairs = Vec::with_capacity(task_toks.len() - 1);
+            for tok in task_toks[1 .. ].iter() {
+              let kv_toks: Vec<_> = tok.splitn(2, '=').collect();
+              if kv_toks.len() != 2 || kv_toks[0].is_empty() || kv_toks[1].is_empty() {
+                return Err(fail(format!("v0.task:{}: expected <KEY>=<VALUE>, not {:?}", directive, tok)));
+              }
+              if !is_matrix_key(kv_toks[0]) {
+                return Err(fail(format!("v0.task:{}: invalid matrix key {:?}", directive, kv_toks[0])));
               }
-              let mut file_path = sysroot.base_dir.join("mutable_cache");
-              for comp in PathBuf::from(cache_toks[1]).components() {
-                match comp {
-                  Component::Normal(c) => {
-                    file_path.push(c);
+              pairs.push((kv_toks[0].to_string(), kv_toks[1].to_string()));
+            }
+            match directive {
+              "matrix" => {
+                for (key, values) in pairs.into_iter() {
+                  if task_builder.matrix.iter().any(|&(ref k, _)| k == &key) {
+                    return Err(fail(format!("v0.task:matrix: duplicate matrix key {:?}", key)));
                   }
-                  _ => {
-                    return Err(fail("gup.py: v0.mutable_cache:append: invalid path"));
+                  if task_builder.matrix.len() >= MAX_MATRIX_KEYS {
+                    return Err(fail(format!("v0.task:matrix: more than {} matrix keys", MAX_MATRIX_KEYS)));
                   }
-                }
-              }
-              match cache_toks[2] {
-                "fetch_once" => {
-                  if cache_toks.len() <= 3 {
-                    return Err(fail("gup.py: v0.mutable_cache:append: fetch_once missing url argument"));
+                  let values: Vec<_> = values.split(',').map(|v| v.to_string()).collect();
+                  if values.len() > MAX_MATRIX_VALUES {
+                    return Err(fail(format!("v0.task:matrix: more than {} values for matrix key {:?}", MAX_MATRIX_VALUES, key)));
                   }
-                  match File::open(&file_path) {
-                    Ok(_) => {}
-                    Err(_) => {
-                      let mut new_file = match File::create(&file_path) {
-                        Err(_) => return Err(fail("gup.py: v0.mutable_cache:append: failed to open new file")),
-                        Ok(f) => f,
-                      };
-                      let mut writer = BufWriter::new(new_file);
-                      {
-                        let mut headers = CurlList::new();
-                        headers.append("Accept: application/octet-stream").unwrap();
-                        let mut ez = CurlEasy::new();
-                        ez.http_headers(headers).unwrap();
-                        ez.follow_location(true).unwrap();
-                        ez.url(cache_toks[3]).unwrap();
-                        {
-                          let mut xfer = ez.transfer();
-                          xfer.write_function(|data| {
-                            match writer.write_all(data) {
-                              Err(e) => panic!("gup.py: v0.mutable_cache:append: fetch_once: write error: {:?}", e),
-                              Ok(_) => {}
-                            }
-                            Ok(data.len())
-                          }).unwrap();
-                          xfer.perform().unwrap();
-                        }
-                      }
+                  for value in values.iter() {
+                    if value.is_empty() {
+                      return Err(fail(format!("v0.task:matrix: empty value for matrix key {:?}", key)));
                     }
+                    TaskSpecBuilder::default().apply_matrix_value(&key, value)?;
                   }
-                }
-                "copy_once" => {
-                  eprintln!("TRACE: gup.py: v0.mutable_cache:append: unhandled op: copy_once");
-                }
-                "link_once" => {
-                  eprintln!("TRACE: gup.py: v0.mutable_cache:append: unhandled op: link_once");
-                }
-                _ => {
-                  eprintln!("TRACE: gup.py: v0.mutable_cache:append: unknown op");
+                  task_builder.matrix.push((key, values));
                 }
               }
+              _ => task_builder.matrix_exclude.push(pairs),
             }
-            _ => return Err(fail("gup.py syntax error")),
           }
-        }
-        "v0.pre_run" | "v0.run_prelude" => {
-          // TODO
-        }
-        "v0.post_run" => {
-          // TODO
-        }
-        "task" => {
-          panic!("gup.py syntax error: must specify a directive version");
-        }
-        "v0.task" => {
-          // FIXME: use `split_ascii_whitespace` as soon as stabilized:
-          // https://github.com/rust-lang/rust/pull/58047
-          let task_toks: Vec<_> = directive_toks[1].split_whitespace().collect();
-          match task_toks[0] {
-            "begin" => {
-              if task_builder.is_some() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              task_builder = Some(TaskSpecBuilder::default());
+          "only_refs" | "only_paths" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail(format!("v0.task:{} takes at least 1 argument", directive)));
             }
-            "end" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              let task_builder = task_builder.take().unwrap();
-              tasks.push(task_builder.into_task()?);
-            }
-            "name" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              if task_toks.len() <= 1 {
-                return Err(fail("v0.task:name takes 1 argument"));
-              }
-              let mut iter_state = 0;
-              let mut iter = directive_toks[1].chars();
-              loop {
-                let c = iter.as_str().chars().next().unwrap();
-                match iter_state {
-                  0 => if c.is_ascii_whitespace() {
-                    iter_state = 1;
-                  },
-                  1 => if !c.is_ascii_whitespace() {
-                    iter_state = 2;
-                    break;
-                  },
-                  _ => unreachable!(),
-                }
-                if iter.next().is_none() {
-                  break;
-                }
-              }
-              if iter_state != 2 {
-                panic!("bug");
-              }
-              task_builder.as_mut().unwrap()
-                .name = iter.as_str().to_string();
+            let patterns = task_toks[1 .. ].iter().map(|s| s.to_string());
+            match directive {
+              "only_refs" => task_builder.only_refs.extend(patterns),
+              _ => task_builder.only_paths.extend(patterns),
             }
-            "toolchain" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              if task_toks.len() <= 1 {
-                return Err(fail("v0.task:toolchain takes 1 argument"));
-              }
-              let toolchain = match Toolchain::from_desc_str_no_builtin(task_toks[1]) {
-                Some(toolchain) => toolchain,
-                None => return Err(fail("v0.task: unsupported toolchain")),
-              };
-              task_builder.as_mut().unwrap()
-                .toolchain = Some(toolchain);
+          }
+          "retries" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:retries takes 1 argument"));
             }
-            "require_docker" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              if task_toks.len() <= 1 {
-                return Err(fail("v0.task:require_docker takes 1 argument"));
-              }
-              task_builder.as_mut().unwrap()
-                .require_docker = task_toks[1].parse()
-                  .map_err(|_| fail("v0.task:require_docker takes boolean argument"))?;
+            task_builder.retries = task_toks[1].parse()
+              .map_err(|_| fail("v0.task:retries takes nonnegative integer argument"))?;
+          }
+          "retry_on" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:retry_on takes at least 1 argument"));
             }
-            "require_nvidia_docker" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              if task_toks.len() <= 1 {
-                return Err(fail("v0.task:require_nvidia_docker takes 1 argument"));
+            for tok in task_toks[1 .. ].iter() {
+              let exit_code: i32 = tok.parse()
+                .map_err(|_| fail("v0.task:retry_on takes exit code arguments"))?;
+              if exit_code == 0 {
+                return Err(fail("v0.task:retry_on: exit code 0 is not a failure"));
               }
-              task_builder.as_mut().unwrap()
-                .require_nvidia_docker = task_toks[1].parse()
-                  .map_err(|_| fail("v0.task:require_nvidia_docker takes boolean argument"))?;
+              task_builder.retry_on.push(exit_code);
             }
-            "require_distro" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              if task_toks.len() <= 2 {
-                return Err(fail("v0.task:require_distro takes 2 arguments"));
-              }
-              let distro_id = match task_toks[1] {
-                "alpine" => DistroIdV0::Alpine,
-                "centos" => DistroIdV0::Centos,
-                "debian" => DistroIdV0::Debian,
-                "ubuntu" => DistroIdV0::Ubuntu,
-                _ => return Err(fail("v0.task: unsupported distro")),
-              };
-              let mut ver = Version::Exact;
-              let mut ver_pat = None;
-              if task_toks[2].starts_with("==") {
-                ver = Version::Exact;
-                ver_pat = Some("==");
-              } else if task_toks[2].starts_with(">=") {
-                ver = Version::AtLeast;
-                ver_pat = Some(">=");
-              }
-              let code_str = if let Some(pat) = ver_pat {
-                let code_toks: Vec<_> = task_toks[2].splitn(2, pat).collect();
-                // FIXME: length check.
-                code_toks[1]
-              } else {
-                task_toks[2]
-              };
-              let code = match (distro_id, code_str) {
-                (DistroIdV0::Alpine, "3.8") => DistroCodenameV0::Alpine3_8,
-                (DistroIdV0::Alpine, "3.9") => DistroCodenameV0::Alpine3_9,
-                (DistroIdV0::Centos, "6") => DistroCodenameV0::Centos6,
-                (DistroIdV0::Centos, "7") => DistroCodenameV0::Centos7,
-                (DistroIdV0::Debian, "wheezy") => DistroCodenameV0::DebianWheezy,
-                (DistroIdV0::Debian, "7") |
-                (DistroIdV0::Debian, "wheezy") => DistroCodenameV0::DebianWheezy,
-                (DistroIdV0::Debian, "8") |
-                (DistroIdV0::Debian, "jessie") => DistroCodenameV0::DebianJessie,
-                (DistroIdV0::Debian, "9") |
-                (DistroIdV0::Debian, "stretch") => DistroCodenameV0::DebianStretch,
-                (DistroIdV0::Debian, "10") |
-                (DistroIdV0::Debian, "buster") => DistroCodenameV0::DebianBuster,
-                (DistroIdV0::Ubuntu, "14.04") |
-                (DistroIdV0::Ubuntu, "trusty") => DistroCodenameV0::UbuntuTrusty,
-                (DistroIdV0::Ubuntu, "16.04") |
-                (DistroIdV0::Ubuntu, "xenial") => DistroCodenameV0::UbuntuXenial,
-                (DistroIdV0::Ubuntu, "18.04") |
-                (DistroIdV0::Ubuntu, "bionic") => DistroCodenameV0::UbuntuBionic,
-                _ => return Err(fail("v0.task: unsupported distro version")),
-              };
-              task_builder.as_mut().unwrap()
-                .require_distro = Some((ver, code));
+          }
+          "allow_errors" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:allow_errors takes 1 argument"));
             }
-            "require_cuda" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              if task_toks.len() <= 1 {
-                return Err(fail("v0.task:require_cuda takes 1 argument"));
-              }
-              let mut ver = Version::Exact;
-              let mut ver_pat = None;
-              if task_toks[1] == "*" {
-                ver = Version::Any;
-              } else if task_toks[1].starts_with("==") {
-                ver = Version::Exact;
-                ver_pat = Some("==");
-              } else if task_toks[1].starts_with(">=") {
-                ver = Version::AtLeast;
-                ver_pat = Some(">=");
-              }
-              let maybe_code = if ver == Version::Any {
-                None
-              } else {
-                let code_str = if let Some(pat) = ver_pat {
-                  let ver_toks: Vec<_> = task_toks[1].splitn(2, pat).collect();
-                  // FIXME: length check.
-                  ver_toks[1]
-                } else {
-                  task_toks[1]
-                };
-                let code = match code_str {
-                  "6.5" => CudaVersionV0{major: 6, minor: 5},
-                  "7.0" => CudaVersionV0{major: 7, minor: 0},
-                  "7.5" => CudaVersionV0{major: 7, minor: 5},
-                  "8.0" => CudaVersionV0{major: 8, minor: 0},
-                  "9.0" => CudaVersionV0{major: 9, minor: 0},
-                  "9.1" => CudaVersionV0{major: 9, minor: 1},
-                  "9.2" => CudaVersionV0{major: 9, minor: 2},
-                  "10.0" => CudaVersionV0{major: 10, minor: 0},
-                  "10.1" => CudaVersionV0{major: 10, minor: 1},
-                  _ => return Err(fail("v0.task: unsupported cuda version")),
-                };
-                Some(code)
-              };
-              task_builder.as_mut().unwrap()
-                .require_cuda = Some((ver, maybe_code));
+            task_builder.allow_errors = task_toks[1].parse()
+              .map_err(|_| fail("v0.task:allow_errors takes boolean argument"))?;
+          }
+          "limits" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() <= 1 {
+              return Err(fail("v0.task:limits takes at least 1 argument"));
             }
-            "require_gpu_arch" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              if task_toks.len() <= 1 {
-                return Err(fail("v0.task:require_gpu_arch takes 1 argument"));
-              }
-              // TODO
-              match task_toks[1] {
-                "*" => {}
-                _ => return Err(fail("gup.py syntax error")),
+            for tok in task_toks[1 .. ].iter() {
+              let kv: Vec<_> = tok.splitn(2, '=').collect();
+              if kv.len() != 2 {
+                return Err(fail(format!("v0.task:limits: expected <KEY>=<VALUE>, not {:?}", tok)));
               }
+              task_builder.limits.set(kv[0], kv[1])
+                .map_err(|e| fail(format!("v0.task:limits: {}", e.excuses.join(": "))))?;
             }
-            "allow_errors" => {
-              if task_builder.is_none() {
-                // TODO: fail.
-                return Err(fail("gup.py syntax error"));
-              }
-              if task_toks.len() <= 1 {
-                return Err(fail("v0.task:allow_errors takes 1 argument"));
-              }
-              task_builder.as_mut().unwrap()
-                .allow_errors = task_toks[1].parse()
-                  .map_err(|_| fail("v0.task:allow_errors takes boolean argument"))?;
+          }
+          "cache" => {
+            let task_builder = _task_builder(task_builder, directive)?;
+            if task_toks.len() != 3 {
+              return Err(fail("v0.task:cache takes 2 arguments"));
             }
-            _ => return Err(fail("gup.py syntax error")),
+            task_builder.add_cache(task_toks[1], task_toks[2])
+              .map_err(|e| fail(format!("v0.task:cache: {}", e.excuses.join(": "))))?;
           }
+          "" => return Err(fail("v0.task: missing directive")),
+          _ => return Err(fail(format!("unknown directive v0.task:{}", directive))),
         }
-        _ => return Err(fail("gup.py syntax error")),
-      }
-    } else {
-      //eprintln!("DEBUG: sh? line toks: {:?}", line_toks);
-      if task_builder.is_none() {
-        return Err(fail("gup.py syntax error"));
       }
-      task_builder.as_mut().unwrap()
-        .sh.push(line);
+      _ => return Err(fail(format!("unknown directive {}", directive_toks[0]))),
+    }
+  } else {
+    //eprintln!("DEBUG: sh? line toks: {:?}", line_toks);
+    match task_builder.as_mut() {
+      None => return Err(fail("shell line outside of a task, missing v0.task:begin")),
+      Some(task_builder) => task_builder.sh.push(line),
     }
   }
-  if task_builder.is_some() {
-    return Err(fail("gup.py syntax error"));
-  }
-  Ok((raw_out, tasks))
+  Ok(())
 }
 
 struct MonitorJoin {
@@ -1032,4 +2250,47 @@ impl ConsoleMonitor {
     ];
     MonitorJoin{joins}
   }
+
+  pub fn serialize_to_tail<Stdout, Stderr>(stdout: Stdout, stderr: Stderr, echo: bool, max_lines: usize, consumer: Box<Fn(Vec<String>) + Send>) -> MonitorJoin
+  where Stdout: Read + Send + 'static, Stderr: Read + Send + 'static {
+    let (stdout_tx, mon_rx) = bounded(64);
+    let stderr_tx = stdout_tx.clone();
+    let joins = vec![
+      thread::spawn(move || {
+        let buf = BufReader::with_capacity(64, stdout);
+        for line in buf.lines() {
+          let line = line.unwrap();
+          stdout_tx.send(line).unwrap();
+        }
+      }),
+      thread::spawn(move || {
+        let buf = BufReader::with_capacity(64, stderr);
+        for line in buf.lines() {
+          let line = line.unwrap();
+          stderr_tx.send(line).unwrap();
+        }
+      }),
+      thread::spawn(move || {
+        let mut tail: VecDeque<String> = VecDeque::with_capacity(max_lines);
+        loop {
+          match mon_rx.recv() {
+            Err(_) => break,
+            Ok(line) => {
+              if echo {
+                println!("{}", line);
+              }
+              if max_lines > 0 {
+                if tail.len() >= max_lines {
+                  tail.pop_front();
+                }
+                tail.push_back(line);
+              }
+            }
+          }
+        }
+        (consumer)(tail.into_iter().collect());
+      }),
+    ];
+    MonitorJoin{joins}
+  }
 }
@@ -0,0 +1,122 @@
+/// Matches a path or ref name against a glob pattern. `*` matches any run of
+/// characters other than `/`, `**` matches any run of characters including
+/// `/`, and `?` matches any one character other than `/`. A pattern ending
+/// in `/` matches everything under that directory.
+///
+/// Patterns come from untrusted taskspecs, so matching takes time
+/// proportional to the length of the pattern times the length of the name,
+/// whatever the pattern.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+  if pattern.ends_with('/') {
+    return glob_match_bytes(format!("{}**", pattern).as_bytes(), s.as_bytes());
+  }
+  glob_match_bytes(pattern.as_bytes(), s.as_bytes())
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+  Byte(u8),
+  /// `?`.
+  AnyByte,
+  /// `*`.
+  Star,
+  /// `**`.
+  DoubleStar,
+  /// Starts a `**/`, which is followed by a `DoubleStar` and a `/` but may
+  /// also match no directories at all, skipping both.
+  SkipDirs,
+}
+
+fn tokenize(p: &[u8]) -> Vec<Token> {
+  let mut tokens = Vec::with_capacity(p.len());
+  let mut i = 0;
+  while i < p.len() {
+    match p[i] {
+      b'*' if p[i .. ].starts_with(b"**") => {
+        i += 2;
+        while i < p.len() && p[i] == b'*' {
+          i += 1;
+        }
+        match i < p.len() && p[i] == b'/' {
+          false => tokens.push(Token::DoubleStar),
+          true  => {
+            i += 1;
+            tokens.push(Token::SkipDirs);
+            tokens.push(Token::DoubleStar);
+            tokens.push(Token::Byte(b'/'));
+          }
+        }
+        continue;
+      }
+      b'*' => tokens.push(Token::Star),
+      b'?' => tokens.push(Token::AnyByte),
+      c => tokens.push(Token::Byte(c)),
+    }
+    i += 1;
+  }
+  tokens
+}
+
+/// Lets every reached state also reach the states after the tokens which
+/// match the empty string.
+fn close(tokens: &[Token], states: &mut [bool]) {
+  for i in 0 .. tokens.len() {
+    if !states[i] {
+      continue;
+    }
+    match tokens[i] {
+      Token::Star | Token::DoubleStar => {
+        states[i + 1] = true;
+      }
+      Token::SkipDirs => {
+        states[i + 1] = true;
+        states[i + 3] = true;
+      }
+      _ => {}
+    }
+  }
+}
+
+fn glob_match_bytes(p: &[u8], s: &[u8]) -> bool {
+  // Simulates the pattern as an automaton whose state `i` means the tokens
+  // before `i` have matched, instead of backtracking.
+  let tokens = tokenize(p);
+  let mut states = vec![false; tokens.len() + 1];
+  states[0] = true;
+  close(&tokens, &mut states);
+  for &c in s.iter() {
+    let mut next = vec![false; tokens.len() + 1];
+    for i in 0 .. tokens.len() {
+      if !states[i] {
+        continue;
+      }
+      match tokens[i] {
+        Token::Byte(x) => {
+          if c == x {
+            next[i + 1] = true;
+          }
+        }
+        Token::AnyByte => {
+          if c != b'/' {
+            next[i + 1] = true;
+          }
+        }
+        Token::Star => {
+          if c != b'/' {
+            next[i] = true;
+          }
+        }
+        Token::DoubleStar => {
+          next[i] = true;
+        }
+        Token::SkipDirs => {}
+      }
+    }
+    close(&tokens, &mut next);
+    if !next.iter().any(|&state| state) {
+      return false;
+    }
+    states = next;
+  }
+  states[tokens.len()]
+}
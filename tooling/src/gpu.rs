@@ -0,0 +1,390 @@
+use crate::config::{Config, LocalGpusConfig};
+use crate::query::{Maybe, Open, fail};
+
+use libloading::{Library, Symbol};
+use serde::{Serialize, Serializer};
+
+use std::fmt;
+use std::fs::{read_dir, read_to_string};
+use std::os::raw::{c_int, c_uint, c_void};
+use std::path::{Path};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct ComputeCapability {
+  pub major: u32,
+  pub minor: u32,
+}
+
+impl ComputeCapability {
+  /// Parses a compute capability written as `sm_70` or `7.0`.
+  pub fn from_str(s: &str) -> Option<ComputeCapability> {
+    if s.starts_with("sm_") {
+      let digits = &s[3 .. ];
+      if digits.len() < 2 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+      }
+      let (major, minor) = digits.split_at(digits.len() - 1);
+      return Some(ComputeCapability{
+        major: major.parse().ok()?,
+        minor: minor.parse().ok()?,
+      });
+    }
+    let toks: Vec<_> = s.splitn(2, '.').collect();
+    if toks.len() != 2 {
+      return None;
+    }
+    Some(ComputeCapability{
+      major: toks[0].parse().ok()?,
+      minor: toks[1].parse().ok()?,
+    })
+  }
+}
+
+impl fmt::Display for ComputeCapability {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "sm_{}{}", self.major, self.minor)
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum GpuArch {
+  Kepler,
+  Maxwell,
+  Pascal,
+  Volta,
+  Turing,
+}
+
+impl GpuArch {
+  pub fn from_desc_str(s: &str) -> Option<GpuArch> {
+    match s {
+      "kepler" => Some(GpuArch::Kepler),
+      "maxwell" => Some(GpuArch::Maxwell),
+      "pascal" => Some(GpuArch::Pascal),
+      "volta" => Some(GpuArch::Volta),
+      "turing" => Some(GpuArch::Turing),
+      _ => None,
+    }
+  }
+
+  pub fn to_desc_str(&self) -> &'static str {
+    match self {
+      &GpuArch::Kepler => "kepler",
+      &GpuArch::Maxwell => "maxwell",
+      &GpuArch::Pascal => "pascal",
+      &GpuArch::Volta => "volta",
+      &GpuArch::Turing => "turing",
+    }
+  }
+
+  pub fn of(cc: ComputeCapability) -> Option<GpuArch> {
+    match (cc.major, cc.minor) {
+      (3, _) => Some(GpuArch::Kepler),
+      (5, _) => Some(GpuArch::Maxwell),
+      (6, _) => Some(GpuArch::Pascal),
+      (7, minor) if minor < 5 => Some(GpuArch::Volta),
+      (7, _) => Some(GpuArch::Turing),
+      _ => None,
+    }
+  }
+
+  /// Lowest compute capability of a device of this architecture.
+  pub fn min_capability(&self) -> ComputeCapability {
+    let (major, minor) = match self {
+      &GpuArch::Kepler => (3, 0),
+      &GpuArch::Maxwell => (5, 0),
+      &GpuArch::Pascal => (6, 0),
+      &GpuArch::Volta => (7, 0),
+      &GpuArch::Turing => (7, 5),
+    };
+    ComputeCapability{major, minor}
+  }
+}
+
+/// GPU architecture a task requires, from `v0.task:require_gpu_arch`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GpuArchReq {
+  Exact(ComputeCapability),
+  AtLeast(ComputeCapability),
+  Arch(GpuArch),
+}
+
+impl GpuArchReq {
+  /// Parses `sm_70`, `==sm_70`, `>=sm_70`, `volta` or `>=volta`. Returns
+  /// `Ok(None)` for `*`, which any device or none at all satisfies.
+  pub fn from_str(s: &str) -> Maybe<Option<GpuArchReq>> {
+    if s == "*" {
+      return Ok(None);
+    }
+    let (at_least, rest) = if s.starts_with(">=") {
+      (true, &s[2 .. ])
+    } else if s.starts_with("==") {
+      (false, &s[2 .. ])
+    } else {
+      (false, s)
+    };
+    let req = match (GpuArch::from_desc_str(rest), ComputeCapability::from_str(rest)) {
+      (Some(arch), _) => match at_least {
+        false => GpuArchReq::Arch(arch),
+        true  => GpuArchReq::AtLeast(arch.min_capability()),
+      },
+      (None, Some(cc)) => match at_least {
+        false => GpuArchReq::Exact(cc),
+        true  => GpuArchReq::AtLeast(cc),
+      },
+      (None, None) => return Err(fail(format!("unsupported gpu arch: {:?}", s))),
+    };
+    Ok(Some(req))
+  }
+
+  pub fn matches(&self, cc: ComputeCapability) -> bool {
+    match self {
+      &GpuArchReq::Exact(req_cc) => cc == req_cc,
+      &GpuArchReq::AtLeast(req_cc) => cc >= req_cc,
+      &GpuArchReq::Arch(arch) => GpuArch::of(cc) == Some(arch),
+    }
+  }
+}
+
+impl fmt::Display for GpuArchReq {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &GpuArchReq::Exact(cc) => write!(f, "{}", cc),
+      &GpuArchReq::AtLeast(cc) => write!(f, ">={}", cc),
+      &GpuArchReq::Arch(arch) => write!(f, "{}", arch.to_desc_str()),
+    }
+  }
+}
+
+/// Serialized as written in `require_gpu_arch`, e.g. `>=sm_70`.
+impl Serialize for GpuArchReq {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&self.to_string())
+  }
+}
+
+const PCI_VENDOR_NVIDIA: u16 = 0x10de;
+
+/// Compute capabilities of NVIDIA devices by PCI device ID, for when NVML is
+/// not available.
+const NVIDIA_DEVICES: &'static [(u16, &'static str, u32, u32)] = &[
+  (0x1004, "GeForce GTX 780", 3, 5),
+  (0x1005, "GeForce GTX TITAN", 3, 5),
+  (0x100a, "GeForce GTX 780 Ti", 3, 5),
+  (0x1021, "Tesla K20Xm", 3, 5),
+  (0x1022, "Tesla K20c", 3, 5),
+  (0x1023, "Tesla K40m", 3, 5),
+  (0x1024, "Tesla K40c", 3, 5),
+  (0x1028, "Tesla K20m", 3, 5),
+  (0x102d, "Tesla K80", 3, 7),
+  (0x1180, "GeForce GTX 680", 3, 0),
+  (0x118f, "Tesla K10", 3, 0),
+  (0x1380, "GeForce GTX 750 Ti", 5, 0),
+  (0x13c0, "GeForce GTX 980", 5, 2),
+  (0x13c2, "GeForce GTX 970", 5, 2),
+  (0x13f2, "Tesla M60", 5, 2),
+  (0x17c2, "GeForce GTX TITAN X", 5, 2),
+  (0x17c8, "GeForce GTX 980 Ti", 5, 2),
+  (0x17fd, "Tesla M40", 5, 2),
+  (0x15f7, "Tesla P100 PCIe 12GB", 6, 0),
+  (0x15f8, "Tesla P100 PCIe 16GB", 6, 0),
+  (0x15f9, "Tesla P100 SXM2 16GB", 6, 0),
+  (0x1b00, "TITAN X (Pascal)", 6, 1),
+  (0x1b02, "TITAN Xp", 6, 1),
+  (0x1b06, "GeForce GTX 1080 Ti", 6, 1),
+  (0x1b38, "Tesla P40", 6, 1),
+  (0x1b80, "GeForce GTX 1080", 6, 1),
+  (0x1b81, "GeForce GTX 1070", 6, 1),
+  (0x1bb3, "Tesla P4", 6, 1),
+  (0x1c03, "GeForce GTX 1060 6GB", 6, 1),
+  (0x1d81, "TITAN V", 7, 0),
+  (0x1db1, "Tesla V100 SXM2 16GB", 7, 0),
+  (0x1db4, "Tesla V100 PCIe 16GB", 7, 0),
+  (0x1db5, "Tesla V100 SXM2 32GB", 7, 0),
+  (0x1db6, "Tesla V100 PCIe 32GB", 7, 0),
+  (0x1e02, "TITAN RTX", 7, 5),
+  (0x1e04, "GeForce RTX 2080 Ti", 7, 5),
+  (0x1e07, "GeForce RTX 2080 Ti", 7, 5),
+  (0x1e82, "GeForce RTX 2080", 7, 5),
+  (0x1e87, "GeForce RTX 2080", 7, 5),
+  (0x1eb8, "Tesla T4", 7, 5),
+  (0x1f02, "GeForce RTX 2070", 7, 5),
+  (0x1f07, "GeForce RTX 2070", 7, 5),
+];
+
+#[derive(Clone, Debug)]
+pub struct Gpu {
+  /// PCI slot, as `<domain>:<bus>:<device>.<function>`.
+  pub slot: String,
+  /// Index of the device as seen by the nvidia container runtime.
+  pub index: u32,
+  pub pci_device: u16,
+  pub name: Option<String>,
+  /// Compute capability, if the device could be identified.
+  pub compute_capability: Option<ComputeCapability>,
+}
+
+/// Normalizes a PCI slot as written by `lspci` or in the machine config, with
+/// or without the domain, to the form used in sysfs.
+pub fn normalize_pci_slot(slot: &str) -> Option<String> {
+  let toks: Vec<_> = slot.trim().split(':').collect();
+  let (domain, bus, dev_func) = match toks.len() {
+    2 => (0, toks[0], toks[1]),
+    3 => (u32::from_str_radix(toks[0], 16).ok()?, toks[1], toks[2]),
+    _ => return None,
+  };
+  let bus = u8::from_str_radix(bus, 16).ok()?;
+  let dev_func_toks: Vec<_> = dev_func.splitn(2, '.').collect();
+  if dev_func_toks.len() != 2 {
+    return None;
+  }
+  let device = u8::from_str_radix(dev_func_toks[0], 16).ok()?;
+  let function = u8::from_str_radix(dev_func_toks[1], 16).ok()?;
+  Some(format!("{:04x}:{:02x}:{:02x}.{:x}", domain, bus, device, function))
+}
+
+fn read_sysfs_hex(path: &Path) -> Option<u32> {
+  let text = read_to_string(path).ok()?;
+  let text = text.trim();
+  let text = if text.starts_with("0x") { &text[2 .. ] } else { text };
+  u32::from_str_radix(text, 16).ok()
+}
+
+/// Detects the NVIDIA GPUs on the machine, from sysfs, and identifies them
+/// through NVML if it is available, otherwise through the embedded table of
+/// known devices. With `slots`, only the GPUs in those PCI slots are kept.
+pub fn detect_gpus(slots: Option<&[String]>) -> Vec<Gpu> {
+  let mut devices = Vec::new();
+  if let Ok(entries) = read_dir("/sys/bus/pci/devices") {
+    for entry in entries {
+      let entry = match entry {
+        Err(_) => continue,
+        Ok(entry) => entry,
+      };
+      let path = entry.path();
+      let class = read_sysfs_hex(&path.join("class")).unwrap_or(0);
+      let vendor = read_sysfs_hex(&path.join("vendor")).unwrap_or(0);
+      // Display controllers, i.e. VGA and 3D controllers.
+      if class >> 16 != 0x03 || vendor != PCI_VENDOR_NVIDIA as u32 {
+        continue;
+      }
+      let device = read_sysfs_hex(&path.join("device")).unwrap_or(0) as u16;
+      devices.push((entry.file_name().to_string_lossy().to_lowercase(), device));
+    }
+  }
+  // The nvidia container runtime numbers devices in PCI bus order.
+  devices.sort();
+  let nvml_ccs = nvml_compute_capabilities().unwrap_or_default();
+  let mut gpus = Vec::with_capacity(devices.len());
+  for (index, (slot, device)) in devices.into_iter().enumerate() {
+    let known = NVIDIA_DEVICES.iter().find(|&&(id, _, _, _)| id == device);
+    let nvml_cc = nvml_ccs.iter().find(|&&(ref s, _)| s == &slot).map(|&(_, cc)| cc);
+    let gpu = Gpu{
+      slot,
+      index: index as u32,
+      pci_device: device,
+      name: known.map(|&(_, name, _, _)| name.to_string()),
+      compute_capability: nvml_cc.or_else(|| known.map(|&(_, _, major, minor)| ComputeCapability{major, minor})),
+    };
+    if let Some(slots) = slots {
+      if !slots.iter().any(|s| normalize_pci_slot(s).as_ref() == Some(&gpu.slot)) {
+        continue;
+      }
+    }
+    gpus.push(gpu);
+  }
+  gpus
+}
+
+/// GPUs given to guppybot: those listed in the `[local_machine]` section of
+/// the machine config, or all of them if there is no list.
+pub fn local_gpus(config: &Config) -> Vec<Gpu> {
+  let gpus_cfg = LocalGpusConfig::open(config).unwrap_or_default();
+  match gpus_cfg.slots.is_empty() {
+    false => detect_gpus(Some(&gpus_cfg.slots)),
+    true  => detect_gpus(None),
+  }
+}
+
+/// Indices of the GPUs a task may use, given the GPUs of the machine: those
+/// meeting its requirement if it has one, otherwise all of them.
+pub fn task_gpus(gpus: &[Gpu], req: Option<&GpuArchReq>) -> Vec<u32> {
+  match req {
+    None => gpus.iter().map(|gpu| gpu.index).collect(),
+    Some(req) => compatible_gpus(gpus, req),
+  }
+}
+
+/// Indices of the GPUs meeting a requirement.
+pub fn compatible_gpus(gpus: &[Gpu], req: &GpuArchReq) -> Vec<u32> {
+  gpus.iter()
+    .filter(|gpu| gpu.compute_capability.map(|cc| req.matches(cc)).unwrap_or(false))
+    .map(|gpu| gpu.index)
+    .collect()
+}
+
+/// `nvmlPciInfo_t`; the fields used here are at the same offsets in every
+/// version of the struct, and this one is the largest.
+#[allow(dead_code)]
+#[repr(C)]
+struct NvmlPciInfo {
+  bus_id_legacy: [u8; 16],
+  domain: c_uint,
+  bus: c_uint,
+  device: c_uint,
+  pci_device_id: c_uint,
+  pci_sub_system_id: c_uint,
+  bus_id: [u8; 32],
+}
+
+fn nvml_compute_capabilities() -> Maybe<Vec<(String, ComputeCapability)>> {
+  let lib = Library::new("libnvidia-ml.so.1")
+    .map_err(|_| fail("failed to load 'libnvidia-ml.so.1'"))?;
+  let mut ccs = Vec::new();
+  unsafe {
+    let init: Symbol<unsafe extern "C" fn () -> c_int> =
+        lib.get(b"nvmlInit_v2")
+          .map_err(|_| fail("failed to get symbol for `nvmlInit_v2`"))?;
+    let shutdown: Symbol<unsafe extern "C" fn () -> c_int> =
+        lib.get(b"nvmlShutdown")
+          .map_err(|_| fail("failed to get symbol for `nvmlShutdown`"))?;
+    let get_count: Symbol<unsafe extern "C" fn (count: *mut c_uint) -> c_int> =
+        lib.get(b"nvmlDeviceGetCount_v2")
+          .map_err(|_| fail("failed to get symbol for `nvmlDeviceGetCount_v2`"))?;
+    let get_handle: Symbol<unsafe extern "C" fn (index: c_uint, device: *mut *mut c_void) -> c_int> =
+        lib.get(b"nvmlDeviceGetHandleByIndex_v2")
+          .map_err(|_| fail("failed to get symbol for `nvmlDeviceGetHandleByIndex_v2`"))?;
+    let get_cc: Symbol<unsafe extern "C" fn (device: *mut c_void, major: *mut c_int, minor: *mut c_int) -> c_int> =
+        lib.get(b"nvmlDeviceGetCudaComputeCapability")
+          .map_err(|_| fail("failed to get symbol for `nvmlDeviceGetCudaComputeCapability`"))?;
+    let get_pci_info: Symbol<unsafe extern "C" fn (device: *mut c_void, pci: *mut NvmlPciInfo) -> c_int> =
+        lib.get(b"nvmlDeviceGetPciInfo_v3")
+          .or_else(|_| lib.get(b"nvmlDeviceGetPciInfo_v2"))
+          .map_err(|_| fail("failed to get symbol for `nvmlDeviceGetPciInfo`"))?;
+    match (init)() {
+      0 => {}
+      e => return Err(fail(format!("`nvmlInit_v2` returned nonzero: {}", e))),
+    }
+    let mut count: c_uint = 0;
+    if (get_count)(&mut count as *mut _) == 0 {
+      for index in 0 .. count {
+        let mut device: *mut c_void = 0 as *mut _;
+        if (get_handle)(index, &mut device as *mut _) != 0 {
+          continue;
+        }
+        let mut pci: NvmlPciInfo = std::mem::zeroed();
+        let (mut major, mut minor): (c_int, c_int) = (-1, -1);
+        if (get_pci_info)(device, &mut pci as *mut _) != 0 ||
+            (get_cc)(device, &mut major as *mut _, &mut minor as *mut _) != 0 ||
+            major < 0 || minor < 0
+        {
+          continue;
+        }
+        let slot = format!("{:04x}:{:02x}:{:02x}.0", pci.domain, pci.bus, pci.device);
+        ccs.push((slot, ComputeCapability{major: major as u32, minor: minor as u32}));
+      }
+    }
+    (shutdown)();
+  }
+  Ok(ccs)
+}
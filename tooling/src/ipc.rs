@@ -32,6 +32,7 @@ pub enum Ctl2Bot {
   _AckRetryApiAuth,
   _UndoApiAuth,
   _AckUndoApiAuth,
+  Drain,
   EchoApiId,
   EchoMachineId,
   PrintConfig,
@@ -59,6 +60,7 @@ pub enum Ctl2Bot {
   },
   AckRegisterMachine,
   ReloadConfig,
+  Resume,
   UnregisterCiMachine,
   UnregisterCiRepo,
   UnregisterMachine,
@@ -73,6 +75,7 @@ pub enum Bot2Ctl {
   _AckRetryApiAuth(Ack<()>),
   _UndoApiAuth(Option<()>),
   _AckUndoApiAuth(Option<()>),
+  Drain(Option<Drain>),
   EchoApiId(Option<EchoApiId>),
   EchoMachineId(Option<EchoMachineId>),
   PrintConfig(Option<PrintConfig>),
@@ -88,6 +91,7 @@ pub enum Bot2Ctl {
   ConfirmRegisterMachine(Option<()>),
   AckRegisterMachine(Ack<()>),
   ReloadConfig(Option<()>),
+  Resume(Option<()>),
   UnregisterCiMachine(Option<()>),
   UnregisterCiRepo(Option<()>),
   UnregisterMachine(Option<()>),
@@ -105,6 +109,12 @@ pub struct QueryApiAuthState {
   pub auth_bit: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Drain {
+  pub running_tasks: u64,
+  pub queued_tasks: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EchoApiId {
   pub api_id: String,
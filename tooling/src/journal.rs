@@ -0,0 +1,176 @@
+use crate::query::{Maybe, fail};
+use crate::state::{Sysroot};
+
+use std::fs::{File, create_dir_all, read_dir, remove_dir_all, rename};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// Where a CI run came from, and so where its results are reported: runs
+/// from the registry report through the outbox, all others to the local run
+/// log.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum RunSource {
+  Registry,
+  Webhook,
+  Poll,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct JournalRun {
+  pub source: RunSource,
+  pub api_key: Vec<u8>,
+  pub ci_run_key: Vec<u8>,
+  pub remote_url: String,
+  pub ref_full: Option<String>,
+  pub commit_hash: Option<String>,
+  pub task_count: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum JournalTaskState {
+  Pending,
+  Running,
+  Done{failed: bool},
+}
+
+/// On-disk record of accepted CI runs, kept under `<sysroot>/runs`.
+///
+/// Each run gets a directory named after its run key, holding the run record,
+/// one state file per task, the git checkout the tasks run against, and the
+/// artifacts the tasks pass on to the tasks depending on them. The
+/// directory is removed once every task in the run is done.
+pub struct Journal {
+  dir: PathBuf,
+}
+
+fn write_atomic<T: serde::Serialize>(path: &Path, value: &T) -> Maybe {
+  let tmp_path = match (path.parent(), path.file_name()) {
+    (Some(dir), Some(name)) => dir.join(format!(".{}.tmp", name.to_string_lossy())),
+    _ => return Err(fail("journal: bad file path")),
+  };
+  {
+    let file = File::create(&tmp_path)
+      .map_err(|_| fail("journal: failed to create file"))?;
+    let mut writer = BufWriter::new(file);
+    bincode::serialize_into(&mut writer, value)
+      .map_err(|_| fail("journal: failed to write file"))?;
+    let file = writer.into_inner()
+      .map_err(|_| fail("journal: failed to write file"))?;
+    file.sync_all()
+      .map_err(|_| fail("journal: failed to write file"))?;
+  }
+  rename(&tmp_path, path)
+    .map_err(|_| fail("journal: failed to write file"))?;
+  Ok(())
+}
+
+fn read_file<T: serde::de::DeserializeOwned>(path: &Path) -> Maybe<T> {
+  let file = File::open(path)
+    .map_err(|_| fail("journal: failed to open file"))?;
+  bincode::deserialize_from(BufReader::new(file))
+    .map_err(|_| fail("journal: corrupt file"))
+}
+
+impl Journal {
+  pub fn open(sysroot: &Sysroot) -> Maybe<Journal> {
+    let dir = sysroot.base_dir.join("runs");
+    create_dir_all(&dir)
+      .map_err(|_| fail("failed to create runs directory in sysroot"))?;
+    Ok(Journal{dir})
+  }
+
+  pub fn run_dir(&self, ci_run_key: &[u8]) -> PathBuf {
+    self.dir.join(hex::encode(ci_run_key))
+  }
+
+  /// Creates the run directory and returns the path to use for its checkout.
+  pub fn prepare_run(&self, ci_run_key: &[u8]) -> Maybe<PathBuf> {
+    let checkout_dir = self.run_dir(ci_run_key).join("checkout");
+    create_dir_all(&checkout_dir)
+      .map_err(|_| fail("journal: failed to create checkout directory"))?;
+    Ok(checkout_dir)
+  }
+
+  pub fn begin_run(&self, run: &JournalRun) -> Maybe {
+    let run_dir = self.run_dir(&run.ci_run_key);
+    create_dir_all(&run_dir)
+      .map_err(|_| fail("journal: failed to create run directory"))?;
+    for task_idx in 0 .. run.task_count {
+      write_atomic(&run_dir.join(format!("task.{}", task_idx + 1)), &JournalTaskState::Pending)?;
+    }
+    // The run record goes last: a run without one was never accepted.
+    write_atomic(&run_dir.join("run"), run)?;
+    Ok(())
+  }
+
+  /// Creates and returns the directory where a task leaves its artifacts for
+  /// the tasks that depend on it.
+  pub fn artifacts_dir(&self, ci_run_key: &[u8], task_nr: u64) -> Maybe<PathBuf> {
+    let artifacts_dir = self.run_dir(ci_run_key).join("artifacts").join(format!("{}", task_nr));
+    create_dir_all(&artifacts_dir)
+      .map_err(|_| fail("journal: failed to create artifacts directory"))?;
+    Ok(artifacts_dir)
+  }
+
+  /// Records the state of a task. Returns `true` if this completed the run,
+  /// in which case the run directory has been removed.
+  pub fn set_task_state(&self, ci_run_key: &[u8], task_nr: u64, state: JournalTaskState) -> Maybe<bool> {
+    let run_dir = self.run_dir(ci_run_key);
+    let run: JournalRun = read_file(&run_dir.join("run"))?;
+    write_atomic(&run_dir.join(format!("task.{}", task_nr)), &state)?;
+    for task_idx in 0 .. run.task_count {
+      match read_file(&run_dir.join(format!("task.{}", task_idx + 1)))? {
+        JournalTaskState::Done{..} => {}
+        _ => return Ok(false),
+      }
+    }
+    self.finish_run(ci_run_key)?;
+    Ok(true)
+  }
+
+  pub fn finish_run(&self, ci_run_key: &[u8]) -> Maybe {
+    let run_dir = self.run_dir(ci_run_key);
+    if !run_dir.exists() {
+      return Ok(());
+    }
+    remove_dir_all(&run_dir)
+      .map_err(|_| fail("journal: failed to remove run directory"))
+  }
+
+  /// Loads every journaled run along with the states of its tasks, indexed by
+  /// `task_nr - 1`. Directories without a run record are left-over checkouts
+  /// of runs that were never accepted, and are removed.
+  pub fn load_runs(&self) -> Maybe<Vec<(JournalRun, Vec<JournalTaskState>)>> {
+    let mut runs = Vec::new();
+    for entry in read_dir(&self.dir).map_err(|_| fail("journal: failed to read runs directory"))? {
+      let entry = entry.map_err(|_| fail("journal: failed to read runs directory"))?;
+      let run_dir = entry.path();
+      if !run_dir.is_dir() {
+        continue;
+      }
+      let run: JournalRun = match read_file(&run_dir.join("run")) {
+        Err(_) => {
+          eprintln!("WARNING: journal: discarding incomplete run: {}", run_dir.display());
+          remove_dir_all(&run_dir).ok();
+          continue;
+        }
+        Ok(run) => run,
+      };
+      let mut states = Vec::with_capacity(run.task_count as usize);
+      for task_idx in 0 .. run.task_count {
+        // A missing or corrupt task state is treated as interrupted.
+        let state = read_file(&run_dir.join(format!("task.{}", task_idx + 1)))
+          .unwrap_or(JournalTaskState::Running);
+        states.push(state);
+      }
+      runs.push((run, states));
+    }
+    Ok(runs)
+  }
+}
+
+impl JournalRun {
+  pub fn checkout_dir(&self, journal: &Journal) -> PathBuf {
+    journal.run_dir(&self.ci_run_key).join("checkout")
+  }
+}
@@ -6,21 +6,35 @@ extern crate chrono;
 extern crate curl;
 extern crate dirs;
 extern crate hex;
+extern crate libc;
 extern crate libloading;
 extern crate monosodium;
 extern crate num_cpus;
+extern crate openssl;
 extern crate schemas;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
+extern crate serde_json;
 extern crate tempfile;
 extern crate toml;
 extern crate url;
 extern crate ws;
 
 pub mod assets;
+pub mod cache;
 pub mod config;
+pub mod dag;
 pub mod deps;
 pub mod docker;
+pub mod glob;
+pub mod gpu;
 pub mod ipc;
+pub mod journal;
+pub mod outbox;
+pub mod poll;
 pub mod query;
+pub mod runlog;
 pub mod state;
+pub mod taskfile;
+pub mod webhook;
+pub mod wire;
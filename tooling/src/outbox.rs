@@ -0,0 +1,207 @@
+use crate::query::{Maybe, fail};
+use crate::state::{Sysroot};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use std::collections::{VecDeque};
+use std::fs::{File, create_dir_all, read_dir, remove_file, rename};
+use std::io::{Write, BufReader};
+use std::path::{PathBuf};
+
+/// Messages to the registry which must not be lost if the websocket drops.
+/// The timestamp is taken when the message is queued, not when it is
+/// (re)transmitted.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum OutboxMsg {
+  StartCiTask{
+    api_key: Vec<u8>,
+    ci_run_key: Vec<u8>,
+    task_nr: u64,
+    task_name: Option<String>,
+    taskspec: Option<Vec<u8>>,
+    ts: String,
+  },
+  AppendCiTaskData{
+    api_key: Vec<u8>,
+    ci_run_key: Vec<u8>,
+    task_nr: u64,
+    part_nr: u64,
+    key: String,
+    data: Vec<u8>,
+    ts: String,
+  },
+  DoneCiTask{
+    api_key: Vec<u8>,
+    ci_run_key: Vec<u8>,
+    task_nr: u64,
+    failed: bool,
+    ts: String,
+  },
+  /// Accepts a `_NewCiRun` with `failed_early` set, ahead of the reason in
+  /// task 0. The registry does not reply to it.
+  FailedEarlyCiRun{
+    api_key: Vec<u8>,
+    ci_run_key: Vec<u8>,
+    ts: String,
+  },
+}
+
+impl OutboxMsg {
+  pub fn ci_run_key(&self) -> &[u8] {
+    match self {
+      &OutboxMsg::StartCiTask{ref ci_run_key, ..} |
+      &OutboxMsg::AppendCiTaskData{ref ci_run_key, ..} |
+      &OutboxMsg::DoneCiTask{ref ci_run_key, ..} |
+      &OutboxMsg::FailedEarlyCiRun{ref ci_run_key, ..} => ci_run_key,
+    }
+  }
+
+  /// Whether the registry replies to the message, which acknowledges it.
+  pub fn expects_reply(&self) -> bool {
+    match self {
+      &OutboxMsg::FailedEarlyCiRun{..} => false,
+      _ => true,
+    }
+  }
+}
+
+/// Durable queue of outbound registry messages.
+///
+/// Each message is stored under `<sysroot>/outbox` in its own file, named by
+/// its sequence number. Messages stay on disk until acknowledged; the highest
+/// acknowledged sequence number is kept in `<sysroot>/outbox/.acked`, so that
+/// only unacknowledged messages are replayed after a reconnect. Acks are
+/// matched to messages by the order they were sent in, and a message whose
+/// ack was lost is sent again. Messages go out with their sequence number as
+/// the message id, so the registry can drop the ones it already has.
+pub struct Outbox {
+  dir: PathBuf,
+  next_seq: u64,
+  acked_seq: u64,
+  entries: VecDeque<(u64, OutboxMsg)>,
+}
+
+impl Outbox {
+  pub fn open(sysroot: &Sysroot) -> Maybe<Outbox> {
+    let dir = sysroot.base_dir.join("outbox");
+    create_dir_all(&dir)
+      .map_err(|_| fail("failed to create outbox directory in sysroot"))?;
+    let acked_seq = match File::open(dir.join(".acked")) {
+      Err(_) => 0,
+      Ok(mut file) => {
+        file.read_u64::<LittleEndian>()
+          .map_err(|_| fail("failed to read outbox ack"))?
+      }
+    };
+    let mut seqs = Vec::new();
+    for entry in read_dir(&dir).map_err(|_| fail("failed to read outbox directory"))? {
+      let entry = entry.map_err(|_| fail("failed to read outbox directory"))?;
+      let name = entry.file_name();
+      let name = match name.to_str() {
+        None => continue,
+        Some(s) => s,
+      };
+      if !name.ends_with(".msg") {
+        continue;
+      }
+      match u64::from_str_radix(&name[ .. name.len() - 4], 16) {
+        Err(_) => continue,
+        Ok(seq) => seqs.push(seq),
+      }
+    }
+    seqs.sort();
+    let mut next_seq = acked_seq + 1;
+    let mut entries = VecDeque::with_capacity(seqs.len());
+    for seq in seqs.into_iter() {
+      let path = dir.join(format!("{:016x}.msg", seq));
+      if seq <= acked_seq {
+        // A crash between writing the ack and removing the message file.
+        remove_file(&path).ok();
+        continue;
+      }
+      let file = File::open(&path)
+        .map_err(|_| fail("failed to open outbox message"))?;
+      let msg: OutboxMsg = match bincode::deserialize_from(BufReader::new(file)) {
+        Err(_) => {
+          eprintln!("WARNING: outbox message {} is corrupt, discarding", seq);
+          remove_file(&path).ok();
+          continue;
+        }
+        Ok(msg) => msg,
+      };
+      next_seq = seq + 1;
+      entries.push_back((seq, msg));
+    }
+    Ok(Outbox{
+      dir,
+      next_seq,
+      acked_seq,
+      entries,
+    })
+  }
+
+  pub fn push(&mut self, msg: OutboxMsg) -> Maybe<u64> {
+    let seq = self.next_seq;
+    let tmp_path = self.dir.join(format!(".{:016x}.tmp", seq));
+    {
+      let mut file = File::create(&tmp_path)
+        .map_err(|_| fail("failed to create outbox message"))?;
+      bincode::serialize_into(&mut file, &msg)
+        .map_err(|_| fail("failed to write outbox message"))?;
+      file.sync_all()
+        .map_err(|_| fail("failed to write outbox message"))?;
+    }
+    rename(&tmp_path, self.dir.join(format!("{:016x}.msg", seq)))
+      .map_err(|_| fail("failed to write outbox message"))?;
+    self.next_seq += 1;
+    self.entries.push_back((seq, msg));
+    Ok(seq)
+  }
+
+  /// Unacknowledged messages with sequence numbers after `seq`, in order.
+  pub fn pending_after(&self, seq: u64) -> Vec<(u64, OutboxMsg)> {
+    self.entries.iter()
+      .filter(|&&(s, _)| s > seq)
+      .cloned()
+      .collect()
+  }
+
+  /// Acknowledges all messages up to and including `seq`.
+  pub fn ack(&mut self, seq: u64) -> Maybe {
+    if seq <= self.acked_seq {
+      return Ok(());
+    }
+    let tmp_path = self.dir.join(".acked.tmp");
+    {
+      let mut file = File::create(&tmp_path)
+        .map_err(|_| fail("failed to write outbox ack"))?;
+      file.write_u64::<LittleEndian>(seq)
+        .and_then(|_| file.flush())
+        .and_then(|_| file.sync_all())
+        .map_err(|_| fail("failed to write outbox ack"))?;
+    }
+    rename(&tmp_path, self.dir.join(".acked"))
+      .map_err(|_| fail("failed to write outbox ack"))?;
+    self.acked_seq = seq;
+    while let Some(&(s, _)) = self.entries.front() {
+      if s > seq {
+        break;
+      }
+      self.entries.pop_front();
+      remove_file(self.dir.join(format!("{:016x}.msg", s))).ok();
+    }
+    Ok(())
+  }
+
+  pub fn acked_seq(&self) -> u64 {
+    self.acked_seq
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+}
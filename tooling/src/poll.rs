@@ -0,0 +1,110 @@
+use crate::query::{Maybe, fail};
+use crate::state::{Sysroot};
+use crate::webhook::{normalize_repo_url};
+
+use tempfile::{NamedTempFile};
+
+use std::collections::{BTreeMap};
+use std::fs::{File, create_dir_all};
+use std::io::{BufReader};
+use std::path::{PathBuf};
+use std::sync::{Mutex};
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+struct PollRecord {
+  remote_url: String,
+  refs: BTreeMap<String, String>,
+}
+
+/// Last-seen commits of polled refs, kept under `<sysroot>/poll` as one JSON
+/// file per repo.
+pub struct PollState {
+  dir: PathBuf,
+  // Held across each read and write of a state file.
+  lock: Mutex<()>,
+}
+
+impl PollState {
+  pub fn open(sysroot: &Sysroot) -> Maybe<PollState> {
+    let dir = sysroot.base_dir.join("poll");
+    create_dir_all(&dir)
+      .map_err(|_| fail("failed to create poll directory in sysroot"))?;
+    Ok(PollState{dir, lock: Mutex::new(())})
+  }
+
+  fn path(&self, remote_url: &str) -> PathBuf {
+    let digest = openssl::sha::sha256(normalize_repo_url(remote_url).as_bytes());
+    self.dir.join(format!("{}.json", hex::encode(&digest[ .. 16])))
+  }
+
+  fn load(&self, remote_url: &str) -> Option<PollRecord> {
+    let path = self.path(remote_url);
+    let file = File::open(&path).ok()?;
+    match serde_json::from_reader(BufReader::new(file)) {
+      Err(_) => {
+        eprintln!("WARNING: poll: discarding corrupt state: {}", path.display());
+        None
+      }
+      Ok(record) => Some(record),
+    }
+  }
+
+  fn save(&self, record: &PollRecord) -> Maybe {
+    let tmp_file = NamedTempFile::new_in(&self.dir)
+      .map_err(|_| fail("poll: failed to create state file"))?;
+    serde_json::to_writer_pretty(tmp_file.as_file(), record)
+      .map_err(|_| fail("poll: failed to write state file"))?;
+    tmp_file.as_file().sync_all()
+      .map_err(|_| fail("poll: failed to write state file"))?;
+    tmp_file.persist(self.path(&record.remote_url))
+      .map_err(|_| fail("poll: failed to write state file"))?;
+    Ok(())
+  }
+
+  /// Returns the refs just listed for a repo which moved or appeared since
+  /// the last recorded commit, as `(ref, commit, previous commit)` triples.
+  /// Refs which are no longer listed are forgotten, but the new commits of
+  /// moved refs are not recorded: that is left to `record`, once a run for
+  /// them was accepted, so that a run which was refused or lost in a crash
+  /// is offered again on the next poll.
+  /// Nothing is returned the first time a repo is polled, so that enabling
+  /// polling does not run every existing ref.
+  pub fn moved(&self, remote_url: &str, refs: &[(String, String)]) -> Maybe<Vec<(String, String, Option<String>)>> {
+    let _guard = self.lock.lock().unwrap();
+    let prev = self.load(remote_url);
+    let mut moved = Vec::new();
+    let mut record = PollRecord{
+      remote_url: remote_url.to_string(),
+      refs: BTreeMap::new(),
+    };
+    for &(ref ref_full, ref commit_hash) in refs.iter() {
+      match prev {
+        None => {
+          record.refs.insert(ref_full.clone(), commit_hash.clone());
+        }
+        Some(ref prev) => {
+          let prev_hash = prev.refs.get(ref_full);
+          if let Some(prev_hash) = prev_hash {
+            record.refs.insert(ref_full.clone(), prev_hash.clone());
+          }
+          if prev_hash != Some(commit_hash) {
+            moved.push((ref_full.clone(), commit_hash.clone(), prev_hash.cloned()));
+          }
+        }
+      }
+    }
+    self.save(&record)?;
+    Ok(moved)
+  }
+
+  /// Records that a run was accepted for a ref which moved to `commit_hash`.
+  pub fn record(&self, remote_url: &str, ref_full: &str, commit_hash: &str) -> Maybe {
+    let _guard = self.lock.lock().unwrap();
+    let mut record = self.load(remote_url).unwrap_or_else(|| PollRecord{
+      remote_url: remote_url.to_string(),
+      refs: BTreeMap::new(),
+    });
+    record.refs.insert(ref_full.to_string(), commit_hash.to_string());
+    self.save(&record)
+  }
+}
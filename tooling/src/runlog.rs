@@ -0,0 +1,122 @@
+use crate::journal::{RunSource};
+use crate::outbox::{OutboxMsg};
+use crate::query::{Maybe, fail};
+use crate::state::{Sysroot};
+use crate::wire::{NewCiRunReject};
+
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Serialize, Debug)]
+pub struct RunLogRun {
+  pub source: RunSource,
+  pub remote_url: String,
+  pub ref_full: Option<String>,
+  pub commit_hash: Option<String>,
+  pub originator: Option<String>,
+  /// `None` if the run was rejected.
+  pub task_count: Option<u64>,
+  pub ts: String,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum StatusLine<'a> {
+  Start{
+    task_nr: u64,
+    task_name: &'a Option<String>,
+    ts: &'a str,
+  },
+  Done{
+    task_nr: u64,
+    failed: bool,
+    ts: &'a str,
+  },
+}
+
+/// Results of CI runs that did not come from the registry, kept under
+/// `<sysroot>/logs`.
+///
+/// Each run gets a directory named after its run key, holding `run.json`,
+/// a `status` file with one JSON line per started or finished task, and the
+/// data of each task in `task.<TASK_NR>.<KEY>.log` (e.g. the console output
+/// in `task.1.console.log`). Rejected runs get `reason.json` instead, and
+/// `spec.out` if gup.py was run.
+pub struct RunLog {
+  dir: PathBuf,
+}
+
+fn append(path: &Path, data: &[u8]) -> Maybe {
+  let mut file = OpenOptions::new().create(true).append(true).open(path)
+    .map_err(|_| fail("run log: failed to open file"))?;
+  file.write_all(data)
+    .map_err(|_| fail("run log: failed to write file"))
+}
+
+fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> Maybe {
+  let mut file = File::create(path)
+    .map_err(|_| fail("run log: failed to create file"))?;
+  serde_json::to_writer_pretty(&mut file, value)
+    .map_err(|_| fail("run log: failed to write file"))?;
+  writeln!(&mut file, "")
+    .map_err(|_| fail("run log: failed to write file"))
+}
+
+fn log_key(key: &str) -> String {
+  key.chars()
+    .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+    .collect::<String>()
+    .to_lowercase()
+}
+
+impl RunLog {
+  pub fn open(sysroot: &Sysroot) -> Maybe<RunLog> {
+    let dir = sysroot.base_dir.join("logs");
+    create_dir_all(&dir)
+      .map_err(|_| fail("failed to create logs directory in sysroot"))?;
+    Ok(RunLog{dir})
+  }
+
+  pub fn run_dir(&self, ci_run_key: &[u8]) -> PathBuf {
+    self.dir.join(hex::encode(ci_run_key))
+  }
+
+  pub fn begin_run(&self, ci_run_key: &[u8], run: &RunLogRun) -> Maybe {
+    let run_dir = self.run_dir(ci_run_key);
+    create_dir_all(&run_dir)
+      .map_err(|_| fail("run log: failed to create run directory"))?;
+    write_json(&run_dir.join("run.json"), run)
+  }
+
+  pub fn reject_run(&self, ci_run_key: &[u8], run: &RunLogRun, reason: &NewCiRunReject, spec_out: Option<&[u8]>) -> Maybe {
+    self.begin_run(ci_run_key, run)?;
+    let run_dir = self.run_dir(ci_run_key);
+    write_json(&run_dir.join("reason.json"), reason)?;
+    if let Some(spec_out) = spec_out {
+      append(&run_dir.join("spec.out"), spec_out)?;
+    }
+    Ok(())
+  }
+
+  pub fn record(&self, msg: &OutboxMsg) -> Maybe {
+    let run_dir = self.run_dir(msg.ci_run_key());
+    let line = match msg {
+      &OutboxMsg::StartCiTask{task_nr, ref task_name, ref ts, ..} => {
+        StatusLine::Start{task_nr, task_name, ts}
+      }
+      &OutboxMsg::DoneCiTask{task_nr, failed, ref ts, ..} => {
+        StatusLine::Done{task_nr, failed, ts}
+      }
+      &OutboxMsg::AppendCiTaskData{task_nr, ref key, ref data, ..} => {
+        return append(&run_dir.join(format!("task.{}.{}.log", task_nr, log_key(key))), data);
+      }
+      // Rejected local runs are recorded by `reject_run`.
+      &OutboxMsg::FailedEarlyCiRun{..} => return Ok(()),
+    };
+    let mut buf = serde_json::to_vec(&line)
+      .map_err(|_| fail("run log: failed to serialize status"))?;
+    buf.push(b'\n');
+    append(&run_dir.join("status"), &buf)
+  }
+}
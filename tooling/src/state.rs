@@ -6,6 +6,7 @@ use crate::query::{Maybe, fail};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use monosodium::{generic_hash};
 use monosodium::util::{CryptoBuf};
+use serde::{Serialize, Serializer};
 use schemas::v1::{
   CudaVersionV0,
   DistroIdV0::{self, *},
@@ -80,6 +81,25 @@ impl Toolchain {
   }
 }
 
+/// Serialized as its description, e.g. `python3`.
+impl Serialize for Toolchain {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&self.to_desc_string())
+  }
+}
+
+fn serialize_cuda<S: Serializer>(cuda: &Option<CudaVersionV0>, serializer: S) -> Result<S::Ok, S::Error> {
+  cuda.map(|cuda| cuda.to_desc_str().to_string()).serialize(serializer)
+}
+
+fn serialize_distro_codename<S: Serializer>(codename: &DistroCodenameV0, serializer: S) -> Result<S::Ok, S::Error> {
+  serializer.serialize_str(&codename.to_desc_str().to_string())
+}
+
+fn serialize_distro_id<S: Serializer>(distro_id: &DistroIdV0, serializer: S) -> Result<S::Ok, S::Error> {
+  serializer.serialize_str(&distro_id.to_desc_str().to_string())
+}
+
 #[derive(Default)]
 pub struct ImageSpecBuilder {
   pub cuda: Option<CudaVersionV0>,
@@ -103,10 +123,15 @@ impl ImageSpecBuilder {
   }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+/// Serialized with the same descriptions as `to_desc`, so that the format
+/// does not depend on the wire protocol types.
+#[derive(Clone, PartialEq, Eq, Serialize, Debug)]
 pub struct ImageSpec {
+  #[serde(serialize_with = "serialize_cuda")]
   pub cuda: Option<CudaVersionV0>,
+  #[serde(serialize_with = "serialize_distro_codename")]
   pub distro_codename: DistroCodenameV0,
+  #[serde(serialize_with = "serialize_distro_id")]
   pub distro_id: DistroIdV0,
   pub docker: bool,
   pub nvidia_docker: bool,
@@ -462,6 +487,17 @@ impl ImageManifest {
     Ok(())
   }
 
+  /// Like `lookup_docker_image`, but never builds the image; also returns
+  /// whether the image was already built.
+  pub fn peek_docker_image(&self, lookup_image: &ImageSpec, root_manifest: &RootManifest) -> (DockerImage, bool) {
+    let built = self.imagespecs.iter().any(|image| image == lookup_image);
+    let docker_image = DockerImage{
+      imagespec: lookup_image.clone(),
+      hash_digest: lookup_image.to_hash_digest(root_manifest),
+    };
+    (docker_image, built)
+  }
+
   pub fn lookup_docker_image(&mut self, lookup_image: &ImageSpec, sysroot: &Sysroot, root_manifest: &RootManifest) -> Maybe<DockerImage> {
     // FIXME
     for image in self.imagespecs.iter() {
@@ -488,6 +524,7 @@ pub struct RootManifest {
   root_key_buf: CryptoBuf,
   auth_bit: bool,
   mach_reg_bit: bool,
+  drain_bit: bool,
 }
 
 impl RootManifest {
@@ -507,10 +544,16 @@ impl RootManifest {
       0x02 => true,
       _ => unreachable!(),
     };
+    let drain_bit = match flag_bits & 0x04 {
+      0    => false,
+      0x04 => true,
+      _ => unreachable!(),
+    };
     Ok(RootManifest{
       root_key_buf,
       auth_bit,
       mach_reg_bit,
+      drain_bit,
     })
   }
 
@@ -536,6 +579,7 @@ impl RootManifest {
       root_key_buf,
       auth_bit: false,
       mach_reg_bit: false,
+      drain_bit: false,
     })
   }
 
@@ -562,7 +606,11 @@ impl RootManifest {
       false => 0,
       true  => 0x02,
     };
-    let flag_bits = auth_mask | mach_reg_mask;
+    let drain_mask: u8 = match self.drain_bit {
+      false => 0,
+      true  => 0x04,
+    };
+    let flag_bits = auth_mask | mach_reg_mask | drain_mask;
     let manifest_path = sysroot.base_dir.join("root");
     let mut manifest_file = OpenOptions::new()
       .read(true).write(true)
@@ -597,8 +645,20 @@ impl RootManifest {
     self.write_flag_bits(sysroot)?;
     Ok(prev)
   }
+
+  pub fn drain_bit(&self) -> bool {
+    self.drain_bit
+  }
+
+  pub fn set_drain_bit(&mut self, bit: bool, sysroot: &Sysroot) -> Maybe<bool> {
+    let prev = self.drain_bit;
+    self.drain_bit = bit;
+    self.write_flag_bits(sysroot)?;
+    Ok(prev)
+  }
 }
 
+#[derive(Clone)]
 pub struct Sysroot {
   pub base_dir: PathBuf,
   pub sock_dir: PathBuf,
@@ -0,0 +1,281 @@
+use crate::docker::{MAX_SPEC_OUTPUT, DockerImage, GitCheckoutSpec, SpecError, TaskLimits, TaskSpec, _taskspecs};
+use crate::query::{Maybe, fail};
+use crate::state::{Sysroot};
+
+use serde_json::{Value as JsonValue};
+use url::{Url};
+
+use std::collections::{BTreeMap};
+use std::fs::{File, symlink_metadata};
+use std::io::{Read};
+use std::path::{Path, PathBuf};
+
+/// Names of the declarative taskspec files looked for in the root of a
+/// checkout, which are used instead of `gup.py` when present.
+pub const TASKFILE_NAMES: &'static [&'static str] = &["gup.toml", "gup.json"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskFileFormat {
+  Toml,
+  Json,
+}
+
+impl TaskFileFormat {
+  /// Format of a taskspec file by its extension, or `None` for anything
+  /// else (such as a `gup.py` script).
+  pub fn of_path(path: &Path) -> Option<TaskFileFormat> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("toml") => Some(TaskFileFormat::Toml),
+      Some("json") => Some(TaskFileFormat::Json),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TaskFile {
+  #[serde(default)]
+  mutable_cache: Vec<MutableCacheEntry>,
+  #[serde(default, alias = "tasks")]
+  task: Vec<TaskEntry>,
+}
+
+/// An entry of the mutable cache, as from `guppy.mutable_cache()`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct MutableCacheEntry {
+  path: Option<String>,
+  fetch_once: Option<String>,
+  copy_once: Option<String>,
+  symlink_once: Option<String>,
+  sha256: Option<String>,
+}
+
+/// A task, with the same fields and defaults as `guppy.task()`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TaskEntry {
+  name: String,
+  #[serde(default = "default_toolchain")]
+  toolchain: String,
+  #[serde(default = "default_true")]
+  require_docker: bool,
+  #[serde(default = "default_true")]
+  require_nvidia_docker: bool,
+  require_distro: Option<String>,
+  require_cuda: Option<String>,
+  require_gpu_arch: Option<String>,
+  #[serde(default)]
+  allow_errors: bool,
+  #[serde(default)]
+  retries: u32,
+  #[serde(default)]
+  retry_on: Vec<i32>,
+  #[serde(default)]
+  only_refs: Vec<String>,
+  #[serde(default)]
+  only_paths: Vec<String>,
+  #[serde(default)]
+  depends_on: Vec<String>,
+  #[serde(default)]
+  matrix: BTreeMap<String, Vec<String>>,
+  #[serde(default)]
+  matrix_exclude: Vec<BTreeMap<String, String>>,
+  #[serde(default)]
+  env: BTreeMap<String, String>,
+  #[serde(default)]
+  caches: BTreeMap<String, String>,
+  #[serde(default)]
+  limits: BTreeMap<String, JsonValue>,
+  #[serde(default)]
+  sh: Vec<String>,
+}
+
+fn default_toolchain() -> String {
+  "default".to_string()
+}
+
+fn default_true() -> bool {
+  true
+}
+
+impl MutableCacheEntry {
+  fn to_v1(&self) -> Maybe<JsonValue> {
+    let (op, src) = match (&self.fetch_once, &self.copy_once, &self.symlink_once) {
+      (&Some(ref src), &None, &None) => ("fetch_once", src),
+      (&None, &Some(ref src), &None) => ("copy_once", src),
+      (&None, &None, &Some(ref src)) => ("symlink_once", src),
+      _ => return Err(fail("expected exactly one of fetch_once, copy_once and symlink_once")),
+    };
+    let path = match self.path {
+      Some(ref path) => path.clone(),
+      None => {
+        // Like gup.py, default to the last component of the source.
+        let src_path = match op {
+          "fetch_once" => Url::parse(src)
+            .map(|url| url.path().to_string())
+            .map_err(|_| fail(format!("fetch_once: invalid url {:?}", src)))?,
+          _ => src.clone(),
+        };
+        match src_path.rsplit('/').next() {
+          Some(name) if !name.is_empty() => name.to_string(),
+          _ => return Err(fail(format!("could not infer a cache path from {:?}", src))),
+        }
+      }
+    };
+    Ok(serde_json::json!({
+      "kind": "mutable_cache",
+      "path": path,
+      "op": op,
+      "src": src,
+      "sha256": self.sha256,
+    }))
+  }
+}
+
+impl TaskEntry {
+  /// The task as gup.py would print it in the v1 protocol.
+  fn to_v1(&self) -> JsonValue {
+    let matrix: Vec<_> = self.matrix.iter().map(|(key, values)| {
+      serde_json::json!({"key": key, "values": values})
+    }).collect();
+    serde_json::json!({
+      "kind": "task",
+      "name": self.name,
+      "toolchain": self.toolchain,
+      "require_docker": self.require_docker,
+      "require_nvidia_docker": self.require_nvidia_docker,
+      "require_distro": self.require_distro,
+      "require_cuda": self.require_cuda,
+      "require_gpu_arch": self.require_gpu_arch,
+      "allow_errors": self.allow_errors,
+      "retries": self.retries,
+      "retry_on": self.retry_on,
+      "only_refs": self.only_refs,
+      "only_paths": self.only_paths,
+      "depends_on": self.depends_on,
+      "matrix": matrix,
+      "matrix_exclude": self.matrix_exclude,
+      "env": self.env,
+      "caches": self.caches,
+      "limits": self.limits,
+      "sh": self.sh,
+    })
+  }
+}
+
+/// Parses a declarative taskspec file of the checkout in `checkout_dir`. The
+/// tasks mean the same as those printed by a gup.py script, and go through
+/// the same checks.
+pub fn parse_taskfile(text: &str, format: TaskFileFormat, checkout_dir: Option<&Path>, sysroot: &Sysroot, dry_run: bool) -> Result<Vec<TaskSpec>, SpecError> {
+  let file: TaskFile = match format {
+    TaskFileFormat::Toml => toml::from_str(text)
+      .map_err(|e| fail(format!("gup.toml is not valid: {}", e)))?,
+    TaskFileFormat::Json => serde_json::from_str(text)
+      .map_err(|e| fail(format!("gup.json is not valid: {}", e)))?,
+  };
+  // Each line of v1 output goes with where it came from, for error messages;
+  // errors in tasks already name the task.
+  let mut lines: Vec<(Option<String>, JsonValue)> = Vec::new();
+  let mut errors = Vec::new();
+  lines.push((None, serde_json::json!({"kind": "protocol", "version": "v1"})));
+  for (entry_idx, entry) in file.mutable_cache.iter().enumerate() {
+    let context = format!("mutable_cache {}", entry_idx + 1);
+    match entry.to_v1() {
+      Err(e) => errors.push(format!("{}: {}", context, e.excuses.join(": "))),
+      Ok(line) => lines.push((Some(context), line)),
+    }
+  }
+  for task in file.task.iter() {
+    lines.push((None, task.to_v1()));
+  }
+  if errors.is_empty() {
+    let mut out = String::new();
+    for &(_, ref line) in lines.iter() {
+      out.push_str(&line.to_string());
+      out.push('\n');
+    }
+    match _taskspecs(out.as_bytes(), checkout_dir, sysroot, dry_run) {
+      Ok(tasks) => return Ok(tasks),
+      Err(e) => {
+        if e.errors.is_empty() {
+          errors.push(e.message);
+        }
+        for err in e.errors.iter() {
+          match lines.get((err.line_nr as usize).wrapping_sub(1)) {
+            Some(&(Some(ref context), _)) => errors.push(format!("{}: {}", context, err.message)),
+            _ => errors.push(err.message.clone()),
+          }
+        }
+      }
+    }
+  }
+  Err(SpecError{
+    message: errors.join("\n"),
+    line_nr: None,
+    errors: Vec::new(),
+  })
+}
+
+/// Loads a declarative taskspec file. The file must be a regular file, not
+/// a symlink, and at most `MAX_SPEC_OUTPUT` bytes long.
+pub fn load_taskfile(path: &Path, sysroot: &Sysroot, dry_run: bool) -> Result<Vec<TaskSpec>, SpecError> {
+  let format = TaskFileFormat::of_path(path)
+    .ok_or_else(|| fail(format!("not a taskspec file: {}", path.display())))?;
+  check_regular_file(path)?;
+  let mut buf = Vec::new();
+  File::open(path)
+    .and_then(|file| file.take(MAX_SPEC_OUTPUT + 1).read_to_end(&mut buf))
+    .map_err(|_| fail(format!("failed to read taskspec file: {}", path.display())))?;
+  if buf.len() as u64 > MAX_SPEC_OUTPUT {
+    return Err(fail(format!("taskspec: {} is more than {} bytes long", path.display(), MAX_SPEC_OUTPUT)).into());
+  }
+  let text = String::from_utf8(buf)
+    .map_err(|_| fail(format!("taskspec file is not valid utf-8: {}", path.display())))?;
+  parse_taskfile(&text, format, path.parent(), sysroot, dry_run)
+}
+
+fn check_regular_file(path: &Path) -> Maybe {
+  match symlink_metadata(path) {
+    Err(_) => Err(fail(format!("failed to read taskspec file: {}", path.display()))),
+    Ok(ref meta) if meta.file_type().is_file() => Ok(()),
+    Ok(_) => Err(fail(format!("taskspec file is not a regular file: {}", path.display()))),
+  }
+}
+
+/// Finds the declarative taskspec file in the root of a checkout, if there
+/// is one. Having more than one is an error, as is one which is not a
+/// regular file inside the checkout, such as a symlink.
+pub fn find_taskfile(dir: &Path) -> Maybe<Option<PathBuf>> {
+  let mut paths = Vec::new();
+  for name in TASKFILE_NAMES.iter() {
+    let path = dir.join(name);
+    if symlink_metadata(&path).is_err() {
+      continue;
+    }
+    check_regular_file(&path)?;
+    let inside = match (path.canonicalize(), dir.canonicalize()) {
+      (Ok(canonical_path), Ok(canonical_dir)) => canonical_path.starts_with(&canonical_dir),
+      _ => false,
+    };
+    if !inside {
+      return Err(fail(format!("taskspec file is outside the checkout: {}", path.display())));
+    }
+    paths.push(path);
+  }
+  if paths.len() > 1 {
+    return Err(fail(format!("found more than one of {}", TASKFILE_NAMES.join(", "))));
+  }
+  Ok(paths.into_iter().next())
+}
+/// Gets the taskspecs of a checkout, from its declarative taskspec file if
+/// it has one, or else by running its gup.py in the builtin image under
+/// `limits`. Also returns the output of gup.py, if it ran.
+pub fn checkout_taskspecs(builtin_image: &DockerImage, checkout: &GitCheckoutSpec, sysroot: &Sysroot, limits: &TaskLimits) -> (Vec<u8>, Result<Vec<TaskSpec>, SpecError>) {
+  match find_taskfile(checkout.dir.path()) {
+    Err(e) => (Vec::new(), Err(e.into())),
+    Ok(Some(path)) => (Vec::new(), load_taskfile(&path, sysroot, false)),
+    Ok(None) => builtin_image._run_spec(checkout, sysroot, limits),
+  }
+}
@@ -0,0 +1,418 @@
+use crate::config::{CiConfig, CiRepoConfig, Config, WebhookConfig};
+use crate::query::{Maybe, Open, fail};
+
+use openssl::hash::{MessageDigest};
+use openssl::pkey::{PKey};
+use openssl::sign::{Signer};
+use serde_json::{Value as JsonValue};
+
+use std::collections::{HashMap};
+use std::io::{Read, Write, BufRead, BufReader, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WebhookProvider {
+  GitHub,
+  Gitea,
+  GitLab,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WebhookEventKind {
+  Push,
+  PullRequest,
+}
+
+/// A push or pull request that should be run, from a repo in the ci config.
+#[derive(Clone, Debug)]
+pub struct WebhookEvent {
+  pub provider: WebhookProvider,
+  pub kind: WebhookEventKind,
+  /// The `remote_url` of the matching repo in the ci config, rather than any
+  /// url from the payload.
+  pub remote_url: String,
+  pub ref_full: String,
+  pub commit_hash: String,
+  /// Previous head of a pushed ref, unless the ref is new.
+  pub base_hash: Option<String>,
+  pub originator: Option<String>,
+}
+
+struct Request {
+  method: String,
+  headers: HashMap<String, String>,
+  body: Vec<u8>,
+}
+
+struct Response {
+  status: u16,
+  reason: &'static str,
+  body: String,
+}
+
+impl Response {
+  fn new<S: Into<String>>(status: u16, reason: &'static str, body: S) -> Response {
+    Response{status, reason, body: body.into()}
+  }
+}
+
+/// Reduces the forms of a repo url to `host/owner/repo`, so that e.g.
+/// `https://github.com/owner/repo.git` and `git@github.com:owner/repo`
+/// compare equal.
+pub fn normalize_repo_url(url: &str) -> String {
+  let mut s = url.trim();
+  let scp_like = !s.contains("://");
+  if let Some(pos) = s.find("://") {
+    s = &s[pos + 3 .. ];
+  }
+  if let Some(pos) = s.find('@') {
+    if s[ .. pos].find('/').is_none() {
+      s = &s[pos + 1 .. ];
+    }
+  }
+  let mut s = s.to_string();
+  if scp_like {
+    if let Some(pos) = s.find(':') {
+      s.replace_range(pos .. pos + 1, "/");
+    }
+  }
+  while s.ends_with('/') {
+    s.pop();
+  }
+  if s.ends_with(".git") {
+    let len = s.len();
+    s.truncate(len - 4);
+  }
+  s.to_lowercase()
+}
+
+fn hmac_sha256(secret: &[u8], data: &[u8]) -> Maybe<Vec<u8>> {
+  let key = PKey::hmac(secret)
+    .map_err(|_| fail("webhook: bad hmac key"))?;
+  let mut signer = Signer::new(MessageDigest::sha256(), &key)
+    .map_err(|_| fail("webhook: hmac failure"))?;
+  signer.update(data)
+    .map_err(|_| fail("webhook: hmac failure"))?;
+  signer.sign_to_vec()
+    .map_err(|_| fail("webhook: hmac failure"))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
+
+fn json_str<'a>(value: &'a JsonValue, path: &[&str]) -> Option<&'a str> {
+  let mut v = value;
+  for key in path.iter() {
+    v = v.get(key)?;
+  }
+  v.as_str()
+}
+
+fn is_zero_hash(hash: &str) -> bool {
+  hash.chars().all(|c| c == '0')
+}
+
+impl WebhookProvider {
+  fn detect(headers: &HashMap<String, String>) -> Option<(WebhookProvider, String)> {
+    // Gitea also sends the GitHub headers, so it goes first.
+    if let Some(event) = headers.get("x-gitea-event") {
+      return Some((WebhookProvider::Gitea, event.clone()));
+    }
+    if let Some(event) = headers.get("x-gitlab-event") {
+      return Some((WebhookProvider::GitLab, event.clone()));
+    }
+    if let Some(event) = headers.get("x-github-event") {
+      return Some((WebhookProvider::GitHub, event.clone()));
+    }
+    None
+  }
+
+  fn verify(&self, headers: &HashMap<String, String>, body: &[u8], secret: &str) -> bool {
+    match self {
+      &WebhookProvider::GitHub => {
+        let sig = match headers.get("x-hub-signature-256") {
+          Some(sig) if sig.starts_with("sha256=") => &sig[7 .. ],
+          _ => return false,
+        };
+        match (hex::decode(sig.trim()), hmac_sha256(secret.as_bytes(), body)) {
+          (Ok(sig), Ok(mac)) => constant_time_eq(&sig, &mac),
+          _ => false,
+        }
+      }
+      &WebhookProvider::Gitea => {
+        let sig = match headers.get("x-gitea-signature") {
+          None => return false,
+          Some(sig) => sig,
+        };
+        match (hex::decode(sig.trim()), hmac_sha256(secret.as_bytes(), body)) {
+          (Ok(sig), Ok(mac)) => constant_time_eq(&sig, &mac),
+          _ => false,
+        }
+      }
+      &WebhookProvider::GitLab => {
+        // GitLab sends the secret itself rather than a signature.
+        match headers.get("x-gitlab-token") {
+          None => false,
+          Some(token) => constant_time_eq(token.as_bytes(), secret.as_bytes()),
+        }
+      }
+    }
+  }
+
+  fn repo_urls<'a>(&self, payload: &'a JsonValue) -> Vec<&'a str> {
+    let paths: &[&[&str]] = match self {
+      &WebhookProvider::GitHub |
+      &WebhookProvider::Gitea => &[
+        &["repository", "clone_url"],
+        &["repository", "ssh_url"],
+        &["repository", "html_url"],
+      ],
+      &WebhookProvider::GitLab => &[
+        &["project", "git_http_url"],
+        &["project", "git_ssh_url"],
+        &["project", "web_url"],
+      ],
+    };
+    paths.iter().filter_map(|path| json_str(payload, path)).collect()
+  }
+
+  /// Returns the event if it should be run, without its `remote_url`, or
+  /// `None` for events that should be ignored.
+  fn parse(&self, event: &str, payload: &JsonValue) -> Option<WebhookEvent> {
+    let (kind, ref_full, commit_hash, base_hash, originator) = self._parse(event, payload)?;
+    Some(WebhookEvent{
+      provider: *self,
+      kind,
+      remote_url: String::new(),
+      ref_full,
+      commit_hash: commit_hash.to_string(),
+      base_hash: base_hash.filter(|h| !is_zero_hash(h)).map(|h| h.to_string()),
+      originator: originator.map(|s| s.to_string()),
+    })
+  }
+
+  fn _parse<'a>(&self, event: &str, payload: &'a JsonValue) -> Option<(WebhookEventKind, String, &'a str, Option<&'a str>, Option<&'a str>)> {
+    match (self, event) {
+      (&WebhookProvider::GitHub, "push") |
+      (&WebhookProvider::Gitea, "push") => {
+        let ref_full = json_str(payload, &["ref"])?;
+        let commit_hash = json_str(payload, &["after"])?;
+        if is_zero_hash(commit_hash) {
+          // Branch or tag deletion.
+          return None;
+        }
+        let originator = json_str(payload, &["sender", "login"])
+          .or_else(|| json_str(payload, &["pusher", "name"]));
+        Some((WebhookEventKind::Push, ref_full.to_string(), commit_hash, json_str(payload, &["before"]), originator))
+      }
+      (&WebhookProvider::GitLab, "Push Hook") |
+      (&WebhookProvider::GitLab, "Tag Push Hook") => {
+        let ref_full = json_str(payload, &["ref"])?;
+        let commit_hash = json_str(payload, &["after"])?;
+        if is_zero_hash(commit_hash) {
+          return None;
+        }
+        let originator = json_str(payload, &["user_username"]);
+        Some((WebhookEventKind::Push, ref_full.to_string(), commit_hash, json_str(payload, &["before"]), originator))
+      }
+      (&WebhookProvider::GitHub, "pull_request") |
+      (&WebhookProvider::Gitea, "pull_request") => {
+        match json_str(payload, &["action"])? {
+          "opened" | "reopened" | "synchronize" | "synchronized" => {}
+          _ => return None,
+        }
+        let pr_nr = payload.get("number")?.as_u64()?;
+        let commit_hash = json_str(payload, &["pull_request", "head", "sha"])?;
+        let originator = json_str(payload, &["sender", "login"])
+          .or_else(|| json_str(payload, &["pull_request", "user", "login"]));
+        Some((WebhookEventKind::PullRequest, format!("refs/pull/{}/head", pr_nr), commit_hash, None, originator))
+      }
+      (&WebhookProvider::GitLab, "Merge Request Hook") => {
+        let attrs = payload.get("object_attributes")?;
+        match json_str(attrs, &["action"])? {
+          "open" | "reopen" => {}
+          // Updates without an `oldrev` only touch the title, labels, etc.
+          "update" if attrs.get("oldrev").is_some() => {}
+          _ => return None,
+        }
+        let mr_iid = attrs.get("iid")?.as_u64()?;
+        let commit_hash = json_str(attrs, &["last_commit", "id"])?;
+        let originator = json_str(payload, &["user", "username"]);
+        Some((WebhookEventKind::PullRequest, format!("refs/merge-requests/{}/head", mr_iid), commit_hash, None, originator))
+      }
+      _ => None,
+    }
+  }
+}
+
+/// Minimal HTTP listener for repository webhooks.
+///
+/// Requests are handled one at a time, and each must arrive in full within
+/// `REQUEST_TIMEOUT_SECS` of being accepted, so that a slow client cannot
+/// hold up the deliveries behind it. The ci config is read for each
+/// request, so repos and secrets can be changed without restarting the
+/// daemon; changing the listen address does need a restart.
+pub struct WebhookServer {
+  listener: TcpListener,
+  cfg: WebhookConfig,
+  config: Config,
+}
+
+impl WebhookServer {
+  pub fn bind(cfg: &WebhookConfig, config: Config) -> Maybe<WebhookServer> {
+    let listener = TcpListener::bind(&cfg.listen_addr)
+      .map_err(|e| fail(format!("webhook: failed to listen on {}: {:?}", cfg.listen_addr, e)))?;
+    Ok(WebhookServer{
+      listener,
+      cfg: cfg.clone(),
+      config,
+    })
+  }
+
+  pub fn serve<F: FnMut(WebhookEvent)>(&self, mut on_event: F) {
+    for stream in self.listener.incoming() {
+      let mut stream = match stream {
+        Err(_) => continue,
+        Ok(stream) => stream,
+      };
+      stream.set_write_timeout(Some(Duration::from_secs(10))).ok();
+      let rep = match self.read_request(&mut stream) {
+        Err(rep) => rep,
+        Ok(req) => self.handle(req, &mut on_event),
+      };
+      eprintln!("TRACE: guppybot: webhook: {} {}", rep.status, rep.body);
+      write!(&mut stream,
+          "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+          rep.status, rep.reason, rep.body.len(), rep.body).ok();
+    }
+  }
+
+  fn read_request(&self, stream: &mut TcpStream) -> Result<Request, Response> {
+    let mut reader = BufReader::new(DeadlineReader{
+      stream,
+      deadline: Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS),
+    });
+    let mut header_bytes = 0;
+    let mut lines = Vec::new();
+    loop {
+      let mut line = String::new();
+      match reader.read_line(&mut line) {
+        Err(ref e) if is_timeout(e) => return Err(Response::new(408, "Request Timeout", "request timed out")),
+        Err(_) | Ok(0) => return Err(Response::new(400, "Bad Request", "incomplete request")),
+        Ok(n) => header_bytes += n,
+      }
+      if header_bytes > MAX_HEADER_BYTES {
+        return Err(Response::new(431, "Request Header Fields Too Large", "headers too large"));
+      }
+      let line = line.trim_end_matches(|c| c == '\r' || c == '\n').to_string();
+      if line.is_empty() {
+        break;
+      }
+      lines.push(line);
+    }
+    if lines.is_empty() {
+      return Err(Response::new(400, "Bad Request", "missing request line"));
+    }
+    let method = lines[0].split(' ').next().unwrap_or("").to_string();
+    let mut headers = HashMap::new();
+    for line in lines[1 .. ].iter() {
+      let pos = match line.find(':') {
+        None => return Err(Response::new(400, "Bad Request", "bad header")),
+        Some(pos) => pos,
+      };
+      headers.insert(line[ .. pos].trim().to_lowercase(), line[pos + 1 .. ].trim().to_string());
+    }
+    if method != "POST" {
+      return Ok(Request{method, headers, body: Vec::new()});
+    }
+    if headers.contains_key("transfer-encoding") {
+      return Err(Response::new(411, "Length Required", "chunked requests are not supported"));
+    }
+    let body_len: u64 = match headers.get("content-length").and_then(|s| s.parse().ok()) {
+      None => return Err(Response::new(411, "Length Required", "missing content length")),
+      Some(len) => len,
+    };
+    if body_len > self.cfg.max_body_bytes {
+      return Err(Response::new(413, "Payload Too Large", "payload too large"));
+    }
+    let mut body = Vec::with_capacity(body_len as usize);
+    match reader.take(body_len).read_to_end(&mut body) {
+      Ok(n) if n as u64 == body_len => {}
+      Err(ref e) if is_timeout(e) => return Err(Response::new(408, "Request Timeout", "request timed out")),
+      _ => return Err(Response::new(400, "Bad Request", "incomplete body")),
+    }
+    Ok(Request{method, headers, body})
+  }
+
+  fn handle<F: FnMut(WebhookEvent)>(&self, req: Request, on_event: &mut F) -> Response {
+    if req.method != "POST" {
+      return Response::new(405, "Method Not Allowed", "only POST is supported");
+    }
+    let (provider, event) = match WebhookProvider::detect(&req.headers) {
+      None => return Response::new(400, "Bad Request", "unknown webhook provider"),
+      Some(x) => x,
+    };
+    match req.headers.get("content-type") {
+      Some(ty) if ty.starts_with("application/json") => {}
+      _ => return Response::new(415, "Unsupported Media Type", "payload must be application/json"),
+    }
+    let payload: JsonValue = match serde_json::from_slice(&req.body) {
+      Err(_) => return Response::new(400, "Bad Request", "payload is not valid json"),
+      Ok(payload) => payload,
+    };
+    let ci_cfg = match CiConfig::open(&self.config) {
+      Err(e) => {
+        eprintln!("TRACE: guppybot: webhook: failed to load ci config: {:?}", e);
+        return Response::new(500, "Internal Server Error", "failed to load ci config");
+      }
+      Ok(cfg) => cfg,
+    };
+    let repo: &CiRepoConfig = match provider.repo_urls(&payload).into_iter().filter_map(|url| ci_cfg.find_repo(url)).next() {
+      None => return Response::new(404, "Not Found", "repository is not configured"),
+      Some(repo) => repo,
+    };
+    let secret = match repo.webhook_secret {
+      None => return Response::new(403, "Forbidden", "repository has no webhook secret"),
+      Some(ref secret) => secret,
+    };
+    if !provider.verify(&req.headers, &req.body, secret) {
+      return Response::new(401, "Unauthorized", "bad signature");
+    }
+    match provider.parse(&event, &payload) {
+      None => Response::new(202, "Accepted", format!("ignored: {}", event)),
+      Some(event) => {
+        on_event(WebhookEvent{
+          remote_url: repo.remote_url.clone(),
+          ..event
+        });
+        Response::new(202, "Accepted", "queued")
+      }
+    }
+  }
+}
+
+/// Reads from a stream until a deadline, however the reads are spread out.
+struct DeadlineReader<'a> {
+  stream: &'a mut TcpStream,
+  deadline: Instant,
+}
+
+impl<'a> Read for DeadlineReader<'a> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let now = Instant::now();
+    if now >= self.deadline {
+      return Err(ErrorKind::TimedOut.into());
+    }
+    self.stream.set_read_timeout(Some(self.deadline - now))?;
+    self.stream.read(buf)
+  }
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+  e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock
+}
@@ -0,0 +1,117 @@
+use crate::query::{Maybe, fail};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use monosodium::{auth_sign, auth_verify};
+use schemas::{Revise, deserialize_revision, serialize_revision_into};
+use serde::{Deserialize, Serialize};
+
+use std::io::{Cursor};
+
+/// Why a CI run was turned down before any of its tasks started.
+///
+/// The daemon replies to a rejected `_NewCiRun` with `failed_early` set, then
+/// attaches this as JSON under the "Reason" key of task 0.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NewCiRunReject {
+  NotAccepting{
+    message: String,
+  },
+  Policy{
+    message: String,
+  },
+  Internal{
+    message: String,
+  },
+  Checkout{
+    message: String,
+  },
+  Taskspec{
+    message: String,
+    line_nr: Option<u64>,
+  },
+  NoImage{
+    task_nr: u64,
+    task_name: String,
+  },
+  NoGpu{
+    task_nr: u64,
+    task_name: String,
+    require_gpu_arch: String,
+  },
+}
+
+impl NewCiRunReject {
+  pub fn to_json(&self) -> Vec<u8> {
+    serde_json::to_vec(self).unwrap_or_else(|_| b"{}".to_vec())
+  }
+}
+
+/// Signs a registry message. The wire format is the 32-byte signature,
+/// followed by the little-endian length of the serialized message as a u32,
+/// followed by the serialized message; the signature covers everything after
+/// itself.
+pub fn sign_message<'a, T: Revise<'a> + Serialize>(secret_token: &[u8], msg: &'a T) -> Maybe<Vec<u8>> {
+  sign_message_with_id(secret_token, msg, None)
+}
+
+/// Signs a registry message like `sign_message`, followed by the message id
+/// as a little-endian u64, if there is one. The id stays the same when the
+/// message is sent again, e.g. after a reconnect, so that the receiver can
+/// drop repeats; ids of later messages are greater.
+pub fn sign_message_with_id<'a, T: Revise<'a> + Serialize>(secret_token: &[u8], msg: &'a T, msg_id: Option<u64>) -> Maybe<Vec<u8>> {
+  let mut bin: Vec<u8> = Vec::with_capacity(64);
+  bin.resize(36, 0_u8);
+  assert_eq!(36, bin.len());
+  serialize_revision_into(&mut bin, msg)
+    .map_err(|_| fail("API message serialization failure"))?;
+  assert!(36 <= bin.len());
+  let msg_bin_len = bin.len() - 36;
+  assert!(msg_bin_len <= u32::max_value() as usize);
+  Cursor::new(&mut bin[32 .. 36])
+    .write_u32::<LittleEndian>(msg_bin_len as u32).unwrap();
+  if let Some(msg_id) = msg_id {
+    bin.write_u64::<LittleEndian>(msg_id).unwrap();
+  }
+  let (sig_buf, payload_buf) = bin.split_at_mut(32);
+  auth_sign(
+      sig_buf,
+      payload_buf,
+      secret_token,
+  )
+    .map_err(|_| fail("API message signing failure"))?;
+  Ok(bin)
+}
+
+/// Verifies and deserializes a registry message signed by `sign_message` or
+/// `sign_message_with_id`, dropping any message id.
+pub fn verify_message<'a, T: Revise<'a> + Deserialize<'a>>(secret_token: &[u8], bin: &'a [u8]) -> Maybe<T> {
+  verify_message_with_id(secret_token, bin).map(|(msg, _)| msg)
+}
+
+/// Verifies and deserializes a registry message, along with its message id
+/// if it was signed with one.
+pub fn verify_message_with_id<'a, T: Revise<'a> + Deserialize<'a>>(secret_token: &[u8], bin: &'a [u8]) -> Maybe<(T, Option<u64>)> {
+  if bin.len() < 36 {
+    return Err(fail("API message protocol failure"));
+  }
+  auth_verify(
+      &bin[0 .. 32],
+      &bin[32 .. ],
+      secret_token,
+  )
+    .map_err(|_| fail("API message verification failure"))?;
+  let msg_bin_len = Cursor::new(&bin[32 .. 36])
+    .read_u32::<LittleEndian>().unwrap() as usize;
+  let msg_id = match bin[36 .. ].len().checked_sub(msg_bin_len) {
+    Some(0) => None,
+    Some(8) => {
+      Some(Cursor::new(&bin[36 + msg_bin_len .. ])
+        .read_u64::<LittleEndian>().unwrap())
+    }
+    _ => return Err(fail("API message self-consistency failure")),
+  };
+  let msg: T = deserialize_revision(&bin[36 .. 36 + msg_bin_len])
+    .map_err(|_| fail("API message deserialization failure"))?;
+  Ok((msg, msg_id))
+}
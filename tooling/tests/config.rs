@@ -0,0 +1,88 @@
+extern crate tempfile;
+extern crate tooling;
+
+use tempfile::{TempDir};
+use tooling::config::{CiConfig, Config, MutableCacheConfig, trusts_run};
+use tooling::query::{Open};
+
+use std::fs;
+use std::os::unix::fs::{PermissionsExt};
+
+fn config_with_ci(tmp_dir: &TempDir, ci: Option<&str>) -> Config {
+  if let Some(ci) = ci {
+    fs::write(tmp_dir.path().join("ci"), ci).unwrap();
+  }
+  Config::with_dir(tmp_dir.path().to_path_buf())
+}
+
+#[test]
+fn missing_ci_config_is_none() {
+  let tmp_dir = TempDir::new().unwrap();
+  let config = config_with_ci(&tmp_dir, None);
+  assert!(CiConfig::open_optional(&config).unwrap().is_none());
+}
+
+#[test]
+fn valid_ci_config_is_some() {
+  let tmp_dir = TempDir::new().unwrap();
+  let config = config_with_ci(&tmp_dir, Some("[[repos]]
+remote_url = \"https://github.com/owner/repo\"
+pr_policy = \"allowed_users\"
+"));
+  let ci_cfg = CiConfig::open_optional(&config).unwrap().unwrap();
+  assert_eq!(ci_cfg.repos.len(), 1);
+}
+
+#[test]
+fn invalid_ci_config_is_an_error() {
+  let invalid = &[
+    "[[repos]\n",
+    "[[repos]]\npr_policy = \"allowed_users\"\n",
+    "[[repos]]\nremote_url = \"https://github.com/owner/repo\"\npr_policy = \"sometimes\"\n",
+  ];
+  for ci in invalid.iter() {
+    let tmp_dir = TempDir::new().unwrap();
+    let config = config_with_ci(&tmp_dir, Some(ci));
+    assert!(CiConfig::open_optional(&config).is_err(), "accepted {:?}", ci);
+  }
+}
+
+#[test]
+fn open_ci_config_with_secrets_is_an_error() {
+  let tmp_dir = TempDir::new().unwrap();
+  let config = config_with_ci(&tmp_dir, Some("[[repos]]
+remote_url = \"https://github.com/owner/repo\"
+webhook_secret = \"hunter2\"
+"));
+  fs::set_permissions(tmp_dir.path().join("ci"), fs::Permissions::from_mode(0o644)).unwrap();
+  assert!(CiConfig::open_optional(&config).is_err());
+}
+
+#[test]
+fn missing_machine_config_is_the_default() {
+  let tmp_dir = TempDir::new().unwrap();
+  let config = Config::with_dir(tmp_dir.path().to_path_buf());
+  assert_eq!(MutableCacheConfig::open(&config).unwrap().max_size, None);
+}
+
+#[test]
+fn invalid_machine_config_is_an_error() {
+  let invalid = &[
+    "[mutable_cache\n",
+    "[mutable_cache]\nmax_size = \"lots\"\n",
+  ];
+  for machine in invalid.iter() {
+    let tmp_dir = TempDir::new().unwrap();
+    fs::write(tmp_dir.path().join("machine"), machine).unwrap();
+    let config = Config::with_dir(tmp_dir.path().to_path_buf());
+    assert!(MutableCacheConfig::open(&config).is_err(), "accepted {:?}", machine);
+  }
+}
+
+#[test]
+fn runs_of_unknown_refs_are_not_trusted() {
+  let remote_url = "https://github.com/owner/repo";
+  assert!(trusts_run(None, remote_url, Some("refs/heads/master"), None));
+  assert!(!trusts_run(None, remote_url, Some("refs/pull/1/head"), Some("owner")));
+  assert!(!trusts_run(None, remote_url, None, None));
+}
@@ -0,0 +1,56 @@
+extern crate tooling;
+
+use tooling::glob::{glob_match};
+
+use std::time::{Duration, Instant};
+
+/// `(pattern, name, matches)`.
+const CASES: &'static [(&'static str, &'static str, bool)] = &[
+  ("refs/heads/master", "refs/heads/master", true),
+  ("refs/heads/master", "refs/heads/master2", false),
+  ("refs/heads/*", "refs/heads/master", true),
+  ("refs/heads/*", "refs/heads/release/1.0", false),
+  ("refs/heads/release/*", "refs/heads/release/1.0", true),
+  ("refs/tags/v?.0", "refs/tags/v1.0", true),
+  ("refs/tags/v?.0", "refs/tags/v10.0", false),
+  ("a?b", "a/b", false),
+  ("src/**", "src/a/b.rs", true),
+  ("src/**", "src/", true),
+  ("src/**", "src", false),
+  ("**/*.md", "README.md", true),
+  ("**/*.md", "docs/guide/intro.md", true),
+  ("**/*.md", "docs/guide/intro.mdx", false),
+  ("docs/**/index.html", "docs/index.html", true),
+  ("docs/**/index.html", "docs/a/b/index.html", true),
+  ("docs/**/index.html", "docsindex.html", false),
+  ("docs/", "docs/a/b.txt", true),
+  ("docs/", "docsx/b.txt", false),
+  ("a**b", "axx/yyb", true),
+  ("*.rs", "src/main.rs", false),
+  ("*", "", true),
+  ("", "", true),
+  ("", "a", false),
+];
+
+#[test]
+fn glob_cases() {
+  for &(pattern, name, expected) in CASES.iter() {
+    assert_eq!(glob_match(pattern, name), expected, "{:?} against {:?}", pattern, name);
+  }
+}
+
+#[test]
+fn glob_pathological_patterns_are_fast() {
+  let name = "a".repeat(4096);
+  let patterns = vec![
+    format!("{}b", "*a".repeat(64)),
+    format!("{}b", "**a".repeat(64)),
+    format!("{}b", "**/a".repeat(64)),
+  ];
+  let start = Instant::now();
+  for pattern in patterns.iter() {
+    assert!(!glob_match(pattern, &name));
+  }
+  assert!(start.elapsed() < Duration::from_secs(10));
+  assert!(glob_match(&"*a".repeat(64), &name));
+}
@@ -0,0 +1,512 @@
+extern crate tempfile;
+extern crate tooling;
+
+use tempfile::{TempDir};
+use tooling::cache::{CacheIndex, build_cache_mounts, file_sha256, prepare_build_caches, set_mutable_cache_quota};
+use tooling::docker::{MAX_SPEC_OUTPUT, TaskLimits, _taskspecs};
+use tooling::state::{Sysroot};
+use tooling::taskfile::{find_taskfile, load_taskfile};
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener};
+use std::path::{Path};
+use std::thread;
+
+/// Outputs of gup.py that should parse.
+const VALID: &'static [&'static str] = &[
+  "",
+  "#-guppy:v0.task:begin
+#-guppy:v0.task:name test
+#-guppy:v0.task:toolchain python3
+#-guppy:v0.task:require_docker true
+#-guppy:v0.task:require_nvidia_docker false
+#-guppy:v0.task:require_distro ubuntu ==xenial
+#-guppy:v0.task:allow_errors false
+#-guppy:v0.task:cache pip /root/.cache/pip
+#-guppy:v0.task:cache ccache /ccache/
+#-guppy:v0.task:limits cpus=8 memory=32g shm=8g
+echo hello
+#-guppy:v0.task:end
+",
+  "#-guppy:v0.mutable_cache:append data/link.txt symlink_once data/file.txt
+#-guppy:v0.task:begin
+#-guppy:v0.task:name build with  spaces
+#-guppy:v0.task:require_docker true
+#-guppy:v0.task:require_nvidia_docker true
+#-guppy:v0.task:require_distro ubuntu >=16.04
+#-guppy:v0.task:require_cuda >=9.0
+#-guppy:v0.task:require_gpu_arch >=sm_60
+#-guppy:v0.task:retries 2
+#-guppy:v0.task:retry_on 1 137
+#-guppy:v0.task:only_refs refs/heads/*
+#-guppy:v0.task:only_paths src/**
+make
+#-guppy:v0.task:end
+#-guppy:v0.task:begin
+#-guppy:v0.task:name test
+#-guppy:v0.task:require_docker true
+#-guppy:v0.task:require_nvidia_docker true
+#-guppy:v0.task:require_distro ubuntu ==bionic
+#-guppy:v0.task:depends_on build with  spaces
+#-guppy:v0.task:matrix cuda=9.0,10.0 mode=debug,release
+#-guppy:v0.task:matrix_exclude cuda=9.0 mode=release
+make test
+#-guppy:v0.task:end
+",
+  r#"{"kind": "protocol", "version": "v1"}
+{"kind": "task", "name": "multi line", "require_distro": "ubuntu ==xenial", "env": {"A_B": "x y"}, "limits": {"cpus": 0.5, "pids": 512}, "sh": ["for i in 1 2; do\n  echo \"$i\"\ndone", "echo '#-guppy:v0.task:end'"]}
+{"kind": "task", "name": "test", "toolchain": "default", "require_distro": "ubuntu ==bionic", "depends_on": ["multi line"], "matrix": [{"key": "mode", "values": ["debug", "release"]}]}
+"#,
+];
+
+/// Outputs of gup.py that should not parse, with the line numbers of every
+/// error expected to be reported.
+const INVALID: &'static [(&'static [u8], &'static [u64])] = &[
+  (b"echo outside\n", &[1]),
+  (b"#-guppy:task:begin\n", &[1]),
+  (b"#-guppy:v0.task\n", &[1]),
+  (b"#-guppy:v0.task:\n", &[1]),
+  (b"#-guppy:v0.mutable_cache\n", &[1]),
+  (b"#-guppy:v0.mutable_cache:append\n", &[1]),
+  (b"#-guppy:v0.mutable_cache:append ../escape copy_once /x\n", &[1]),
+  (b"#-guppy:v0.mutable_cache:append data copy_once /etc/passwd\n", &[1]),
+  (b"#-guppy:v0.mutable_cache:append data frob_once x\n", &[1]),
+  (b"#-guppy:v0.mutable_cache:append data copy_once x sha256=abc\n", &[1]),
+  (b"#-guppy:v0.mutable_cache:append data symlink_once data\n", &[1]),
+  (b"#-guppy:v0.nonsense:begin\n", &[1]),
+  (b"#-guppy:v0.task:end\n", &[1]),
+  (b"#-guppy:v0.task:name orphan\n", &[1]),
+  (b"#-guppy:v0.task:begin\n", &[1]),
+  (b"#-guppy:v0.task:begin\n#-guppy:v0.task:name\n#-guppy:v0.task:end\n", &[2, 3]),
+  (b"#-guppy:v0.task:begin\n#-guppy:v0.task:begin\n#-guppy:v0.task:require_distro ubuntu xenial\n#-guppy:v0.task:end\n", &[2]),
+  (b"#-guppy:v0.task:begin
+#-guppy:v0.task:name a
+#-guppy:v0.task:require_docker maybe
+#-guppy:v0.task:require_distro ubuntu ==warty
+#-guppy:v0.task:require_cuda 3.0
+#-guppy:v0.task:require_gpu_arch sm_
+#-guppy:v0.task:retries -1
+#-guppy:v0.task:retry_on 0
+#-guppy:v0.task:matrix novalue
+#-guppy:v0.task:frobnicate
+#-guppy:v0.task:end
+", &[3, 4, 5, 6, 7, 8, 9, 10, 11]),
+  (b"#-guppy:v0.task:begin
+#-guppy:v0.task:name a
+#-guppy:v0.task:require_distro ubuntu ==xenial
+#-guppy:v0.task:matrix_exclude cuda=9.0
+#-guppy:v0.task:end
+", &[5]),
+  (b"#-guppy:v0.task:begin
+#-guppy:v0.task:name a
+#-guppy:v0.task:require_distro ubuntu ==xenial
+#-guppy:v0.task:cache pip
+#-guppy:v0.task:cache pip relative/path
+#-guppy:v0.task:cache .hidden /cache
+#-guppy:v0.task:cache work /work/target
+#-guppy:v0.task:cache root /
+#-guppy:v0.task:cache up /cache/../etc
+#-guppy:v0.task:cache pip /root/.cache/pip
+#-guppy:v0.task:cache pip /root/.cache/pip2
+#-guppy:v0.task:cache pip2 /root/.cache/pip
+#-guppy:v0.task:end
+", &[4, 5, 6, 7, 8, 9, 11, 12]),
+  (b"#-guppy:v0.task:begin
+#-guppy:v0.task:name a
+#-guppy:v0.task:require_distro ubuntu ==xenial
+#-guppy:v0.task:limits
+#-guppy:v0.task:limits cpus
+#-guppy:v0.task:limits cpus=0
+#-guppy:v0.task:limits memory=lots
+#-guppy:v0.task:limits pids=-1
+#-guppy:v0.task:limits gpus=1
+#-guppy:v0.task:limits cpus=2 shm=1g
+#-guppy:v0.task:end
+", &[4, 5, 6, 7, 8, 9]),
+  (b"#-guppy:v0.task:begin\r\n#-guppy:v0.task:name a\r\n\xff\r\n#-guppy:v0.task:end\r\n", &[3, 4]),
+  (b"{\"kind\": \"task\", \"name\": \"a\", \"require_distro\": \"ubuntu ==xenial\"}\n", &[1]),
+  (b"{\"kind\": \"protocol\", \"version\": \"v2\"}\n", &[1]),
+  (br#"{"kind": "protocol", "version": "v1"}
+#-guppy:v0.task:begin
+{"kind": "task", "name": "a", "require_distro": "ubuntu ==warty"}
+{"kind": "task", "name": "b", "require_distro": "ubuntu ==xenial", "env": {"1X": "y"}}
+{"kind": "task", "name": "c", "require_distro": "ubuntu ==xenial", "frobnicate": true}
+{"kind": "protocol", "version": "v1"}
+"#, &[2, 3, 4, 5, 6]),
+  (br#"{"kind": "protocol", "version": "v1"}
+{"kind": "task", "name": "a", "require_distro": "ubuntu ==xenial", "env": {"CI": "0"}}
+{"kind": "task", "name": "b", "require_distro": "ubuntu ==xenial", "env": {"NVIDIA_VISIBLE_DEVICES": "all"}}
+{"kind": "task", "name": "c", "require_distro": "ubuntu ==xenial", "env": {"GUPPY_MATRIX_MODE": "debug"}}
+{"kind": "task", "name": "d", "require_distro": "ubuntu ==xenial", "env": {"CIRCLECI": "1", "NVIDIA": "1"}}
+"#, &[2, 3, 4]),
+  (b"#-guppy:v0.task:begin
+#-guppy:v0.task:name a
+#-guppy:v0.task:require_distro ubuntu ==xenial
+#-guppy:v0.task:matrix k=0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31,32,33,34,35,36,37,38,39,40,41,42,43,44,45,46,47,48,49,50,51,52,53,54,55,56,57,58,59,60,61,62,63,64
+#-guppy:v0.task:matrix x=0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19 y=0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19
+#-guppy:v0.task:end
+#-guppy:v0.task:begin
+#-guppy:v0.task:name b
+#-guppy:v0.task:require_distro ubuntu ==xenial
+#-guppy:v0.task:matrix a=1 b=1 c=1 d=1 e=1 f=1 g=1 h=1 i=1
+#-guppy:v0.task:end
+", &[4, 6, 10]),
+];
+
+fn test_sysroot(tmp_dir: &TempDir) -> Sysroot {
+  Sysroot{
+    base_dir: tmp_dir.path().to_path_buf(),
+    sock_dir: tmp_dir.path().to_path_buf(),
+  }
+}
+
+/// Answers the next `count` HTTP requests on a local port with `response`,
+/// and returns a URL on that port.
+fn serve_http(response: &'static [u8], count: usize) -> String {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}/data.bin", listener.local_addr().unwrap());
+  thread::spawn(move || {
+    for stream in listener.incoming().take(count) {
+      let mut stream = stream.unwrap();
+      let mut request = Vec::new();
+      let mut buf = [0; 1024];
+      while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf).unwrap() {
+          0 => break,
+          n => request.extend_from_slice(&buf[ .. n]),
+        }
+      }
+      stream.write_all(response).unwrap();
+    }
+  });
+  url
+}
+
+const DATA_RESPONSE: &'static [u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndata";
+
+#[test]
+fn valid_corpus_parses() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  for out in VALID.iter() {
+    if let Err(e) = _taskspecs(out.as_bytes(), None, &sysroot, false) {
+      panic!("failed to parse {:?}: {}", out, e.message);
+    }
+  }
+}
+
+#[test]
+fn valid_corpus_expands_matrix() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let tasks = _taskspecs(VALID[2].as_bytes(), None, &sysroot, false).unwrap();
+  let names: Vec<_> = tasks.iter().map(|task| task.name.as_str()).collect();
+  assert_eq!(names, vec![
+    "build with  spaces",
+    "test [cuda=9.0, mode=debug]",
+    "test [cuda=10.0, mode=debug]",
+    "test [cuda=10.0, mode=release]",
+  ]);
+}
+
+#[test]
+fn v1_keeps_multi_line_scripts_and_env() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let tasks = _taskspecs(VALID[3].as_bytes(), None, &sysroot, false).unwrap();
+  assert_eq!(tasks.len(), 3);
+  assert_eq!(tasks[0].sh, vec!["for i in 1 2; do\n  echo \"$i\"\ndone", "echo '#-guppy:v0.task:end'"]);
+  assert_eq!(tasks[0].task_env(), vec!["A_B=x y"]);
+  assert_eq!(tasks[2].task_env(), vec!["GUPPY_MATRIX_MODE=release"]);
+}
+
+#[test]
+fn task_limits_are_capped_by_machine_policy() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let tasks = _taskspecs(VALID[1].as_bytes(), None, &sysroot, false).unwrap();
+  assert_eq!(tasks[0].limits, TaskLimits{
+    cpus: Some(8.0),
+    memory: Some(32 << 30),
+    pids: None,
+    shm: Some(8 << 30),
+  });
+  let defaults = TaskLimits{cpus: Some(2.0), pids: Some(4096), .. TaskLimits::default()};
+  let maxima = TaskLimits{cpus: Some(4.0), memory: Some(16 << 30), .. TaskLimits::default()};
+  assert_eq!(tasks[0].limits.under_policy(&defaults, &maxima), TaskLimits{
+    cpus: Some(4.0),
+    memory: Some(16 << 30),
+    pids: Some(4096),
+    shm: Some(8 << 30),
+  });
+  let tasks = _taskspecs(VALID[3].as_bytes(), None, &sysroot, false).unwrap();
+  assert_eq!(tasks[0].limits, TaskLimits{cpus: Some(0.5), pids: Some(512), .. TaskLimits::default()});
+  assert_eq!(tasks[1].limits, TaskLimits::default());
+}
+
+#[test]
+fn build_caches_are_per_repo_and_toolchain() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let tasks = _taskspecs(VALID[1].as_bytes(), None, &sysroot, false).unwrap();
+  let paths: Vec<_> = tasks[0].caches.iter().map(|cache| cache.path.as_str()).collect();
+  assert_eq!(paths, vec!["/root/.cache/pip", "/ccache"]);
+  let https_mounts = build_cache_mounts(&tasks[0], "https://github.com/Owner/Repo.git", true, &sysroot);
+  let ssh_mounts = build_cache_mounts(&tasks[0], "git@github.com:owner/repo", false, &sysroot);
+  let other_mounts = build_cache_mounts(&tasks[0], "https://github.com/owner/other", true, &sysroot);
+  assert_eq!(https_mounts[0].dir, ssh_mounts[0].dir);
+  assert_ne!(https_mounts[0].dir, other_mounts[0].dir);
+  assert!(https_mounts[0].dir.starts_with(tmp_dir.path().join("build_caches")));
+  assert!(https_mounts[0].dir.ends_with("python3/pip"));
+  assert!(!https_mounts[0].read_only);
+  assert!(ssh_mounts[0].read_only);
+}
+
+#[test]
+fn build_caches_in_use_are_not_emptied() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let tasks = _taskspecs(VALID[1].as_bytes(), None, &sysroot, false).unwrap();
+  let remote_url = "https://github.com/owner/repo";
+  let mut mounts = build_cache_mounts(&tasks[0], remote_url, true, &sysroot);
+  prepare_build_caches(&mut mounts, remote_url, None).unwrap();
+  let data_path = mounts[0].dir.join("data.bin");
+  fs::write(&data_path, b"0123456789").unwrap();
+  let mut other_mounts = build_cache_mounts(&tasks[0], remote_url, true, &sysroot);
+  prepare_build_caches(&mut other_mounts, remote_url, Some(1)).unwrap();
+  assert!(data_path.exists());
+  drop(mounts);
+  drop(other_mounts);
+  let mut mounts = build_cache_mounts(&tasks[0], remote_url, true, &sysroot);
+  prepare_build_caches(&mut mounts, remote_url, Some(1)).unwrap();
+  assert!(mounts[0].dir.is_dir());
+  assert!(!data_path.exists());
+}
+
+#[test]
+fn mutable_cache_ops() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let checkout_dir = TempDir::new().unwrap();
+  fs::write(checkout_dir.path().join("data.bin"), b"data").unwrap();
+  let src_path = checkout_dir.path().join("data.bin");
+  let sha256 = file_sha256(&src_path).unwrap();
+  let url = serve_http(DATA_RESPONSE, 1);
+  let out = format!("#-guppy:v0.mutable_cache:append d/copy.bin copy_once data.bin
+#-guppy:v0.mutable_cache:append link.bin symlink_once d/copy.bin
+#-guppy:v0.mutable_cache:append fetched.bin fetch_once {} sha256={}
+#-guppy:v0.mutable_cache:append outside.bin copy_once ../data.bin
+#-guppy:v0.mutable_cache:append local.bin fetch_once file://{}
+#-guppy:v0.mutable_cache:append fetched.bin fetch_once {} sha256={}
+", url, sha256, src_path.display(), url, "0".repeat(64));
+  let e = _taskspecs(out.as_bytes(), Some(checkout_dir.path()), &sysroot, false).unwrap_err();
+  let err_line_nrs: Vec<_> = e.errors.iter().map(|err| err.line_nr).collect();
+  assert_eq!(err_line_nrs, vec![4, 5, 6], "{}", e.message);
+  let cache_dir = tmp_dir.path().join("mutable_cache");
+  assert_eq!(fs::read(cache_dir.join("d/copy.bin")).unwrap(), b"data");
+  assert_eq!(fs::read_link(cache_dir.join("link.bin")).unwrap(), Path::new("d/copy.bin"));
+  assert_eq!(fs::read(cache_dir.join("link.bin")).unwrap(), b"data");
+  assert_eq!(fs::read(cache_dir.join("fetched.bin")).unwrap(), b"data");
+  assert!(!cache_dir.join("outside.bin").exists());
+  assert!(!cache_dir.join("local.bin").exists());
+}
+
+#[test]
+fn mutable_cache_fetch_refuses_redirects_off_http() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let url = serve_http(b"HTTP/1.1 302 Found\r\nLocation: file:///etc/passwd\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", 3);
+  let out = format!("#-guppy:v0.mutable_cache:append passwd fetch_once {}\n", url);
+  let e = _taskspecs(out.as_bytes(), None, &sysroot, false).unwrap_err();
+  assert!(e.message.contains("unsupported url scheme"), "{}", e.message);
+  assert!(!tmp_dir.path().join("mutable_cache/passwd").exists());
+}
+
+#[test]
+fn mutable_cache_symlinks_stay_in_the_cache() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let checkout_dir = TempDir::new().unwrap();
+  fs::write(checkout_dir.path().join("data.bin"), b"data").unwrap();
+  // `x/y/a` links to `../../t`, so that `x/y/a/escape`, linking with three
+  // `..` for its apparent depth, would end up two levels above the cache.
+  let out = "#-guppy:v0.mutable_cache:append x/y/a symlink_once t
+#-guppy:v0.mutable_cache:append t/data.bin copy_once data.bin
+#-guppy:v0.mutable_cache:append x/y/a/escape symlink_once data.bin
+#-guppy:v0.mutable_cache:append x/y/a/copy.bin copy_once data.bin
+#-guppy:v0.mutable_cache:append dir symlink_once t
+#-guppy:v0.mutable_cache:append t fetch_once https://example.com/data.bin
+";
+  let e = _taskspecs(out.as_bytes(), Some(checkout_dir.path()), &sysroot, false).unwrap_err();
+  let err_line_nrs: Vec<_> = e.errors.iter().map(|err| err.line_nr).collect();
+  assert_eq!(err_line_nrs, vec![3, 4, 5, 6], "{}", e.message);
+  let cache_dir = tmp_dir.path().join("mutable_cache");
+  assert_eq!(fs::read_dir(cache_dir.join("t")).unwrap().count(), 1);
+  assert!(fs::symlink_metadata(cache_dir.join("dir")).is_err());
+  assert!(fs::symlink_metadata(cache_dir.join("t/escape")).is_err());
+}
+
+#[test]
+fn taskfiles_must_be_regular_files_in_the_checkout() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let outside_dir = TempDir::new().unwrap();
+  fs::write(outside_dir.path().join("gup.toml"), b"").unwrap();
+  let checkout_dir = TempDir::new().unwrap();
+  let path = checkout_dir.path().join("gup.toml");
+  std::os::unix::fs::symlink(outside_dir.path().join("gup.toml"), &path).unwrap();
+  assert!(find_taskfile(checkout_dir.path()).is_err());
+  assert!(load_taskfile(&path, &sysroot, true).is_err());
+  fs::remove_file(&path).unwrap();
+  fs::write(&path, vec![b'#'; MAX_SPEC_OUTPUT as usize + 1]).unwrap();
+  assert_eq!(find_taskfile(checkout_dir.path()).unwrap(), Some(path.clone()));
+  let e = load_taskfile(&path, &sysroot, true).unwrap_err();
+  assert!(e.message.contains("bytes long"), "{}", e.message);
+}
+
+#[test]
+fn dry_run_leaves_mutable_cache_alone() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let checkout_dir = TempDir::new().unwrap();
+  fs::write(checkout_dir.path().join("data.bin"), b"data").unwrap();
+  let out = "#-guppy:v0.mutable_cache:append d/copy.bin copy_once data.bin
+#-guppy:v0.mutable_cache:append link.bin symlink_once d/copy.bin
+#-guppy:v0.mutable_cache:append fetched.bin fetch_once https://example.com/data.bin
+#-guppy:v0.mutable_cache:append outside.bin copy_once ../data.bin
+#-guppy:v0.mutable_cache:append self.bin symlink_once self.bin
+";
+  let e = _taskspecs(out.as_bytes(), Some(checkout_dir.path()), &sysroot, true).unwrap_err();
+  let err_line_nrs: Vec<_> = e.errors.iter().map(|err| err.line_nr).collect();
+  assert_eq!(err_line_nrs, vec![4, 5], "{}", e.message);
+  assert!(!tmp_dir.path().join("mutable_cache").exists());
+  assert!(!tmp_dir.path().join("mutable_cache.json").exists());
+}
+
+#[test]
+fn mutable_cache_index_evicts_lru() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let checkout_dir = TempDir::new().unwrap();
+  fs::write(checkout_dir.path().join("data.bin"), b"data").unwrap();
+  let src_path = checkout_dir.path().join("data.bin");
+  let url = serve_http(DATA_RESPONSE, 2);
+  let out = format!("#-guppy:v0.mutable_cache:append d/copy.bin copy_once data.bin
+#-guppy:v0.mutable_cache:append link.bin symlink_once d/copy.bin
+#-guppy:v0.mutable_cache:append one.bin fetch_once {}
+", url);
+  _taskspecs(out.as_bytes(), Some(checkout_dir.path()), &sysroot, false).unwrap();
+  let mut index = CacheIndex::load(&sysroot).unwrap();
+  let paths: Vec<_> = index.entries.keys().map(|path| path.as_str()).collect();
+  assert_eq!(paths, vec!["d/copy.bin", "link.bin", "one.bin"]);
+  assert_eq!(index.total_size(), 8);
+  assert_eq!(index.entries["one.bin"].sha256, Some(file_sha256(&src_path).unwrap()));
+  for path in ["d/copy.bin", "link.bin", "one.bin"].iter() {
+    index.verify(path, &sysroot).unwrap();
+  }
+  // The copy is the least recently used; evicting it takes its link along.
+  index.entries.get_mut("d/copy.bin").unwrap().last_used = 1;
+  index.entries.get_mut("one.bin").unwrap().last_used = 2;
+  index.save(&sysroot).unwrap();
+  set_mutable_cache_quota(&sysroot, Some(4)).unwrap();
+  let out = format!("#-guppy:v0.mutable_cache:append two.bin fetch_once {}\n", url);
+  _taskspecs(out.as_bytes(), Some(checkout_dir.path()), &sysroot, false).unwrap();
+  let index = CacheIndex::load(&sysroot).unwrap();
+  let paths: Vec<_> = index.entries.keys().map(|path| path.as_str()).collect();
+  assert_eq!(paths, vec!["one.bin", "two.bin"]);
+  let cache_dir = tmp_dir.path().join("mutable_cache");
+  assert!(!cache_dir.join("d/copy.bin").exists());
+  assert!(fs::symlink_metadata(cache_dir.join("link.bin")).is_err());
+}
+
+#[test]
+fn mutable_cache_never_evicts_entries_of_the_same_spec() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  set_mutable_cache_quota(&sysroot, Some(3)).unwrap();
+  let url = serve_http(DATA_RESPONSE, 2);
+  let out = format!("#-guppy:v0.mutable_cache:append one.bin fetch_once {}
+#-guppy:v0.mutable_cache:append link.bin symlink_once one.bin
+#-guppy:v0.mutable_cache:append two.bin fetch_once {}
+", url, url);
+  _taskspecs(out.as_bytes(), None, &sysroot, false).unwrap();
+  let index = CacheIndex::load(&sysroot).unwrap();
+  let paths: Vec<_> = index.entries.keys().map(|path| path.as_str()).collect();
+  assert_eq!(paths, vec!["link.bin", "one.bin", "two.bin"]);
+  assert_eq!(index.total_size(), 8);
+}
+
+#[test]
+fn invalid_corpus_reports_every_error() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  for &(out, line_nrs) in INVALID.iter() {
+    let e = match _taskspecs(out, None, &sysroot, false) {
+      Err(e) => e,
+      Ok(_) => panic!("parsed invalid output {:?}", out),
+    };
+    let err_line_nrs: Vec<_> = e.errors.iter().map(|err| err.line_nr).collect();
+    assert_eq!(err_line_nrs, line_nrs, "wrong errors for {:?}: {}", out, e.message);
+    assert_eq!(e.line_nr, line_nrs.first().cloned());
+  }
+}
+
+#[test]
+fn invalid_directive_reports_expected_form() {
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let out = "#-guppy:v0.task:begin\n#-guppy:v0.task:require_distro ubuntu\n#-guppy:v0.task:end\n";
+  let e = _taskspecs(out.as_bytes(), None, &sysroot, false).unwrap_err();
+  assert_eq!(e.errors[0].line_nr, 2);
+  assert_eq!(e.errors[0].line, "#-guppy:v0.task:require_distro ubuntu");
+  assert_eq!(e.errors[0].expected, Some("#-guppy:v0.task:require_distro <DISTRO> <VERSION>"));
+}
+
+/// Xorshift, so that the mutated corpus is the same on every run.
+struct Rng(u64);
+
+impl Rng {
+  fn next(&mut self) -> u64 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    self.0
+  }
+
+  fn below(&mut self, n: usize) -> usize {
+    (self.next() % n as u64) as usize
+  }
+}
+
+#[test]
+fn mutated_corpus_never_panics() {
+  const INTERESTING: &'static [u8] = b"#-:= ,\n\r\t.*0\xff";
+  let tmp_dir = TempDir::new().unwrap();
+  let sysroot = test_sysroot(&tmp_dir);
+  let mut seeds: Vec<&[u8]> = VALID.iter().map(|out| out.as_bytes()).collect();
+  seeds.extend(INVALID.iter().map(|&(out, _)| out));
+  let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+  for _ in 0 .. 5000 {
+    let mut out = seeds[rng.below(seeds.len())].to_vec();
+    for _ in 0 .. 1 + rng.below(4) {
+      if out.is_empty() {
+        break;
+      }
+      let pos = rng.below(out.len());
+      match rng.below(4) {
+        0 => out.truncate(pos),
+        1 => {
+          out.remove(pos);
+        }
+        2 => out[pos] = INTERESTING[rng.below(INTERESTING.len())],
+        _ => {
+          let byte = INTERESTING[rng.below(INTERESTING.len())];
+          out.insert(pos, byte);
+        }
+      }
+    }
+    // Only panics matter here; most of these are expected to fail.
+    let _ = _taskspecs(&out, None, &sysroot, false);
+  }
+}
@@ -0,0 +1,6 @@
+extern crate git_version;
+extern crate guppy_registry_dev;
+
+fn main() {
+  guppy_registry_dev::run_main(git_version::GIT_HEAD_COMMIT_HASH);
+}